@@ -10,8 +10,9 @@ use core::fmt;
 use std::{
     convert::Infallible,
     fmt::Display,
+    io::{self, Read},
     num::NonZeroUsize,
-    ops::RangeBounds,
+    ops::{Deref, RangeBounds},
     str::{self, FromStr},
     sync::Arc,
 };
@@ -40,12 +41,7 @@ pub struct AppendOnlyStr {
 impl std::fmt::Debug for AppendOnlyStr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AppendOnlyStr")
-            .field(
-                "data",
-                &&*self
-                    .str_slice(..)
-                    .expect("A full slice should always valid"),
-            )
+            .field("data", &self.get_str())
             .field("len", &self.len)
             .finish()
     }
@@ -53,13 +49,21 @@ impl std::fmt::Debug for AppendOnlyStr {
 
 impl Display for AppendOnlyStr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            self.str_slice(..)
-                .expect("A full slice should always valid")
-                .as_str()
-        )
+        f.write_str(self)
+    }
+}
+
+impl Deref for AppendOnlyStr {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.get_str()
+    }
+}
+
+impl AsRef<str> for AppendOnlyStr {
+    fn as_ref(&self) -> &str {
+        self.get_str()
     }
 }
 
@@ -111,10 +115,54 @@ impl AppendOnlyStr {
         })
     }
 
+    /// Reads everything from `read` directly into a new buffer. The data is read in chunks and
+    /// validated as it comes in, so no intermediate `String` is needed
+    /// # Errors
+    /// - Reading failed
+    /// - The data isn't valid utf-8
+    pub fn from_reader<R: Read>(mut read: R) -> io::Result<Self> {
+        const CHUNK_SIZE: usize = 8192;
+        let mut ret = Self::new();
+        let mut buf = [0; CHUNK_SIZE];
+        // The amount of bytes at the start of `buf` that are part of a char which was split by the
+        // end of the previous chunk
+        let mut carried = 0;
+        loop {
+            let amount = match read.read(&mut buf[carried..]) {
+                Ok(0) => break,
+                Ok(amount) => amount,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let filled = carried + amount;
+            let valid = match str::from_utf8(&buf[..filled]) {
+                Ok(_) => filled,
+                // The chunk ended in the middle of a char
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            ret.reserve(valid);
+            // SAFETY:
+            // We just reserved enough space, and the bytes were checked to be valid utf-8
+            unsafe {
+                ret.write_unchecked(&buf[..valid]);
+            }
+            buf.copy_within(valid..filled, 0);
+            carried = filled - valid;
+        }
+        if carried != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The data ended in the middle of a char",
+            ));
+        }
+        Ok(ret)
+    }
+
     /// Appends a single character to the buffer
-    pub fn push(c: char) {
+    pub fn push(&mut self, c: char) {
         let mut buf = [0; 4];
-        c.encode_utf8(&mut buf);
+        self.push_str(c.encode_utf8(&mut buf));
     }
 
     /// Guarantees that the buffer will be
@@ -232,19 +280,27 @@ impl AppendOnlyStr {
         })
     }
 
-    /// # Panics
-    /// This function panics during debug builds to check for valid utf-8 even though they should
-    /// always be valid
+    /// Creates a string slice referring to that place in memory. Like `slice` this stays valid
+    /// after the buffer has been reallocated.
+    ///
+    /// Returns None if the range is out of bounds, or either end of it isn't on a char boundary
     pub fn str_slice(&self, range: impl RangeBounds<usize> + fmt::Debug) -> Option<StrSlice> {
         let byteslice = self.slice(range)?;
-        debug_assert!(
-            str::from_utf8(&byteslice).is_ok(),
-            "{:?}",
-            byteslice.as_bytes()
-        );
+        if !self.is_char_boundary(byteslice.start) || !self.is_char_boundary(byteslice.end) {
+            return None;
+        }
         Some(StrSlice { byteslice })
     }
 
+    /// Whether `index` is at the start of a char or the end of the buffer. Only the byte at
+    /// `index` is looked at, rather than validating the entire buffer
+    fn is_char_boundary(&self, index: usize) -> bool {
+        // Continuation bytes of a char are the only ones starting with 0b10
+        self.get_byte_slice()
+            .get(index)
+            .is_none_or(|byte| byte & 0xC0 != 0x80)
+    }
+
     /// Creates a string slice pointing at the end of the buffer
     #[must_use]
     pub fn str_slice_end(&self) -> StrSlice {
@@ -262,7 +318,10 @@ unsafe impl Send for AppendOnlyStr {}
 
 #[cfg(test)]
 mod test {
-    use std::str::FromStr;
+    use std::{
+        io::{self, Read},
+        str::FromStr,
+    };
 
     use crate::AppendOnlyStr;
 
@@ -283,4 +342,81 @@ mod test {
     fn zero_size_alloc() {
         let _ = AppendOnlyStr::from_str("");
     }
+
+    #[test]
+    fn push_multibyte() {
+        let mut val = AppendOnlyStr::from_str("a").unwrap();
+        val.push('é');
+        val.push('🦀');
+        assert_eq!(val.len(), 1 + 2 + 4);
+        assert_eq!(&*val.slice(1..3).unwrap(), "é".as_bytes());
+        assert_eq!(&*val.slice(3..).unwrap(), "🦀".as_bytes());
+        assert_eq!(val.str_slice(..).unwrap().as_str(), "aé🦀");
+    }
+
+    /// Reads a single byte at the time, so that every multi-byte char gets split
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn from_reader() {
+        let text = "aé🦀\nb".repeat(3000);
+        let val = AppendOnlyStr::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(&*val, text);
+        let val = AppendOnlyStr::from_reader(Trickle(text.as_bytes())).unwrap();
+        assert_eq!(&*val, text);
+        assert!(AppendOnlyStr::from_reader(&b""[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn from_reader_invalid() {
+        let err = AppendOnlyStr::from_reader(&b"ab\xffcd"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let truncated = &"a🦀".as_bytes()[..3];
+        let err = AppendOnlyStr::from_reader(Trickle(truncated)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn out_of_range_slices() {
+        let val = AppendOnlyStr::from_str("aé").unwrap();
+        assert!(val.slice(0..4).is_none());
+        let (start, end) = (3, 2);
+        assert!(val.slice(start..end).is_none());
+        assert!(val.slice(..=usize::MAX).is_none());
+        assert!(val.str_slice(0..4).is_none());
+        assert!(val.str_slice(0..2).is_none());
+        assert!(val.str_slice(2..).is_none());
+
+        let slice = val.str_slice(1..).unwrap();
+        assert!(slice.subslice(..1).is_none());
+        assert!(slice.subslice(..3).is_none());
+        assert_eq!(slice.subslice(..2).unwrap().as_str(), "é");
+
+        let bytes = val.slice(1..3).unwrap();
+        assert!(bytes.subslice(..3).is_none());
+        assert_eq!(&*bytes.subslice(1..).unwrap(), &"é".as_bytes()[1..]);
+    }
+
+    #[test]
+    fn deref_to_str() {
+        let mut val = AppendOnlyStr::from_str("andy").unwrap();
+        val.push_str("son\n");
+        assert!(val.starts_with("andys"));
+        assert_eq!(val.trim_end(), "andyson");
+        assert_eq!(val.find('s'), Some(4));
+        let as_ref: &str = val.as_ref();
+        assert_eq!(as_ref, "andyson\n");
+        assert_eq!(&*AppendOnlyStr::new(), "");
+    }
 }
@@ -66,14 +66,15 @@ impl ByteSlice {
         }
     }
 
-    /// Creates a new `ByteSlice` with a range within the current range
+    /// Creates a new `ByteSlice` with a range within the current range.
+    /// Returns None if the range doesn't fit within the current range
     #[must_use]
     pub fn subslice(&self, range: impl RangeBounds<usize>) -> Option<Self> {
         let (start, end) = get_range(range, 0, self.len())?;
         Some(Self {
             raw: Arc::clone(&self.raw),
-            start,
-            end,
+            start: self.start + start,
+            end: self.start + end,
         })
     }
 }
@@ -205,7 +206,9 @@ impl StrSlice {
     /// returns None if the index is at a char boundary or if an invalid range is given
     pub fn subslice(&self, range: impl RangeBounds<usize>) -> Option<Self> {
         let (relative_start, relative_end) = get_range(range, 0, self.len())?;
-        if !self.as_str().is_char_boundary(relative_start) {
+        if !self.as_str().is_char_boundary(relative_start)
+            || !self.as_str().is_char_boundary(relative_end)
+        {
             return None;
         }
         Some(Self {
@@ -250,11 +253,11 @@ pub(crate) fn get_range(
 ) -> Option<(usize, usize)> {
     let start = match range.start_bound() {
         Bound::Included(&v) => v,
-        Bound::Excluded(&v) => v + 1,
+        Bound::Excluded(&v) => v.checked_add(1)?,
         Bound::Unbounded => min_len,
     };
     let end = match range.end_bound() {
-        Bound::Included(&v) => v + 1,
+        Bound::Included(&v) => v.checked_add(1)?,
         Bound::Excluded(&v) => v,
         Bound::Unbounded => max_len,
     };
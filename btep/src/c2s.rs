@@ -1,10 +1,9 @@
 //! mdoule for client updates sendt to the server
 
-use std::{io, path::PathBuf, str::FromStr};
+use std::{io, path::PathBuf};
 
 use crossterm::style::Color;
 use tokio::io::AsyncReadExt;
-use utils::other::CursorPos;
 
 use crate::{Deserialize, Serialize};
 
@@ -18,10 +17,9 @@ pub enum C2S {
     Backspace(usize),
     /// The client pressed enter
     Enter,
-    /// The client pressed entered insert mode at a position
-    // TODO: this should use the `EnterInsert` instead which should be more immune to server-client
-    // desync
-    EnterInsert(CursorPos),
+    /// The client entered insert mode. The position is given relative to a piece, which is more
+    /// immune to server-client desync than a cursor position
+    EnterInsert(EnterInsert),
     /// A client left insert mode
     ExitInsert,
     /// Force a save to happen
@@ -30,34 +28,71 @@ pub enum C2S {
     Path(PathBuf),
     /// Sets the desired color of the client
     SetColor(Color),
+    /// Tells the server that the client is still alive. The server answers with a `S2C::Pong`
+    Ping,
+    /// Renames the file at the first path to the second path. This is sent instead of a `Path`
+    /// and the server answers with the new content of the folder, or `S2C::Rejected`
+    Rename((PathBuf, PathBuf)),
+    /// Creates a new empty file. Like `Rename` this is sent instead of a `Path`
+    CreateFile(PathBuf),
+    /// The client wrote a run of text at once, like when typing fast or pasting. This is the same
+    /// as sending a `Char` for every char
+    Str(String),
 }
 
-// #[derive(Clone, Copy, Debug)]
-// /// A representation of entering insert mode which shuold be more accurate than just sending the
-// /// clients cursors position
-// pub struct EnterInsert {
-//     /// The id of the buffer that was split
-//     pub id: usize,
-//     /// The offset in that buffer
-//     /// Option because appending a character has special behaviour
-//     pub offset: Option<usize>,
-// }
-//
-// impl Serialize for EnterInsert {
-//     fn serialize(&self) -> VecDeque<u8> {
-//         todo!()
-//     }
-// }
-//
-// impl Deserialize for EnterInsert {
-//     async fn deserialize<T>(_data: &mut T) -> io::Result<Self>
-//     where
-//         T: AsyncReadExt,
-//         Self: Sized,
-//     {
-//         todo!()
-//     }
-// }
+impl C2S {
+    /// The name of the kind of action, without any of the data it holds
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Char(_) => "char",
+            Self::Backspace(_) => "backspace",
+            Self::Enter => "enter",
+            Self::EnterInsert(_) => "enter_insert",
+            Self::ExitInsert => "exit_insert",
+            Self::Save => "save",
+            Self::Path(_) => "path",
+            Self::SetColor(_) => "set_color",
+            Self::Ping => "ping",
+            Self::Rename(_) => "rename",
+            Self::CreateFile(_) => "create_file",
+            Self::Str(_) => "str",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A representation of entering insert mode which shuold be more accurate than just sending the
+/// clients cursors position
+pub struct EnterInsert {
+    /// The id of the piece that was split
+    pub id: usize,
+    /// The offset in that piece. Parts of a piece that got split keep its id, so this stays
+    /// valid for clients that saw the piece get split before they saw this
+    /// Option because appending to the end of the text doesn't split anything. The id is unused
+    /// in that case
+    pub offset: Option<usize>,
+}
+
+impl Serialize for EnterInsert {
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.id.serialize();
+        ret.extend(self.offset.serialize());
+        ret
+    }
+}
+
+impl Deserialize for EnterInsert {
+    async fn deserialize<T>(data: &mut T) -> io::Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send,
+        Self: Sized,
+    {
+        let id = usize::deserialize(data).await?;
+        let offset = Option::deserialize(data).await?;
+        Ok(Self { id, offset })
+    }
+}
 
 impl Serialize for C2S {
     fn serialize(&self) -> Vec<u8> {
@@ -74,6 +109,27 @@ impl Serialize for C2S {
                 .collect(),
             Self::Enter => [10].into(),
             Self::SetColor(color) => std::iter::once(11).chain(color.serialize()).collect(),
+            Self::Ping => [12].into(),
+            Self::Rename((from, to)) => std::iter::once(13)
+                .chain(
+                    from.to_str()
+                        .expect("The os doesn't use utf-8 paths for some reason")
+                        .serialize(),
+                )
+                .chain(
+                    to.to_str()
+                        .expect("The os doesn't use utf-8 paths for some reason")
+                        .serialize(),
+                )
+                .collect(),
+            Self::CreateFile(path) => std::iter::once(14)
+                .chain(
+                    path.to_str()
+                        .expect("The os doesn't use utf-8 paths for some reason")
+                        .serialize(),
+                )
+                .collect(),
+            Self::Str(text) => std::iter::once(15).chain(text.serialize()).collect(),
         }
     }
 }
@@ -85,17 +141,28 @@ impl Deserialize for C2S {
         Self: Sized,
     {
         Ok(match data.read_u8().await? {
-            1 => Self::Char(
-                char::from_u32(data.read_u32().await?).expect("An invalid char was supplied"),
-            ),
-            2 => Self::EnterInsert(CursorPos::deserialize(data).await?),
+            1 => Self::Char(char::deserialize(data).await?),
+            2 => Self::EnterInsert(EnterInsert::deserialize(data).await?),
             3 => Self::Save,
             4 => Self::ExitInsert,
-            5 => Self::Path(PathBuf::from_str(&String::deserialize(data).await?).unwrap()),
+            5 => Self::Path(PathBuf::from(String::deserialize(data).await?)),
             8 => Self::Backspace(data.read_u64().await? as usize),
             10 => Self::Enter,
             11 => Self::SetColor(Color::deserialize(data).await?),
-            x => unreachable!("{x}"),
+            12 => Self::Ping,
+            13 => {
+                let from = PathBuf::from(String::deserialize(data).await?);
+                let to = PathBuf::from(String::deserialize(data).await?);
+                Self::Rename((from, to))
+            }
+            14 => Self::CreateFile(PathBuf::from(String::deserialize(data).await?)),
+            15 => Self::Str(String::deserialize(data).await?),
+            x => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("An invalid specifier was found ({x})"),
+                ))
+            }
         })
     }
 }
@@ -0,0 +1,356 @@
+//! Framing for payloads that are too large to comfortably send in one go.
+//!
+//! A chunked payload is a series of chunks, each prefixed with its length as a big-endian `u32`.
+//! The payload is terminated by an empty chunk, and no chunk is longer than [`BUFFER_SIZE`].
+use std::{
+    cmp,
+    io::{self, Write},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::BUFFER_SIZE;
+
+/// Frames `data` as a chunked payload
+/// # Panics
+/// Shouldn't panic as writing to a `Vec` can't fail
+#[must_use]
+pub fn chunk(data: &[u8]) -> Vec<u8> {
+    let mut writer = ChunkWriter::new(Vec::with_capacity(
+        data.len() + (data.len() / BUFFER_SIZE + 2) * 4,
+    ));
+    writer
+        .write_all(data)
+        .expect("Writing to a Vec can't fail");
+    writer.finish().expect("Writing to a Vec can't fail")
+}
+
+/// Frames everything written to it as a chunked payload, which is written to the inner writer a
+/// chunk at a time. The payload is only terminated by calling `finish`
+pub struct ChunkWriter<W> {
+    inner: W,
+    /// The part of the current chunk that hasn't been written yet
+    buf: Vec<u8>,
+}
+
+impl<W> ChunkWriter<W>
+where
+    W: Write,
+{
+    /// Starts writing a chunked payload to `inner`
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(BUFFER_SIZE),
+        }
+    }
+
+    /// Writes the current chunk, if there is anything in it
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.inner.write_all(&(self.buf.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes what is left along with the terminating chunk, and gives back the inner writer
+    /// # Errors
+    /// - Failing to write to the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        Ok(self.inner)
+    }
+}
+
+impl<W> Write for ChunkWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == BUFFER_SIZE {
+            self.write_chunk()?;
+        }
+        let amount = cmp::min(data.len(), BUFFER_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..amount]);
+        Ok(amount)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+/// The async counterpart of `ChunkWriter`. Every chunk is written to the inner writer as soon as
+/// it is full, so no more than a single chunk is kept around no matter how large the payload is.
+/// The payload is only terminated by calling `finish`
+pub struct AsyncChunkWriter<'a, W> {
+    inner: &'a mut W,
+    /// The part of the current chunk that hasn't been written yet
+    buf: Vec<u8>,
+}
+
+impl<'a, W> AsyncChunkWriter<'a, W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Starts writing a chunked payload to `inner`
+    pub fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(BUFFER_SIZE),
+        }
+    }
+
+    /// Writes the current chunk, if there is anything in it
+    async fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.inner.write_u32(self.buf.len() as u32).await?;
+        self.inner.write_all(&self.buf).await?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Adds `data` to the payload, writing every chunk that fills up along the way
+    /// # Errors
+    /// - Failing to write to the inner writer
+    pub async fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.buf.len() == BUFFER_SIZE {
+                self.write_chunk().await?;
+            }
+            let amount = cmp::min(data.len(), BUFFER_SIZE - self.buf.len());
+            self.buf.extend_from_slice(&data[..amount]);
+            data = &data[amount..];
+        }
+        Ok(())
+    }
+
+    /// Writes what is left along with the terminating chunk. This does not flush the inner writer
+    /// # Errors
+    /// - Failing to write to the inner writer
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.write_chunk().await?;
+        self.inner.write_u32(0).await
+    }
+}
+
+/// Reads a chunked payload as if it were one continuous stream.
+/// Reaching the terminating chunk is treated as EOF
+pub struct ChunkReader<'a, R> {
+    inner: &'a mut R,
+    /// The amount of bytes left in the current chunk
+    remaining: usize,
+    /// The length prefix of the next chunk. It might arrive over multiple reads
+    header: [u8; 4],
+    header_len: usize,
+    finished: bool,
+}
+
+impl<'a, R> ChunkReader<'a, R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Starts reading a chunked payload from `inner`
+    pub fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            header: [0; 4],
+            header_len: 0,
+            finished: false,
+        }
+    }
+
+    /// Consumes the rest of the payload including the terminating chunk.
+    /// # Errors
+    /// - There was unread data left in the payload
+    /// - Failing to read from the inner reader
+    pub async fn finish(mut self) -> io::Result<()> {
+        let mut buf = [0; 64];
+        if self.read(&mut buf).await? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The chunked payload contained trailing data",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R> AsyncRead for ChunkReader<'_, R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.remaining == 0 {
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+            let mut header = ReadBuf::new(&mut this.header[this.header_len..]);
+            ready!(Pin::new(&mut *this.inner).poll_read(cx, &mut header))?;
+            let read = header.filled().len();
+            if read == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.header_len += read;
+            if this.header_len == this.header.len() {
+                this.header_len = 0;
+                this.remaining = u32::from_be_bytes(this.header) as usize;
+                this.finished = this.remaining == 0;
+            }
+        }
+
+        let max = cmp::min(buf.remaining(), this.remaining);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        if read == 0 && max != 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        buf.advance(read);
+        this.remaining -= read;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::{executor::block_on, join};
+    use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+    use super::{chunk, AsyncChunkWriter, ChunkReader};
+    use crate::BUFFER_SIZE;
+
+    /// A reader which only ever hands out a single byte at a time, and is pending every other
+    /// time it's polled
+    struct Trickle<R> {
+        inner: R,
+        pending: bool,
+    }
+
+    impl<R> AsyncRead for Trickle<R>
+    where
+        R: AsyncRead + Unpin,
+    {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let mut byte = [0];
+            let mut one = ReadBuf::new(&mut byte);
+            let ret = Pin::new(&mut self.inner).poll_read(cx, &mut one);
+            buf.put_slice(one.filled());
+            ret
+        }
+    }
+
+    fn payload() -> Vec<u8> {
+        (0..BUFFER_SIZE * 3 + 17).map(|x| (x % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = payload();
+        let framed = chunk(&data);
+        let mut slice = &framed[..];
+        let mut reader = ChunkReader::new(&mut slice);
+        let mut ret = Vec::new();
+        block_on(reader.read_to_end(&mut ret)).unwrap();
+        assert_eq!(ret, data);
+        assert!(slice.is_empty());
+    }
+
+    #[test]
+    fn empty_payload() {
+        let framed = chunk(&[]);
+        assert_eq!(framed, [0; 4]);
+        let mut slice = &framed[..];
+        let mut ret = Vec::new();
+        block_on(ChunkReader::new(&mut slice).read_to_end(&mut ret)).unwrap();
+        assert!(ret.is_empty());
+    }
+
+    #[test]
+    fn slow_reader() {
+        let data = payload();
+        let framed = chunk(&data);
+        // The pipe is far smaller than a chunk, so the writer has to wait for the reader
+        let (mut writer, reader) = duplex(16);
+        let mut reader = Trickle {
+            inner: reader,
+            pending: false,
+        };
+        let (written, read) = block_on(async {
+            join!(writer.write_all(&framed), async {
+                let mut chunks = ChunkReader::new(&mut reader);
+                let mut ret = vec![0; data.len()];
+                chunks.read_exact(&mut ret).await?;
+                chunks.finish().await?;
+                Ok::<_, io::Error>(ret)
+            })
+        });
+        written.unwrap();
+        assert_eq!(read.unwrap(), data);
+    }
+
+    #[test]
+    fn async_writer() {
+        let data = payload();
+        let mut framed = Vec::new();
+        block_on(async {
+            let mut writer = AsyncChunkWriter::new(&mut framed);
+            // Written in uneven parts, which still have to end up in full chunks
+            for part in data.chunks(1000) {
+                writer.write_all(part).await?;
+            }
+            writer.finish().await
+        })
+        .unwrap();
+        assert_eq!(framed, chunk(&data));
+    }
+
+    #[test]
+    fn trailing_data() {
+        let framed = chunk(b"andy");
+        let mut slice = &framed[..];
+        let mut reader = ChunkReader::new(&mut slice);
+        let mut buf = [0; 2];
+        block_on(reader.read_exact(&mut buf)).unwrap();
+        assert!(block_on(reader.finish()).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let framed = chunk(b"andy");
+        let mut slice = &framed[..framed.len() - 6];
+        let mut ret = Vec::new();
+        let err = block_on(ChunkReader::new(&mut slice).read_to_end(&mut ret)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
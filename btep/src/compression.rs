@@ -0,0 +1,162 @@
+//! Gzip compression of payloads. This is mainly useful for the initial transfer of a file, which
+//! can get quite large
+use std::{
+    cmp,
+    io::{self, Write},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Compresses everything written to it using gzip, and writes the result to `writer`
+pub fn encoder<W>(writer: W) -> GzEncoder<W>
+where
+    W: Write,
+{
+    GzEncoder::new(writer, Compression::default())
+}
+
+/// The amount of compressed bytes a `Decoder` decompresses at a time
+const INPUT_SIZE: usize = 1024;
+
+/// Decompresses gzip compressed data while it's being read from the inner reader. Only a small
+/// part of the compressed data is decompressed at a time, and reading fails once more than the
+/// maximum amount of bytes have come out of it. That way a tiny payload can't make the reader
+/// allocate huge amounts of memory
+pub struct Decoder<R> {
+    inner: R,
+    decoder: flate2::write::GzDecoder<Capped>,
+    /// How much of the decompressed data has been handed out already
+    pos: usize,
+    /// Whether the inner reader has reached EOF
+    finished: bool,
+}
+
+/// Collects the decompressed data, refusing to take more than `max` bytes in total
+struct Capped {
+    buf: Vec<u8>,
+    written: u64,
+    max: u64,
+}
+
+impl Write for Capped {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.written += data.len() as u64;
+        if self.written > self.max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The payload decompresses into more than {} bytes", self.max),
+            ));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R> Decoder<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Starts decompressing what is read from `inner`, allowing at most `max` bytes to come out
+    pub fn new(inner: R, max: u64) -> Self {
+        Self {
+            inner,
+            decoder: flate2::write::GzDecoder::new(Capped {
+                buf: Vec::new(),
+                written: 0,
+                max,
+            }),
+            pos: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<R> AsyncRead for Decoder<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let decompressed = &mut this.decoder.get_mut().buf;
+            if this.pos < decompressed.len() {
+                let amount = cmp::min(buf.remaining(), decompressed.len() - this.pos);
+                buf.put_slice(&decompressed[this.pos..this.pos + amount]);
+                this.pos += amount;
+                if this.pos == decompressed.len() {
+                    decompressed.clear();
+                    this.pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut input = [0; INPUT_SIZE];
+            let mut input = ReadBuf::new(&mut input);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut input))?;
+            if input.filled().is_empty() {
+                this.decoder.try_finish()?;
+                this.finished = true;
+            } else {
+                this.decoder.write_all(input.filled())?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+
+    use futures::executor::block_on;
+    use tokio::io::AsyncReadExt;
+
+    use super::{encoder, Decoder};
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = encoder(Vec::new());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(data: &[u8], max: u64) -> io::Result<Vec<u8>> {
+        let mut ret = Vec::new();
+        block_on(Decoder::new(data, max).read_to_end(&mut ret))?;
+        Ok(ret)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = "andy\n".repeat(1000);
+        let compressed = compress(data.as_bytes());
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed, 5000).unwrap(), data.as_bytes());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decompress(b"not gzip", 5000).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        // Compresses down to almost nothing
+        let compressed = compress(&vec![0; 1 << 20]);
+        assert!(compressed.len() < 2000);
+        let err = decompress(&compressed, 5000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -4,7 +4,11 @@
 //! This crate implements a custom binary
 //! text transfer protocol.
 pub mod c2s;
+pub mod chunks;
+#[cfg(feature = "compression")]
+pub mod compression;
 pub mod s2c;
+pub mod version;
 
 /// Reexports stuff for easier access
 pub mod prelude {
@@ -13,16 +17,38 @@ pub mod prelude {
 }
 
 use core::str;
-use std::{io, mem};
+use std::{
+    cmp,
+    io::{self, Write},
+    mem,
+};
 
 use crossterm::style::Color;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use utils::other::CursorPos;
 
+/// The size of the buffers used when sending and receiving data
+pub static BUFFER_SIZE: usize = 8192;
+
+/// The most bytes the description of a file in an `S2C::Full` may take up. This bounds both what
+/// it may decompress into and the text of its buffers, whose length isn't sent ahead of them
+pub static MAX_FULL_SIZE: u64 = 1 << 30;
+
 /// A trait allow for serialization into the Btep™ format
 pub trait Serialize {
     /// The method provide by `Serialize`.
     fn serialize(&self) -> Vec<u8>;
+
+    /// Writes the same bytes as `serialize` into `writer`. Large types write themselves piece by
+    /// piece instead of collecting everything first
+    /// # Errors
+    /// - Failing to write to the writer
+    fn serialize_into<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_all(&self.serialize())
+    }
 }
 
 /// `Deserialize` allows for deserialization and is supposed to be the opposite of `Serialize`.
@@ -40,12 +66,38 @@ impl Serialize for usize {
     }
 }
 
+impl Deserialize for usize {
+    async fn deserialize<T>(data: &mut T) -> io::Result<Self>
+    where
+        Self: Sized,
+        T: AsyncReadExt + Unpin + Send,
+    {
+        Ok(data.read_u64().await? as Self)
+    }
+}
+
 impl Serialize for char {
     fn serialize(&self) -> Vec<u8> {
         (*self as u32).to_be_bytes().into()
     }
 }
 
+impl Deserialize for char {
+    async fn deserialize<T>(data: &mut T) -> io::Result<Self>
+    where
+        Self: Sized,
+        T: AsyncReadExt + Unpin + Send,
+    {
+        let val = data.read_u32().await?;
+        char::from_u32(val).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{val:#x} is not a valid char"),
+            )
+        })
+    }
+}
+
 impl Serialize for CursorPos {
     fn serialize(&self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(const { mem::size_of::<u64>() * 2 });
@@ -127,7 +179,12 @@ impl Deserialize for Color {
                 Self::Rgb { r, g, b }
             }
             18 => Self::AnsiValue(data.read_u8().await?),
-            _ => unreachable!(),
+            x => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{x} is not a valid color"),
+                ))
+            }
         })
     }
 }
@@ -156,8 +213,8 @@ where
         R: AsyncReadExt + Unpin + Send,
     {
         let size = data.read_u64().await? as usize;
-        println!("{size}");
-        let mut ret = Self::with_capacity(size);
+        // The size can't be trusted, so we don't preallocate all of it up front
+        let mut ret = Self::with_capacity(cmp::min(size, 1024));
         for _ in 0..size {
             ret.push(T::deserialize(data).await?);
         }
@@ -165,6 +222,39 @@ where
     }
 }
 
+/// `None` is sent as a 0 byte, and `Some` as a 1 byte followed by the value
+impl<T> Serialize for Option<T>
+where
+    T: Serialize,
+{
+    fn serialize(&self) -> Vec<u8> {
+        match self {
+            None => [0].into(),
+            Some(val) => std::iter::once(1).chain(val.serialize()).collect(),
+        }
+    }
+}
+
+impl<T> Deserialize for Option<T>
+where
+    T: Deserialize,
+{
+    async fn deserialize<R>(data: &mut R) -> io::Result<Self>
+    where
+        Self: Sized,
+        R: AsyncReadExt + Unpin + Send,
+    {
+        match data.read_u8().await? {
+            0 => Ok(None),
+            1 => Ok(Some(T::deserialize(data).await?)),
+            x => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{x} is not a valid option specifier"),
+            )),
+        }
+    }
+}
+
 impl<T> Serialize for [T]
 where
     T: Serialize,
@@ -200,16 +290,82 @@ impl Deserialize for String {
         Self: Sized,
         T: AsyncReadExt + Unpin + Send,
     {
-        let len = data.read_u64().await? as usize;
-        let mut buf = vec![0; len];
-        data.read_exact(&mut buf).await?;
-        Ok(String::from_utf8(buf).expect("Invalid utf was sent"))
+        let len = data.read_u64().await?;
+        // The length can't be trusted, so the buffer only grows as data actually arrives
+        let mut buf = Vec::new();
+        (&mut *data).take(len).read_to_end(&mut buf).await?;
+        if buf.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Pairs are sent as the first value followed by the second
+impl<A, B> Serialize for (A, B)
+where
+    A: Serialize,
+    B: Serialize,
+{
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.0.serialize();
+        ret.extend(self.1.serialize());
+        ret
     }
 }
 
+impl<A, B> Deserialize for (A, B)
+where
+    A: Deserialize + Send,
+    B: Deserialize,
+{
+    async fn deserialize<T>(data: &mut T) -> io::Result<Self>
+    where
+        Self: Sized,
+        T: AsyncReadExt + Unpin + Send,
+    {
+        Ok((A::deserialize(data).await?, B::deserialize(data).await?))
+    }
+}
+
+/// Triples are sent as their values in order
+impl<A, B, C> Serialize for (A, B, C)
+where
+    A: Serialize,
+    B: Serialize,
+    C: Serialize,
+{
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.0.serialize();
+        ret.extend(self.1.serialize());
+        ret.extend(self.2.serialize());
+        ret
+    }
+}
+
+impl<A, B, C> Deserialize for (A, B, C)
+where
+    A: Deserialize + Send,
+    B: Deserialize + Send,
+    C: Deserialize,
+{
+    async fn deserialize<T>(data: &mut T) -> io::Result<Self>
+    where
+        Self: Sized,
+        T: AsyncReadExt + Unpin + Send,
+    {
+        Ok((
+            A::deserialize(data).await?,
+            B::deserialize(data).await?,
+            C::deserialize(data).await?,
+        ))
+    }
+}
+
+/// Booleans are sent as a single byte, which is either 0 or 1
 impl Serialize for bool {
     fn serialize(&self) -> Vec<u8> {
-        [if *self { 1 } else { 0 }].into()
+        [u8::from(*self)].into()
     }
 }
 
@@ -219,6 +375,149 @@ impl Deserialize for bool {
         Self: Sized,
         T: AsyncReadExt + Unpin + Send,
     {
-        Ok(data.read_u8().await? != 0)
+        match data.read_u8().await? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{x} is not a valid bool"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crossterm::style::Color;
+    use futures::executor::block_on;
+
+    use crate::{
+        c2s::{EnterInsert, C2S},
+        s2c::S2C,
+        Deserialize, Serialize,
+    };
+
+    /// A xorshift generator, so that the fuzz test is reproducible
+    fn random_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                (*seed >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for i in 0..5000 {
+            let mut data = random_bytes(&mut seed, i % 48);
+            // Make sure that every message kind gets hit
+            if let Some(first) = data.first_mut() {
+                *first %= 16;
+            }
+            let _ = block_on(C2S::deserialize(&mut &data[..]));
+            let _ = block_on(S2C::<String>::deserialize(&mut &data[..]));
+        }
+    }
+
+    #[test]
+    fn bool_round_trip() {
+        for val in [true, false] {
+            let data = val.serialize();
+            assert_eq!(data, [u8::from(val)]);
+            assert_eq!(block_on(bool::deserialize(&mut &data[..])).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn char_round_trip() {
+        for val in ['a', 'é', '🦀', '\0'] {
+            let data = val.serialize();
+            assert_eq!(block_on(char::deserialize(&mut &data[..])).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn invalid_char() {
+        for val in [0xd800u32, 0x11_0000] {
+            let err = block_on(char::deserialize(&mut &val.to_be_bytes()[..])).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn option_round_trip() {
+        let vals: [Option<Vec<String>>; 3] = [
+            None,
+            Some(Vec::new()),
+            Some(vec!["andy".into(), String::new(), "son".into()]),
+        ];
+        for val in vals {
+            let data = val.serialize();
+            assert_eq!(
+                block_on(Option::<Vec<String>>::deserialize(&mut &data[..])).unwrap(),
+                val
+            );
+        }
+        for val in [None, Some(None), Some(Some(true))] {
+            let data = val.serialize();
+            assert_eq!(
+                block_on(Option::<Option<bool>>::deserialize(&mut &data[..])).unwrap(),
+                val
+            );
+        }
+        let err = block_on(Option::<bool>::deserialize(&mut &[2, 1][..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pair_round_trip() {
+        let val = vec![(0, Color::Red), (3, Color::Rgb { r: 1, g: 2, b: 3 })];
+        let data = val.serialize();
+        assert_eq!(
+            block_on(Vec::<(usize, Color)>::deserialize(&mut &data[..])).unwrap(),
+            val
+        );
+    }
+
+    #[test]
+    fn invalid_bool() {
+        let err = block_on(bool::deserialize(&mut &[2][..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn enter_insert_round_trip() {
+        for val in [
+            EnterInsert {
+                id: 3,
+                offset: Some(7),
+            },
+            EnterInsert {
+                id: 0,
+                offset: None,
+            },
+        ] {
+            let data = C2S::EnterInsert(val).serialize();
+            let C2S::EnterInsert(round_trip) = block_on(C2S::deserialize(&mut &data[..])).unwrap()
+            else {
+                panic!("Deserialized into a different message");
+            };
+            assert_eq!(round_trip, val);
+        }
+    }
+
+    #[test]
+    fn str_round_trip() {
+        let data = C2S::Str("multi\nline 🦀".to_string()).serialize();
+        let C2S::Str(round_trip) = block_on(C2S::deserialize(&mut &data[..])).unwrap() else {
+            panic!("Deserialized into a different message");
+        };
+        assert_eq!(round_trip, "multi\nline 🦀");
     }
 }
@@ -5,26 +5,56 @@ use std::{
     fs::{self, DirEntry},
     io, mem,
 };
-use tokio::io::AsyncReadExt;
-use {crate::c2s::C2S, crate::Deserialize, crate::Serialize};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use {
+    crate::c2s::C2S,
+    crate::chunks::{AsyncChunkWriter, ChunkReader, ChunkWriter},
+    crate::Deserialize,
+    crate::Serialize,
+};
 
 /// S2C or Server to Client
 /// Encodes information that originates from the server and sendt to the client
 pub enum S2C<T> {
     /// Sends a full description of the layout of a file.
-    Full(T),
-    Folder(Vec<Inhabitant>),
+    /// The `bool` signifies whether the file is read-only.
+    /// The description itself is sent in chunks, see [`crate::chunks`], and is preceded by a byte
+    /// telling whether it has been compressed, which is only done when both sides support it. See
+    /// [`crate::version::Features`]. Serializing an `S2C::Full` never compresses it, and
+    /// collects the entire description first. Use a [`FullWriter`] to avoid either
+    Full((T, bool)),
+    /// The contents of a folder. The `bool` signifies whether files starting with a `.` are shown
+    /// by default
+    Folder((Vec<Inhabitant>, bool)),
     /// A client has made an update to their buffer
     Update((usize, C2S)),
-    /// A client has connected with a username and a color
-    NewClient((String, Color)),
+    /// A client has connected with the id the server assigned to it, a username and a color
+    NewClient((usize, String, Color)),
+    /// The server refused to apply the last update sent by the client
+    Rejected,
+    /// The answer to a `C2S::Ping`
+    Pong,
+    /// The client with the given id disconnected. Its id gets reused if it connects again
+    ClientLeft(usize),
+    /// The file has been written to disk
+    Saved,
+    /// Sent instead of the file or folder asked for with a `C2S::Path` when the user hasn't been
+    /// granted access to it
+    AccessDenied,
+    /// The id, username and color of everyone else connected to the file. This is sent right after
+    /// the colors that follow an `S2C::Full`
+    Presence(Vec<(usize, String, Color)>),
+    /// Sent instead of the file asked for with a `C2S::Path` when it is larger than the server
+    /// allows. Contains the largest size allowed in bytes
+    TooLarge(u64),
 }
 
 #[derive(Debug)]
 pub struct Inhabitant {
     pub name: OsString,
     pub is_folder: bool,
-    // TODO: Prob add like filesize stuff too
+    /// The size of the file in bytes
+    pub size: u64,
 }
 
 impl Serialize for Inhabitant {
@@ -37,6 +67,7 @@ impl Serialize for Inhabitant {
                 .serialize(),
         );
         ret.extend(self.is_folder.serialize());
+        ret.extend(self.size.to_be_bytes());
         ret
     }
 }
@@ -49,9 +80,11 @@ impl Deserialize for Inhabitant {
     {
         let name = String::deserialize(data).await?;
         let is_folder = bool::deserialize(data).await?;
+        let size = data.read_u64().await?;
         Ok(Self {
             name: name.into(),
             is_folder,
+            size,
         })
     }
 }
@@ -59,13 +92,110 @@ impl Deserialize for Inhabitant {
 impl TryFrom<DirEntry> for Inhabitant {
     type Error = io::Error;
     fn try_from(val: DirEntry) -> Result<Self, Self::Error> {
+        let metadata = fs::metadata(val.path())?;
         Ok(Self {
             name: val.file_name(),
-            is_folder: fs::metadata(val.path())?.is_dir(),
+            is_folder: metadata.is_dir(),
+            size: metadata.len(),
         })
     }
 }
 
+/// Writes an `S2C::Full` straight to an async writer. The description of the file is sent a chunk
+/// at a time while it's being written, rather than being serialized up front
+pub struct FullWriter<'a, W> {
+    chunks: AsyncChunkWriter<'a, W>,
+    /// Compresses the description before it goes into the chunks. Holds onto whatever it has
+    /// compressed until it gets written
+    #[cfg(feature = "compression")]
+    encoder: Option<flate2::write::GzEncoder<Vec<u8>>>,
+}
+
+impl<'a, W> FullWriter<'a, W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Writes the start of an `S2C::Full` to `writer`. The description gets compressed when
+    /// `compress` is set, which is ignored without the `compression` feature
+    /// # Errors
+    /// - Failing to write to the writer
+    pub async fn start(writer: &'a mut W, read_only: bool, compress: bool) -> io::Result<Self> {
+        let compress = compress && cfg!(feature = "compression");
+        let mut header = vec![0];
+        header.extend(read_only.serialize());
+        header.extend(compress.serialize());
+        writer.write_all(&header).await?;
+        Ok(Self {
+            chunks: AsyncChunkWriter::new(writer),
+            #[cfg(feature = "compression")]
+            encoder: compress.then(|| crate::compression::encoder(Vec::new())),
+        })
+    }
+
+    /// Adds `data` to the description
+    /// # Errors
+    /// - Failing to write to the writer
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "compression")]
+        if let Some(encoder) = &mut self.encoder {
+            // Compressed a chunk at a time, so that the encoder never holds much
+            for part in data.chunks(crate::BUFFER_SIZE) {
+                std::io::Write::write_all(encoder, part)?;
+                let compressed = encoder.get_mut();
+                self.chunks.write_all(compressed).await?;
+                compressed.clear();
+            }
+            return Ok(());
+        }
+        self.chunks.write_all(data).await
+    }
+
+    /// Ends the description, which ends the `S2C::Full`. This does not flush the writer
+    /// # Errors
+    /// - Failing to write to the writer
+    #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+    pub async fn finish(mut self) -> io::Result<()> {
+        #[cfg(feature = "compression")]
+        if let Some(encoder) = self.encoder.take() {
+            self.chunks.write_all(&encoder.finish()?).await?;
+        }
+        self.chunks.finish().await
+    }
+}
+
+/// Reads the payload of an `S2C::Full`, decompressing it if needed
+async fn read_full<T, D>(data: &mut D) -> io::Result<T>
+where
+    T: Deserialize,
+    D: AsyncReadExt + Unpin + Send,
+{
+    let compressed = bool::deserialize(data).await?;
+    let mut reader = ChunkReader::new(data);
+    if !compressed {
+        let ret = T::deserialize(&mut reader).await?;
+        reader.finish().await?;
+        return Ok(ret);
+    }
+    #[cfg(feature = "compression")]
+    {
+        let mut decoder = crate::compression::Decoder::new(&mut reader, crate::MAX_FULL_SIZE);
+        let ret = T::deserialize(&mut decoder).await?;
+        if decoder.read(&mut [0]).await? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The compressed payload contained trailing data",
+            ));
+        }
+        reader.finish().await?;
+        Ok(ret)
+    }
+    #[cfg(not(feature = "compression"))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Received a compressed file without compression support",
+    ))
+}
+
 impl<T> Serialize for S2C<T>
 where
     T: Serialize,
@@ -73,17 +203,24 @@ where
     fn serialize(&self) -> Vec<u8> {
         let mut ret = Vec::new();
         match self {
-            Self::Full(x) => {
+            Self::Full((x, read_only)) => {
                 ret.push(0);
-                ret.extend(x.serialize());
+                ret.extend(read_only.serialize());
+                ret.extend(false.serialize());
+                let mut chunks = ChunkWriter::new(ret);
+                ret = x
+                    .serialize_into(&mut chunks)
+                    .and_then(|()| chunks.finish())
+                    .expect("Writing to a Vec can't fail");
             }
             Self::Update((id, action)) => {
                 ret.push(1);
                 ret.extend((*id as u64).to_be_bytes());
                 ret.extend(action.serialize());
             }
-            Self::NewClient((username, color)) => {
+            Self::NewClient((id, username, color)) => {
                 ret.push(2);
+                ret.extend((*id as u64).to_be_bytes());
                 ret.extend(username.serialize());
                 ret.extend(color.serialize());
             }
@@ -91,6 +228,22 @@ where
                 ret.push(3);
                 ret.extend(x.serialize());
             }
+            Self::Rejected => ret.push(4),
+            Self::Pong => ret.push(5),
+            Self::ClientLeft(id) => {
+                ret.push(6);
+                ret.extend(id.serialize());
+            }
+            Self::Saved => ret.push(7),
+            Self::AccessDenied => ret.push(8),
+            Self::Presence(x) => {
+                ret.push(9);
+                ret.extend(x.serialize());
+            }
+            Self::TooLarge(max) => {
+                ret.push(10);
+                ret.extend(max.to_be_bytes());
+            }
         };
         ret
     }
@@ -106,7 +259,10 @@ where
         Self: Sized,
     {
         Ok(match data.read_u8().await? {
-            0 => Self::Full(T::deserialize(data).await?),
+            0 => {
+                let read_only = bool::deserialize(data).await?;
+                Self::Full((read_full(data).await?, read_only))
+            }
             1 => {
                 let mut buf = [0; mem::size_of::<u64>()];
                 data.read_exact(&mut buf).await?;
@@ -115,12 +271,104 @@ where
                 Self::Update((id, action))
             }
             2 => {
+                let id = data.read_u64().await? as usize;
                 let username = String::deserialize(data).await?;
                 let color = <Color as Deserialize>::deserialize(data).await?;
-                Self::NewClient((username, color))
+                Self::NewClient((id, username, color))
+            }
+            3 => Self::Folder(<(Vec<Inhabitant>, bool)>::deserialize(data).await?),
+            4 => Self::Rejected,
+            5 => Self::Pong,
+            6 => Self::ClientLeft(data.read_u64().await? as usize),
+            7 => Self::Saved,
+            8 => Self::AccessDenied,
+            9 => Self::Presence(Vec::deserialize(data).await?),
+            10 => Self::TooLarge(data.read_u64().await?),
+            x => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("An invalid specifier was found ({x})"),
+                ))
             }
-            3 => Self::Folder(Vec::deserialize(data).await?),
-            x => panic!("An invalid specifier was found ({x})"),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crossterm::style::Color;
+    use futures::executor::block_on;
+
+    use super::{FullWriter, S2C};
+    use crate::{Deserialize, Serialize};
+
+    fn full(data: &[u8]) -> (String, bool) {
+        let mut slice = data;
+        let S2C::Full(full) = block_on(S2C::<String>::deserialize(&mut slice)).unwrap() else {
+            panic!("Expected a full message");
+        };
+        assert!(slice.is_empty());
+        full
+    }
+
+    #[test]
+    fn full_round_trip() {
+        let data = S2C::Full(("andy".to_string(), true)).serialize();
+        assert_eq!(full(&data), ("andy".to_string(), true));
+    }
+
+    #[test]
+    fn full_spanning_chunks() {
+        let text = "andy\n".repeat(crate::BUFFER_SIZE);
+        let data = S2C::Full((text.clone(), false)).serialize();
+        assert_eq!(full(&data), (text, false));
+    }
+
+    #[test]
+    fn presence_round_trip() {
+        let presence = vec![
+            (0, "andy".to_string(), Color::Red),
+            (2, "son".to_string(), Color::Blue),
+        ];
+        let data = S2C::<String>::Presence(presence.clone()).serialize();
+        let S2C::Presence(round_trip) =
+            block_on(S2C::<String>::deserialize(&mut &data[..])).unwrap()
+        else {
+            panic!("Deserialized into a different message");
+        };
+        assert_eq!(round_trip, presence);
+    }
+
+    /// Writes `text` using a `FullWriter`, in parts that don't line up with the chunks
+    fn written_full(text: &str, compress: bool) -> Vec<u8> {
+        let mut ret = Vec::new();
+        block_on(async {
+            let mut writer = FullWriter::start(&mut ret, true, compress).await?;
+            // A `String` is serialized as its length followed by its bytes
+            writer.write_all(&(text.len() as u64).to_be_bytes()).await?;
+            for part in text.as_bytes().chunks(1000) {
+                writer.write_all(part).await?;
+            }
+            writer.finish().await
+        })
+        .unwrap();
+        ret
+    }
+
+    #[test]
+    fn full_writer() {
+        let text = "andy\n".repeat(crate::BUFFER_SIZE);
+        let data = written_full(&text, false);
+        assert_eq!(data, S2C::Full((text.clone(), true)).serialize());
+        assert_eq!(full(&data), (text, true));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_full() {
+        let text = "andy\n".repeat(1000);
+        let data = written_full(&text, true);
+        assert!(data.len() < text.len());
+        assert_eq!(full(&data), (text, true));
+    }
+}
@@ -0,0 +1,152 @@
+//! The handshake that starts every connection after authorization.
+//!
+//! Both sides send `MAGIC` followed by `VERSION` as a big-endian `u16`, and check that the other
+//! side sent the same. This turns mismatched client and server builds into a clear error rather
+//! than messages being misinterpreted. The version is followed by a byte telling which optional
+//! [`Features`] the sender was built with.
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The bytes that precede the version
+pub const MAGIC: [u8; 4] = *b"BTEP";
+
+/// The version of the protocol.
+/// This has to be bumped whenever the encoding of a message changes
+pub const VERSION: u16 = 17;
+
+/// The optional parts of the protocol a build supports. These only get used when both sides
+/// support them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// Whether an `S2C::Full` may be compressed
+    pub compression: bool,
+}
+
+impl Features {
+    /// The bit in the features byte that tells whether compression is supported
+    const COMPRESSION: u8 = 1;
+
+    /// The features this build supports
+    #[must_use]
+    pub const fn supported() -> Self {
+        Self {
+            compression: cfg!(feature = "compression"),
+        }
+    }
+
+    /// The features supported by both this build and `other`
+    #[must_use]
+    pub const fn common(self, other: Self) -> Self {
+        Self {
+            compression: self.compression && other.compression,
+        }
+    }
+
+    const fn to_byte(self) -> u8 {
+        if self.compression {
+            Self::COMPRESSION
+        } else {
+            0
+        }
+    }
+
+    /// Bits that this build doesn't know about are ignored, as they belong to features it
+    /// doesn't support
+    const fn from_byte(byte: u8) -> Self {
+        Self {
+            compression: byte & Self::COMPRESSION != 0,
+        }
+    }
+}
+
+/// Writes the magic and version, followed by the features this build supports. This does not
+/// flush the writer
+/// # Errors
+/// - Failing to write to the writer
+pub async fn write_version<W>(writer: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&MAGIC).await?;
+    writer.write_u16(VERSION).await?;
+    writer.write_u8(Features::supported().to_byte()).await
+}
+
+/// Reads the magic and version sent by the other side, and returns the features supported by
+/// both sides
+/// # Errors
+/// - Failing to read from the reader
+/// - The magic bytes are wrong, meaning the other side doesn't speak btep
+/// - The other side uses a different version of the protocol
+pub async fn read_version<R>(reader: &mut R) -> io::Result<Features>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The other side doesn't speak the btep protocol",
+        ));
+    }
+    let version = reader.read_u16().await?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Protocol version mismatch: this build uses version {VERSION}, but the other \
+                 side uses version {version}. Are the client and server the same version?"
+            ),
+        ));
+    }
+    let theirs = Features::from_byte(reader.read_u8().await?);
+    Ok(Features::supported().common(theirs))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use futures::executor::block_on;
+
+    use super::{read_version, write_version, Features, MAGIC, VERSION};
+
+    #[test]
+    fn matching_version() {
+        let mut data = Vec::new();
+        block_on(write_version(&mut data)).unwrap();
+        assert_eq!(data.len(), MAGIC.len() + 3);
+        assert_eq!(
+            block_on(read_version(&mut &data[..])).unwrap(),
+            Features::supported()
+        );
+    }
+
+    #[test]
+    fn features() {
+        let mut data = MAGIC.to_vec();
+        data.extend(VERSION.to_be_bytes());
+        // Nothing is supported, except for features this build doesn't know about
+        data.push(0xfe);
+        let common = block_on(read_version(&mut &data[..])).unwrap();
+        assert!(!common.compression);
+
+        *data.last_mut().unwrap() = 0xff;
+        let common = block_on(read_version(&mut &data[..])).unwrap();
+        assert_eq!(common.compression, cfg!(feature = "compression"));
+    }
+
+    #[test]
+    fn mismatched_version() {
+        let mut data = MAGIC.to_vec();
+        data.extend((VERSION + 1).to_be_bytes());
+        let err = block_on(read_version(&mut &data[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("version mismatch"));
+
+        let err = block_on(read_version(&mut &b"andy\0\x01"[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -3,6 +3,7 @@
 //! connect with a client etc.
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use crossterm::style::Color;
+use server::ServerConfig;
 #[cfg(feature = "security")]
 use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
 #[cfg(feature = "security")]
@@ -10,8 +11,9 @@ use std::str::FromStr;
 use std::{
     io::{self, Write},
     net::{Ipv4Addr, SocketAddrV4},
-    num::NonZeroU64,
+    num::{NonZeroU64, NonZeroUsize},
     path::PathBuf,
+    time::Duration,
 };
 #[cfg(feature = "security")]
 use termion::input::TermRead;
@@ -34,6 +36,27 @@ enum Verbosity {
     Trace,
 }
 
+/// The line ending files are saved with
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LineEndingArg {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+    /// Whatever most lines of the file used when it was opened
+    Keep,
+}
+
+impl LineEndingArg {
+    const fn line_ending(self) -> Option<server::LineEnding> {
+        match self {
+            Self::Lf => Some(server::LineEnding::Lf),
+            Self::Crlf => Some(server::LineEnding::CrLf),
+            Self::Keep => None,
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// hosts a server
@@ -58,6 +81,59 @@ struct ServerArgs {
     #[arg(long, default_value = "10")]
     save_interval: NonZeroU64,
 
+    /// also saves once nobody has edited the file for this many milliseconds
+    #[arg(long)]
+    save_debounce: Option<NonZeroU64>,
+
+    /// shares the file(s) without allowing clients to edit them
+    #[arg(long, default_value = "false")]
+    read_only: bool,
+
+    /// shows files starting with a `.` in folders by default. They can still be toggled with `.`
+    #[arg(long, default_value = "false")]
+    show_hidden: bool,
+
+    /// never disconnects clients that have stopped responding
+    #[arg(long, default_value = "false")]
+    disable_client_timeout: bool,
+
+    /// specifies the time in seconds a client can go without being heard from before it gets
+    /// disconnected
+    #[arg(long, default_value = "30")]
+    client_timeout: NonZeroU64,
+
+    /// disconnects clients that haven't done anything but stay connected for this many seconds
+    #[arg(long)]
+    idle_timeout: Option<NonZeroU64>,
+
+    /// the line ending files are saved with. `\r\n` is always shown as a regular newline while
+    /// editing
+    #[arg(long, value_enum, default_value = "keep")]
+    line_ending: LineEndingArg,
+
+    /// turns away clients once this many are connected
+    #[arg(long)]
+    max_clients: Option<NonZeroUsize>,
+
+    /// refuses to open files larger than this many bytes
+    #[arg(long)]
+    max_file_size: Option<NonZeroU64>,
+
+    /// serves a list of the open files, how many clients are connected to each of them and
+    /// whether they have unsaved edits on this port. It uses the same ip as the server
+    #[arg(long)]
+    status_port: Option<u16>,
+
+    /// appends everything clients do to the open files to this file, so that it can be replayed
+    /// with --replay
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// prints what every file looks like at the end of a recording made with --record, instead
+    /// of running the server
+    #[arg(long, conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
     /// IP-address the server should be hosted on
     ///
     /// 0.0.0.0 in order to host on the local network
@@ -75,9 +151,19 @@ struct ServerArgs {
     #[arg(short = 'a', long)]
     address: Option<SocketAddrV4>,
     #[cfg(feature = "security")]
+    /// the amount of failed logins in a row after which an address gets locked out for an hour.
+    /// Before that every failed login doubles the time until the next attempt is allowed
+    #[arg(long, default_value = "10")]
+    auth_lockout: u32,
+    #[cfg(feature = "security")]
     /// Add a new user which can access files hosted
     #[arg(long, action = clap::ArgAction::SetTrue)]
     add_user: bool,
+    #[cfg(feature = "security")]
+    /// Lets a user open a path, relative to the hosted folder. Users that have been granted access
+    /// to something can only open what they have been granted access to
+    #[arg(long, num_args = 2, value_names = ["USERNAME", "PATH"], conflicts_with = "add_user")]
+    grant_access: Option<Vec<String>>,
 }
 
 #[derive(Args, Debug)]
@@ -143,6 +229,17 @@ fn main() -> color_eyre::Result<()> {
         .unwrap();
 
     match &cli.command {
+        Commands::Server(ServerArgs {
+            replay: Some(replay),
+            ..
+        }) => {
+            for (path, lines) in server::replay(replay)? {
+                println!("==> {} <==", path.display());
+                for line in lines {
+                    println!("{line}");
+                }
+            }
+        }
         Commands::Server(ServerArgs {
             path,
             ip,
@@ -151,8 +248,24 @@ fn main() -> color_eyre::Result<()> {
             verbosity,
             disable_auto_save,
             save_interval,
+            save_debounce,
+            read_only,
+            show_hidden,
+            disable_client_timeout,
+            client_timeout,
+            idle_timeout,
+            line_ending,
+            max_clients,
+            max_file_size,
+            status_port,
+            record,
+            replay: None,
+            #[cfg(feature = "security")]
+            auth_lockout,
             #[cfg(feature = "security")]
                 add_user: false,
+            #[cfg(feature = "security")]
+                grant_access: None,
         }) => {
             tracing_subscriber::fmt()
                 .with_level(true)
@@ -161,9 +274,27 @@ fn main() -> color_eyre::Result<()> {
             trace!("{cli:?}");
             let address = address.unwrap_or(SocketAddrV4::new(*ip, *port));
             server::run(
-                (!disable_auto_save).then_some(*save_interval),
-                address,
-                path.as_ref().expect("A path is required to run the server"),
+                ServerConfig {
+                    address,
+                    path: path.clone().expect("A path is required to run the server"),
+                    save_interval: (!disable_auto_save)
+                        .then(|| Duration::from_secs(save_interval.get())),
+                    save_debounce: save_debounce
+                        .filter(|_| !disable_auto_save)
+                        .map(|x| Duration::from_millis(x.get())),
+                    read_only: *read_only,
+                    show_hidden: *show_hidden,
+                    client_timeout: (!disable_client_timeout)
+                        .then(|| Duration::from_secs(client_timeout.get())),
+                    idle_timeout: idle_timeout.map(|x| Duration::from_secs(x.get())),
+                    line_ending: line_ending.line_ending(),
+                    max_clients: *max_clients,
+                    max_file_size: *max_file_size,
+                    status_address: status_port.map(|port| SocketAddrV4::new(*address.ip(), port)),
+                    record: record.clone(),
+                    #[cfg(feature = "security")]
+                    auth_lockout: *auth_lockout,
+                },
                 #[cfg(feature = "security")]
                 pool,
             );
@@ -179,6 +310,28 @@ fn main() -> color_eyre::Result<()> {
                 }
             }
         }
+        #[cfg(feature = "security")]
+        Commands::Server(ServerArgs {
+            grant_access: Some(grant),
+            ..
+        }) => {
+            let [username, path] = &grant[..] else {
+                unreachable!("clap makes sure that there are two values")
+            };
+            let ret = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .unwrap()
+                .block_on(server::grant_access(&pool, username, path));
+            if let Err(e) = ret {
+                match e {
+                    sqlx::Error::Database(db) if db.is_unique_violation() => {
+                        println!("{username} already has access to {path}")
+                    }
+                    _ => println!("An unknown error occurred: {e:?}"),
+                }
+            }
+        }
         Commands::Client(ClientArgs {
             username,
             #[cfg(feature = "security")]
@@ -1,15 +1,19 @@
 use std::{
-    cmp, io,
+    env, fs, io,
     ops::{Index, IndexMut},
+    path::PathBuf,
 };
 
-use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{
+    event::{KeyCode, KeyEvent, KeyModifiers},
+    terminal,
+};
 use futures::executor::block_on;
 use trie::Trie;
 use utils::other::CursorPos;
 
 use super::{
-    buffer::{Buffer, BufferData, BufferTypeData},
+    buffer::{visible_inhabitants, Buffer, BufferData, BufferTypeData, ViewPosition},
     client::{Client, Mode},
 };
 
@@ -19,6 +23,7 @@ pub(crate) struct Bindings {
     insert: Trie<KeyEvent, Action>,
     normal: Trie<KeyEvent, Action>,
     command: Trie<KeyEvent, Action>,
+    search: Trie<KeyEvent, Action>,
 }
 
 impl Default for Bindings {
@@ -41,69 +46,60 @@ impl Default for Bindings {
                 );
                 trie.insert(
                     [KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE)],
-                    Box::new(|client: &mut Client| {
-                        block_on(async {
-                            if !client.curr().data.modifiable {
-                                return Ok(());
-                            }
-                            let BufferTypeData::Regular { text, .. } =
-                                &client.curr().data.buffer_type
-                            else {
-                                todo!("You can only type in regular buffers")
-                            };
-                            client.curr_mut().cursorpos.col = cmp::min(
-                                text.lines().nth(client.curr().cursorpos.row).unwrap().len(),
-                                client.curr().cursorpos.col + 1,
-                            );
-                            client.enter_insert(client.curr().cursorpos).await?;
-                            Ok(())
-                        })
-                    }),
+                    Box::new(|client: &mut Client| block_on(client.append())),
                 );
                 trie.insert(
                     [KeyEvent::new(KeyCode::Char('A'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| block_on(client.append_to_line())),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('0'), KeyModifiers::NONE)],
                     Box::new(|client: &mut Client| {
-                        block_on(async {
-                            if !client.curr().data.modifiable {
-                                return Ok(());
-                            }
-                            let BufferTypeData::Regular { text, .. } =
-                                &client.curr().data.buffer_type
-                            else {
-                                todo!("You can only type in regular buffers")
-                            };
-                            client.curr_mut().cursorpos.col =
-                                text.lines().nth(client.curr().cursorpos.row).unwrap().len();
-                            client.enter_insert(client.curr().cursorpos).await?;
-                            Ok(())
-                        })
+                        client.move_to_line_start();
+                        Ok(())
                     }),
                 );
                 trie.insert(
-                    [KeyEvent::new(KeyCode::Char('o'), KeyModifiers::NONE)],
+                    [KeyEvent::new(KeyCode::Char('$'), KeyModifiers::NONE)],
                     Box::new(|client: &mut Client| {
-                        block_on(async {
-                            if !client.curr().data.modifiable {
-                                return Ok(());
-                            }
-                            let BufferTypeData::Regular { text, .. } =
-                                &client.curr().data.buffer_type
-                            else {
-                                todo!("You can only type in regular buffers")
-                            };
-                            let pos = CursorPos {
-                                row: client.curr().cursorpos.row,
-                                col: text
-                                    .lines()
-                                    .nth(client.curr_mut().cursorpos.row)
-                                    .map_or(0, |x| x.chars().count()),
-                            };
-                            client.enter_insert(pos).await?;
-                            client.type_char('\n').await?;
-                            Ok(())
-                        })
+                        client.move_to_line_end();
+                        Ok(())
                     }),
                 );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('o'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| block_on(client.open_line_below())),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('O'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| block_on(client.open_line_above())),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL)],
+                    Box::new(|client: &mut Client| block_on(client.add_to_number(1))),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('x'), KeyModifiers::CONTROL)],
+                    Box::new(|client: &mut Client| block_on(client.add_to_number(-1))),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('~'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| block_on(client.toggle_case())),
+                );
+                trie.insert(
+                    [
+                        KeyEvent::new(KeyCode::Char('>'), KeyModifiers::NONE),
+                        KeyEvent::new(KeyCode::Char('>'), KeyModifiers::NONE),
+                    ],
+                    Box::new(|client: &mut Client| block_on(client.indent_line())),
+                );
+                trie.insert(
+                    [
+                        KeyEvent::new(KeyCode::Char('<'), KeyModifiers::NONE),
+                        KeyEvent::new(KeyCode::Char('<'), KeyModifiers::NONE),
+                    ],
+                    Box::new(|client: &mut Client| block_on(client.dedent_line())),
+                );
                 trie.insert(
                     [KeyEvent::new(KeyCode::Char(':'), KeyModifiers::NONE)],
                     Box::new(|client: &mut Client| {
@@ -115,6 +111,62 @@ impl Default for Bindings {
                     [KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)],
                     Box::new(|_| Ok(())),
                 );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('R'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| {
+                        if let BufferTypeData::Folder { .. } = client.curr().data.buffer_type {
+                            client
+                                .modeinfo
+                                .set_mode(Mode::Command("rename ".to_string()));
+                        }
+                        Ok(())
+                    }),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('/'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| {
+                        if let BufferTypeData::Folder { .. } = client.curr().data.buffer_type {
+                            client
+                                .modeinfo
+                                .set_mode(Mode::Command("filter ".to_string()));
+                        } else {
+                            client.start_search();
+                        }
+                        Ok(())
+                    }),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('.'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| {
+                        client.toggle_hidden();
+                        Ok(())
+                    }),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('-'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| block_on(client.open_parent())),
+                );
+                trie.insert(
+                    [
+                        KeyEvent::new(KeyCode::Char('g'), KeyModifiers::NONE),
+                        KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE),
+                    ],
+                    Box::new(|client: &mut Client| {
+                        client.go_to_definition();
+                        Ok(())
+                    }),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Char('%'), KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| {
+                        if let BufferTypeData::Folder { .. } = client.curr().data.buffer_type {
+                            client.modeinfo.set_mode(Mode::Command("new ".to_string()));
+                        } else {
+                            client.jump_to_matching_bracket();
+                        }
+                        Ok(())
+                    }),
+                );
                 for x in [KeyCode::Char('h'), KeyCode::Left] {
                     trie.insert(
                         [KeyEvent::new(x, KeyModifiers::NONE)],
@@ -151,13 +203,38 @@ impl Default for Bindings {
                         }),
                     );
                 }
+                for (c, position) in [
+                    ('z', ViewPosition::Center),
+                    ('t', ViewPosition::Top),
+                    ('b', ViewPosition::Bottom),
+                ] {
+                    trie.insert(
+                        [
+                            KeyEvent::new(KeyCode::Char('z'), KeyModifiers::NONE),
+                            KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+                        ],
+                        Box::new(move |client: &mut Client| {
+                            // The last row is taken up by the status line
+                            let rows = terminal::size()?.1.saturating_sub(1);
+                            client
+                                .curr_mut()
+                                .reposition_view(usize::from(rows), position);
+                            Ok(())
+                        }),
+                    );
+                }
                 trie.insert(
                     [KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)],
                     Box::new(|client: &mut Client| {
                         let Buffer {
                             data:
                                 BufferData {
-                                    buffer_type: BufferTypeData::Folder { inhabitants },
+                                    buffer_type:
+                                        BufferTypeData::Folder {
+                                            inhabitants,
+                                            filter,
+                                            show_hidden,
+                                        },
                                     ..
                                 },
                             cursorpos: CursorPos { row, .. },
@@ -167,17 +244,34 @@ impl Default for Bindings {
                         else {
                             return Ok(());
                         };
-                        *client.curr_mut() = block_on(async {
+                        let Some(inhabitant) =
+                            visible_inhabitants(inhabitants, filter, *show_hidden).nth(*row)
+                        else {
+                            return Ok(());
+                        };
+                        let buffer = block_on(async {
                             Buffer::connect(
                                 client.server_addr,
                                 &client.username.clone(),
                                 #[cfg(feature = "security")]
                                 client.password.clone(),
                                 &client.color,
-                                path.as_ref().unwrap().join(inhabitants[*row].name.clone()),
+                                path.as_ref().unwrap().join(inhabitant.name.clone()),
                             )
                             .await
-                        })?;
+                        });
+                        match buffer {
+                            Ok(buffer) => *client.curr_mut() = buffer,
+                            Err(e)
+                                if matches!(
+                                    e.kind(),
+                                    io::ErrorKind::PermissionDenied | io::ErrorKind::FileTooLarge
+                                ) =>
+                            {
+                                client.info = Some(e.to_string());
+                            }
+                            Err(e) => return Err(e),
+                        }
 
                         Ok(())
                     }),
@@ -188,7 +282,7 @@ impl Default for Bindings {
                 let mut trie: Trie<KeyEvent, Action> = Trie::new();
                 trie.insert(
                     [KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)],
-                    Box::new(|client: &mut Client| block_on(client.type_char('\n'))),
+                    Box::new(|client: &mut Client| block_on(client.type_newline())),
                 );
                 trie.insert(
                     [KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)],
@@ -251,20 +345,6 @@ impl Default for Bindings {
                         Ok(())
                     }),
                 );
-                trie.insert(
-                    [KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)],
-                    Box::new(|client: &mut Client| {
-                        let Mode::Command(ref x) = client.modeinfo.mode else {
-                            unreachable!()
-                        };
-                        let x = x.clone();
-                        if block_on(client.execute_command(&x))? {
-                            return Ok(());
-                        }
-                        client.modeinfo.set_mode(Mode::Normal);
-                        Ok(())
-                    }),
-                );
                 for c in ('a'..='z').chain('A'..='Z') {
                     trie.insert(
                         [KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)],
@@ -279,8 +359,214 @@ impl Default for Bindings {
                 }
                 trie
             },
+            search: {
+                let mut trie: Trie<KeyEvent, Action> = Trie::new();
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| {
+                        let Mode::Search { ref mut query, .. } = client.modeinfo.mode else {
+                            unreachable!()
+                        };
+                        if query.pop().is_none() {
+                            client.end_search(true);
+                        } else {
+                            client.preview_search();
+                        }
+                        Ok(())
+                    }),
+                );
+                trie.insert(
+                    [KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)],
+                    Box::new(|client: &mut Client| {
+                        client.end_search(true);
+                        Ok(())
+                    }),
+                );
+                trie
+            },
+        }
+    }
+}
+
+impl Bindings {
+    /// Reads the users bindings file and adds the bindings within it.
+    /// A missing file isn't an error
+    /// # Returns
+    /// A warning for everything that couldn't be used
+    pub(crate) fn load_user_config(&mut self) -> Vec<String> {
+        let Some(path) = config_path() else {
+            return Vec::new();
+        };
+        match fs::read_to_string(&path) {
+            Ok(config) => self.apply_config(&config),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => vec![format!("Failed to read {}: {e}", path.display())],
+        }
+    }
+
+    /// Adds the bindings in `config`. Every line binds a key sequence in a mode to an action,
+    /// written as `<mode> <keys> <action>`. Empty lines and lines starting with `#` are skipped
+    /// # Returns
+    /// A warning for every line that couldn't be used
+    pub(crate) fn apply_config(&mut self, config: &str) -> Vec<String> {
+        config
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .filter_map(|(i, line)| {
+                self.map_definition(line)
+                    .err()
+                    .map(|e| format!("bindings line {}: {e}", i + 1))
+            })
+            .collect()
+    }
+
+    /// Adds a binding written as `<mode> <keys> <action>`
+    /// # Errors
+    /// A message describing what was wrong with the definition
+    pub(crate) fn map_definition(&mut self, definition: &str) -> Result<(), String> {
+        let mut words = definition.split_whitespace();
+        let (Some(mode), Some(keys), Some(action), None) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            return Err("expected `<mode> <keys> <action>`".to_string());
+        };
+        self.map(mode, keys, action)
+    }
+
+    /// Binds `keys` in `mode` to the action named `action`
+    /// # Errors
+    /// A message describing what was wrong, if the mode, keys or action name is invalid
+    pub(crate) fn map(&mut self, mode: &str, keys: &str, action: &str) -> Result<(), String> {
+        let mode = match mode {
+            "n" | "normal" => Mode::Normal,
+            "i" | "insert" => Mode::Insert,
+            "c" | "command" => Mode::Command(String::new()),
+            "s" | "search" => Mode::Search {
+                query: String::new(),
+                origin: CursorPos::default(),
+            },
+            _ => return Err(format!("unknown mode `{mode}`")),
+        };
+        let keys = parse_keys(keys).ok_or_else(|| format!("invalid key sequence `{keys}`"))?;
+        let action = named_action(action).ok_or_else(|| format!("unknown action `{action}`"))?;
+        self[&mode].insert(keys, action);
+        Ok(())
+    }
+}
+
+/// The path of the users bindings file. This is `andyeditor/bindings` within
+/// `$XDG_CONFIG_HOME`, or `~/.config` if that isn't set
+fn config_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("andyeditor").join("bindings"))
+}
+
+/// Parses a key sequence like `jk` or `<C-w>`.
+/// Special keys are written within angle brackets: `<Esc>`, `<CR>`, `<BS>`, `<Tab>`,
+/// `<Space>`, `<lt>` and the arrow keys `<Left>`, `<Right>`, `<Up>` and `<Down>`.
+/// `<C-x>` and `<A-x>` hold down control and alt respectively
+fn parse_keys(keys: &str) -> Option<Vec<KeyEvent>> {
+    let mut ret = Vec::new();
+    let mut chars = keys.chars();
+    while let Some(c) = chars.next() {
+        if c != '<' {
+            ret.push(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE));
+            continue;
         }
+        let special = chars.by_ref().take_while(|&c| c != '>').collect::<String>();
+        let (modifiers, key) = match special.split_once('-') {
+            Some(("C", key)) => (KeyModifiers::CONTROL, key),
+            Some(("A" | "M", key)) => (KeyModifiers::ALT, key),
+            _ => (KeyModifiers::NONE, special.as_str()),
+        };
+        let code = match key {
+            "Esc" => KeyCode::Esc,
+            "CR" | "Enter" => KeyCode::Enter,
+            "BS" => KeyCode::Backspace,
+            "Tab" => KeyCode::Tab,
+            "Space" => KeyCode::Char(' '),
+            "lt" => KeyCode::Char('<'),
+            "Left" => KeyCode::Left,
+            "Right" => KeyCode::Right,
+            "Up" => KeyCode::Up,
+            "Down" => KeyCode::Down,
+            _ => {
+                let mut key_chars = key.chars();
+                match (key_chars.next(), key_chars.next()) {
+                    (Some(c), None) if modifiers != KeyModifiers::NONE => KeyCode::Char(c),
+                    _ => return None,
+                }
+            }
+        };
+        ret.push(KeyEvent::new(code, modifiers));
     }
+    (!ret.is_empty()).then_some(ret)
+}
+
+/// Creates the action with the given name. These are the actions that can be used in the
+/// bindings file
+fn named_action(name: &str) -> Option<Action> {
+    Some(match name {
+        "move_left" => Box::new(|client: &mut Client| {
+            client.move_left();
+            Ok(())
+        }),
+        "move_right" => Box::new(|client: &mut Client| {
+            client.move_right();
+            Ok(())
+        }),
+        "move_up" => Box::new(|client: &mut Client| {
+            client.move_up();
+            Ok(())
+        }),
+        "move_down" => Box::new(|client: &mut Client| {
+            client.move_down();
+            Ok(())
+        }),
+        "move_line_start" => Box::new(|client: &mut Client| {
+            client.move_to_line_start();
+            Ok(())
+        }),
+        "move_line_end" => Box::new(|client: &mut Client| {
+            client.move_to_line_end();
+            Ok(())
+        }),
+        "move_matching_bracket" => Box::new(|client: &mut Client| {
+            client.jump_to_matching_bracket();
+            Ok(())
+        }),
+        "go_to_definition" => Box::new(|client: &mut Client| {
+            client.go_to_definition();
+            Ok(())
+        }),
+        "enter_insert" => {
+            Box::new(|client: &mut Client| block_on(client.enter_insert(client.curr().cursorpos)))
+        }
+        "append" => Box::new(|client: &mut Client| block_on(client.append())),
+        "append_to_line" => Box::new(|client: &mut Client| block_on(client.append_to_line())),
+        "exit_insert" => Box::new(|client: &mut Client| block_on(client.exit_insert())),
+        "backspace" => Box::new(|client: &mut Client| {
+            block_on(async {
+                client.backspace().await?;
+                Ok(())
+            })
+        }),
+        "newline" => Box::new(|client: &mut Client| block_on(client.type_char('\n'))),
+        "command_mode" => Box::new(|client: &mut Client| {
+            client.modeinfo.set_mode(Mode::Command(String::new()));
+            Ok(())
+        }),
+        "save" => Box::new(|client: &mut Client| {
+            block_on(client.execute_command("w"))?;
+            Ok(())
+        }),
+        "nop" => Box::new(|_| Ok(())),
+        _ => return None,
+    })
 }
 
 impl Index<&Mode> for Bindings {
@@ -291,6 +577,7 @@ impl Index<&Mode> for Bindings {
             Mode::Normal => &self.normal,
             Mode::Insert => &self.insert,
             Mode::Command(_) => &self.command,
+            Mode::Search { .. } => &self.search,
         }
     }
 }
@@ -301,6 +588,68 @@ impl IndexMut<&Mode> for Bindings {
             Mode::Normal => &mut self.normal,
             Mode::Insert => &mut self.insert,
             Mode::Command(_) => &mut self.command,
+            Mode::Search { .. } => &mut self.search,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    use super::{parse_keys, Bindings};
+    use crate::editor::client::Mode;
+
+    #[test]
+    fn parse_key_sequences() {
+        assert_eq!(
+            parse_keys("jk"),
+            Some(vec![
+                KeyEvent::new(KeyCode::Char('j'), KeyModifiers::NONE),
+                KeyEvent::new(KeyCode::Char('k'), KeyModifiers::NONE)
+            ])
+        );
+        assert_eq!(
+            parse_keys("<C-w><Esc><lt>"),
+            Some(vec![
+                KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL),
+                KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE),
+                KeyEvent::new(KeyCode::Char('<'), KeyModifiers::NONE)
+            ])
+        );
+        assert_eq!(parse_keys(""), None);
+        assert_eq!(parse_keys("<Nope>"), None);
+        assert_eq!(parse_keys("<C-ab>"), None);
+    }
+
+    #[test]
+    fn apply_config() {
+        let mut bindings = Bindings::default();
+        let warnings = bindings.apply_config(
+            "# comment\n\ninsert jk exit_insert\nnormal x explode\nvisual x nop\nnormal <Nope> nop\nnormal",
+        );
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].starts_with("bindings line 4"));
+        assert!(bindings[&Mode::Insert]
+            .get(parse_keys("jk").unwrap())
+            .is_some());
+        assert!(bindings[&Mode::Normal]
+            .get(parse_keys("x").unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn map_definition() {
+        let mut bindings = Bindings::default();
+        assert!(bindings.map_definition("n x nop").is_ok());
+        assert!(bindings[&Mode::Normal]
+            .get(parse_keys("x").unwrap())
+            .is_some());
+        assert!(bindings.map_definition("n x").is_err());
+        assert!(bindings.map_definition("n x nop extra").is_err());
+        assert_eq!(
+            bindings.map_definition("x x nop"),
+            Err("unknown mode `x`".to_string())
+        );
+    }
+}
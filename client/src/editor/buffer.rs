@@ -1,13 +1,13 @@
-use std::{io, net::SocketAddrV4, path::PathBuf};
+use std::{collections::HashMap, io, net::SocketAddrV4, path::PathBuf};
 
 use btep::{
-    c2s::C2S,
+    c2s::{EnterInsert, C2S},
     s2c::{Inhabitant, S2C},
-    Deserialize, Serialize,
+    version, Deserialize, Serialize,
 };
 
-use crossterm::{style::Color, terminal};
-use text::Text;
+use crossterm::style::Color;
+use text::{snapshot::Snapshot, Text};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader},
     net::{
@@ -17,7 +17,12 @@ use tokio::{
 };
 use utils::other::CursorPos;
 
-use super::BUFFER_SIZE;
+use super::{
+    draw::cell_positions,
+    highlight::{self, Highlighter},
+    BUFFER_SIZE,
+};
+use crate::errors::AuthError;
 
 /// The main state for the entire editor. The entireity of the
 /// view presented to the user can be rebuild from this
@@ -30,6 +35,19 @@ pub struct Buffer {
     pub(crate) line_offset: usize,
     pub(crate) socket: Option<Socket>,
     pub path: Option<PathBuf>,
+    /// Whether the text has changed since the server last saved it
+    pub(crate) modified: bool,
+    /// Chars that have been typed but not sent to the server yet. They are sent together as a
+    /// single message before anything else is sent
+    pub(crate) typed: String,
+    /// Whether the connection to the server was lost. The buffer can still be viewed, but not
+    /// edited
+    pub(crate) disconnected: bool,
+    /// Snapshots of the text taken with `:checkpoint`, by name
+    pub(crate) checkpoints: HashMap<String, Snapshot>,
+    /// Colors the lines of the text when drawing them. This is picked by the extension of the
+    /// path
+    pub(crate) highlighter: Option<Highlighter>,
 }
 
 #[derive(Debug)]
@@ -46,16 +64,44 @@ pub enum BufferTypeData {
         /// The rope stores the entire file being edited.
         text: Text,
         /// An map from id to their color in the buffer
-        colors: Vec<Color>,
+        colors: HashMap<usize, Color>,
+        /// The usernames of everyone connected to the file, including ourselves, by id
+        presence: HashMap<usize, String>,
     },
     Folder {
         inhabitants: Vec<Inhabitant>,
+        /// Only the inhabitants whose name contains this are shown
+        filter: String,
+        /// Whether inhabitants whose name starts with a `.` are shown
+        show_hidden: bool,
     },
 }
 
+/// The inhabitants of a folder buffer that are shown with `filter`, and whether hidden ones are
+/// shown. The rows of a folder buffer refer to these rather than to all the inhabitants
+pub(crate) fn visible_inhabitants<'a>(
+    inhabitants: &'a [Inhabitant],
+    filter: &'a str,
+    show_hidden: bool,
+) -> impl Iterator<Item = &'a Inhabitant> {
+    inhabitants.iter().filter(move |x| {
+        let name = x.name.to_string_lossy();
+        (show_hidden || !name.starts_with('.')) && name.contains(filter)
+    })
+}
+
+/// Where `Buffer::reposition_view` puts the line the cursor is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ViewPosition {
+    Top,
+    Center,
+    Bottom,
+}
+
 #[derive(Debug)]
 pub struct Socket {
-    pub reader: OwnedReadHalf,
+    /// Buffered, so that a message doesn't have to be read from the socket a few bytes at a time
+    pub reader: BufReader<OwnedReadHalf>,
     pub writer: OwnedWriteHalf,
 }
 
@@ -67,35 +113,72 @@ impl Buffer {
         color: &Color,
         path: P,
     ) -> io::Result<Self> {
-        let Ok(mut socket) = connect_with_auth(
+        let mut socket = connect_with_auth(
             address,
             username,
             #[cfg(feature = "security")]
             password,
         )
-        .await
-        else {
-            panic!("Failed to connect to the server. Maybe the server is not running?")
-        };
+        .await?;
         let path_buf = path.into();
         socket
             .write_all(&C2S::Path(path_buf.clone()).serialize())
             .await?;
         socket.write_all(&C2S::SetColor(*color).serialize()).await?;
         socket.flush().await?;
-        let mut reader = BufReader::with_capacity(BUFFER_SIZE, &mut socket);
+        let (read, writer) = socket.into_split();
+        // The reader is kept for the rest of the connection, as it might already hold messages
+        // that were sent right after the ones read here
+        let mut reader = BufReader::with_capacity(BUFFER_SIZE, read);
         match S2C::<Text>::deserialize(&mut reader).await? {
-            S2C::Full(initial_text) => {
-                let colors = Vec::<Color>::deserialize(&mut reader).await?;
-                assert!(
-                    reader.buffer().is_empty(),
-                    "Could not process everything the server sent"
+            S2C::Full((initial_text, read_only)) => {
+                let colors = Vec::<(usize, Color)>::deserialize(&mut reader).await?;
+                let S2C::Presence(others) = S2C::<Text>::deserialize(&mut reader).await? else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expected the server to send who is connected",
+                    ));
+                };
+                let mut buf = Buffer::new(
+                    username,
+                    initial_text,
+                    colors.into_iter().collect(),
+                    None,
+                    Some(path_buf),
                 );
-                let buf = Buffer::new(username, initial_text, colors, Some(socket), Some(path_buf));
+                buf.socket = Some(Socket { reader, writer });
+                if let BufferTypeData::Regular {
+                    id,
+                    colors,
+                    presence,
+                    ..
+                } = &mut buf.data.buffer_type
+                {
+                    for (id, username, color) in others {
+                        colors.insert(id, color);
+                        presence.insert(id, username);
+                    }
+                    colors.insert(*id, *color);
+                    presence.insert(*id, username.to_string());
+                }
+                buf.data.modifiable = !read_only;
                 Ok(buf)
             }
-            S2C::Folder(inhabitants) => Ok(Buffer::new_folder(inhabitants, path_buf)),
-            _ => panic!("Initial message in wrong protocol"),
+            S2C::Folder((inhabitants, show_hidden)) => {
+                Ok(Buffer::new_folder(inhabitants, show_hidden, path_buf))
+            }
+            S2C::AccessDenied => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("You don't have access to {path_buf:?}"),
+            )),
+            S2C::TooLarge(max) => Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("{path_buf:?} is larger than the {max} bytes the server allows"),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Initial message in wrong protocol",
+            )),
         }
     }
     /// Creates a new appstate
@@ -103,23 +186,34 @@ impl Buffer {
     pub fn new<P: Into<PathBuf>>(
         username: &str,
         mut text: Text,
-        colors: Vec<Color>,
+        colors: HashMap<usize, Color>,
         socket: Option<TcpStream>,
         path: Option<P>,
     ) -> Self {
         let id = text.add_client(username);
+        let path = path.map(|x| x.into());
         Self {
-            path: path.map(|x| x.into()),
+            highlighter: path.as_deref().and_then(highlight::for_path),
+            path,
             data: BufferData {
-                buffer_type: BufferTypeData::Regular { text, colors, id },
+                buffer_type: BufferTypeData::Regular {
+                    text,
+                    colors,
+                    presence: HashMap::new(),
+                    id,
+                },
                 modifiable: true,
             },
             cursorpos: CursorPos::default(),
             line_offset: 0,
+            modified: false,
+            typed: String::new(),
+            disconnected: false,
+            checkpoints: HashMap::new(),
             socket: socket.map(|x| {
                 let (read, writer) = x.into_split();
                 Socket {
-                    reader: read,
+                    reader: BufReader::with_capacity(BUFFER_SIZE, read),
                     writer,
                 }
             }),
@@ -127,19 +221,39 @@ impl Buffer {
     }
 
     #[must_use]
-    pub fn new_folder<P: Into<PathBuf>>(inhabitants: Vec<Inhabitant>, path: P) -> Self {
+    pub fn new_folder<P: Into<PathBuf>>(
+        inhabitants: Vec<Inhabitant>,
+        show_hidden: bool,
+        path: P,
+    ) -> Self {
         Self {
             data: BufferData {
-                buffer_type: BufferTypeData::Folder { inhabitants },
+                buffer_type: BufferTypeData::Folder {
+                    inhabitants,
+                    filter: String::new(),
+                    show_hidden,
+                },
                 modifiable: false,
             },
             cursorpos: CursorPos::default(),
             line_offset: 0,
+            modified: false,
+            typed: String::new(),
+            disconnected: false,
+            checkpoints: HashMap::new(),
+            highlighter: None,
             socket: None,
             path: Some(path.into()),
         }
     }
 
+    /// Drops the connection to the server and makes the buffer read-only
+    pub(crate) fn disconnect(&mut self) {
+        self.socket = None;
+        self.disconnected = true;
+        self.data.modifiable = false;
+    }
+
     /// Returns an immutable reference to the internal
     /// cursors position
     #[must_use]
@@ -149,124 +263,277 @@ impl Buffer {
 
     /// save the current buffer
     pub(super) async fn save(&mut self) -> tokio::io::Result<()> {
+        self.send(C2S::Save).await?;
         if let Some(Socket { ref mut writer, .. }) = self.socket {
-            writer.write_all(&C2S::Save.serialize()).await?;
+            writer.flush().await?;
+        }
+        Ok(())
+    }
 
+    /// Lets the server know that we are still connected
+    pub(crate) async fn ping(&mut self) -> tokio::io::Result<()> {
+        self.send(C2S::Ping).await?;
+        if let Some(Socket { ref mut writer, .. }) = self.socket {
             writer.flush().await?;
         }
         Ok(())
     }
 
-    /// Fetches the network for any updates and updates the internal buffer accordingly
+    /// Sends `action` to the server, after the chars that were typed before it. This does not
+    /// flush the writer
+    pub(crate) async fn send(&mut self, action: C2S) -> io::Result<()> {
+        self.send_typed().await?;
+        if let Some(Socket { ref mut writer, .. }) = self.socket {
+            writer.write_all(&action.serialize()).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the typed chars that haven't been sent yet. A single char is sent as a `C2S::Char`
+    /// and everything longer as a `C2S::Str`. This does not flush the writer
+    /// # Returns
+    /// Whether anything was sent
+    pub(crate) async fn send_typed(&mut self) -> io::Result<bool> {
+        let typed = std::mem::take(&mut self.typed);
+        let Some(Socket { ref mut writer, .. }) = self.socket else {
+            return Ok(false);
+        };
+        let mut chars = typed.chars();
+        let action = match (chars.next(), chars.next()) {
+            (None, _) => return Ok(false),
+            (Some(c), None) => C2S::Char(c),
+            (Some(_), Some(_)) => C2S::Str(typed),
+        };
+        writer.write_all(&action.serialize()).await?;
+        Ok(true)
+    }
+
+    /// Fetches the network for any updates and updates the internal buffer accordingly. Every
+    /// message that has already been read into the buffer of the reader is handled, as those
+    /// don't make the socket readable again
     /// # Return value
     /// returns true if the screen should be redrawn
+    /// # Errors
+    /// - Failing to read from the server, or it sending something malformed
     /// # Panics
     /// the message received wasn't formatted properly
     pub async fn update(&mut self) -> io::Result<bool> {
-        let Some(Socket { ref mut reader, .. }) = self.socket else {
-            return Ok(false);
-        };
+        let mut redraw = false;
+        while let Some(Socket { ref mut reader, .. }) = self.socket {
+            let message = S2C::<Text>::deserialize(reader).await?;
+            redraw |= self.handle_message(message)?;
+            if self
+                .socket
+                .as_ref()
+                .is_none_or(|x| x.reader.buffer().is_empty())
+            {
+                break;
+            }
+        }
+        Ok(redraw)
+    }
 
-        match S2C::<Text>::deserialize(reader).await? {
-            S2C::Full(_) => unreachable!("A full buffer shouldn't be sent"),
-            S2C::Folder(_) => unreachable!("A folder shouldn't be sent"),
+    /// Applies a message from the server
+    /// # Return value
+    /// returns true if the screen should be redrawn
+    /// # Errors
+    /// the message isn't one the server sends after connecting, or it refers to a client that
+    /// doesn't exist
+    fn handle_message(&mut self, message: S2C<Text>) -> io::Result<bool> {
+        match message {
+            S2C::Full(_) => Err(unexpected("A full buffer shouldn't be sent")),
+            S2C::AccessDenied => Err(unexpected("Access is only denied when connecting")),
+            S2C::Presence(_) => Err(unexpected("Presence is only sent when connecting")),
+            S2C::TooLarge(_) => Err(unexpected("Files are only too large when connecting")),
+            S2C::Folder(_) => Err(unexpected("A folder shouldn't be sent")),
             S2C::Update((client_id, action)) => {
                 let BufferTypeData::Regular { text, .. } = &mut self.data.buffer_type else {
-                    panic!("Only updates in Regul mode are supported (at the moment)");
+                    return Err(unexpected("Only regular buffers receive updates"));
                 };
+                if client_id >= text.clients().len() {
+                    return Err(unexpected("An update was sent by an unknown client"));
+                }
                 let client = text.client_mut(client_id);
-                match action {
-                    C2S::Char(c) => {
-                        client.push_char(c);
-                    }
+                if client.data.is_none()
+                    && matches!(
+                        action,
+                        C2S::Char(_) | C2S::Backspace(_) | C2S::Enter | C2S::Str(_)
+                    )
+                {
+                    return Err(unexpected("A client edited without being in insert mode"));
+                }
+                let pushed = match action {
+                    C2S::Char(c) => client.push_char(c),
                     C2S::Backspace(swaps) => {
                         client.backspace_with_swaps(swaps);
+                        true
+                    }
+                    C2S::Enter => client.push_char('\n'),
+                    C2S::Str(text) => client.push_str(&text),
+                    C2S::EnterInsert(EnterInsert { id, offset }) => {
+                        if !client.enter_insert_at(id, offset) {
+                            return Err(unexpected("A client entered insert mode out of bounds"));
+                        }
+                        return Ok(true);
+                    }
+                    C2S::ExitInsert => {
+                        client.exit_insert();
+                        return Ok(true);
                     }
-                    C2S::Enter => {
-                        client.push_char('\n');
+                    C2S::Save
+                    | C2S::Path(_)
+                    | C2S::SetColor(_)
+                    | C2S::Ping
+                    | C2S::Rename(_)
+                    | C2S::CreateFile(_) => {
+                        return Err(unexpected("Only edits are sent as updates"));
                     }
-                    C2S::EnterInsert(pos) => drop(client.enter_insert(pos)),
-                    C2S::ExitInsert => client.exit_insert(),
-                    C2S::Save | C2S::Path(_) | C2S::SetColor(_) => unreachable!(),
                 };
+                if !pushed {
+                    return Err(unexpected("A client ran out of ids for new pieces"));
+                }
+                self.modified = true;
                 Ok(true)
             }
-            S2C::NewClient((username, color)) => {
-                let BufferTypeData::Regular { text, colors, .. } = &mut self.data.buffer_type
+            S2C::NewClient((id, username, color)) => {
+                let BufferTypeData::Regular {
+                    text,
+                    colors,
+                    presence,
+                    ..
+                } = &mut self.data.buffer_type
                 else {
-                    panic!("New clients cannot join non-regular files");
+                    return Err(unexpected("New clients cannot join non-regular files"));
                 };
-                text.add_client(&username);
-                colors.push(color);
+                if text
+                    .clients()
+                    .get(id)
+                    .map_or(id != text.clients().len(), |x| x.connected)
+                {
+                    return Err(unexpected("A new client was given an id that isn't free"));
+                }
+                text.add_client_at(id, &username);
+                colors.insert(id, color);
+                presence.insert(id, username);
                 Ok(false)
             }
+            S2C::ClientLeft(id) => {
+                let BufferTypeData::Regular {
+                    text,
+                    colors,
+                    presence,
+                    ..
+                } = &mut self.data.buffer_type
+                else {
+                    return Err(unexpected("Clients cannot leave non-regular files"));
+                };
+                if id >= text.clients().len() {
+                    return Err(unexpected("An unknown client left"));
+                }
+                text.remove_client(id);
+                colors.remove(&id);
+                presence.remove(&id);
+                Ok(true)
+            }
+            S2C::Rejected => {
+                self.data.modifiable = false;
+                Ok(true)
+            }
+            S2C::Pong => Ok(false),
+            S2C::Saved => {
+                self.modified = false;
+                Ok(true)
+            }
         }
     }
 
-    /// Recalculates the cursor position using the size of the terminal
-    pub fn recalculate_cursor(&mut self, (_cols, rows): (u16, u16)) -> io::Result<()> {
-        let size = terminal::size()?;
-        match &mut self.data.buffer_type {
-            BufferTypeData::Regular { text, .. } => {
-                if self.line_offset > self.cursorpos.row {
-                    self.line_offset = self.cursorpos.row;
-                } else {
-                    let mut current_line = 0;
-                    let mut relative_col = 0;
-                    let mut cursor_offset = 0;
-                    'outer: for buf in text.bufs() {
-                        let read_lock = buf.read();
-                        for c in read_lock.text.chars() {
-                            if c == '\n' {
-                                relative_col = 0;
-                                if current_line >= size.1 as usize + self.line_offset {
-                                    break 'outer;
-                                };
-                                current_line += 1;
-                            } else if current_line >= self.line_offset {
-                                if relative_col >= size.0 as usize - 3 {
-                                    relative_col = 0;
-                                    current_line += 1;
-                                    if self.cursor().row - self.line_offset >= current_line {
-                                        cursor_offset += 1;
-                                    }
-                                } else {
-                                    relative_col += 1;
-                                }
-                            }
-                        }
-                    }
-                    if self.line_offset + usize::from(rows) <= self.cursorpos.row + cursor_offset {
-                        self.line_offset += self.cursorpos.row + cursor_offset
-                            - self.line_offset
-                            - usize::from(rows)
-                            + 1;
-                    }
-                }
-                Ok(())
-            }
-            BufferTypeData::Folder { inhabitants: _ } => {
-                if self.line_offset > self.cursorpos.row {
-                    self.line_offset = self.cursorpos.row;
-                } else {
-                    let _cursor_offset = || todo!();
-                    if self.line_offset + usize::from(rows) <= self.cursorpos.row {
-                        self.line_offset +=
-                            self.cursorpos.row - self.line_offset - usize::from(rows) + 1;
-                    }
-                }
-                Ok(())
+    /// Scrolls the view so that the line the cursor is on ends up at `position` of a view that is
+    /// `rows` tall. The cursor itself doesn't move
+    pub(crate) fn reposition_view(&mut self, rows: usize, position: ViewPosition) {
+        let row = self.cursorpos.row;
+        self.line_offset = match position {
+            ViewPosition::Top => row,
+            ViewPosition::Center => row.saturating_sub(rows / 2),
+            ViewPosition::Bottom => row.saturating_sub(rows.saturating_sub(1)),
+        };
+    }
+
+    /// Scrolls the view so that the cursor is within it, taking the rows that wrapped lines take
+    /// up into account. The text is `cols` wide, which excludes the columns taken up by line
+    /// numbers, and tabs are `tabstop` columns wide
+    pub fn recalculate_cursor(&mut self, (cols, rows): (u16, u16), tabstop: usize) {
+        let rows = usize::from(rows);
+        let row = self.cursorpos.row;
+        // Every line takes up at least one row, so the view never has to start further up than
+        // this
+        self.line_offset = self
+            .line_offset
+            .clamp(row.saturating_sub(rows.saturating_sub(1)), row);
+        let BufferTypeData::Regular { text, .. } = &self.data.buffer_type else {
+            return;
+        };
+        // The same wrapping as the text is drawn with
+        let wrap = |line: &str| cell_positions(line, usize::from(cols), tabstop);
+        // The rows from the start of the line with the cursor up to the cursor
+        let mut used = text.line(row).map_or(1, |line| {
+            let (positions, height) = wrap(&line);
+            positions
+                .get(self.cursorpos.col)
+                .map_or(height, |&(row, _)| row + 1)
+        });
+        let mut offset = row;
+        while offset > self.line_offset {
+            let height = text.line(offset - 1).map_or(1, |line| wrap(&line).1);
+            if used + height > rows {
+                break;
             }
+            used += height;
+            offset -= 1;
         }
+        self.line_offset = offset;
     }
 }
 
+/// Sends a single request to the server over a connection of its own and waits for the answer.
+/// This is used for requests that aren't tied to an open file, such as renaming one
+pub(crate) async fn request(
+    address: SocketAddrV4,
+    username: &str,
+    #[cfg(feature = "security")] password: String,
+    request: &C2S,
+) -> io::Result<S2C<Text>> {
+    let mut socket = connect_with_auth(
+        address,
+        username,
+        #[cfg(feature = "security")]
+        password,
+    )
+    .await?;
+    socket.write_all(&request.serialize()).await?;
+    socket.flush().await?;
+    S2C::deserialize(&mut BufReader::with_capacity(BUFFER_SIZE, &mut socket)).await
+}
+
+/// An error for a message the server shouldn't have sent
+fn unexpected(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Connects to the server and authorizes with it
+/// # Errors
+/// - The server couldn't be reached
+/// - The server rejected us, in which case the inner error is an [`AuthError`]
 async fn connect_with_auth(
     address: SocketAddrV4,
     username: &str,
     #[cfg(feature = "security")] password: String,
 ) -> io::Result<TcpStream> {
-    let mut stream = TcpStream::connect(address).await?;
+    let mut stream = TcpStream::connect(address).await.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to connect to the server at {address}. Maybe the server is not running? ({e})"),
+        )
+    })?;
     stream.write_all(username.as_bytes()).await?;
     #[cfg(feature = "security")]
     {
@@ -277,13 +544,224 @@ async fn connect_with_auth(
     stream.flush().await?;
     let ret = stream.read_u8().await?;
     match ret {
-        0 => (),
-        1 => panic!("You forgot to include a password"),
-        2 => panic!("The username, password combination you supplied isn't authorized"),
-        3 => {
-            panic!("This shouldn't be reachable, but it means that you forgot to supply a password")
+        0 => {
+            version::write_version(&mut stream).await?;
+            stream.flush().await?;
+            version::read_version(&mut stream).await?;
+            Ok(stream)
         }
-        _ => unreachable!(),
+        // 3 shouldn't be reachable, but it means the same thing
+        1 | 3 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            AuthError::MissingPassword,
+        )),
+        2 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            AuthError::Unauthorized,
+        )),
+        4 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            AuthError::TooManyAttempts,
+        )),
+        5 => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            AuthError::ServerFull,
+        )),
+        x => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The server answered the authorization with an unknown byte ({x})"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io, net::SocketAddr, time::Duration};
+
+    use btep::{
+        c2s::{EnterInsert, C2S},
+        s2c::S2C,
+        version, Deserialize, Serialize,
+    };
+    use crossterm::style::Color;
+    use text::Text;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time,
+    };
+
+    use super::{Buffer, BufferTypeData, ViewPosition};
+
+    #[test]
+    fn reposition_view() {
+        let mut buffer = Buffer::new_folder(Vec::new(), false, "");
+        buffer.cursorpos.row = 20;
+        buffer.reposition_view(10, ViewPosition::Center);
+        assert_eq!(buffer.line_offset, 15);
+        buffer.reposition_view(10, ViewPosition::Top);
+        assert_eq!(buffer.line_offset, 20);
+        buffer.reposition_view(10, ViewPosition::Bottom);
+        assert_eq!(buffer.line_offset, 11);
+        assert_eq!(buffer.cursorpos.row, 20);
+
+        buffer.cursorpos.row = 3;
+        buffer.reposition_view(10, ViewPosition::Center);
+        assert_eq!(buffer.line_offset, 0);
+        buffer.reposition_view(10, ViewPosition::Bottom);
+        assert_eq!(buffer.line_offset, 0);
+    }
+
+    #[test]
+    fn recalculate_cursor_wraps() {
+        // Lines of 12, 5 and 2 columns
+        let text = Text::original_from_str("abcdefghij\t\nab\tc\n\u{4e2d}");
+        let mut buffer = Buffer::new("andy", text, HashMap::new(), None, None::<&str>);
+        buffer.cursorpos.row = 2;
+        // The first line takes up 3 rows when wrapping at 5 columns
+        buffer.recalculate_cursor((5, 5), 4);
+        assert_eq!(buffer.line_offset, 0);
+        buffer.recalculate_cursor((5, 4), 4);
+        assert_eq!(buffer.line_offset, 1);
+
+        // The tab gets split up when wrapping at 3 columns, which puts the cursor on the second
+        // row of its line
+        buffer.cursorpos.row = 1;
+        buffer.cursorpos.col = 3;
+        buffer.line_offset = 0;
+        buffer.recalculate_cursor((3, 7), 4);
+        assert_eq!(buffer.line_offset, 0);
+        buffer.recalculate_cursor((3, 6), 4);
+        assert_eq!(buffer.line_offset, 1);
+    }
+
+    #[test]
+    fn update_handles_buffered_and_split_messages() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            let mut text = Text::original_from_str("ab");
+            text.add_client("other");
+            let mut buffer = Buffer::new("andy", text, HashMap::new(), Some(stream), None::<&str>);
+            let update = |action| S2C::<&Text>::Update((0, action)).serialize();
+            // The position `other` enters insert mode at, without entering it in `buffer`
+            let (offset, id) = {
+                let mut text = Text::original_from_str("ab");
+                text.add_client("other");
+                text.client_mut(0).enter_insert((0, 1).into()).unwrap()
+            };
+
+            // Both messages arrive at once, and the socket isn't readable after the first one
+            let mut data = update(C2S::EnterInsert(EnterInsert { id, offset }));
+            data.extend(update(C2S::Char('x')));
+            server.write_all(&data).await.unwrap();
+            assert!(buffer.update().await.unwrap());
+            assert_eq!(text_of(&mut buffer).contents(), "axb");
+
+            // A message that is split in two is still read whole
+            let data = update(C2S::Str("yz".into()));
+            let (first, second) = data.split_at(data.len() / 2);
+            server.write_all(first).await.unwrap();
+            let write = async {
+                time::sleep(Duration::from_millis(10)).await;
+                server.write_all(second).await.unwrap();
+            };
+            let (updated, ()) = tokio::join!(buffer.update(), write);
+            assert!(updated.unwrap());
+            assert_eq!(text_of(&mut buffer).contents(), "axyzb");
+        });
+    }
+
+    #[test]
+    fn unexpected_messages() {
+        let mut text = Text::original_from_str("ab");
+        text.add_client("other");
+        let mut buffer = Buffer::new("andy", text, HashMap::new(), None, None::<&str>);
+        for message in [
+            S2C::Folder((Vec::new(), false)),
+            S2C::Update((0, C2S::Save)),
+            S2C::Update((0, C2S::Char('x'))),
+            S2C::Update((
+                0,
+                C2S::EnterInsert(EnterInsert {
+                    id: 7,
+                    offset: Some(3),
+                }),
+            )),
+            S2C::Update((2, C2S::Char('x'))),
+            // `other` and andy are 0 and 1
+            S2C::NewClient((1, "son".to_string(), Color::Red)),
+            S2C::NewClient((3, "son".to_string(), Color::Red)),
+            S2C::ClientLeft(2),
+        ] {
+            let err = buffer.handle_message(message).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(text_of(&mut buffer).contents(), "ab");
+        assert!(buffer
+            .handle_message(S2C::NewClient((2, "son".to_string(), Color::Red)))
+            .is_ok());
+    }
+
+    #[test]
+    fn connect_keeps_buffered_messages() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let SocketAddr::V4(address) = listener.local_addr().unwrap() else {
+                unreachable!("The listener is bound to an ipv4 address");
+            };
+            let server = async {
+                let (mut server, _) = listener.accept().await.unwrap();
+                while server.read_u8().await.unwrap() != 255 {}
+                server.write_u8(0).await.unwrap();
+                version::write_version(&mut server).await.unwrap();
+                version::read_version(&mut server).await.unwrap();
+                C2S::deserialize(&mut server).await.unwrap();
+                C2S::deserialize(&mut server).await.unwrap();
+
+                let text = Text::original_from_str("ab");
+                // Everything is sent at once, so the message after the presence ends up in the
+                // same read as the ones `connect` waits for
+                let mut data = S2C::Full((&text, false)).serialize();
+                data.extend(Vec::<(usize, Color)>::new().serialize());
+                data.extend(S2C::<&Text>::Presence(Vec::new()).serialize());
+                data.extend(S2C::<&Text>::Saved.serialize());
+                server.write_all(&data).await.unwrap();
+                server
+            };
+            let connect = Buffer::connect(
+                address,
+                "andy",
+                #[cfg(feature = "security")]
+                String::new(),
+                &Color::Red,
+                "a",
+            );
+            let (buffer, _server) = tokio::join!(connect, server);
+            let mut buffer = buffer.unwrap();
+            buffer.modified = true;
+            assert!(buffer.update().await.unwrap());
+            assert!(!buffer.modified);
+            assert_eq!(text_of(&mut buffer).contents(), "ab");
+        });
+    }
+
+    fn text_of(buffer: &mut Buffer) -> &mut Text {
+        let BufferTypeData::Regular { text, .. } = &mut buffer.data.buffer_type else {
+            panic!("The buffer isn't regular");
+        };
+        text
     }
-    Ok(stream)
 }
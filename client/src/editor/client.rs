@@ -1,17 +1,32 @@
 use crate::editor::buffer;
 use std::fmt::Debug;
 use std::net::SocketAddrV4;
-use std::{cmp, io, path::Path};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use std::{
+    cmp,
+    collections::HashMap,
+    ffi::OsString,
+    fs, io, iter,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+use tokio::net::TcpStream;
 
-use btep::{c2s::C2S, Serialize};
-use crossterm::{event::KeyEvent, style::Color};
+use btep::{
+    c2s::{EnterInsert, C2S},
+    s2c::S2C,
+};
+use crossterm::{event::KeyEvent, style::Color, terminal};
 use text::Text;
+use unicode_segmentation::UnicodeSegmentation;
 use utils::other::CursorPos;
 
 use crate::editor::buffer::Buffer;
 
-use super::buffer::BufferTypeData;
+use super::{
+    buffer::{visible_inhabitants, BufferData, BufferTypeData},
+    draw::{cell_positions, display_col, tab_width},
+    BUFFER_SIZE,
+};
 /// Represents a single client.
 pub struct Client {
     #[cfg(feature = "security")]
@@ -28,6 +43,110 @@ pub struct Client {
     pub(crate) modeinfo: ModeInfo,
     /// Stores a message that should be rendered to the user
     pub(crate) info: Option<String>,
+    /// The options set with `:set`
+    pub(crate) options: EditorOptions,
+}
+
+/// The options that change how the editor behaves. They are set with `:set <option>` and
+/// `:set no<option>` for the ones that are toggled, and `:set <option>=<value>` for the others
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EditorOptions {
+    /// Whether line numbers are drawn in front of every line
+    pub(crate) number: bool,
+    /// The amount of columns a tab is wide
+    pub(crate) tabstop: usize,
+    /// Whether the tab key types spaces up to the next tabstop instead of a tab
+    pub(crate) expandtab: bool,
+    /// Whether new lines start with the same whitespace as the line they were made from
+    pub(crate) autoindent: bool,
+    /// Whether the line the cursor is on is highlighted
+    pub(crate) cursorline: bool,
+    /// Whether whitespace at the end of lines is highlighted
+    pub(crate) list: bool,
+    /// How many milliseconds to wait for the rest of a binding before executing the keys typed
+    /// so far. At 0 they are executed right away
+    pub(crate) timeoutlen: u64,
+    /// Whether searching ignores the case of letters
+    pub(crate) ignorecase: bool,
+    /// Whether searches containing an uppercase letter still care about case with `ignorecase`
+    pub(crate) smartcase: bool,
+}
+
+impl Default for EditorOptions {
+    fn default() -> Self {
+        Self {
+            number: false,
+            tabstop: 8,
+            expandtab: false,
+            autoindent: true,
+            cursorline: true,
+            list: false,
+            timeoutlen: 1000,
+            ignorecase: false,
+            smartcase: false,
+        }
+    }
+}
+
+impl EditorOptions {
+    /// Sets an option written as `<option>`, `no<option>` or `<option>=<value>`
+    /// # Errors
+    /// A message describing what was wrong, if the option doesn't exist or the value is invalid
+    pub(crate) fn set(&mut self, option: &str) -> Result<(), String> {
+        if let Some((name, value)) = option.split_once('=') {
+            return match name {
+                "tabstop" => match value.parse::<usize>() {
+                    Ok(tabstop) if tabstop != 0 => {
+                        self.tabstop = tabstop;
+                        Ok(())
+                    }
+                    _ => Err(format!("Invalid tabstop `{value}`")),
+                },
+                "timeoutlen" => match value.parse() {
+                    Ok(timeoutlen) => {
+                        self.timeoutlen = timeoutlen;
+                        Ok(())
+                    }
+                    Err(_) => Err(format!("Invalid timeoutlen `{value}`")),
+                },
+                _ if self.toggle(name).is_some() => Err(format!("`{name}` doesn't take a value")),
+                _ => Err(format!("Unknown option `{name}`")),
+            };
+        }
+        let (name, value) = match option.strip_prefix("no") {
+            Some(name) if self.toggle(name).is_some() => (name, false),
+            _ => (option, true),
+        };
+        match self.toggle(name) {
+            Some(toggle) => {
+                *toggle = value;
+                Ok(())
+            }
+            None if matches!(name, "tabstop" | "timeoutlen") => {
+                Err(format!("`{name}` needs a value"))
+            }
+            None => Err(format!("Unknown option `{option}`")),
+        }
+    }
+
+    /// The option that is turned on and off with `name`
+    fn toggle(&mut self, name: &str) -> Option<&mut bool> {
+        Some(match name {
+            "number" => &mut self.number,
+            "expandtab" => &mut self.expandtab,
+            "autoindent" => &mut self.autoindent,
+            "cursorline" => &mut self.cursorline,
+            "list" => &mut self.list,
+            "ignorecase" => &mut self.ignorecase,
+            "smartcase" => &mut self.smartcase,
+            _ => return None,
+        })
+    }
+
+    /// Whether searching for `query` ignores the case of letters
+    fn ignores_case(&self, query: &str) -> bool {
+        self.ignorecase && !(self.smartcase && query.chars().any(char::is_uppercase))
+    }
 }
 
 impl Client {
@@ -59,6 +178,7 @@ impl Client {
             modeinfo: ModeInfo::default(),
             color: color.to_owned(),
             info: Some("Press Escape then :help to view help".to_string()),
+            options: EditorOptions::default(),
         })
     }
 
@@ -81,10 +201,15 @@ impl Client {
             "help" => self.add_buffer(
                 "Doesn't matter".to_string(),
                 Text::original_from_str(include_str!("../../../help")),
-                Vec::new(),
+                HashMap::new(),
                 None,
                 None,
             ),
+            "participants" => self.show_participants(),
+            "reconnect" => self.reconnect().await,
+            "filter" => self.filter_folder(""),
+            "checkpoint" => self.checkpoint(""),
+            "restore" => self.restore("").await?,
             "bn" | "bufnext" => {
                 self.current_buffer = (self.current_buffer + 1) % self.buffers.len()
             }
@@ -92,17 +217,297 @@ impl Client {
                 self.current_buffer =
                     (self.current_buffer + self.buffers.len() + 1) % self.buffers.len()
             }
-            _ => (),
+            _ => {
+                if let Some(name) = cmd.strip_prefix("rename ") {
+                    self.rename(name).await?;
+                } else if let Some(filter) = cmd.strip_prefix("filter ") {
+                    self.filter_folder(filter);
+                } else if let Some(name) = cmd.strip_prefix("new ") {
+                    self.create_file(name).await?;
+                } else if let Some(name) = cmd.strip_prefix("checkpoint ") {
+                    self.checkpoint(name);
+                } else if let Some(name) = cmd.strip_prefix("restore ") {
+                    self.restore(name).await?;
+                } else if let Some(path) = cmd.strip_prefix("r ") {
+                    self.read_file(path).await?;
+                } else if let Some(path) = cmd.strip_prefix("export ") {
+                    self.export(path);
+                } else if let Some(option) = cmd.strip_prefix("set ") {
+                    if let Err(e) = self.options.set(option) {
+                        self.info = Some(e);
+                    }
+                }
+            }
         }
         Ok(false)
     }
 
+    /// Opens a read-only buffer listing everyone connected to the current file
+    fn show_participants(&mut self) {
+        let BufferTypeData::Regular {
+            colors, presence, ..
+        } = &self.curr().data.buffer_type
+        else {
+            self.info = Some("Only files have participants".to_string());
+            return;
+        };
+        let list = participant_list(presence, colors);
+        self.add_buffer(
+            "Doesn't matter".to_string(),
+            Text::original_from_str(&list),
+            HashMap::new(),
+            None,
+            None,
+        );
+        self.curr_mut().data.modifiable = false;
+    }
+
+    /// Remembers the text of the current buffer, so that it can be rolled back to with
+    /// `restore`
+    fn checkpoint(&mut self, name: &str) {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            self.info = Some("Only files can be checkpointed".to_string());
+            return;
+        };
+        let snapshot = text.snapshot();
+        self.curr_mut()
+            .checkpoints
+            .insert(name.to_string(), snapshot);
+    }
+
+    /// Rolls the current buffer back to the checkpoint `name`. Rather than restoring the pieces,
+    /// the text in between is replaced through the same edits as typing would do, so that the
+    /// server and everyone else end up with the same text
+    async fn restore(&mut self, name: &str) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            self.info = Some("The buffer can't be modified".to_string());
+            return Ok(());
+        }
+        let Some(snapshot) = self.curr().checkpoints.get(name) else {
+            self.info = Some(format!("No checkpoint named '{name}'"));
+            return Ok(());
+        };
+        let new = snapshot.contents().chars().collect::<Vec<_>>();
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            unreachable!("Only files have checkpoints")
+        };
+        let old = text.contents().chars().collect::<Vec<_>>();
+        let prefix = iter::zip(&old, &new).take_while(|(a, b)| a == b).count();
+        let suffix = iter::zip(old[prefix..].iter().rev(), new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let (removed, added) = (
+            &old[prefix..old.len() - suffix],
+            &new[prefix..new.len() - suffix],
+        );
+        if removed.is_empty() && added.is_empty() {
+            return Ok(());
+        }
+
+        let end =
+            old[..prefix + removed.len()]
+                .iter()
+                .fold(CursorPos::default(), |pos, &c| match c {
+                    '\n' => CursorPos {
+                        row: pos.row + 1,
+                        col: 0,
+                    },
+                    _ => CursorPos {
+                        col: pos.col + 1,
+                        ..pos
+                    },
+                });
+        self.curr_mut().cursorpos = end;
+        self.enter_insert(end).await?;
+        for _ in removed {
+            if self.backspace().await?.is_none() {
+                break;
+            }
+        }
+        for &c in added {
+            self.type_char(c).await?;
+        }
+        self.exit_insert().await
+    }
+
+    /// Writes the text of the current buffer to `path` on this machine, without involving the
+    /// server. Missing parent folders are created, and lines are ended like the file on the
+    /// server
+    fn export(&mut self, path: &str) {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            self.info = Some("Only files can be exported".to_string());
+            return;
+        };
+        let contents = text.line_ending.apply(&text.contents()).into_owned();
+        let path = Path::new(path);
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(path, contents));
+        self.info = Some(match written {
+            Ok(()) => format!("Exported to {}", path.display()),
+            Err(e) => format!("Could not export to {}: {e}", path.display()),
+        });
+    }
+
+    /// Renames the file under the cursor in a folder buffer
+    pub(crate) async fn rename(&mut self, name: &str) -> io::Result<()> {
+        let Buffer {
+            data:
+                BufferData {
+                    buffer_type:
+                        BufferTypeData::Folder {
+                            inhabitants,
+                            filter,
+                            show_hidden,
+                        },
+                    ..
+                },
+            cursorpos,
+            path: Some(path),
+            ..
+        } = self.curr()
+        else {
+            self.info = Some("Only files in a folder can be renamed".to_string());
+            return Ok(());
+        };
+        let Some(inhabitant) =
+            visible_inhabitants(inhabitants, filter, *show_hidden).nth(cursorpos.row)
+        else {
+            return Ok(());
+        };
+        let rename = C2S::Rename((path.join(&inhabitant.name), path.join(name)));
+        if !self.folder_request(&rename).await? {
+            self.info = Some("The server refused to rename the file".to_string());
+        }
+        Ok(())
+    }
+
+    /// Only shows the inhabitants of the current folder buffer whose name contains `filter`. An
+    /// empty filter shows all of them
+    pub(crate) fn filter_folder(&mut self, filter: &str) {
+        let curr = self.curr_mut();
+        let BufferTypeData::Folder {
+            filter: old_filter, ..
+        } = &mut curr.data.buffer_type
+        else {
+            self.info = Some("Only folders can be filtered".to_string());
+            return;
+        };
+        *old_filter = filter.to_string();
+        curr.cursorpos = CursorPos::default();
+        curr.line_offset = 0;
+    }
+
+    /// Toggles whether files starting with a `.` are shown in the current folder buffer. The
+    /// cursor stays on the same file when it is still shown
+    pub(crate) fn toggle_hidden(&mut self) {
+        let curr = self.curr_mut();
+        let BufferTypeData::Folder {
+            inhabitants,
+            filter,
+            show_hidden,
+        } = &mut curr.data.buffer_type
+        else {
+            return;
+        };
+        let selected = visible_inhabitants(inhabitants, filter, *show_hidden)
+            .nth(curr.cursorpos.row)
+            .map(|x| x.name.clone());
+        *show_hidden = !*show_hidden;
+        curr.cursorpos.col = 0;
+        if let Some(row) = selected.and_then(|selected| {
+            visible_inhabitants(inhabitants, filter, *show_hidden).position(|x| x.name == selected)
+        }) {
+            curr.cursorpos.row = row;
+        }
+        self.move_by((0, 0));
+    }
+
+    /// Creates a new empty file in a folder buffer and moves the cursor to it
+    pub(crate) async fn create_file(&mut self, name: &str) -> io::Result<()> {
+        let Buffer {
+            data:
+                BufferData {
+                    buffer_type: BufferTypeData::Folder { .. },
+                    ..
+                },
+            path: Some(path),
+            ..
+        } = self.curr()
+        else {
+            self.info = Some("Files can only be created from a folder".to_string());
+            return Ok(());
+        };
+        if !self
+            .folder_request(&C2S::CreateFile(path.join(name)))
+            .await?
+        {
+            self.info = Some("The server refused to create the file".to_string());
+            return Ok(());
+        }
+        let curr = self.curr_mut();
+        let BufferTypeData::Folder {
+            inhabitants,
+            filter,
+            show_hidden,
+        } = &mut curr.data.buffer_type
+        else {
+            unreachable!("The buffer was a folder before the request");
+        };
+        // The new file has to be visible to move the cursor to it
+        if !name.contains(filter.as_str()) {
+            filter.clear();
+        }
+        if name.starts_with('.') {
+            *show_hidden = true;
+        }
+        if let Some(row) =
+            visible_inhabitants(inhabitants, filter, *show_hidden).position(|x| x.name == name)
+        {
+            curr.cursorpos = CursorPos { row, col: 0 };
+        }
+        Ok(())
+    }
+
+    /// Sends a request that modifies the folder of the current buffer, and updates it with the
+    /// answer
+    /// # Returns
+    /// false if the server refused the request
+    async fn folder_request(&mut self, request: &C2S) -> io::Result<bool> {
+        let response = buffer::request(
+            self.server_addr,
+            &self.username,
+            #[cfg(feature = "security")]
+            self.password.clone(),
+            request,
+        )
+        .await?;
+        match response {
+            S2C::Folder((new_inhabitants, _)) => {
+                let curr = self.curr_mut();
+                let BufferTypeData::Folder { inhabitants, .. } = &mut curr.data.buffer_type else {
+                    unreachable!("Only folder buffers send folder requests");
+                };
+                *inhabitants = new_inhabitants;
+                self.move_by((0, 0));
+                self.curr_mut().cursorpos.col = 0;
+                Ok(true)
+            }
+            S2C::Rejected | S2C::AccessDenied => Ok(false),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The server answered with something unexpected",
+            )),
+        }
+    }
+
     /// adds a buffer and switches to it
     fn add_buffer(
         &mut self,
         username: String,
         text: Text,
-        colors: Vec<Color>,
+        colors: HashMap<usize, Color>,
         socket: Option<TcpStream>,
         path: Option<&Path>,
     ) {
@@ -111,6 +516,108 @@ impl Client {
         self.current_buffer = self.buffers.len() - 1;
     }
 
+    /// Marks the buffer at `index` as disconnected after the connection to the server was lost,
+    /// and tells the user about it
+    pub(crate) fn connection_lost(&mut self, index: usize) {
+        self.buffers[index].disconnect();
+        if index == self.current_buffer && self.modeinfo.mode == Mode::Insert {
+            self.modeinfo.set_mode(Mode::Normal);
+        }
+        self.info = Some("Connection lost, use :reconnect to connect again".to_string());
+    }
+
+    /// Connects the current buffer to the server again, replacing its contents with what the
+    /// server currently has. Edits that never reached the server are discarded
+    pub(crate) async fn reconnect(&mut self) {
+        let Some(path) = self.curr().path.clone() else {
+            self.info = Some("This buffer isn't shared through the server".to_string());
+            return;
+        };
+        let buffer = Buffer::connect(
+            self.server_addr,
+            &self.username,
+            #[cfg(feature = "security")]
+            self.password.clone(),
+            &self.color,
+            path,
+        )
+        .await;
+        match buffer {
+            Ok(buffer) => {
+                let discarded = self.curr().disconnected && self.curr().modified;
+                let cursorpos = self.curr().cursorpos;
+                *self.curr_mut() = buffer;
+                self.curr_mut().cursorpos = cursorpos;
+                self.move_by((0, 0));
+                self.info = Some(
+                    if discarded {
+                        "Reconnected, edits the server didn't receive were discarded"
+                    } else {
+                        "Reconnected"
+                    }
+                    .to_string(),
+                );
+            }
+            Err(e) => self.info = Some(format!("Failed to reconnect: {e}")),
+        }
+    }
+
+    /// Replaces the current folder buffer with its parent folder, with the cursor on the folder
+    /// that was left. Nothing happens at the root of what the server shares
+    pub(crate) async fn open_parent(&mut self) -> io::Result<()> {
+        let Buffer {
+            data:
+                BufferData {
+                    buffer_type: BufferTypeData::Folder { .. },
+                    ..
+                },
+            path: Some(path),
+            ..
+        } = self.curr()
+        else {
+            return Ok(());
+        };
+        let Some((parent, left)) = parent_folder(path) else {
+            return Ok(());
+        };
+        let buffer = Buffer::connect(
+            self.server_addr,
+            &self.username,
+            #[cfg(feature = "security")]
+            self.password.clone(),
+            &self.color,
+            parent,
+        )
+        .await;
+        match buffer {
+            Ok(buffer) => *self.curr_mut() = buffer,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::FileTooLarge
+                ) =>
+            {
+                self.info = Some(e.to_string());
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+        let curr = self.curr_mut();
+        if let BufferTypeData::Folder {
+            inhabitants,
+            filter,
+            show_hidden,
+        } = &curr.data.buffer_type
+        {
+            if let Some(row) =
+                visible_inhabitants(inhabitants, filter, *show_hidden).position(|x| x.name == left)
+            {
+                curr.cursorpos.row = row;
+            }
+        }
+        Ok(())
+    }
+
     fn close_current_buffer(&mut self) -> bool {
         self.buffers.remove(self.current_buffer);
         if self.current_buffer == self.buffers.len() {
@@ -122,9 +629,238 @@ impl Client {
         false
     }
 
+    /// Types a tab in insert mode, or spaces up to the next tabstop when `expandtab` is set
+    pub(crate) async fn type_tab(&mut self) -> io::Result<()> {
+        if !self.options.expandtab {
+            return self.type_char('\t').await;
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let line = text.line(row).unwrap_or_default();
+        let col = display_col(&line, col, self.options.tabstop);
+        for _ in 0..tab_width(col, self.options.tabstop) {
+            self.type_char(' ').await?;
+        }
+        Ok(())
+    }
+
+    /// The whitespace a new line made from `row` starts with. This is empty unless `autoindent`
+    /// is set
+    fn indent_of(&self, row: usize) -> String {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return String::new();
+        };
+        if !self.options.autoindent {
+            return String::new();
+        }
+        let line = text.line(row).unwrap_or_default();
+        line.chars().take_while(|c| c.is_whitespace()).collect()
+    }
+
+    /// Types a newline in insert mode, followed by the indentation of the line the cursor was on
+    /// when `autoindent` is set. Whitespace after the cursor doesn't count towards the indentation
+    pub(crate) async fn type_newline(&mut self) -> io::Result<()> {
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let indent = self.indent_of(row).chars().take(col).collect::<String>();
+        self.type_char('\n').await?;
+        for c in indent.chars() {
+            self.type_char(c).await?;
+        }
+        Ok(())
+    }
+
+    /// Opens a new line below the cursor and starts inserting on it
+    pub(crate) async fn open_line_below(&mut self) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            return Ok(());
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let row = self.curr().cursorpos.row;
+        let col = text.line(row).map_or(0, |x| x.chars().count());
+        self.curr_mut().cursorpos = CursorPos { row, col };
+        self.enter_insert(CursorPos { row, col }).await?;
+        self.type_newline().await
+    }
+
+    /// Opens a new line above the cursor and starts inserting on it
+    pub(crate) async fn open_line_above(&mut self) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            return Ok(());
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let row = self.curr().cursorpos.row;
+        let indent = self.indent_of(row);
+        if let Some(above) = row.checked_sub(1) {
+            let col = text.line(above).map_or(0, |x| x.chars().count());
+            self.curr_mut().cursorpos = CursorPos { row: above, col };
+            self.enter_insert(CursorPos { row: above, col }).await?;
+            self.type_char('\n').await?;
+            for c in indent.chars() {
+                self.type_char(c).await?;
+            }
+        } else {
+            // There is no line to append to, so the new line is typed in front of the first one
+            // and insert mode is entered again at its end
+            self.curr_mut().cursorpos = CursorPos::default();
+            self.enter_insert(CursorPos::default()).await?;
+            for c in indent.chars().chain(iter::once('\n')) {
+                self.type_char(c).await?;
+            }
+            self.exit_insert().await?;
+            let pos = CursorPos {
+                row: 0,
+                col: indent.chars().count(),
+            };
+            self.curr_mut().cursorpos = pos;
+            self.enter_insert(pos).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds a level of indentation to the start of the line the cursor is on. This is a tab, or
+    /// `tabstop` spaces when `expandtab` is set
+    pub(crate) async fn indent_line(&mut self) -> io::Result<()> {
+        if !self.curr().data.modifiable
+            || !matches!(self.curr().data.buffer_type, BufferTypeData::Regular { .. })
+        {
+            return Ok(());
+        }
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let indent = if self.options.expandtab {
+            " ".repeat(self.options.tabstop)
+        } else {
+            "\t".to_string()
+        };
+        self.curr_mut().cursorpos = CursorPos { row, col: 0 };
+        self.enter_insert(CursorPos { row, col: 0 }).await?;
+        for c in indent.chars() {
+            self.type_char(c).await?;
+        }
+        self.exit_insert().await?;
+        self.curr_mut().cursorpos = CursorPos {
+            row,
+            col: col + indent.chars().count(),
+        };
+        self.move_by((0, 0));
+        Ok(())
+    }
+
+    /// Removes up to one level of indentation from the start of the line the cursor is on. This
+    /// is either a single tab, or up to `tabstop` spaces
+    pub(crate) async fn dedent_line(&mut self) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            return Ok(());
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let line = text.line(row).unwrap_or_default();
+        let removed = if line.starts_with('\t') {
+            1
+        } else {
+            line.chars()
+                .take(self.options.tabstop)
+                .take_while(|&c| c == ' ')
+                .count()
+        };
+        if removed == 0 {
+            return Ok(());
+        }
+        self.curr_mut().cursorpos = CursorPos { row, col: removed };
+        self.enter_insert(CursorPos { row, col: removed }).await?;
+        for _ in 0..removed {
+            self.backspace().await?;
+        }
+        self.exit_insert().await?;
+        self.curr_mut().cursorpos = CursorPos {
+            row,
+            col: col.saturating_sub(removed),
+        };
+        self.move_by((0, 0));
+        Ok(())
+    }
+
+    /// Swaps the case of the char under the cursor and moves the cursor past it. Chars without a
+    /// case only move the cursor
+    pub(crate) async fn toggle_case(&mut self) -> io::Result<()> {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let Some(c) = text.line(row).and_then(|x| x.chars().nth(col)) else {
+            return Ok(());
+        };
+        let toggled = if c.is_lowercase() {
+            c.to_uppercase().collect::<String>()
+        } else if c.is_uppercase() {
+            c.to_lowercase().collect()
+        } else {
+            String::new()
+        };
+        if toggled.is_empty() || !self.curr().data.modifiable {
+            self.move_right();
+            return Ok(());
+        }
+        self.curr_mut().cursorpos = CursorPos { row, col: col + 1 };
+        self.enter_insert(CursorPos { row, col: col + 1 }).await?;
+        self.backspace().await?;
+        for c in toggled.chars() {
+            self.type_char(c).await?;
+        }
+        self.exit_insert().await?;
+        self.curr_mut().cursorpos = CursorPos {
+            row,
+            col: col + toggled.chars().count(),
+        };
+        self.move_by((0, 0));
+        Ok(())
+    }
+
+    /// Adds `delta` to the number the cursor is on, or the first number after it on the same line.
+    /// The cursor ends up on the last digit of the number
+    pub(crate) async fn add_to_number(&mut self, delta: i64) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            return Ok(());
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let Some((range, replacement)) = text
+            .line(row)
+            .and_then(|line| add_to_number(&line, col, delta))
+        else {
+            return Ok(());
+        };
+        self.curr_mut().cursorpos = CursorPos {
+            row,
+            col: range.end,
+        };
+        self.enter_insert(self.curr().cursorpos).await?;
+        for _ in range.clone() {
+            self.backspace().await?;
+        }
+        for c in replacement.chars() {
+            self.type_char(c).await?;
+        }
+        self.exit_insert().await?;
+        self.curr_mut().cursorpos = CursorPos {
+            row,
+            col: range.start + replacement.chars().count() - 1,
+        };
+        Ok(())
+    }
+
     /// types a char in insert mode
-    /// This function handles sending the request *without* flushing the stream.
-    /// Cursor movement is also handled
+    /// The char is sent together with the other chars typed in the same batch of key events, see
+    /// `Buffer::send_typed`. Cursor movement is also handled
     pub(crate) async fn type_char(&mut self, c: char) -> io::Result<()> {
         let BufferTypeData::Regular {
             ref mut text,
@@ -134,7 +870,10 @@ impl Client {
         else {
             todo!("You can only type in regular buffers")
         };
-        text.client_mut(curr_id).push_char(c);
+        if !text.client_mut(curr_id).push_char(c) {
+            return Err(out_of_ids());
+        }
+        self.curr_mut().modified = true;
         match c {
             '\n' => {
                 self.curr_mut().cursorpos.col = 0;
@@ -142,12 +881,104 @@ impl Client {
             }
             _ => self.curr_mut().cursorpos.col += 1,
         }
-        if let Some(buffer::Socket { ref mut writer, .. }) = self.curr_mut().socket {
-            writer.write_all(&C2S::Char(c).serialize()).await?
+        self.curr_mut().typed.push(c);
+        Ok(())
+    }
+
+    /// Inserts a pasted block of text at the cursor and moves the cursor past it. In normal mode
+    /// this enters insert mode for the duration of the paste, and in command mode the text gets
+    /// added to the command instead.
+    /// This function handles sending the request *without* flushing the stream
+    pub(crate) async fn paste(&mut self, text: &str) -> io::Result<()> {
+        // Terminals tend to send newlines as carriage returns
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let was_normal = match self.modeinfo.mode {
+            Mode::Normal => true,
+            Mode::Insert => false,
+            Mode::Command(ref mut cmd) => {
+                cmd.extend(text.chars().filter(|&c| c != '\n'));
+                return Ok(());
+            }
+            Mode::Search { ref mut query, .. } => {
+                query.extend(text.chars().filter(|&c| c != '\n'));
+                self.preview_search();
+                return Ok(());
+            }
+        };
+        if !matches!(self.curr().data.buffer_type, BufferTypeData::Regular { .. })
+            || !self.curr().data.modifiable
+        {
+            return Ok(());
+        }
+        if was_normal {
+            self.enter_insert(self.curr().cursorpos).await?;
+        }
+        self.insert_str(text).await?;
+        if was_normal {
+            self.exit_insert().await?;
         }
         Ok(())
     }
 
+    /// Inserts `text` at the cursor while in insert mode, and moves the cursor past it. It is
+    /// sent as a single `C2S::Str`.
+    /// This function handles sending the request *without* flushing the stream
+    async fn insert_str(&mut self, text: String) -> io::Result<()> {
+        let BufferTypeData::Regular {
+            text: ref mut buffer_text,
+            id: curr_id,
+            ..
+        } = self.curr_mut().data.buffer_type
+        else {
+            unreachable!("You can only be in insert mode in regular buffers")
+        };
+        if !buffer_text.client_mut(curr_id).push_str(&text) {
+            return Err(out_of_ids());
+        }
+        self.curr_mut().modified = true;
+        match text.rsplit_once('\n') {
+            Some((before, after)) => {
+                self.curr_mut().cursorpos.row += before.matches('\n').count() + 1;
+                self.curr_mut().cursorpos.col = after.chars().count();
+            }
+            None => self.curr_mut().cursorpos.col += text.chars().count(),
+        }
+        self.curr_mut().send(C2S::Str(text)).await
+    }
+
+    /// Inserts the contents of the local file at `path` at the cursor, like `:r` in vim. Large
+    /// files are sent to the server in chunks of `BUFFER_SIZE` bytes
+    async fn read_file(&mut self, path: &str) -> io::Result<()> {
+        if !matches!(self.curr().data.buffer_type, BufferTypeData::Regular { .. })
+            || !self.curr().data.modifiable
+        {
+            self.info = Some("The buffer can't be modified".to_string());
+            return Ok(());
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents.replace("\r\n", "\n"),
+            Err(e) => {
+                self.info = Some(format!("Could not read {path}: {e}"));
+                return Ok(());
+            }
+        };
+        if contents.is_empty() {
+            return Ok(());
+        }
+        self.enter_insert(self.curr().cursorpos).await?;
+        let mut rest = contents.as_str();
+        while !rest.is_empty() {
+            let mut end = cmp::min(BUFFER_SIZE, rest.len());
+            while !rest.is_char_boundary(end) {
+                end += 1;
+            }
+            let (chunk, after) = rest.split_at(end);
+            self.insert_str(chunk.to_string()).await?;
+            rest = after;
+        }
+        self.exit_insert().await
+    }
+
     pub(crate) async fn exit_insert(&mut self) -> io::Result<()> {
         let BufferTypeData::Regular {
             ref mut text,
@@ -160,30 +991,26 @@ impl Client {
         text.client_mut(curr_id).exit_insert();
         self.modeinfo.set_mode(Mode::Normal);
 
-        if let Some(buffer::Socket { ref mut writer, .. }) = self.curr_mut().socket {
-            writer.write_all(&C2S::ExitInsert.serialize()).await?
-        }
+        self.curr_mut().send(C2S::ExitInsert).await?;
         let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
             todo!()
         };
-        let curr_line_len = text.lines().nth(self.curr().cursorpos.row).unwrap().len();
+        let curr_line = text.line(self.curr().cursorpos.row).unwrap_or_default();
 
-        if self.curr().cursorpos.col == curr_line_len {
-            self.curr_mut().cursorpos.col = self.curr_mut().cursorpos.col.saturating_sub(1);
+        if self.curr().cursorpos.col == curr_line.chars().count() {
+            self.curr_mut().cursorpos.col =
+                grapheme_start(&curr_line, self.curr().cursorpos.col.saturating_sub(1));
         }
         Ok(())
     }
 
     pub(crate) async fn backspace(&mut self) -> io::Result<Option<char>> {
         let prev_line_len = (self.curr_mut().cursorpos.row != 0).then(|| {
-            let BufferTypeData::Regular { ref mut text, .. } = self.curr_mut().data.buffer_type
-            else {
+            let BufferTypeData::Regular { ref text, .. } = self.curr().data.buffer_type else {
                 todo!()
             };
-            text.lines()
-                .nth(self.curr_mut().cursorpos.row - 1)
-                .unwrap()
-                .len()
+            text.line(self.curr().cursorpos.row - 1)
+                .map_or(0, |x| x.chars().count())
         });
 
         let BufferTypeData::Regular {
@@ -195,82 +1022,333 @@ impl Client {
             todo!()
         };
         let (deleted, swaps) = text.client_mut(curr_id).backspace();
-        if let Some(buffer::Socket { ref mut writer, .. }) = self.curr_mut().socket {
-            writer.write_all(&C2S::Backspace(swaps).serialize()).await?;
-        }
+        self.curr_mut().send(C2S::Backspace(swaps)).await?;
 
         if deleted.is_some() {
-            if self.curr_mut().cursorpos.col == 0 {
+            self.curr_mut().modified = true;
+        }
+        // The deleted character decides where the cursor goes, rather than the column, as that
+        // might be out of date with what other clients did
+        match deleted {
+            Some('\n') => {
                 self.curr_mut().cursorpos.row -= 1;
-                self.curr_mut().cursorpos.col = prev_line_len.unwrap();
-            } else {
-                self.curr_mut().cursorpos.col -= 1;
+                self.curr_mut().cursorpos.col = prev_line_len.unwrap_or(0);
+            }
+            Some(_) => {
+                let col = &mut self.curr_mut().cursorpos.col;
+                *col = col.saturating_sub(1);
             }
+            None => (),
         }
 
         Ok(deleted)
     }
 
+    /// Moves the cursor one grapheme to the left
     pub(crate) fn move_left(&mut self) {
-        self.curr_mut().cursorpos.col = self.curr_mut().cursorpos.col.saturating_sub(1);
+        self.move_by((0, -1));
     }
 
     pub(crate) fn move_up(&mut self) {
-        self.curr_mut().cursorpos.row = self.curr_mut().cursorpos.row.saturating_sub(1);
-        self.curr_mut().cursorpos.col = cmp::min(self.curr_mut().cursorpos.col, {
-            match &self.curr().data.buffer_type {
-                BufferTypeData::Regular { text, .. } => text
-                    .lines()
-                    .nth(self.curr().cursorpos.row)
-                    .map_or(0, |x| x.chars().count().saturating_sub(1)),
-                BufferTypeData::Folder { inhabitants } => inhabitants
-                    .get(self.curr().cursorpos.row)
-                    .map_or(0, |x| x.name.len().saturating_sub(1)),
-            }
-        });
+        self.move_by((-1, 0));
     }
 
     pub(crate) fn move_down(&mut self) {
-        self.curr_mut().cursorpos.row = cmp::min(self.curr_mut().cursorpos.row + 1, {
-            match &self.curr().data.buffer_type {
-                BufferTypeData::Regular { text, .. } => text.lines().count().saturating_sub(1),
-                BufferTypeData::Folder { inhabitants } => inhabitants.len().saturating_sub(1),
+        self.move_by((1, 0));
+    }
+
+    /// Moves the cursor one grapheme to the right, without moving past the last one
+    pub(crate) fn move_right(&mut self) {
+        let CursorPos { row, col } = self.curr().cursorpos;
+        self.curr_mut().cursorpos.col = match &self.curr().data.buffer_type {
+            BufferTypeData::Regular { text, .. } => {
+                text.line(row).map_or(col, |x| next_grapheme(&x, col))
             }
-        });
-        self.curr_mut().cursorpos.col = cmp::min(self.curr_mut().cursorpos.col, {
-            match &self.curr().data.buffer_type {
-                BufferTypeData::Regular { text, .. } => text
-                    .lines()
-                    .nth(self.curr_mut().cursorpos.row)
-                    .map_or(0, |x| x.chars().count().saturating_sub(2)),
-                BufferTypeData::Folder { inhabitants } => inhabitants[self.curr().cursorpos.row]
-                    .name
-                    .len()
-                    .saturating_sub(1),
+            BufferTypeData::Folder { .. } => col + 1,
+        };
+        self.move_by((0, 0));
+    }
+
+    /// Moves the cursor to the start of the line
+    pub(crate) fn move_to_line_start(&mut self) {
+        self.curr_mut().cursorpos.col = 0;
+    }
+
+    /// Finds the bracket matching the one under the cursor, skipping over the pairs of brackets
+    /// in between. This is where `%` jumps to.
+    /// None if the cursor isn't on a bracket, or it isn't matched
+    pub(crate) fn matching_bracket(&self) -> Option<CursorPos> {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return None;
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let bracket = text.line(row)?.chars().nth(col)?;
+        let (open, close, forwards) = match bracket {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => (')', '(', false),
+            ']' => (']', '[', false),
+            '}' => ('}', '{', false),
+            _ => return None,
+        };
+        // Every char from the cursor onwards in the direction of the match, with its position
+        let chars: Box<dyn Iterator<Item = (CursorPos, char)>> = if forwards {
+            Box::new((row..text.line_count()).flat_map(|r| {
+                let line = text.line(r).unwrap_or_default();
+                let skip = if r == row { col } else { 0 };
+                line.chars()
+                    .enumerate()
+                    .skip(skip)
+                    .map(move |(col, c)| ((r, col).into(), c))
+                    .collect::<Vec<_>>()
+            }))
+        } else {
+            Box::new((0..=row).rev().flat_map(|r| {
+                let line = text.line(r).unwrap_or_default();
+                let take = if r == row { col + 1 } else { usize::MAX };
+                let mut chars = line
+                    .chars()
+                    .enumerate()
+                    .take(take)
+                    .map(move |(col, c)| ((r, col).into(), c))
+                    .collect::<Vec<_>>();
+                chars.reverse();
+                chars
+            }))
+        };
+        let mut depth = 0usize;
+        for (pos, c) in chars {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(pos);
+                }
             }
-        });
+        }
+        None
     }
 
-    pub(crate) fn move_right(&mut self) {
-        self.curr_mut().cursorpos.col = cmp::min(self.curr_mut().cursorpos.col + 1, {
-            match &self.curr().data.buffer_type {
-                BufferTypeData::Regular { text, .. } => text
-                    .lines()
-                    .nth(self.curr().cursorpos.row)
-                    .map_or(0, |x| x.chars().count().saturating_sub(1)),
-                BufferTypeData::Folder { inhabitants } => inhabitants[self.curr().cursorpos.row]
-                    .name
-                    .len()
-                    .saturating_sub(1),
+    /// Starts searching the current buffer from the cursor
+    pub(crate) fn start_search(&mut self) {
+        if let BufferTypeData::Regular { .. } = self.curr().data.buffer_type {
+            let origin = self.curr().cursorpos;
+            self.modeinfo.set_mode(Mode::Search {
+                query: String::new(),
+                origin,
+            });
+        }
+    }
+
+    /// Moves the cursor to the first match of the search after where the search started. The
+    /// cursor goes back to where it started when nothing matches. Only the cursor moves, so
+    /// nothing is sent to the server
+    pub(crate) fn preview_search(&mut self) {
+        let Mode::Search { ref query, origin } = self.modeinfo.mode else {
+            return;
+        };
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return;
+        };
+        let ignore_case = self.options.ignores_case(query);
+        self.curr_mut().cursorpos = find(text, query, origin, ignore_case).unwrap_or(origin);
+    }
+
+    /// The match of the search that the cursor is on, as its row and the columns it covers
+    pub(crate) fn search_match(&self) -> Option<(usize, Range<usize>)> {
+        let Mode::Search { ref query, .. } = self.modeinfo.mode else {
+            return None;
+        };
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return None;
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let ignore_case = self.options.ignores_case(query);
+        let query = query.chars().collect::<Vec<_>>();
+        let found = text
+            .line(row)?
+            .chars()
+            .skip(col)
+            .take(query.len())
+            .collect::<Vec<_>>();
+        (!query.is_empty() && chars_match(&found, &query, ignore_case))
+            .then_some((row, col..col + query.len()))
+    }
+
+    /// Leaves search mode. The cursor stays on the match, unless `cancel` is set, in which case
+    /// it goes back to where the search started
+    pub(crate) fn end_search(&mut self, cancel: bool) {
+        if let Mode::Search { origin, .. } = self.modeinfo.mode {
+            if cancel {
+                self.curr_mut().cursorpos = origin;
             }
-        });
+        }
+        self.modeinfo.set_mode(Mode::Normal);
     }
 
-    /// Note this does not flush the writer
+    /// Moves the cursor to the first occurrence of the word under it, which is a simple take on
+    /// going to where it is defined
+    pub(crate) fn go_to_definition(&mut self) {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return;
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let line = text
+            .line(row)
+            .unwrap_or_default()
+            .chars()
+            .collect::<Vec<_>>();
+        let Some(word) = word_at(&line, col) else {
+            self.info = Some("There is no word under the cursor".to_string());
+            return;
+        };
+        if let Some(pos) = first_word(text, &line[word]) {
+            self.curr_mut().cursorpos = pos;
+        }
+    }
+
+    /// Moves the cursor to the bracket matching the one under it, if there is one
+    pub(crate) fn jump_to_matching_bracket(&mut self) {
+        if let Some(pos) = self.matching_bracket() {
+            self.curr_mut().cursorpos = pos;
+        }
+    }
+
+    /// Moves the cursor onto the last grapheme of the line
+    pub(crate) fn move_to_line_end(&mut self) {
+        self.curr_mut().cursorpos.col = usize::MAX;
+        self.move_by((0, 0));
+    }
+
+    /// Enters insert mode after the grapheme under the cursor
+    pub(crate) async fn append(&mut self) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            return Ok(());
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let CursorPos { row, col } = self.curr().cursorpos;
+        let line = text.line(row).unwrap_or_default();
+        self.curr_mut().cursorpos.col = next_grapheme(&line, col);
+        self.enter_insert(self.curr().cursorpos).await
+    }
+
+    /// Enters insert mode at the end of the line. The column is counted in chars, like
+    /// everywhere else, rather than bytes
+    pub(crate) async fn append_to_line(&mut self) -> io::Result<()> {
+        if !self.curr().data.modifiable {
+            return Ok(());
+        }
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return Ok(());
+        };
+        let row = self.curr().cursorpos.row;
+        self.curr_mut().cursorpos.col = text.line(row).map_or(0, |x| x.chars().count());
+        self.enter_insert(self.curr().cursorpos).await
+    }
+
+    /// Moves the cursor by `offset`, keeping it on a char of the current buffer and at the start
+    /// of a grapheme
+    fn move_by(&mut self, offset: (isize, isize)) {
+        let line_lengths = match &self.curr().data.buffer_type {
+            BufferTypeData::Regular { text, .. } => {
+                text.lines().map(|x| x.chars().count()).collect::<Vec<_>>()
+            }
+            BufferTypeData::Folder {
+                inhabitants,
+                filter,
+                show_hidden,
+            } => visible_inhabitants(inhabitants, filter, *show_hidden)
+                .map(|x| x.name.len())
+                .collect(),
+        };
+        self.curr_mut().cursorpos = self
+            .curr()
+            .cursorpos
+            .saturating_add(offset)
+            .clamp_to(&line_lengths);
+        self.snap_to_grapheme();
+    }
+
+    /// Moves the cursor to the char that is drawn at `column` and `row` of the terminal. Clicks
+    /// below the text, or while not in normal mode, are ignored
+    /// # Errors
+    /// - failing to get the size of the terminal
+    pub(crate) fn click(&mut self, column: u16, row: u16) -> io::Result<()> {
+        let size = terminal::size()?;
+        // The last row is taken up by the status line
+        if self.modeinfo.mode != Mode::Normal || row >= size.1.saturating_sub(1) {
+            return Ok(());
+        }
+        let (column, row) = (usize::from(column), usize::from(row));
+        let text_col = 3 + self.gutter_width();
+        let width = usize::from(size.0).saturating_sub(text_col);
+        let line_offset = self.curr().line_offset;
+        let cursorpos = match &self.curr().data.buffer_type {
+            BufferTypeData::Regular { text, .. } => {
+                let mut screen_row = 0;
+                let mut clicked = None;
+                for line_row in line_offset..text.line_count() {
+                    let line = text.line(line_row).unwrap_or_default();
+                    let (positions, rows) = cell_positions(&line, width, self.options.tabstop);
+                    if row < screen_row + rows {
+                        let target = (row - screen_row, column.saturating_sub(text_col));
+                        let col = positions.iter().rposition(|&x| x <= target).unwrap_or(0);
+                        clicked = Some(CursorPos {
+                            row: line_row,
+                            col: grapheme_start(&line, col),
+                        });
+                        break;
+                    }
+                    screen_row += rows;
+                }
+                let Some(cursorpos) = clicked else {
+                    return Ok(());
+                };
+                cursorpos
+            }
+            BufferTypeData::Folder {
+                inhabitants,
+                filter,
+                show_hidden,
+            } => {
+                if line_offset + row
+                    >= visible_inhabitants(inhabitants, filter, *show_hidden).count()
+                {
+                    return Ok(());
+                }
+                CursorPos {
+                    row: line_offset + row,
+                    col: 0,
+                }
+            }
+        };
+        self.curr_mut().cursorpos = cursorpos;
+        Ok(())
+    }
+
+    /// Moves the cursor to the start of the grapheme it is in, so that it never ends up in the
+    /// middle of one after moving vertically
+    fn snap_to_grapheme(&mut self) {
+        let BufferTypeData::Regular { text, .. } = &self.curr().data.buffer_type else {
+            return;
+        };
+        if let Some(line) = text.line(self.curr().cursorpos.row) {
+            let col = grapheme_start(&line, self.curr().cursorpos.col);
+            self.curr_mut().cursorpos.col = col;
+        }
+    }
+
+    /// Note this does not flush the writer.
+    /// `pos` is moved onto the text, and so is the cursor if it was at `pos`
     pub(crate) async fn enter_insert(&mut self, pos: CursorPos) -> io::Result<()> {
         if !self.curr().data.modifiable {
             return Ok(());
         }
+        let cursor_at_pos = self.curr().cursorpos == pos;
         let BufferTypeData::Regular {
             ref mut text,
             id: curr_id,
@@ -279,15 +1357,27 @@ impl Client {
         else {
             unreachable!()
         };
-        let (_offset, _id) = text.client_mut(curr_id).enter_insert(pos);
-        if let Some(buffer::Socket { ref mut writer, .. }) = self.curr_mut().socket {
-            writer.write_all(&C2S::EnterInsert(pos).serialize()).await?;
+        let pos = text.clamp(pos);
+        let (offset, id) = text
+            .client_mut(curr_id)
+            .enter_insert(pos)
+            .ok_or_else(out_of_ids)?;
+        if cursor_at_pos {
+            self.curr_mut().cursorpos = pos;
         }
+        self.curr_mut()
+            .send(C2S::EnterInsert(EnterInsert { id, offset }))
+            .await?;
         self.modeinfo.set_mode(Mode::Insert);
         Ok(())
     }
 }
 
+/// The error for when this client can't create any more pieces, see `Piece::next_id`
+fn out_of_ids() -> io::Error {
+    io::Error::other("This client has run out of ids for new pieces")
+}
+
 #[derive(Debug, Default)]
 pub struct ModeInfo {
     pub(crate) keymap: Vec<KeyEvent>,
@@ -311,6 +1401,9 @@ pub enum Mode {
     Insert,
     /// Writing a higher level command (: in (neo)vi(m))
     Command(String),
+    /// Searching the current buffer (/ in (neo)vi(m)). The cursor is moved to the first match
+    /// while typing, and goes back to `origin` if the search is cancelled
+    Search { query: String, origin: CursorPos },
 }
 
 impl Default for Mode {
@@ -318,3 +1411,615 @@ impl Default for Mode {
         Self::Normal
     }
 }
+
+/// The char index of the start of every grapheme cluster in `line`
+fn grapheme_starts(line: &str) -> impl Iterator<Item = usize> + '_ {
+    line.graphemes(true).scan(0, |start, grapheme| {
+        let ret = *start;
+        *start += grapheme.chars().count();
+        Some(ret)
+    })
+}
+
+/// The char index of the start of the grapheme that contains the char at `col`
+pub(crate) fn grapheme_start(line: &str, col: usize) -> usize {
+    grapheme_starts(line)
+        .take_while(|&start| start <= col)
+        .last()
+        .unwrap_or(0)
+}
+
+/// The char index of the start of the grapheme after the one that contains the char at `col`.
+/// This is the amount of chars in `line` if it is the last grapheme
+pub(crate) fn next_grapheme(line: &str, col: usize) -> usize {
+    grapheme_starts(line)
+        .find(|&start| start > col)
+        .unwrap_or_else(|| line.chars().count())
+}
+
+/// Finds the first match of `query` after `from`, wrapping around to the start of the text. A
+/// match at `from` itself is only found after wrapping around. Matches can't span multiple lines
+fn find(text: &Text, query: &str, from: CursorPos, ignore_case: bool) -> Option<CursorPos> {
+    if query.is_empty() {
+        return None;
+    }
+    let query = query.chars().collect::<Vec<_>>();
+    let rows = text.line_count();
+    // The row of the cursor is gone through twice, before and after wrapping around
+    (0..=rows).find_map(|i| {
+        let row = (from.row + i) % rows;
+        let line = text
+            .line(row)
+            .unwrap_or_default()
+            .chars()
+            .collect::<Vec<_>>();
+        line.windows(query.len())
+            .enumerate()
+            .filter(|(_, found)| chars_match(found, &query, ignore_case))
+            .map(|(col, _)| col)
+            .find(|&col| match i {
+                0 => col > from.col,
+                _ if i == rows => col <= from.col,
+                _ => true,
+            })
+            .map(|col| CursorPos { row, col })
+    })
+}
+
+/// Whether `c` can be part of a word, like the name of a variable
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The columns of the word that the char at `col` is part of.
+/// None if that char isn't part of a word
+fn word_at(line: &[char], col: usize) -> Option<Range<usize>> {
+    if !is_word_char(*line.get(col)?) {
+        return None;
+    }
+    let start = line[..col]
+        .iter()
+        .rposition(|&c| !is_word_char(c))
+        .map_or(0, |x| x + 1);
+    let end = line[col..]
+        .iter()
+        .position(|&c| !is_word_char(c))
+        .map_or(line.len(), |x| col + x);
+    Some(start..end)
+}
+
+/// Finds the first occurrence of `word` in the text that isn't part of a longer word
+fn first_word(text: &Text, word: &[char]) -> Option<CursorPos> {
+    (0..text.line_count()).find_map(|row| {
+        let line = text
+            .line(row)
+            .unwrap_or_default()
+            .chars()
+            .collect::<Vec<_>>();
+        line.windows(word.len())
+            .enumerate()
+            .position(|(col, found)| {
+                found == word
+                    && !col.checked_sub(1).is_some_and(|x| is_word_char(line[x]))
+                    && !line
+                        .get(col + word.len())
+                        .copied()
+                        .is_some_and(is_word_char)
+            })
+            .map(|col| CursorPos { row, col })
+    })
+}
+
+/// Whether `found` is the same as `query`. Letters are compared by their lowercase form when
+/// ignoring case, which is done char by char so that columns stay the same
+fn chars_match(found: &[char], query: &[char], ignore_case: bool) -> bool {
+    found.len() == query.len()
+        && iter::zip(found, query).all(|(&a, &b)| {
+            if ignore_case {
+                a.to_lowercase().eq(b.to_lowercase())
+            } else {
+                a == b
+            }
+        })
+}
+
+/// The parent of a folder path sent to the server, together with the name of the folder within
+/// it. `.` and `..` are resolved first, as the server does
+/// # Returns
+/// None if `path` is the root of what the server shares
+fn parent_folder(path: &Path) -> Option<(PathBuf, OsString)> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    let name = normalized.file_name()?.to_os_string();
+    normalized.pop();
+    Some((normalized, name))
+}
+
+/// Finds the number that `col` is on, or the first one after it, in `line` and adds `delta` to it.
+/// A `-` right in front of the digits makes the number negative, and numbers with leading zeros
+/// keep their amount of digits
+/// # Returns
+/// The chars taken up by the number together with what they should be replaced with
+fn add_to_number(line: &str, col: usize, delta: i64) -> Option<(Range<usize>, String)> {
+    let chars = line.chars().collect::<Vec<_>>();
+    let mut start = cmp::min(col, chars.len());
+    if chars.get(start).is_some_and(char::is_ascii_digit) {
+        while start > 0 && chars[start - 1].is_ascii_digit() {
+            start -= 1;
+        }
+    } else {
+        start += chars[start..].iter().position(char::is_ascii_digit)?;
+    }
+    let end = start
+        + chars[start..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+    let digits = chars[start..end].iter().collect::<String>();
+    let negative = start > 0 && chars[start - 1] == '-';
+    let value = digits.parse::<i64>().ok()?;
+    let value = if negative { -value } else { value }.saturating_add(delta);
+    let width = if digits.starts_with('0') {
+        digits.len()
+    } else {
+        0
+    };
+    let replacement = format!(
+        "{}{:0width$}",
+        if value < 0 { "-" } else { "" },
+        value.unsigned_abs()
+    );
+    Some((start - usize::from(negative)..end, replacement))
+}
+
+/// Lists the username and color of everyone in `presence`, one per line and ordered by id
+fn participant_list(presence: &HashMap<usize, String>, colors: &HashMap<usize, Color>) -> String {
+    let mut participants = presence.iter().collect::<Vec<_>>();
+    participants.sort_unstable_by_key(|(id, _)| **id);
+    participants
+        .into_iter()
+        .map(|(id, username)| {
+            let color = colors.get(id).copied().unwrap_or(Color::Reset);
+            format!("{username} ({color:?})\n")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, env, fs, net::SocketAddrV4, path::Path, process};
+
+    use crossterm::style::Color;
+    use futures::executor::block_on;
+    use text::Text;
+
+    use btep::s2c::Inhabitant;
+
+    use super::{
+        add_to_number, grapheme_start, next_grapheme, parent_folder, participant_list,
+        visible_inhabitants, BufferTypeData, Client, EditorOptions, Mode, BUFFER_SIZE,
+    };
+    use crate::editor::{buffer::Buffer, App};
+
+    fn offline_client(text: &str) -> Client {
+        App::new_with_buffer(
+            "andy".to_string(),
+            #[cfg(feature = "security")]
+            String::new(),
+            Text::original_from_str(text),
+            HashMap::new(),
+            None,
+            SocketAddrV4::new([127, 0, 0, 1].into(), 0),
+            &Color::Red,
+            Path::new("test"),
+        )
+        .client
+    }
+
+    fn contents(client: &Client) -> String {
+        let BufferTypeData::Regular { text, .. } = &client.curr().data.buffer_type else {
+            panic!("Expected a regular buffer");
+        };
+        text.contents()
+    }
+
+    #[test]
+    fn matching_bracket() {
+        let mut client = offline_client("f(a[0], {\n  (b)\n}) ]");
+        let mut jump = |from: (usize, usize)| {
+            client.curr_mut().cursorpos = from.into();
+            client.matching_bracket().map(|x| (x.row, x.col))
+        };
+        assert_eq!(jump((0, 1)), Some((2, 1)));
+        assert_eq!(jump((2, 1)), Some((0, 1)));
+        assert_eq!(jump((0, 3)), Some((0, 5)));
+        assert_eq!(jump((0, 8)), Some((2, 0)));
+        assert_eq!(jump((2, 0)), Some((0, 8)));
+        assert_eq!(jump((1, 2)), Some((1, 4)));
+        assert_eq!(jump((2, 3)), None);
+        assert_eq!(jump((0, 0)), None);
+
+        client.curr_mut().cursorpos = (1, 4).into();
+        client.jump_to_matching_bracket();
+        assert_eq!(client.curr().cursorpos, (1, 2).into());
+    }
+
+    #[test]
+    fn search_case() {
+        fn search(client: &mut Client, query: &str) -> (usize, usize) {
+            client.start_search();
+            let Mode::Search {
+                query: ref mut x, ..
+            } = client.modeinfo.mode
+            else {
+                panic!("Expected to be searching");
+            };
+            x.push_str(query);
+            client.preview_search();
+            let pos = client.curr().cursorpos;
+            client.end_search(true);
+            (pos.row, pos.col)
+        }
+
+        let mut client = offline_client("Hello\nhello\nHELLO");
+        assert_eq!(search(&mut client, "hello"), (1, 0));
+        assert_eq!(search(&mut client, "HELLO"), (2, 0));
+
+        client.options.ignorecase = true;
+        assert_eq!(search(&mut client, "hello"), (1, 0));
+        assert_eq!(search(&mut client, "hELLo"), (1, 0));
+        assert_eq!(search(&mut client, "HELLO"), (1, 0));
+
+        client.options.smartcase = true;
+        // A lowercase query still matches mixed case
+        assert_eq!(search(&mut client, "ello"), (0, 1));
+        assert_eq!(search(&mut client, "hello"), (1, 0));
+        // An uppercase letter makes it case sensitive, so nothing but the exact text matches
+        assert_eq!(search(&mut client, "HELLO"), (2, 0));
+        assert_eq!(search(&mut client, "HeLLO"), (0, 0));
+        assert!(client.search_match().is_none());
+    }
+
+    #[test]
+    fn go_to_definition() {
+        let mut client = offline_client("let foobar = 1;\nlet foo = 2;\nfoo + foobar");
+        client.curr_mut().cursorpos = (2, 1).into();
+        client.go_to_definition();
+        assert_eq!(client.curr().cursorpos, (1, 4).into());
+
+        client.curr_mut().cursorpos = (2, 11).into();
+        client.go_to_definition();
+        assert_eq!(client.curr().cursorpos, (0, 4).into());
+
+        client.curr_mut().cursorpos = (2, 4).into();
+        client.go_to_definition();
+        assert_eq!(client.curr().cursorpos, (2, 4).into());
+        assert!(client.info.is_some());
+    }
+
+    #[test]
+    fn read_file() {
+        let path = env::temp_dir().join(format!("editor-read-{}", process::id()));
+        let inserted = "é\r\n".repeat(BUFFER_SIZE);
+        fs::write(&path, &inserted).unwrap();
+        let mut client = offline_client("ab");
+        client.curr_mut().cursorpos.col = 1;
+        block_on(client.execute_command(&format!("r {}", path.display()))).unwrap();
+        assert_eq!(
+            contents(&client),
+            format!("a{}b", inserted.replace("\r\n", "\n"))
+        );
+        assert_eq!(client.curr().cursorpos, (BUFFER_SIZE, 0).into());
+        fs::remove_file(&path).unwrap();
+
+        block_on(client.execute_command(&format!("r {}", path.display()))).unwrap();
+        assert!(client.info.unwrap().starts_with("Could not read"));
+    }
+
+    #[test]
+    fn export() {
+        let root = env::temp_dir().join(format!("editor-export-{}", process::id()));
+        let path = root.join("a/b.txt");
+        let mut client = offline_client("ab\ncd\n");
+        block_on(client.execute_command(&format!("export {}", path.display()))).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ab\ncd\n");
+        assert_eq!(client.info, Some(format!("Exported to {}", path.display())));
+
+        block_on(client.execute_command(&format!("export {}", root.display()))).unwrap();
+        assert!(client.info.unwrap().starts_with("Could not export"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        let mut client = offline_client("ab\ncd");
+        block_on(client.execute_command("checkpoint start")).unwrap();
+        block_on(client.enter_insert((1, 1).into())).unwrap();
+        block_on(client.backspace()).unwrap();
+        block_on(client.type_char('x')).unwrap();
+        block_on(client.type_char('\n')).unwrap();
+        block_on(client.exit_insert()).unwrap();
+        assert_eq!(contents(&client), "ab\nx\nd");
+        block_on(client.execute_command("checkpoint")).unwrap();
+
+        block_on(client.execute_command("restore start")).unwrap();
+        assert_eq!(contents(&client), "ab\ncd");
+        block_on(client.execute_command("restore")).unwrap();
+        assert_eq!(contents(&client), "ab\nx\nd");
+
+        block_on(client.execute_command("restore missing")).unwrap();
+        assert_eq!(
+            client.info.as_deref(),
+            Some("No checkpoint named 'missing'")
+        );
+        assert_eq!(contents(&client), "ab\nx\nd");
+    }
+
+    #[test]
+    fn autoindent() {
+        let mut client = offline_client("  \tab\nc");
+        block_on(client.open_line_below()).unwrap();
+        block_on(client.type_char('x')).unwrap();
+        block_on(client.type_newline()).unwrap();
+        block_on(client.exit_insert()).unwrap();
+        assert_eq!(contents(&client), "  \tab\n  \tx\n  \t\nc");
+
+        client.options.autoindent = false;
+        block_on(client.open_line_below()).unwrap();
+        block_on(client.type_char('y')).unwrap();
+        block_on(client.exit_insert()).unwrap();
+        assert_eq!(contents(&client), "  \tab\n  \tx\n  \t\ny\nc");
+    }
+
+    #[test]
+    fn indentation() {
+        let mut client = offline_client("a\n   b\n\tc\n");
+        block_on(client.indent_line()).unwrap();
+        client.options.expandtab = true;
+        client.options.tabstop = 2;
+        block_on(client.indent_line()).unwrap();
+        assert_eq!(contents(&client), "  \ta\n   b\n\tc\n");
+        assert_eq!(client.curr().cursorpos, (0, 3).into());
+
+        for row in 0..3 {
+            client.curr_mut().cursorpos = (row, 0).into();
+            block_on(client.dedent_line()).unwrap();
+        }
+        assert_eq!(contents(&client), "\ta\n b\nc\n");
+        client.curr_mut().cursorpos = (2, 0).into();
+        block_on(client.dedent_line()).unwrap();
+        assert_eq!(contents(&client), "\ta\n b\nc\n");
+    }
+
+    #[test]
+    fn toggle_case() {
+        let mut client = offline_client("aB-c\n");
+        for _ in 0..4 {
+            block_on(client.toggle_case()).unwrap();
+        }
+        assert_eq!(contents(&client), "Ab-C\n");
+        // The cursor stays on the last char
+        assert_eq!(client.curr().cursorpos, (0, 3).into());
+        block_on(client.toggle_case()).unwrap();
+        assert_eq!(contents(&client), "Ab-c\n");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(add_to_number("a 9 b", 0, 1), Some((2..3, "10".into())));
+        assert_eq!(add_to_number("x123", 2, -1), Some((1..4, "122".into())));
+        assert_eq!(add_to_number("-5", 0, 1), Some((0..2, "-4".into())));
+        assert_eq!(add_to_number("a-1", 2, 2), Some((1..3, "1".into())));
+        assert_eq!(add_to_number("1", 0, -3), Some((0..1, "-2".into())));
+        assert_eq!(add_to_number("007", 1, 1), Some((0..3, "008".into())));
+        assert_eq!(add_to_number("010", 1, -11), Some((0..3, "-001".into())));
+        assert_eq!(add_to_number("12 ab", 3, 1), None);
+        assert_eq!(add_to_number("", 0, 1), None);
+    }
+
+    #[test]
+    fn increment_in_buffer() {
+        let mut client = offline_client("x = -1;\n");
+        block_on(client.add_to_number(2)).unwrap();
+        assert_eq!(contents(&client), "x = 1;\n");
+        assert_eq!(client.curr().cursorpos, (0, 4).into());
+        block_on(client.add_to_number(-10)).unwrap();
+        assert_eq!(contents(&client), "x = -9;\n");
+        assert_eq!(client.curr().cursorpos, (0, 5).into());
+    }
+
+    #[test]
+    fn open_line_above() {
+        let mut client = offline_client("  a\n");
+        block_on(client.open_line_above()).unwrap();
+        block_on(client.type_char('b')).unwrap();
+        block_on(client.exit_insert()).unwrap();
+        assert_eq!(contents(&client), "  b\n  a\n");
+
+        client.curr_mut().cursorpos = (1, 0).into();
+        block_on(client.open_line_above()).unwrap();
+        block_on(client.type_char('c')).unwrap();
+        block_on(client.exit_insert()).unwrap();
+        assert_eq!(contents(&client), "  b\n  c\n  a\n");
+    }
+
+    #[test]
+    fn participants() {
+        let presence = HashMap::from([(2, "son".to_string()), (0, "andy".to_string())]);
+        let colors = HashMap::from([(0, Color::Red)]);
+        assert_eq!(
+            participant_list(&presence, &colors),
+            "andy (Red)\nson (Reset)\n"
+        );
+    }
+
+    #[test]
+    fn graphemes() {
+        let line = "ae\u{301}日x";
+        assert_eq!(next_grapheme(line, 0), 1);
+        assert_eq!(next_grapheme(line, 1), 3);
+        assert_eq!(next_grapheme(line, 2), 3);
+        assert_eq!(next_grapheme(line, 4), 5);
+        assert_eq!(grapheme_start(line, 2), 1);
+        assert_eq!(grapheme_start(line, 3), 3);
+        assert_eq!(grapheme_start(line, 10), 4);
+        assert_eq!(grapheme_start("", 3), 0);
+    }
+
+    #[test]
+    fn vertical_movement() {
+        let mut client = offline_client("abcdef\nabc\n\nabcdef");
+        client.curr_mut().cursorpos = (0, 5).into();
+        client.move_down();
+        // The last char of the shorter line
+        assert_eq!(client.curr().cursorpos, (1, 2).into());
+        client.move_down();
+        assert_eq!(client.curr().cursorpos, (2, 0).into());
+        client.move_down();
+        assert_eq!(client.curr().cursorpos, (3, 0).into());
+
+        let mut client = offline_client("ab\nabcdef");
+        client.curr_mut().cursorpos = (1, 5).into();
+        client.move_up();
+        assert_eq!(client.curr().cursorpos, (0, 1).into());
+    }
+
+    #[test]
+    fn stale_insert_position() {
+        let mut client = offline_client("");
+        client.curr_mut().cursorpos = (5, 0).into();
+        block_on(client.enter_insert(client.curr().cursorpos)).unwrap();
+        assert_eq!(client.curr().cursorpos, (0, 0).into());
+
+        let mut client = offline_client("ab\ncd\n");
+        client.curr_mut().cursorpos = (1, 7).into();
+        block_on(client.enter_insert(client.curr().cursorpos)).unwrap();
+        assert_eq!(client.curr().cursorpos, (1, 2).into());
+    }
+
+    #[test]
+    fn append_after_multibyte() {
+        let mut client = offline_client("café\nx");
+        client.move_to_line_end();
+        assert_eq!(client.curr().cursorpos, (0, 3).into());
+        block_on(client.append()).unwrap();
+        block_on(client.type_char('s')).unwrap();
+        assert_eq!(contents(&client), "cafés\nx");
+        block_on(client.exit_insert()).unwrap();
+
+        client.move_to_line_start();
+        block_on(client.append_to_line()).unwrap();
+        assert_eq!(client.curr().cursorpos, (0, 5).into());
+        block_on(client.type_char('!')).unwrap();
+        assert_eq!(contents(&client), "cafés!\nx");
+    }
+
+    fn folder_client(names: &[&str]) -> Client {
+        let mut client = offline_client("");
+        let inhabitants = names
+            .iter()
+            .map(|&name| Inhabitant {
+                name: name.into(),
+                is_folder: name == "src",
+                size: 0,
+            })
+            .collect();
+        *client.curr_mut() = Buffer::new_folder(inhabitants, false, "test");
+        client
+    }
+
+    fn visible_names(client: &Client) -> Vec<String> {
+        let BufferTypeData::Folder {
+            inhabitants,
+            filter,
+            show_hidden,
+        } = &client.curr().data.buffer_type
+        else {
+            panic!("Expected a folder buffer");
+        };
+        visible_inhabitants(inhabitants, filter, *show_hidden)
+            .map(|x| x.name.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn filter_folder() {
+        let mut client = folder_client(&["src", "Cargo.toml", "Cargo.lock", "README.md"]);
+
+        client.filter_folder("Cargo");
+        assert_eq!(visible_names(&client), ["Cargo.toml", "Cargo.lock"]);
+        for _ in 0..3 {
+            client.move_down();
+        }
+        assert_eq!(client.curr().cursorpos.row, 1);
+
+        client.filter_folder("");
+        assert_eq!(visible_names(&client).len(), 4);
+        assert_eq!(client.curr().cursorpos.row, 0);
+    }
+
+    #[test]
+    fn toggle_hidden() {
+        let mut client = folder_client(&[".git", "src", ".gitignore", "README.md"]);
+        assert_eq!(visible_names(&client), ["src", "README.md"]);
+        client.move_down();
+
+        client.toggle_hidden();
+        assert_eq!(visible_names(&client).len(), 4);
+        // The cursor stays on README.md
+        assert_eq!(client.curr().cursorpos.row, 3);
+
+        client.toggle_hidden();
+        assert_eq!(client.curr().cursorpos.row, 1);
+    }
+
+    #[test]
+    fn parent_folders() {
+        assert_eq!(parent_folder(Path::new(".")), None);
+        assert_eq!(parent_folder(Path::new("a/..")), None);
+        assert_eq!(
+            parent_folder(Path::new("./src/editor")),
+            Some(("src".into(), "editor".into()))
+        );
+        assert_eq!(
+            parent_folder(Path::new("src")),
+            Some(("".into(), "src".into()))
+        );
+    }
+
+    #[test]
+    fn set_options() {
+        let mut options = EditorOptions::default();
+        options.set("number").unwrap();
+        options.set("noautoindent").unwrap();
+        options.set("tabstop=4").unwrap();
+        options.set("timeoutlen=0").unwrap();
+        assert_eq!(
+            options,
+            EditorOptions {
+                number: true,
+                tabstop: 4,
+                autoindent: false,
+                timeoutlen: 0,
+                ..EditorOptions::default()
+            }
+        );
+
+        assert!(options.set("tabstop=0").is_err());
+        assert!(options.set("tabstop").is_err());
+        assert!(options.set("list=1").is_err());
+        assert!(options.set("nosuchoption").is_err());
+        assert!(options.set("notabstop").is_err());
+        assert!(options.set("timeoutlen=-1").is_err());
+        assert!(options.set("timeoutlen").is_err());
+        assert_eq!(options.tabstop, 4);
+    }
+}
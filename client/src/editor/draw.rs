@@ -1,18 +1,31 @@
 use btep::s2c::Inhabitant;
 use crossterm::{
     cursor::{self, MoveToColumn, MoveToNextLine, RestorePosition, SavePosition},
-    style::{Color, Print, SetBackgroundColor, SetForegroundColor},
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
     terminal::{self, ClearType},
 };
-use std::io;
+use std::{collections::HashMap, io, iter};
 use text::Text;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use utils::other::CursorPos;
 
 use crossterm::QueueableCommand;
 
-use super::{buffer::BufferTypeData, client::Mode, Client};
+use super::{
+    buffer::{visible_inhabitants, BufferTypeData},
+    client::Mode,
+    Client,
+};
 
 const PIPE_CHAR: char = '│';
+/// The background of the line the cursor is on when `cursorline` is set
+const CURSORLINE_COLOR: Color = Color::AnsiValue(236);
+/// The background of whitespace at the end of a line when `list` is set
+const TRAILING_WHITESPACE_COLOR: Color = Color::DarkRed;
+/// The background of the match the cursor is on while searching
+const SEARCH_MATCH_COLOR: Color = Color::DarkYellow;
+/// The background of the bracket matching the one under the cursor
+const MATCHING_BRACKET_COLOR: Color = Color::DarkCyan;
 
 impl Client {
     /// draws the current client to the screen
@@ -25,10 +38,28 @@ impl Client {
         E: QueueableCommand + io::Write,
     {
         match &self.curr().data.buffer_type {
-            BufferTypeData::Regular { text, colors, id } => {
-                self.draw_regular(out, text, colors, *id)
+            BufferTypeData::Regular {
+                text, colors, id, ..
+            } => self.draw_regular(out, text, colors, *id),
+            BufferTypeData::Folder {
+                inhabitants,
+                filter,
+                show_hidden,
+            } => self.draw_inhabitants(
+                out,
+                &visible_inhabitants(inhabitants, filter, *show_hidden).collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    /// The width of the line numbers in front of the text of the current buffer. This is 0 when
+    /// they are turned off
+    pub(crate) fn gutter_width(&self) -> usize {
+        match &self.curr().data.buffer_type {
+            BufferTypeData::Regular { text, .. } if self.options.number => {
+                text.line_count().to_string().len() + 1
             }
-            BufferTypeData::Folder { inhabitants } => self.draw_inhabitants(out, inhabitants),
+            _ => 0,
         }
     }
 
@@ -36,7 +67,7 @@ impl Client {
         &self,
         out: &mut E,
         text: &Text,
-        colors: &[Color],
+        colors: &HashMap<usize, Color>,
         id: usize,
     ) -> io::Result<()>
     where
@@ -46,53 +77,158 @@ impl Client {
 
         out.queue(terminal::Clear(ClearType::All))?;
         let size = crossterm::terminal::size()?;
-        let mut current_relative_line = 0;
+        // Only the text from the first visible line is gone through
+        let mut current_relative_line = current_buffer.line_offset;
         let mut next_color = None;
         let mut self_pos = None;
         let mut relative_col = 0;
         let mut cursor_offset = 0;
-        out.queue(cursor::MoveTo(2, 0))?.queue(Print(PIPE_CHAR))?;
-        'outer: for buf in text.bufs() {
+        let gutter = self.gutter_width();
+        // The column the text starts at
+        let text_col = 3 + gutter;
+        let mut newlines = current_buffer.line_offset;
+        // The line within the text that is being drawn, and the char within that line
+        let mut row = current_buffer.line_offset;
+        let mut col = 0;
+        // Where the trailing whitespace starts on every line on the screen
+        let trailing_starts = if self.options.list {
+            (current_buffer.line_offset..current_buffer.line_offset + usize::from(size.1))
+                .map(|row| text.line(row).map_or(0, |x| trailing_whitespace_start(&x)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let matching_bracket = self.matching_bracket();
+        let search_match = self.search_match();
+        // The colors of every line on the screen
+        let highlights = current_buffer
+            .highlighter
+            .map_or_else(Vec::new, |highlighter| {
+                (current_buffer.line_offset..current_buffer.line_offset + usize::from(size.1))
+                    .map(|row| text.line(row).map_or_else(Vec::new, |x| highlighter(&x)))
+                    .collect()
+            });
+        let line_background = |row| {
+            if self.options.cursorline && row == current_buffer.cursor().row {
+                CURSORLINE_COLOR
+            } else {
+                Color::Reset
+            }
+        };
+        // The cursorline is filled up to the edge of the screen
+        let fill_line = |out: &mut E, row, relative_col: usize| -> io::Result<()> {
+            if line_background(row) != Color::Reset {
+                let remaining = (size.0 as usize).saturating_sub(text_col + relative_col);
+                out.queue(Print(format!("{:remaining$}", "")))?
+                    .queue(SetBackgroundColor(Color::Reset))?;
+            }
+            Ok(())
+        };
+        out.queue(cursor::MoveTo(0, 0))?;
+        draw_gutter(out, gutter, Some(newlines + 1))?;
+        out.queue(SetBackgroundColor(line_background(
+            current_buffer.line_offset,
+        )))?;
+        let (skip, bufs) = text
+            .bufs_from_row(current_buffer.line_offset)
+            .map_or((0, None), |(skip, bufs)| (skip, Some(bufs)));
+        'outer: for (i, buf) in bufs.into_iter().flatten().enumerate() {
             let read_lock = buf.read();
-            for c in read_lock.text.chars() {
+            let visible = if i == 0 {
+                &read_lock.text()[skip..]
+            } else {
+                &read_lock.text()[..]
+            };
+            for c in visible.chars() {
                 if c == '\n' {
-                    relative_col = 0;
+                    newlines += 1;
                     if current_relative_line >= size.1 as usize + current_buffer.line_offset {
                         break 'outer;
                     };
+                    row += 1;
                     if current_relative_line >= current_buffer.line_offset {
                         if let Some(x) = next_color.take() {
                             out.queue(SetBackgroundColor(x))?
                                 .queue(Print(" "))?
-                                .queue(MoveToNextLine(1))?
-                                .queue(SetBackgroundColor(Color::Reset))?;
-                        } else {
-                            out.queue(MoveToNextLine(1))?;
+                                .queue(SetBackgroundColor(line_background(row - 1)))?;
+                            relative_col += 1;
                         }
+                        fill_line(out, row - 1, relative_col)?;
+                        out.queue(MoveToNextLine(1))?;
 
-                        out.queue(MoveToColumn(2))?.queue(Print(PIPE_CHAR))?;
+                        draw_gutter(out, gutter, Some(newlines + 1))?;
+                        out.queue(SetBackgroundColor(line_background(row)))?;
                     }
+                    relative_col = 0;
+                    col = 0;
                     current_relative_line += 1;
                 } else if current_relative_line >= current_buffer.line_offset {
-                    if relative_col >= size.0 as usize - 3 {
-                        relative_col = 0;
-                        current_relative_line += 1;
-                        if current_buffer.cursor().row - current_buffer.line_offset
-                            >= current_relative_line - cursor_offset
+                    let trailing = row
+                        .checked_sub(current_buffer.line_offset)
+                        .and_then(|x| trailing_starts.get(x))
+                        .is_some_and(|&start| col >= start);
+                    let foreground = row
+                        .checked_sub(current_buffer.line_offset)
+                        .and_then(|x| highlights.get(x))
+                        .and_then(|x| x.iter().find(|(range, _)| range.contains(&col)))
+                        .map(|&(_, color)| color);
+                    let is_matching_bracket = matching_bracket == Some(CursorPos { row, col });
+                    let is_search_match = search_match
+                        .as_ref()
+                        .is_some_and(|(match_row, cols)| *match_row == row && cols.contains(&col));
+                    col += 1;
+                    // Tabs are drawn as spaces up to the next tabstop. Everything else is drawn
+                    // once and takes up its display width, which is 0 for combining marks
+                    let cells = if c == '\t' {
+                        iter::repeat((' ', 1)).take(tab_width(relative_col, self.options.tabstop))
+                    } else {
+                        iter::repeat((c, c.width().unwrap_or(0))).take(1)
+                    };
+                    for (c, width) in cells {
+                        if width != 0
+                            && relative_col + width > (size.0 as usize).saturating_sub(text_col)
                         {
-                            cursor_offset += 1;
-                        }
-                        out.queue(MoveToNextLine(1))?;
+                            fill_line(out, row, relative_col)?;
+                            relative_col = 0;
+                            current_relative_line += 1;
+                            if current_buffer.cursor().row - current_buffer.line_offset
+                                >= current_relative_line - cursor_offset
+                            {
+                                cursor_offset += 1;
+                            }
+                            out.queue(MoveToNextLine(1))?;
 
-                        out.queue(MoveToColumn(2))?.queue(Print(PIPE_CHAR))?;
-                    }
-                    relative_col += 1;
-                    if let Some(x) = next_color.take() {
-                        out.queue(SetBackgroundColor(x))?
-                            .queue(Print(c))?
-                            .queue(SetBackgroundColor(Color::Reset))?;
-                    } else {
-                        out.queue(Print(c))?;
+                            draw_gutter(out, gutter, None)?;
+                            out.queue(SetBackgroundColor(line_background(row)))?;
+                        }
+                        relative_col += width;
+                        // Only the foreground is colored, so that the backgrounds showing where
+                        // others are stay visible
+                        if let Some(color) = foreground {
+                            out.queue(SetForegroundColor(color))?;
+                        }
+                        if let Some(x) = next_color.take() {
+                            out.queue(SetBackgroundColor(x))?
+                                .queue(Print(c))?
+                                .queue(SetBackgroundColor(line_background(row)))?;
+                        } else if is_search_match {
+                            out.queue(SetBackgroundColor(SEARCH_MATCH_COLOR))?
+                                .queue(Print(c))?
+                                .queue(SetBackgroundColor(line_background(row)))?;
+                        } else if is_matching_bracket {
+                            out.queue(SetBackgroundColor(MATCHING_BRACKET_COLOR))?
+                                .queue(Print(c))?
+                                .queue(SetBackgroundColor(line_background(row)))?;
+                        } else if trailing {
+                            out.queue(SetBackgroundColor(TRAILING_WHITESPACE_COLOR))?
+                                .queue(Print(c))?
+                                .queue(SetBackgroundColor(line_background(row)))?;
+                        } else {
+                            out.queue(Print(c))?;
+                        }
+                        if foreground.is_some() {
+                            out.queue(SetForegroundColor(Color::Reset))?;
+                        }
                     }
                 }
             }
@@ -104,7 +240,7 @@ impl Client {
                             col: relative_col,
                         });
                     } else {
-                        let color = colors[if buf < id { buf } else { buf - 1 }];
+                        let color = colors.get(&buf).copied().unwrap_or(Color::Reset);
 
                         let username = &text.client(buf).username;
                         out.queue(SavePosition)?
@@ -122,44 +258,59 @@ impl Client {
             }
             if let Some((_, occupied)) = read_lock.buf {
                 if occupied {
-                    out.queue(SetBackgroundColor(Color::Reset))?;
+                    out.queue(SetBackgroundColor(line_background(row)))?;
                 }
             }
         }
         if let Some(x) = next_color.take() {
             out.queue(SetBackgroundColor(x))?
                 .queue(Print(' '))?
-                .queue(SetBackgroundColor(Color::Reset))?;
+                .queue(SetBackgroundColor(line_background(row)))?;
+            relative_col += 1;
         }
+        fill_line(out, row, relative_col)?;
+        out.queue(SetBackgroundColor(Color::Reset))?;
         for _ in current_relative_line..size.1 as usize {
-            out.queue(MoveToNextLine(1))?
-                .queue(MoveToColumn(2))?
-                .queue(Print(PIPE_CHAR))?;
+            out.queue(MoveToNextLine(1))?;
+            draw_gutter(out, gutter, None)?;
         }
-        if let Mode::Command(ref cmd) = self.modeinfo.mode {
-            out.queue(cursor::MoveTo(0, size.1))?
+        out.queue(cursor::MoveTo(0, size.1.saturating_sub(1)))?
+            .queue(terminal::Clear(ClearType::CurrentLine))?
+            .queue(SetAttribute(Attribute::Reverse))?
+            .queue(Print(self.status_line(usize::from(size.0))))?
+            .queue(SetAttribute(Attribute::Reset))?;
+        if let Mode::Command(ref line)
+        | Mode::Search {
+            query: ref line, ..
+        } = self.modeinfo.mode
+        {
+            let prompt = if let Mode::Command(_) = self.modeinfo.mode {
+                ':'
+            } else {
+                '/'
+            };
+            // The command goes right above the status line
+            out.queue(cursor::MoveTo(0, size.1.saturating_sub(2)))?
                 .queue(terminal::Clear(ClearType::CurrentLine))?
-                .queue(Print(":"))?
-                .queue(Print(cmd))?;
+                .queue(Print(prompt))?
+                .queue(Print(line))?;
         } else {
-            if let Some(ref info) = self.info {
-                out.queue(cursor::MoveTo(
-                    size.0.saturating_sub(info.len() as u16),
-                    size.1,
-                ))?
-                .queue(terminal::Clear(ClearType::CurrentLine))?
-                .queue(Print(info))?;
-            }
+            let text_col = u16::try_from(text_col).unwrap();
             if let Some(CursorPos { row, col }) = self_pos {
                 let col = u16::try_from(col).unwrap();
-                if col >= size.0 - 3 {
-                    out.queue(cursor::MoveTo(3, u16::try_from(row).unwrap() + 1))?;
+                if col >= size.0.saturating_sub(text_col) {
+                    out.queue(cursor::MoveTo(text_col, u16::try_from(row).unwrap() + 1))?;
                 } else {
-                    out.queue(cursor::MoveTo(col + 3, u16::try_from(row).unwrap()))?;
+                    out.queue(cursor::MoveTo(col + text_col, u16::try_from(row).unwrap()))?;
                 }
             } else {
+                let col = text
+                    .line(current_buffer.cursor().row)
+                    .map_or(current_buffer.cursor().col, |line| {
+                        display_col(&line, current_buffer.cursor().col, self.options.tabstop)
+                    });
                 out.queue(cursor::MoveTo(
-                    u16::try_from(current_buffer.cursor().col + 3).unwrap(),
+                    u16::try_from(col).unwrap() + text_col,
                     u16::try_from(
                         current_buffer.cursor().row - current_buffer.line_offset + cursor_offset,
                     )
@@ -171,21 +322,70 @@ impl Client {
         Ok(())
     }
 
-    fn draw_inhabitants<E>(&self, out: &mut E, inhabitants: &[Inhabitant]) -> io::Result<()>
+    /// Creates the status line of the current buffer, which is exactly `width` chars wide.
+    /// It shows the mode, the path of the buffer, whether it has been modified since the last
+    /// save, whether it has lost its connection, the info message and the position of the cursor
+    fn status_line(&self, width: usize) -> String {
+        let buffer = self.curr();
+        let mode = match self.modeinfo.mode {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Command(_) => "COMMAND",
+            Mode::Search { .. } => "SEARCH",
+        };
+        let path = buffer
+            .path
+            .as_ref()
+            .map_or_else(|| "[No Name]".to_string(), |x| x.display().to_string());
+        let left = format!(
+            " {mode} {path}{}{} ",
+            if buffer.modified { " [+]" } else { "" },
+            if buffer.disconnected {
+                " [disconnected]"
+            } else {
+                ""
+            }
+        );
+        let right = format!(
+            " {}{}:{} ",
+            self.info
+                .as_ref()
+                .map_or_else(String::new, |info| format!("{info}  ")),
+            buffer.cursor().row + 1,
+            buffer.cursor().col + 1,
+        );
+        let padding = width.saturating_sub(left.width() + right.width());
+        format!("{left}{:padding$}{right}", "")
+            .chars()
+            .take(width)
+            .collect()
+    }
+
+    fn draw_inhabitants<E>(&self, out: &mut E, inhabitants: &[&Inhabitant]) -> io::Result<()>
     where
         E: QueueableCommand + io::Write,
     {
         out.queue(terminal::Clear(ClearType::All))?;
         out.queue(cursor::MoveTo(0, 0))?;
+        // The sizes line up in a column right after the longest name
+        let name_width = inhabitants
+            .iter()
+            .map(|x| x.name.to_str().unwrap().width() + usize::from(x.is_folder))
+            .max()
+            .unwrap_or(0)
+            + 2;
         for inhabitant in inhabitants.iter().skip(self.curr().line_offset) {
+            let name = inhabitant.name.to_str().unwrap();
             if inhabitant.is_folder {
                 out.queue(SetForegroundColor(Color::DarkBlue))?;
             }
-            out.queue(Print(inhabitant.name.to_str().unwrap()))?;
+            out.queue(Print(name))?;
             if inhabitant.is_folder {
                 out.queue(Print('/'))?;
                 out.queue(SetForegroundColor(Color::Reset))?;
             }
+            let padding = name_width - name.width() - usize::from(inhabitant.is_folder);
+            out.queue(Print(format!("{:padding$}{}", "", size_column(inhabitant))))?;
             out.queue(cursor::MoveToNextLine(1))?;
         }
 
@@ -198,3 +398,138 @@ impl Client {
         Ok(())
     }
 }
+
+/// What is shown next to the name of an inhabitant in a folder buffer
+fn size_column(inhabitant: &Inhabitant) -> String {
+    if inhabitant.is_folder {
+        "<DIR>".to_string()
+    } else {
+        format!("{}B", inhabitant.size)
+    }
+}
+
+/// The amount of columns a tab takes up when it starts at column `col`
+pub(crate) const fn tab_width(col: usize, tabstop: usize) -> usize {
+    tabstop - col % tabstop
+}
+
+/// The column the char at position `col` of `line` gets drawn at, taking the width of tabs and
+/// wide characters into account
+pub(crate) fn display_col(line: &str, col: usize, tabstop: usize) -> usize {
+    line.chars().take(col).fold(0, |acc, c| {
+        if c == '\t' {
+            acc + tab_width(acc, tabstop)
+        } else {
+            acc + c.width().unwrap_or(0)
+        }
+    })
+}
+
+/// Where every char of `line` starts being drawn when the text is `width` columns wide, as a
+/// (row, column) pair relative to the start of the line. This follows the same wrapping as
+/// `draw_regular`.
+/// # Returns
+/// The positions together with the amount of rows the line takes up
+pub(crate) fn cell_positions(
+    line: &str,
+    width: usize,
+    tabstop: usize,
+) -> (Vec<(usize, usize)>, usize) {
+    let mut row = 0;
+    let mut col = 0;
+    let positions = line
+        .chars()
+        .map(|c| {
+            let cells = if c == '\t' {
+                iter::repeat(1).take(tab_width(col, tabstop))
+            } else {
+                iter::repeat(c.width().unwrap_or(0)).take(1)
+            };
+            let mut start = None;
+            for cell_width in cells {
+                if cell_width != 0 && col + cell_width > width {
+                    row += 1;
+                    col = 0;
+                }
+                start.get_or_insert((row, col));
+                col += cell_width;
+            }
+            start.unwrap_or((row, col))
+        })
+        .collect();
+    (positions, row + 1)
+}
+
+/// The index of the first char of the whitespace at the end of `line`. This is the length of the
+/// line when it doesn't end with whitespace
+fn trailing_whitespace_start(line: &str) -> usize {
+    line.trim_end_matches([' ', '\t']).chars().count()
+}
+
+/// Moves to the start of the current row and draws everything in front of the text. That is the
+/// line number, if `width` isn't 0, followed by `PIPE_CHAR`.
+/// `number` is None for rows that don't start a line, like the continuation of a wrapped line
+fn draw_gutter<E>(out: &mut E, width: usize, number: Option<usize>) -> io::Result<()>
+where
+    E: QueueableCommand + io::Write,
+{
+    out.queue(MoveToColumn(2))?;
+    if width != 0 {
+        let number = number.map_or_else(String::new, |x| x.to_string());
+        out.queue(Print(format!(" {number:>0$}", width - 1)))?;
+    }
+    out.queue(Print(PIPE_CHAR))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cell_positions, display_col, trailing_whitespace_start};
+
+    #[test]
+    fn tabs_and_spaces() {
+        assert_eq!(display_col("\tab", 1, 4), 4);
+        assert_eq!(display_col("a\tb", 2, 4), 4);
+        assert_eq!(display_col("  \t\tb", 4, 4), 8);
+        assert_eq!(display_col("abcd\tb", 5, 4), 8);
+        assert_eq!(display_col(" \t \t", 4, 8), 16);
+        assert_eq!(display_col("ab", 10, 4), 2);
+    }
+
+    #[test]
+    fn wide_chars() {
+        assert_eq!(display_col("日本語", 2, 4), 4);
+        assert_eq!(display_col("日\tb", 2, 4), 4);
+        assert_eq!(display_col("e\u{301}x", 2, 4), 1);
+        assert_eq!(display_col("🦀a", 2, 4), 3);
+    }
+
+    #[test]
+    fn wrapping_positions() {
+        assert_eq!(cell_positions("", 4, 4), (vec![], 1));
+        assert_eq!(
+            cell_positions("abcde", 4, 4),
+            (vec![(0, 0), (0, 1), (0, 2), (0, 3), (1, 0)], 2)
+        );
+        assert_eq!(
+            cell_positions("abc日", 4, 4),
+            (vec![(0, 0), (0, 1), (0, 2), (1, 0)], 2)
+        );
+        assert_eq!(
+            cell_positions("a\tb", 4, 4),
+            (vec![(0, 0), (0, 1), (1, 0)], 2)
+        );
+        assert_eq!(
+            cell_positions("abcd", 4, 4),
+            (vec![(0, 0), (0, 1), (0, 2), (0, 3)], 1)
+        );
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        assert_eq!(trailing_whitespace_start("ab  \t "), 2);
+        assert_eq!(trailing_whitespace_start("a b"), 3);
+        assert_eq!(trailing_whitespace_start(" \t"), 0);
+        assert_eq!(trailing_whitespace_start("æø "), 2);
+    }
+}
@@ -0,0 +1,73 @@
+//! Syntax highlighting of the text in a buffer. A highlighter only ever gets to see a single
+//! line, so it can't know about anything spanning multiple lines
+use std::{ops::Range, path::Path};
+
+use crossterm::style::Color;
+
+/// Colors parts of a line. The ranges are in chars rather than bytes, like the columns of the
+/// cursor, and chars outside of any range keep the default color
+pub type Highlighter = fn(&str) -> Vec<(Range<usize>, Color)>;
+
+/// The color of markdown headings
+const HEADING_COLOR: Color = Color::Magenta;
+/// The color of the lines that start and end a markdown code block
+const FENCE_COLOR: Color = Color::DarkGreen;
+
+/// Picks the highlighter for a file by its extension
+pub(crate) fn for_path(path: &Path) -> Option<Highlighter> {
+    match path.extension()?.to_str()? {
+        "md" | "markdown" => Some(markdown),
+        _ => None,
+    }
+}
+
+/// Highlights headings and the fences around code blocks
+fn markdown(line: &str) -> Vec<(Range<usize>, Color)> {
+    let trimmed = line.trim_start_matches(' ');
+    // Anything indented by 4 spaces is a code block instead
+    if line.len() - trimmed.len() > 3 {
+        return Vec::new();
+    }
+    let hashes = trimmed.len() - trimmed.trim_start_matches('#').len();
+    let is_heading = (1..=6).contains(&hashes)
+        && trimmed[hashes..]
+            .chars()
+            .next()
+            .is_none_or(char::is_whitespace);
+    let color = if is_heading {
+        HEADING_COLOR
+    } else if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+        FENCE_COLOR
+    } else {
+        return Vec::new();
+    };
+    vec![(0..line.chars().count(), color)]
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{for_path, markdown, FENCE_COLOR, HEADING_COLOR};
+
+    #[test]
+    fn markdown_lines() {
+        assert_eq!(markdown("# Title"), vec![(0..7, HEADING_COLOR)]);
+        assert_eq!(markdown("  ### æø"), vec![(0..8, HEADING_COLOR)]);
+        assert_eq!(markdown("##"), vec![(0..2, HEADING_COLOR)]);
+        assert_eq!(markdown("```rust"), vec![(0..7, FENCE_COLOR)]);
+        assert_eq!(markdown("~~~"), vec![(0..3, FENCE_COLOR)]);
+        assert_eq!(markdown("#hashtag"), vec![]);
+        assert_eq!(markdown("####### seven"), vec![]);
+        assert_eq!(markdown("    # indented"), vec![]);
+        assert_eq!(markdown("text"), vec![]);
+    }
+
+    #[test]
+    fn by_extension() {
+        assert!(for_path(Path::new("notes/README.md")).is_some());
+        assert!(for_path(Path::new("a.markdown")).is_some());
+        assert!(for_path(Path::new("main.rs")).is_none());
+        assert!(for_path(Path::new("md")).is_none());
+    }
+}
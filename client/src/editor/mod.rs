@@ -3,11 +3,11 @@
 //! to the queue for sending to the server, but *not*
 //! actually sending them
 
-use std::{io, net::SocketAddrV4, path::Path, time::Duration};
+use std::{collections::HashMap, io, net::SocketAddrV4, path::Path, time::Duration};
 
 use bindings::Bindings;
 use buffer::Buffer;
-use client::{Client, ModeInfo};
+use client::{Client, EditorOptions, ModeInfo};
 use crossterm::{
     event::{KeyCode, KeyEvent},
     style::Color,
@@ -18,8 +18,9 @@ mod bindings;
 mod buffer;
 mod client;
 mod draw;
+mod highlight;
 
-pub static BUFFER_SIZE: usize = 8192;
+pub use btep::BUFFER_SIZE;
 
 pub struct App {
     pub client: Client,
@@ -34,47 +35,44 @@ impl App {
         color: &Color,
         path: &Path,
     ) -> io::Result<Self> {
-        Ok(Self {
-            client: Client::from_path(
-                username,
-                #[cfg(feature = "security")]
-                password.to_owned(),
-                address,
-                color,
-                path,
-            )
-            .await?,
-            bindings: Bindings::default(),
-        })
+        let mut client = Client::from_path(
+            username,
+            #[cfg(feature = "security")]
+            password.to_owned(),
+            address,
+            color,
+            path,
+        )
+        .await?;
+        let bindings = load_bindings(&mut client);
+        Ok(Self { client, bindings })
     }
 
     pub fn new_with_buffer(
         username: String,
         #[cfg(feature = "security")] password: String,
         text: Text,
-        colors: Vec<Color>,
+        colors: HashMap<usize, Color>,
         socket: Option<TcpStream>,
         address: SocketAddrV4,
         color: &Color,
         path: &Path,
     ) -> Self {
-        Self {
-            client: {
-                let buf = Buffer::new(&username, text, colors, socket, Some(path));
-                Client {
-                    buffers: Vec::from([buf]),
-                    current_buffer: 0,
-                    modeinfo: ModeInfo::default(),
-                    info: Some("Prewss Escape then :help to view help".to_string()),
-                    #[cfg(feature = "security")]
-                    password,
-                    username,
-                    color: *color,
-                    server_addr: address,
-                }
-            },
-            bindings: Bindings::default(),
-        }
+        let buf = Buffer::new(&username, text, colors, socket, Some(path));
+        let mut client = Client {
+            buffers: Vec::from([buf]),
+            current_buffer: 0,
+            modeinfo: ModeInfo::default(),
+            info: Some("Prewss Escape then :help to view help".to_string()),
+            #[cfg(feature = "security")]
+            password,
+            username,
+            color: *color,
+            server_addr: address,
+            options: EditorOptions::default(),
+        };
+        let bindings = load_bindings(&mut client);
+        Self { client, bindings }
     }
 
     pub async fn execute_keyevents(&mut self) -> io::Result<bool> {
@@ -82,12 +80,24 @@ impl App {
         while !self.client.modeinfo.keymap.is_empty() {
             self.execute_top_keyevent().await?;
         }
+        self.client.curr_mut().send_typed().await?;
         if let Some(buffer::Socket { ref mut writer, .. }) = self.client.curr_mut().socket {
             writer.flush().await?;
         }
         Ok(false)
     }
 
+    /// Pastes `data` at the cursor. Keys that are still waiting for the rest of a binding are
+    /// executed first so that they don't end up after the pasted text
+    pub async fn handle_paste(&mut self, data: &str) -> io::Result<bool> {
+        self.execute_keyevents().await?;
+        self.client.paste(data).await?;
+        if let Some(buffer::Socket { ref mut writer, .. }) = self.client.curr_mut().socket {
+            writer.flush().await?;
+        }
+        Ok(true)
+    }
+
     /// executes the longest command from the current keymap
     /// # Note
     /// does not flush the socket
@@ -117,13 +127,61 @@ impl App {
                     self.client.type_char(c).await?;
                     true
                 }
+                KeyCode::Tab => {
+                    self.client.type_tab().await?;
+                    true
+                }
+                _ => false,
+            },
+            client::Mode::Command(ref mut cmd) => match ev.code {
+                KeyCode::Char(c) => {
+                    cmd.push(c);
+                    false
+                }
+                KeyCode::Enter => {
+                    let cmd = cmd.clone();
+                    if !self.execute_command(&cmd).await? {
+                        self.client.modeinfo.set_mode(client::Mode::Normal);
+                    }
+                    true
+                }
+                _ => false,
+            },
+            client::Mode::Search { ref mut query, .. } => match ev.code {
+                KeyCode::Char(c) => {
+                    query.push(c);
+                    self.client.preview_search();
+                    false
+                }
+                KeyCode::Enter => {
+                    self.client.end_search(false);
+                    false
+                }
                 _ => false,
             },
-            client::Mode::Command(_) => false,
         })
     }
 
+    /// Executes a command written in command mode. Commands that change the bindings are
+    /// handled here, as the bindings aren't reachable from the client. Everything else is passed
+    /// on to `Client::execute_command`
+    async fn execute_command(&mut self, cmd: &str) -> io::Result<bool> {
+        if let Some(definition) = cmd.strip_prefix("map ") {
+            if let Err(e) = self.bindings.map_definition(definition) {
+                self.client.info = Some(format!("map: {e}"));
+            }
+            return Ok(false);
+        }
+        self.client.execute_command(cmd).await
+    }
+
     pub async fn handle_keyevent(&mut self, input: &KeyEvent) -> io::Result<bool> {
+        // Escape cancels the binding that is being typed, so the keys typed so far are done
+        // right away rather than after the timeout, and escape itself never waits either
+        let is_esc = input.code == KeyCode::Esc;
+        if is_esc {
+            self.execute_keyevents().await?;
+        }
         self.client.modeinfo.keymap.push(*input);
         let mut should_flush = false;
         while !self.bindings[&self.client.modeinfo.mode]
@@ -131,14 +189,130 @@ impl App {
         {
             should_flush = self.execute_top_keyevent().await?;
         }
-        if should_flush {
+        // Everything typed in this batch is sent as one message
+        if self.client.curr_mut().send_typed().await? || should_flush {
             if let Some(buffer::Socket { ref mut writer, .. }) = self.client.curr_mut().socket {
                 writer.flush().await?;
             }
         }
         if !self.client.modeinfo.keymap.is_empty() {
-            self.client.modeinfo.timer = Some(time::sleep(Duration::from_secs(1)));
+            let timeoutlen = self.client.options.timeoutlen;
+            if is_esc || timeoutlen == 0 {
+                self.execute_keyevents().await?;
+            } else {
+                self.client.modeinfo.timer = Some(time::sleep(Duration::from_millis(timeoutlen)));
+            }
         }
         Ok(should_flush)
     }
 }
+
+/// Creates the default bindings with the users bindings file applied on top. Problems with the
+/// file are shown to the user instead of stopping the editor from starting
+fn load_bindings(client: &mut Client) -> Bindings {
+    let mut bindings = Bindings::default();
+    let warnings = bindings.load_user_config();
+    if !warnings.is_empty() {
+        client.info = Some(warnings.join(", "));
+    }
+    bindings
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, net::SocketAddrV4};
+
+    use crossterm::{
+        event::{KeyCode, KeyEvent, KeyModifiers},
+        style::Color,
+    };
+    use futures::executor::block_on;
+    use text::Text;
+
+    use super::{buffer::BufferTypeData, client::Mode, App};
+
+    fn offline_app(text: &str) -> App {
+        App::new_with_buffer(
+            "andy".to_string(),
+            #[cfg(feature = "security")]
+            String::new(),
+            Text::original_from_str(text),
+            HashMap::new(),
+            None,
+            SocketAddrV4::new([127, 0, 0, 1].into(), 0),
+            &Color::Red,
+            "test".as_ref(),
+        )
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn no_timeout() {
+        let mut app = offline_app("a");
+        app.client.options.timeoutlen = 0;
+        // `z` is only the start of `zz`, `zt` and `zb`
+        block_on(app.handle_keyevent(&key(KeyCode::Char('z')))).unwrap();
+        assert!(app.client.modeinfo.keymap.is_empty());
+        assert!(app.client.modeinfo.timer.is_none());
+    }
+
+    #[test]
+    fn incremental_search() {
+        let mut app = offline_app("abc\nxbc\nab");
+        app.client.curr_mut().cursorpos = (0, 1).into();
+        for c in "/ab".chars() {
+            block_on(app.handle_keyevent(&key(KeyCode::Char(c)))).unwrap();
+        }
+        // The `ab` at the start of the text is in front of the cursor, so it only matches after
+        // the one on the last line
+        assert_eq!(app.client.curr().cursorpos, (2, 0).into());
+        block_on(app.handle_keyevent(&key(KeyCode::Backspace))).unwrap();
+        block_on(app.handle_keyevent(&key(KeyCode::Char('x')))).unwrap();
+        assert_eq!(app.client.curr().cursorpos, (0, 1).into());
+        block_on(app.handle_keyevent(&key(KeyCode::Esc))).unwrap();
+        assert_eq!(app.client.modeinfo.mode, Mode::Normal);
+        assert_eq!(app.client.curr().cursorpos, (0, 1).into());
+
+        for c in "/bc".chars() {
+            block_on(app.handle_keyevent(&key(KeyCode::Char(c)))).unwrap();
+        }
+        assert_eq!(app.client.curr().cursorpos, (1, 1).into());
+        block_on(app.handle_keyevent(&key(KeyCode::Enter))).unwrap();
+        assert_eq!(app.client.modeinfo.mode, Mode::Normal);
+        assert_eq!(app.client.curr().cursorpos, (1, 1).into());
+        assert!(!app.client.curr().modified);
+    }
+
+    #[test]
+    fn escape_cancels_pending_keys() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut app = offline_app("a");
+            app.handle_keyevent(&key(KeyCode::Char('z'))).await.unwrap();
+            assert!(app.client.modeinfo.timer.is_some());
+            app.handle_keyevent(&key(KeyCode::Esc)).await.unwrap();
+            assert!(app.client.modeinfo.keymap.is_empty());
+            assert!(app.client.modeinfo.timer.is_none());
+
+            // The pending keys are typed before escape leaves insert mode
+            app.bindings
+                .map_definition("insert jk exit_insert")
+                .unwrap();
+            app.handle_keyevent(&key(KeyCode::Char('i'))).await.unwrap();
+            app.handle_keyevent(&key(KeyCode::Char('j'))).await.unwrap();
+            app.handle_keyevent(&key(KeyCode::Esc)).await.unwrap();
+            assert_eq!(app.client.modeinfo.mode, Mode::Normal);
+            assert!(app.client.modeinfo.timer.is_none());
+            let BufferTypeData::Regular { text, .. } = &app.client.curr().data.buffer_type else {
+                panic!("Expected a regular buffer");
+            };
+            assert_eq!(text.contents(), "ja");
+        });
+    }
+}
@@ -1,8 +1,10 @@
-//! Cleans up the terminal when the application panics
-use std::{io::stdout, panic};
+//! Cleans up the terminal when the application panics, and defines the errors that can happen
+//! while talking to the server
+use std::{error::Error, fmt, io::stdout, panic};
 
 use color_eyre::{config::HookBuilder, eyre};
 use crossterm::{
+    event::DisableMouseCapture,
     execute,
     terminal::{disable_raw_mode, LeaveAlternateScreen},
 };
@@ -22,7 +24,7 @@ pub fn install_hooks() -> color_eyre::Result<()> {
     let panic_hook = panic_hook.into_panic_hook();
     panic::set_hook(Box::new(move |panic_info| {
         disable_raw_mode().unwrap();
-        execute!(stdout(), LeaveAlternateScreen).unwrap();
+        execute!(stdout(), DisableMouseCapture, LeaveAlternateScreen).unwrap();
         panic_hook(panic_info);
     }));
 
@@ -31,7 +33,7 @@ pub fn install_hooks() -> color_eyre::Result<()> {
     eyre::set_hook(Box::new(
         move |error: &(dyn std::error::Error + 'static)| {
             disable_raw_mode().unwrap();
-            execute!(stdout(), LeaveAlternateScreen).unwrap();
+            execute!(stdout(), DisableMouseCapture, LeaveAlternateScreen).unwrap();
 
             eyre_hook(error)
         },
@@ -39,3 +41,41 @@ pub fn install_hooks() -> color_eyre::Result<()> {
 
     Ok(())
 }
+
+/// The server refused to let us in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The server requires a password, but none was supplied
+    MissingPassword,
+    /// The username, password combination isn't authorized
+    Unauthorized,
+    /// Too many attempts to log in failed recently, so the server won't check the password
+    TooManyAttempts,
+    /// The server already has as many clients connected as it allows
+    ServerFull,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPassword => write!(
+                f,
+                "The server requires a password. Maybe it is running with --security?"
+            ),
+            Self::Unauthorized => write!(
+                f,
+                "The username, password combination you supplied isn't authorized"
+            ),
+            Self::TooManyAttempts => write!(
+                f,
+                "Too many attempts to log in have failed. Wait a while before trying again"
+            ),
+            Self::ServerFull => write!(
+                f,
+                "The server is full. Wait for someone to disconnect before trying again"
+            ),
+        }
+    }
+}
+
+impl Error for AuthError {}
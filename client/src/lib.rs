@@ -3,24 +3,38 @@ pub mod editor;
 pub mod errors;
 
 use crossterm::{
-    event::{Event, EventStream},
+    cursor::MoveToColumn,
+    event::{
+        DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+        Event, EventStream, MouseButton, MouseEvent, MouseEventKind,
+    },
     execute,
-    style::Color,
+    style::{Color, Print},
     terminal::{
-        self, disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+        self, disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+        LeaveAlternateScreen,
     },
 };
 use editor::App;
 use futures::{future, FutureExt, StreamExt};
 use std::{
+    future::Future,
     io::{self, Write},
     net::SocketAddrV4,
     path::Path,
+    pin::pin,
     str,
+    time::Duration,
 };
 
 use tokio::{io::Interest, time};
 
+/// How often the server gets pinged. This has to be shorter than the servers client timeout
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The frames of the spinner shown while the first buffer loads
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
 /// Runs a the client side of the editor
 #[allow(clippy::missing_panics_doc)]
 #[allow(clippy::missing_errors_doc)]
@@ -35,22 +49,28 @@ pub async fn run(
     let mut out = io::stdout();
     errors::install_hooks()?;
 
-    let mut app = App::new(
+    let app = App::new(
         username.to_string(),
         #[cfg(feature = "security")]
         password,
         address,
         color,
         path,
-    )
-    .await?;
+    );
+    let mut app = with_spinner(&mut out, &format!("Loading {}", path.display()), app).await??;
 
-    execute!(out, EnterAlternateScreen)?;
+    execute!(
+        out,
+        EnterAlternateScreen,
+        EnableMouseCapture,
+        EnableBracketedPaste
+    )?;
     enable_raw_mode().unwrap();
 
     app.client.redraw(&mut out)?;
 
     let mut reader = EventStream::new();
+    let mut ping_timer = time::interval(PING_INTERVAL);
     loop {
         let event = reader.next().fuse();
         if tokio::select! {
@@ -59,10 +79,23 @@ pub async fn run(
                     Some(Ok(event)) => {
                         match event {
                             Event::Key(event) => {
-                                app.handle_keyevent(&event).await?
+                                let result = app.handle_keyevent(&event).await;
+                                handle_disconnect(&mut app, result)?
+                            }
+                            Event::Mouse(MouseEvent {
+                                kind: MouseEventKind::Down(MouseButton::Left),
+                                column,
+                                row,
+                                ..
+                            }) => {
+                                app.client.click(column, row)?;
+                                true
+                            }
+                            Event::Mouse(_) => false,
+                            Event::Paste(data) => {
+                                let result = app.handle_paste(&data).await;
+                                handle_disconnect(&mut app, result)?
                             }
-                            Event::Mouse(_event) => todo!("No mouse support sorry"),
-                            Event::Paste(_data) => todo!("No paste support sorry"),
                             Event::Resize(_width, _height) => true,
                             Event::FocusGained | Event::FocusLost => false,
                         }
@@ -73,17 +106,18 @@ pub async fn run(
             },
             r = async {
                 if let Some(x) = &mut app.client.buffers[app.client.current_buffer].socket{
-                    x.reader.ready(Interest::READABLE).await
+                    x.reader.get_ref().ready(Interest::READABLE).await
                 } else {
                     future::pending::<()>().await;
                     unreachable!()
                 }
             } => {
-                if r?.is_read_closed() {
-                    break;
-                } else {
-                    app.client.curr_mut().update().await?;
+                if r.as_ref().map_or(true, |x| x.is_read_closed()) {
+                    app.client.connection_lost(app.client.current_buffer);
                     Ok::<bool, io::Error>(true)
+                } else {
+                    let result = app.client.curr_mut().update().await.map(|_| true);
+                    handle_disconnect(&mut app, result)
                 }
             },
             _ = async {
@@ -94,24 +128,94 @@ pub async fn run(
                     unreachable!()
                 }
             } => {
-                app.execute_keyevents().await?;
-
-                Ok(true)
+                let result = app.execute_keyevents().await.map(|_| true);
+                handle_disconnect(&mut app, result)
+            }
+            _ = ping_timer.tick() => {
+                let mut redraw = false;
+                for i in 0..app.client.buffers.len() {
+                    match app.client.buffers[i].ping().await {
+                        Err(e) if is_disconnect(&e) => {
+                            app.client.connection_lost(i);
+                            redraw = true;
+                        }
+                        result => result?,
+                    }
+                }
+                Ok(redraw)
             }
         }? {
             if app.client.buffers.is_empty() {
                 break;
             }
             let size = terminal::size()?;
-            app.client
-                .curr_mut()
-                .recalculate_cursor((size.0, size.1 - 1))?;
+            let gutter = u16::try_from(app.client.gutter_width()).unwrap();
+            let tabstop = app.client.options.tabstop;
+            app.client.curr_mut().recalculate_cursor(
+                (size.0.saturating_sub(gutter), size.1.saturating_sub(1)),
+                tabstop,
+            );
             app.client.redraw(&mut out)?;
             out.flush()?;
         }
     }
 
     disable_raw_mode().unwrap();
-    execute!(io::stdout(), LeaveAlternateScreen)?;
+    execute!(
+        io::stdout(),
+        DisableBracketedPaste,
+        DisableMouseCapture,
+        LeaveAlternateScreen
+    )?;
     Ok(())
 }
+
+/// Waits for `future` while showing `message` next to a spinner, so that large files don't make
+/// it look like the editor hangs. The message is removed again once `future` is done
+async fn with_spinner<W, F>(out: &mut W, message: &str, future: F) -> io::Result<F::Output>
+where
+    W: Write,
+    F: Future,
+{
+    let mut future = pin!(future);
+    let mut frames = time::interval(Duration::from_millis(100));
+    let mut frame = 0;
+    let ret = loop {
+        tokio::select! {
+            ret = &mut future => break ret,
+            _ = frames.tick() => {
+                execute!(
+                    out,
+                    MoveToColumn(0),
+                    Print(format!("{} {message}", SPINNER[frame % SPINNER.len()]))
+                )?;
+                frame += 1;
+            }
+        }
+    };
+    execute!(out, MoveToColumn(0), Clear(ClearType::CurrentLine))?;
+    Ok(ret)
+}
+
+/// Whether `e` means that the connection to the server is gone
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Marks the current buffer as disconnected if `result` failed because the connection to the
+/// server was lost, instead of stopping the editor
+fn handle_disconnect(app: &mut App, result: io::Result<bool>) -> io::Result<bool> {
+    match result {
+        Err(e) if is_disconnect(&e) => {
+            app.client.connection_lost(app.client.current_buffer);
+            Ok(true)
+        }
+        result => result,
+    }
+}
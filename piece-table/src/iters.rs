@@ -120,12 +120,8 @@ mod test {
         let text = "test\nmore tests\n";
         let original: AppendOnlyStr = text.into();
         let piece = Piece {
-            piece_table: iter::once(TableElem {
-                buf: None,
-                text: original.str_slice(..).unwrap(),
-                id: 0,
-            })
-            .collect(),
+            piece_table: iter::once(TableElem::new(None, 0, original.str_slice(..).unwrap()))
+                .collect(),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
                 clients: vec![],
@@ -141,12 +137,8 @@ mod test {
         let text = "test\nmore tests\n";
         let original: AppendOnlyStr = text.into();
         let piece = Piece {
-            piece_table: iter::once(TableElem {
-                buf: None,
-                id: 0,
-                text: original.str_slice(..).unwrap(),
-            })
-            .collect(),
+            piece_table: iter::once(TableElem::new(None, 0, original.str_slice(..).unwrap()))
+                .collect(),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
                 clients: vec![],
@@ -164,11 +156,11 @@ mod test {
         let text = "test\nmore tests\na";
         let original: AppendOnlyStr = text.into();
         let piece = Piece {
-            piece_table: Table::from_iter(std::iter::once(TableElem {
-                buf: None,
-                id: 0,
-                text: original.str_slice(..).unwrap(),
-            })),
+            piece_table: Table::from_iter(std::iter::once(TableElem::new(
+                None,
+                0,
+                original.str_slice(..).unwrap(),
+            ))),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
                 clients: vec![],
@@ -188,16 +180,12 @@ mod test {
         let client1: Arc<RwLock<AppendOnlyStr>> = Arc::new(RwLock::new("def".into()));
         let piece = Piece {
             piece_table: [
-                TableElem {
-                    buf: None,
-                    text: original.str_slice(..).unwrap(),
-                    id: 0,
-                },
-                TableElem {
-                    buf: Some((0, false)),
-                    text: Arc::clone(&client1).read().unwrap().str_slice(..).unwrap(),
-                    id: 1,
-                },
+                TableElem::new(None, 0, original.str_slice(..).unwrap()),
+                TableElem::new(
+                    Some((0, false)),
+                    1,
+                    Arc::clone(&client1).read().unwrap().str_slice(..).unwrap(),
+                ),
             ]
             .into_iter()
             .collect(),
@@ -224,34 +212,26 @@ mod test {
         let client1: Arc<RwLock<AppendOnlyStr>> = Arc::new(RwLock::new("bef".into()));
         let piece = Piece {
             piece_table: Table::from_iter([
-                TableElem {
-                    buf: None,
-                    id: 0,
-                    text: original.str_slice(0..1).unwrap(),
-                },
-                TableElem {
-                    buf: Some((0, false)),
-                    text: Arc::clone(&client1)
+                TableElem::new(None, 0, original.str_slice(0..1).unwrap()),
+                TableElem::new(
+                    Some((0, false)),
+                    1,
+                    Arc::clone(&client1)
                         .read()
                         .unwrap()
                         .str_slice(0..1)
                         .unwrap(),
-                    id: 1,
-                },
-                TableElem {
-                    buf: None,
-                    id: 2,
-                    text: original.str_slice(1..3).unwrap(),
-                },
-                TableElem {
-                    buf: Some((0, false)),
-                    text: Arc::clone(&client1)
+                ),
+                TableElem::new(None, 2, original.str_slice(1..3).unwrap()),
+                TableElem::new(
+                    Some((0, false)),
+                    3,
+                    Arc::clone(&client1)
                         .read()
                         .unwrap()
                         .str_slice(1..3)
                         .unwrap(),
-                    id: 3,
-                },
+                ),
             ]),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
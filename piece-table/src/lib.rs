@@ -1,20 +1,26 @@
 //! A Piece table implementation with multiple clients
-#![feature(linked_list_cursors)]
 #![feature(async_iterator)]
 use std::{
-    io::{self, Read},
+    cmp, fmt,
+    io::{self, Read, Write},
     iter,
     str::FromStr,
     sync::{Arc, RwLock},
 };
 
 pub mod iters;
+mod positions;
 pub mod table;
+pub mod tree;
 
-use append_only_str::{slices::StrSlice, AppendOnlyStr};
+use append_only_str::{
+    slices::{ByteSlice, StrSlice},
+    AppendOnlyStr,
+};
 use btep::{Deserialize, Serialize};
-use table::{InnerTable, LockError, Table};
-use tokio::io::AsyncReadExt;
+use table::{InnerTable, LockError, Measure, Table};
+use tokio::io::{AsyncReadExt, Take};
+use tree::{CursorMut, Key, Summary};
 use utils::{
     bufread::BufReaderExt,
     other::{AutoIncrementing, CursorPos},
@@ -48,8 +54,81 @@ pub struct TableElem {
     pub buf: Option<(usize, bool)>,
     /// The id of this buffer
     pub id: usize,
-    /// A slice to the text
-    pub text: StrSlice,
+    /// Where `text` starts within the piece with this `id`. Pieces keep their id when they are
+    /// split, so this tells the parts apart
+    pub offset: usize,
+    /// A slice to the text.
+    /// Only changed through `set_text`, so that the cached line count stays correct
+    text: StrSlice,
+    /// The amount of newlines in `text`
+    newlines: usize,
+}
+
+impl TableElem {
+    /// Creates a new element, counting the newlines in `text`
+    #[must_use]
+    pub fn new(buf: Option<(usize, bool)>, id: usize, text: StrSlice) -> Self {
+        Self {
+            buf,
+            id,
+            offset: 0,
+            newlines: count_newlines(&text),
+            text,
+        }
+    }
+
+    /// Returns the text of this element
+    #[must_use]
+    pub const fn text(&self) -> &StrSlice {
+        &self.text
+    }
+
+    /// Replaces the text of this element
+    pub fn set_text(&mut self, text: StrSlice) {
+        self.newlines = count_newlines(&text);
+        self.text = text;
+    }
+
+    /// Returns the amount of newlines in the text of this element
+    #[must_use]
+    pub const fn newlines(&self) -> usize {
+        self.newlines
+    }
+}
+
+/// Pieces are found by their id and offset, see `Piece::insert_at_id`
+impl Measure for TableElem {
+    fn measure(&self) -> (Key, Summary) {
+        (
+            (self.id, self.offset),
+            Summary {
+                bytes: self.text.len(),
+                newlines: self.newlines,
+            },
+        )
+    }
+}
+
+/// The description of a single piece, as it's sent as part of a `Piece`
+impl Serialize for TableElem {
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(41);
+        if let Some((bufnr, occupied)) = self.buf {
+            ret.push(if occupied { 2 } else { 1 });
+            ret.extend((bufnr as u64).to_be_bytes());
+        } else {
+            ret.push(0);
+        }
+        ret.extend((self.id as u64).to_be_bytes());
+        ret.extend((self.offset as u64).to_be_bytes());
+        ret.extend((self.text.start() as u64).to_be_bytes());
+        ret.extend((self.text.end() as u64).to_be_bytes());
+        ret
+    }
+}
+
+fn count_newlines(text: &str) -> usize {
+    text.bytes().filter(|&x| x == b'\n').count()
 }
 
 impl Piece {
@@ -58,13 +137,13 @@ impl Piece {
     pub fn new() -> Self {
         let original: AppendOnlyStr = "".into();
         Self {
-            piece_table: std::iter::once(TableElem {
-                id: 0,
-                buf: None,
-                text: original
+            piece_table: std::iter::once(TableElem::new(
+                None,
+                0,
+                original
                     .str_slice(..)
                     .expect("A full slice is always valid"),
-            })
+            ))
             .collect(),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
@@ -77,20 +156,18 @@ impl Piece {
     /// read from somewhere.
     ///
     /// # Errors
-    /// This function errors when the reader fails to read
-    pub fn original_from_reader<T: Read>(mut read: T) -> io::Result<Self> {
-        let mut string = String::new();
-        read.read_to_string(&mut string)?;
-        let original: AppendOnlyStr = string.into();
+    /// This function errors when the reader fails to read, or the data isn't valid utf-8
+    pub fn original_from_reader<T: Read>(read: T) -> io::Result<Self> {
+        let original = AppendOnlyStr::from_reader(read)?;
 
         Ok(Self {
-            piece_table: iter::once(TableElem {
-                buf: None,
-                id: 0,
-                text: original
+            piece_table: iter::once(TableElem::new(
+                None,
+                0,
+                original
                     .str_slice(..)
                     .expect("A full slice is always valid"),
-            })
+            ))
             .collect(),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
@@ -105,13 +182,13 @@ impl Piece {
         let original: AppendOnlyStr = original.into();
 
         Self {
-            piece_table: iter::once(TableElem {
-                buf: None,
-                id: 0,
-                text: original
+            piece_table: iter::once(TableElem::new(
+                None,
+                0,
+                original
                     .str_slice(..)
                     .expect("A full slice is always valid"),
-            })
+            ))
             .collect(),
             buffers: Buffers {
                 original: (AutoIncrementing::new(), original),
@@ -122,142 +199,445 @@ impl Piece {
 
     /// Creates an `InnerTable` within the piece table.
     /// This allows the list to be mutated at that point.
+    /// Positions past the end of a line or the text are clamped, so a desynced client can't make
+    /// this panic
     /// # Returns
-    /// - The offset at which the buffer got split
+    /// - The id of the piece that got split and the offset within it, see `TableElem::offset`.
+    ///   None when inserting at the end of the text. Passing this to `insert_at_id` does the same
+    ///   insertion
     /// - A `TableElem`
+    ///
+    /// None if `clientid` has run out of ids for new pieces, see `next_id`
     /// # Panics
     /// Shouldn't panic
+    #[allow(clippy::type_complexity)]
     pub fn insert_at(
         &mut self,
         pos: CursorPos,
         clientid: usize,
-    ) -> (Option<usize>, InnerTable<TableElem>) {
-        // FIXME:
-        // Rather than this we should store the amount of lines in each `TableElem`
-        let mut row_iter = self.lines();
-        let bytes_to_row: usize = row_iter
-            .by_ref()
-            .take(pos.row)
-            .map(|x| x.len() + '\n'.len_utf8())
-            .sum();
-        let char_nr = bytes_to_row
-            + row_iter
-                .next()
-                .unwrap_or_default()
-                .chars()
-                .take(pos.col)
-                .map(char::len_utf8)
-                .sum::<usize>();
+    ) -> Option<(Option<(usize, usize)>, InnerTable<TableElem>)> {
+        let char_nr = self.offset_of(pos);
+        let (piece_nr, piece_start) = self.piece_at(char_nr);
         let binding = self
             .piece_table
             .write_full()
             .expect("The entire piece table is poisoned");
 
         let mut to_split = binding.write();
-        let mut cursor = to_split.cursor_front_mut();
-        let mut curr_pos = cursor
-            .current()
-            .expect("The Linked list is empty")
-            .read()
-            .text
-            .len();
-        let is_end = loop {
-            if curr_pos > char_nr {
-                break false;
+        let mut cursor = to_split.cursor_at_mut(piece_nr);
+        let split = cursor.current().map(|current| {
+            let current = current.read();
+            (current.id, current.offset + char_nr - piece_start)
+        });
+        let inserted =
+            self.split_at_cursor(&mut cursor, split.map(|_| char_nr - piece_start), clientid)?;
+        Some((split, inserted))
+    }
+
+    /// Creates an `InnerTable` within the piece table like `insert_at`, but the position is
+    /// given as the id of the piece to split and a byte offset within it. None appends to the end
+    /// of the text.
+    /// This doesn't depend on rows and columns, which two clients might disagree on. As pieces
+    /// keep their id when they are split, it also doesn't matter whether the piece got split
+    /// since the position was taken. When the text at the offset has been deleted since, the
+    /// insertion happens at the end of what is left in front of it
+    /// # Returns
+    /// None if there is no piece with the id, the offset isn't on a char boundary within it or
+    /// `clientid` has run out of ids for new pieces
+    /// # Panics
+    /// Shouldn't panic
+    pub fn insert_at_id(
+        &mut self,
+        split: Option<(usize, usize)>,
+        clientid: usize,
+    ) -> Option<InnerTable<TableElem>> {
+        let binding = self
+            .piece_table
+            .write_full()
+            .expect("The entire piece table is poisoned");
+
+        let mut list = binding.write();
+        let Some((id, offset)) = split else {
+            let len = list.len();
+            let mut cursor = list.cursor_at_mut(len);
+            return self.split_at_cursor(&mut cursor, None, clientid);
+        };
+        // The position of the part containing the offset and the offset within it, or the
+        // position of the part following the offset
+        let mut found = None;
+        // The end of the part closest in front of the offset, and its position. The first one
+        // in the list wins ties
+        let mut closest: Option<(usize, usize)> = None;
+        for ((part_id, start), i, summary) in self.piece_table.summaries(&list).up_to((id, offset))
+        {
+            if part_id != id {
+                break;
             }
-            cursor.move_next();
-            if let Some(x) = cursor.current() {
-                curr_pos += x.read().text.len();
-            } else {
-                break true;
+            let end = start + summary.bytes;
+            if offset < end {
+                let offset = offset - start;
+                if !list.get(i)?.read().text.is_char_boundary(offset) {
+                    return None;
+                }
+                found = Some((i, Some(offset)));
+                break;
             }
-        };
-
-        let offset = if is_end {
-            // is_end implies that current refers to the ghost node. We know that we have a
-            // non-zero amount of elements in the list
-            let current = cursor.peek_prev().expect("prev isn't ghost");
-            let buf = current.read().buf;
-            let curr = if let Some((buf, _)) = current.read().buf {
-                &*self.buffers.clients[buf]
-                    .1
-                    .read()
-                    .expect("AppendOnlyStr got poisoned")
-            } else {
-                &self.buffers.original.1
-            };
+            if closest
+                .is_none_or(|(closest_end, j)| closest_end < end || closest_end == end && i < j)
+            {
+                closest = Some((end, i));
+            }
+            // The parts don't overlap, so the ones that start further in front end in front of
+            // this one. Only empty parts can share its end
+            if summary.bytes != 0 {
+                break;
+            }
+        }
+        let (position, offset) = found.or_else(|| closest.map(|(_, i)| (i + 1, Some(0))))?;
+        let mut cursor = list.cursor_at_mut(position);
+        // Offsets are relative to the piece under the cursor, and the ghost element has none
+        let offset = offset.filter(|_| cursor.current().is_some());
+        self.split_at_cursor(&mut cursor, offset, clientid)
+    }
 
-            cursor.insert_before(InnerTable::new(
-                TableElem {
-                    buf,
-                    text: curr.str_slice_end(),
-                    id: self.buffers.clients[clientid]
-                        .0
-                        .write()
-                        .expect("Poison")
-                        .get()
-                        * self.buffers.clients.len()
-                        + clientid,
-                },
-                self.piece_table.state(),
-            ));
-            None
-        } else {
-            let (buf_of_split, current) = {
+    /// Splits the piece under the cursor at `offset` and puts a new piece owned by `clientid` in
+    /// between the halves. When `offset` is None the cursor has to be at the end of the list, and
+    /// the new piece is appended without touching the last piece, as it might be occupied by
+    /// another client.
+    /// Nothing is split if `clientid` has run out of ids for new pieces, in which case None is
+    /// returned
+    fn split_at_cursor(
+        &self,
+        cursor: &mut CursorMut<'_, InnerTable<TableElem>>,
+        offset: Option<usize>,
+        clientid: usize,
+    ) -> Option<InnerTable<TableElem>> {
+        let new_id = self.next_id(clientid)?;
+        if let Some(offset) = offset {
+            let (buf_of_split, id, piece_offset, current) = {
                 let current = cursor
                     .current()
                     .expect("Cursor should not be at the ghost element")
                     .read();
-                (current.buf, current.text.clone())
+                (
+                    current.buf,
+                    current.id,
+                    current.offset,
+                    current.text.clone(),
+                )
             };
-            // byte offset within the current buffer
-            let offset = char_nr - (curr_pos - current.len());
 
             if offset != 0 {
-                cursor.insert_before(InnerTable::new(
-                    TableElem {
-                        buf: buf_of_split.map(|x| (x.0, false)),
-                        text: current
-                            .subslice(..offset)
-                            .expect("offset should be on a byte boundary"),
-                        id: self.buffers.clients[clientid]
-                            .0
-                            .write()
-                            .expect("Poison")
-                            .get()
-                            * self.buffers.clients.len()
-                            + clientid,
-                    },
-                    self.piece_table.state(),
-                ));
+                // Both parts keep the id, so that positions within the piece stay valid
+                let mut head = TableElem::new(
+                    buf_of_split.map(|x| (x.0, false)),
+                    id,
+                    current
+                        .subslice(..offset)
+                        .expect("offset should be on a byte boundary"),
+                );
+                head.offset = piece_offset;
+                cursor.insert_before(InnerTable::new(head, self.piece_table.state()));
             }
 
-            cursor
-                .current()
-                .expect("Current is not on a utf-8 boundary")
-                .write()
-                .unwrap()
-                .text = current
-                .subslice(offset..)
-                .expect("offset is not on a byte boundary");
-            Some(offset)
+            let mut tail = current_mut(cursor);
+            tail.set_text(
+                current
+                    .subslice(offset..)
+                    .expect("offset is not on a byte boundary"),
+            );
+            tail.offset = piece_offset + offset;
+        }
+        // Clients that entered insert mode at the same place are ordered by their id until they
+        // type something, so that the order doesn't depend on which of them came first
+        let empty_of = |piece: Option<&InnerTable<TableElem>>| {
+            piece.and_then(|x| {
+                let x = x.read();
+                x.text.is_empty().then_some(x.buf?.0)
+            })
         };
+        while empty_of(cursor.peek_prev()).is_some_and(|x| x > clientid) {
+            cursor.move_prev();
+        }
+        while empty_of(cursor.current()).is_some_and(|x| x < clientid) {
+            cursor.move_next();
+        }
         let curr = self.buffers.clients[clientid].1.read().unwrap();
         cursor.insert_before(InnerTable::new(
-            TableElem {
-                buf: Some((clientid, true)),
-                text: curr.str_slice_end(),
-                id: self.buffers.clients[clientid]
-                    .0
-                    .write()
-                    .expect("Poison")
-                    .get()
-                    * self.buffers.clients.len()
-                    + clientid,
-            },
+            TableElem::new(Some((clientid, true)), new_id, curr.str_slice_end()),
             self.piece_table.state(),
         ));
-        (offset, cursor.peek_prev().unwrap().clone())
+        Some(cursor.peek_prev().unwrap().clone())
+    }
+
+    /// Generates a new id for a piece created by `clientid`.
+    /// The id only depends on the client and how many ids it has generated, so clients agree on
+    /// it even if they don't agree on the amount of clients. It is never 0, which is the id of
+    /// the original piece
+    /// # Returns
+    /// None once the ids of the client no longer fit in a `usize`. Ids are never handed out
+    /// twice, so the client can't create any more pieces after that
+    /// # Panics
+    /// The id counter of the client is poisoned
+    pub fn next_id(&self, clientid: usize) -> Option<usize> {
+        let count = self.buffers.clients[clientid]
+            .0
+            .write()
+            .expect("Poison")
+            .get()?
+            .checked_add(1)?;
+        // The Cantor pairing of `count` and `clientid`
+        let sum = count.checked_add(clientid)?;
+        (sum.checked_mul(sum.checked_add(1)?)? / 2).checked_add(clientid)
+    }
+
+    /// Deletes the text between `start` (inclusive) and `end` (exclusive).
+    /// Pieces which are partially covered get split, and pieces which are fully covered get
+    /// removed. Pieces that are currently being edited are kept around, but emptied, so that the
+    /// client editing them can keep on typing. Both halves of a split piece keep its id, so that
+    /// positions within either half can still be found.
+    /// # Returns
+    /// The text that was deleted
+    /// # Panics
+    /// The piece table is poisoned
+    pub fn delete_range(&mut self, start: CursorPos, end: CursorPos) -> String {
+        let binding = self
+            .piece_table
+            .write_full()
+            .expect("The entire piece table is poisoned");
+        let mut list = binding.write();
+        // The offsets have to be computed under the same lock as the deletion, so that nothing
+        // can move the text in between
+        // `first` is the position of the first piece that is deleted from, and `pos` is its
+        // position within the text
+        let (start, end, (first, mut pos)) =
+            Self::range_of(&self.piece_table.summaries(&list), start, end);
+        let mut deleted = String::new();
+        if start >= end {
+            return deleted;
+        }
+        let mut cursor = list.cursor_at_mut(first);
+        while pos < end {
+            let Some(current) = cursor.current() else {
+                break;
+            };
+            let (buf, id, offset, text) = {
+                let current = current.read();
+                (
+                    current.buf,
+                    current.id,
+                    current.offset,
+                    current.text.clone(),
+                )
+            };
+            let piece_end = pos + text.len();
+            if piece_end <= start {
+                pos = piece_end;
+                cursor.move_next();
+                continue;
+            }
+
+            let from = start.saturating_sub(pos);
+            let to = cmp::min(end, piece_end) - pos;
+            deleted.push_str(
+                &text
+                    .subslice(from..to)
+                    .expect("The range should be on a char boundary"),
+            );
+            let head = text.subslice(..from).expect("from is on a char boundary");
+            let tail = text.subslice(to..).expect("to is on a char boundary");
+            // A piece being edited has to keep on pointing at the end of its buffer, so only its
+            // start can be moved
+            let occupied = buf.is_some_and(|(_, occupied)| occupied);
+
+            pos = piece_end;
+            if !head.is_empty() && (occupied || !tail.is_empty()) {
+                let mut head = TableElem::new(buf.map(|(x, _)| (x, false)), id, head);
+                head.offset = offset;
+                cursor.insert_before(InnerTable::new(head, self.piece_table.state()));
+                let mut current = current_mut(&mut cursor);
+                current.set_text(tail);
+                current.offset += to;
+            } else if !head.is_empty() {
+                current_mut(&mut cursor).set_text(head);
+            } else if occupied || !tail.is_empty() || cursor.as_tree().len() == 1 {
+                let mut current = current_mut(&mut cursor);
+                current.set_text(tail);
+                current.offset += to;
+            } else {
+                cursor.remove_current();
+                continue;
+            }
+            cursor.move_next();
+        }
+        deleted
+    }
+
+    /// Merges neighbouring pieces that point at contiguous parts of the same buffer into a single
+    /// piece. Only the parts of a piece that was split are merged, as pieces are found by their id
+    /// and offset. Pieces that are currently being edited are left alone
+    /// # Panics
+    /// The piece table is poisoned
+    pub fn compact(&mut self) {
+        let binding = self
+            .piece_table
+            .write_full()
+            .expect("The entire piece table is poisoned");
+        let mut list = binding.write();
+        let mut cursor = list.cursor_front_mut();
+        while let Some(current) = cursor.current() {
+            let (buf, id, offset, text) = {
+                let current = current.read();
+                (
+                    current.buf,
+                    current.id,
+                    current.offset,
+                    current.text.clone(),
+                )
+            };
+            let Some(next) = cursor.peek_next() else {
+                break;
+            };
+            let (next_buf, next_id, next_offset, next_text) = {
+                let next = next.read();
+                (next.buf, next.id, next.offset, next.text.clone())
+            };
+            let occupied = |buf: Option<(usize, bool)>| buf.is_some_and(|(_, occupied)| occupied);
+            if occupied(buf)
+                || occupied(next_buf)
+                || buf.map(|(x, _)| x) != next_buf.map(|(x, _)| x)
+                || id != next_id
+                || offset + text.len() != next_offset
+                || text.end() != next_text.start()
+            {
+                cursor.move_next();
+                continue;
+            }
+
+            let range = text.start()..next_text.end();
+            let merged = if let Some((buf, _)) = buf {
+                self.buffers.clients[buf]
+                    .1
+                    .read()
+                    .expect("AppendOnlyStr got poisoned")
+                    .str_slice(range)
+            } else {
+                self.buffers.original.1.str_slice(range)
+            }
+            .expect("Both pieces are on char boundaries");
+            cursor.move_next();
+            cursor.remove_current();
+            cursor.move_prev();
+            current_mut(&mut cursor).set_text(merged);
+        }
+    }
+
+    /// Rebuilds the buffers so that they only contain the bytes which are still referenced by a
+    /// piece, and points the pieces at the new buffers. Append buffers only ever grow, so this
+    /// bounds the memory used by a table that is edited for a long time
+    /// # Panics
+    /// The piece table is poisoned
+    pub fn shrink_buffers(&mut self) {
+        let binding = self
+            .piece_table
+            .write_full()
+            .expect("The entire piece table is poisoned");
+        let list = binding.write();
+        let pieces = list
+            .iter()
+            .map(|x| {
+                let inner = x.read();
+                (
+                    x.clone(),
+                    inner.buf.map(|(buf, _)| buf),
+                    inner.text.start(),
+                    inner.text.end(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let ranges = |buf: Option<usize>| {
+            pieces
+                .iter()
+                .filter(move |x| x.1 == buf)
+                .map(|x| (x.2, x.3))
+        };
+
+        // The blocks of the original buffer followed by those of each client
+        let (original, original_blocks) = shrink(&self.buffers.original.1, ranges(None));
+        self.buffers.original.1 = original;
+        let mut blocks = vec![original_blocks];
+        for (i, (_, buffer)) in self.buffers.clients.iter().enumerate() {
+            let mut buffer = buffer.write().expect("AppendOnlyStr got poisoned");
+            let (new, client_blocks) = shrink(&buffer, ranges(Some(i)));
+            *buffer = new;
+            blocks.push(client_blocks);
+        }
+
+        for (piece, buf, start, end) in pieces {
+            let blocks = &blocks[buf.map_or(0, |x| x + 1)];
+            let range = move_position(blocks, start)..move_position(blocks, end);
+            let text = if let Some(buf) = buf {
+                self.buffers.clients[buf]
+                    .1
+                    .read()
+                    .expect("AppendOnlyStr got poisoned")
+                    .str_slice(range)
+            } else {
+                self.buffers.original.1.str_slice(range)
+            }
+            .expect("The referenced bytes were copied");
+            piece
+                .write()
+                .expect("The piece got poisoned")
+                .set_text(text);
+        }
+    }
+
+    /// Creates a human readable JSON representation of the piece table. It contains every piece
+    /// with the buffer it points into, its range in that buffer and the text it resolves to, as
+    /// well as the content of every buffer. This is only meant for debugging, and has one piece
+    /// per line so that two tables can be diffed
+    /// # Panics
+    /// The piece table is poisoned
+    #[must_use]
+    pub fn to_debug_json(&self) -> String {
+        let mut ret = String::from("{\n");
+        ret.push_str(&format!(
+            "  \"original\": {},\n",
+            json_str(&self.buffers.original.1)
+        ));
+        ret.push_str("  \"clients\": [");
+        for (i, (_, buffer)) in self.buffers.clients.iter().enumerate() {
+            if i != 0 {
+                ret.push(',');
+            }
+            let buffer = buffer.read().expect("AppendOnlyStr got poisoned");
+            ret.push_str(&format!("\n    {}", json_str(&buffer)));
+        }
+        if !self.buffers.clients.is_empty() {
+            ret.push_str("\n  ");
+        }
+        ret.push_str("],\n  \"pieces\": [");
+        for (i, piece) in self.bufs().enumerate() {
+            if i != 0 {
+                ret.push(',');
+            }
+            let piece = piece.read();
+            let (buffer, occupied) = piece
+                .buf
+                .map_or(("null".to_string(), false), |(buf, occupied)| {
+                    (buf.to_string(), occupied)
+                });
+            ret.push_str(&format!(
+                "\n    {{\"id\": {}, \"buffer\": {buffer}, \"occupied\": {occupied}, \"start\": {}, \"end\": {}, \"text\": {}}}",
+                piece.id,
+                piece.text.start(),
+                piece.text.end(),
+                json_str(&piece.text),
+            ));
+        }
+        ret.push_str("\n  ]\n}");
+        ret
     }
 
     /// Locks down the full list for reading.
@@ -281,59 +661,195 @@ impl Piece {
     }
 }
 
+/// Copies the parts of `buffer` that are covered by `ranges` into a new buffer. Ranges that
+/// overlap or touch stay contiguous.
+/// # Returns
+/// - The new buffer
+/// - The start of each copied block in the old and the new buffer
+fn shrink(
+    buffer: &AppendOnlyStr,
+    ranges: impl Iterator<Item = (usize, usize)>,
+) -> (AppendOnlyStr, Vec<(usize, usize)>) {
+    let mut ranges = ranges.collect::<Vec<_>>();
+    ranges.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = cmp::max(*last_end, end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let mut ret = AppendOnlyStr::with_capacity(merged.iter().map(|(start, end)| end - start).sum());
+    let mut blocks = Vec::with_capacity(merged.len());
+    for (start, end) in merged {
+        blocks.push((start, ret.len()));
+        ret.push_str(&buffer[start..end]);
+    }
+    (ret, blocks)
+}
+
+/// Converts a position in a buffer to its position after the buffer has been shrunk
+fn move_position(blocks: &[(usize, usize)], pos: usize) -> usize {
+    let idx = blocks.partition_point(|(old, _)| *old <= pos) - 1;
+    let (old, new) = blocks[idx];
+    new + pos - old
+}
+
+/// Quotes and escapes a string so that it can be used in JSON
+fn json_str(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len() + 2);
+    ret.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if c.is_control() => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// Returns a write lock on the element the cursor is pointing at
+fn current_mut<'a>(
+    cursor: &'a mut CursorMut<'_, InnerTable<TableElem>>,
+) -> table::TableLockWriter<'a, TableElem> {
+    cursor
+        .current()
+        .expect("The cursor should not be at the ghost element")
+        .write()
+        .expect("The piece got poisoned")
+}
+
 impl Default for Piece {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// Writes the text of every piece in order, exactly as it would be saved
+impl fmt::Display for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for buf in self.bufs() {
+            f.write_str(buf.read().text.as_str())?;
+        }
+        Ok(())
+    }
+}
+
 impl Serialize for &Piece {
     fn serialize(&self) -> Vec<u8> {
         let mut ret = Vec::new();
-        ret.extend((self.buffers.original.0.peek() as u64).to_be_bytes());
-        ret.extend(
-            self.buffers
-                .original
-                .1
-                .str_slice(..)
-                .unwrap()
-                .as_str()
-                .bytes(),
-        );
+        self.serialize_into(&mut ret)
+            .expect("Writing to a Vec can't fail");
+        ret
+    }
+
+    fn serialize_into<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        for (prefix, text) in self.buffer_parts() {
+            writer.write_all(&prefix)?;
+            writer.write_all(text.as_bytes())?;
+        }
+        // Might be useless, but it's a single byte
+        writer.write_all(&[0xff])?;
+        let list = self.piece_table.read_full().unwrap();
+        let list = list.read();
+        writer.write_all(&(list.len() as u64).to_be_bytes())?;
+
+        for piece in list.iter() {
+            writer.write_all(&piece.read().serialize())?;
+        }
+        Ok(())
+    }
+}
+
+impl Piece {
+    /// What a serialized `Piece` starts with, as the bytes that come before the text of each
+    /// buffer together with that text. The text is sliced rather than copied, so it can still be
+    /// written after the locks on the buffers are gone. The `0xff` ending the buffers isn't
+    /// included
+    /// # Panics
+    /// A client buffer got poisoned
+    #[must_use]
+    pub fn buffer_parts(&self) -> Vec<(Vec<u8>, ByteSlice)> {
+        let whole =
+            |buffer: &AppendOnlyStr| buffer.slice(..).expect("The whole buffer is in range");
+        let mut ret = Vec::with_capacity(self.buffers.clients.len() + 1);
+        ret.push((
+            (self.buffers.original.0.peek() as u64).to_be_bytes().into(),
+            whole(&self.buffers.original.1),
+        ));
         for client in &self.buffers.clients {
             // 0xfe is used here because its not representable by utf8, and makes stuff easier to
             // parse. This is useful because the alternative is the specify the strings length,
             // which would take up at least as many bytes
-            ret.push(0xfe);
-            ret.extend((client.0.read().unwrap().peek() as u64).to_be_bytes());
-            ret.extend(
-                client
-                    .1
-                    .read()
-                    .unwrap()
-                    .str_slice(..)
-                    .unwrap()
-                    .as_str()
-                    .bytes(),
-            );
+            let mut prefix = vec![0xfe];
+            prefix.extend((client.0.read().unwrap().peek() as u64).to_be_bytes());
+            ret.push((prefix, whole(&client.1.read().unwrap())));
         }
-        // Might be useless, but it's a single byte
-        ret.push(0xff);
-        ret.extend((self.piece_table.read_full().unwrap().read().len() as u64).to_be_bytes());
+        ret
+    }
+}
 
-        for piece in self.piece_table.read_full().unwrap().read().iter() {
-            let piece = piece.read();
-            if let Some((bufnr, occupied)) = piece.buf {
-                ret.extend([if occupied { 2 } else { 1 }]);
-                ret.extend((bufnr as u64).to_be_bytes());
-            } else {
-                ret.push(0);
+/// Reads the id counters and text of every buffer, which may take up at most `max` bytes in total.
+/// Their length isn't sent along with them, so nothing else bounds them
+async fn read_buffers<T>(data: &mut T, max: u64) -> io::Result<Buffers>
+where
+    T: AsyncReadExt + Unpin + Send,
+{
+    let mut limited = data.take(max);
+    let mut str_buf = String::new();
+    let original_start = limited.read_u64().await? as usize;
+    let mut separator = read_buffer(&mut limited, &mut str_buf).await?;
+    let original = (
+        AutoIncrementing::new_with_start(original_start),
+        AppendOnlyStr::from_str(&str_buf).unwrap(),
+    );
+
+    let mut clients = Vec::new();
+    loop {
+        match separator {
+            0xff => break,
+            0xfe => (),
+            x => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{x:#x} doesn't separate buffers"),
+                ))
             }
-            ret.extend((piece.id as u64).to_be_bytes());
-            ret.extend((piece.text.start() as u64).to_be_bytes());
-            ret.extend((piece.text.end() as u64).to_be_bytes());
         }
-        ret
+        let counter_start = limited.read_u64().await? as usize;
+        let mut str_buf = String::new();
+        separator = read_buffer(&mut limited, &mut str_buf).await?;
+
+        clients.push((
+            Arc::new(RwLock::new(AutoIncrementing::new_with_start(counter_start))),
+            Arc::new(RwLock::<AppendOnlyStr>::new(str_buf.into())),
+        ));
+    }
+    Ok(Buffers { original, clients })
+}
+
+/// Reads the text of a buffer into `buffer`, and returns the byte ending it
+async fn read_buffer<T>(data: &mut Take<T>, buffer: &mut String) -> io::Result<u8>
+where
+    T: AsyncReadExt + Unpin + Send,
+{
+    match data.read_valid_str(buffer).await? {
+        Some(x) => Ok(x),
+        None if data.limit() == 0 => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The buffers are too large",
+        )),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
     }
 }
 
@@ -343,28 +859,7 @@ impl Deserialize for Piece {
         T: AsyncReadExt + Unpin + Send,
         Self: Sized,
     {
-        let mut str_buf = String::new();
-        let original_start = data.read_u64().await?;
-        let mut separator = data.read_valid_str(&mut str_buf).await?;
-
-        let original_buffer: AppendOnlyStr = AppendOnlyStr::from_str(&str_buf).unwrap();
-
-        let mut client_buffers = Vec::new();
-        loop {
-            let mut str_buf = String::new();
-            if separator == Some(255) {
-                break;
-            }
-            let counter_start = data.read_u64().await? as usize;
-
-            str_buf.clear();
-            separator = data.read_valid_str(&mut str_buf).await?;
-
-            client_buffers.push((
-                Arc::new(RwLock::new(AutoIncrementing::new_with_start(counter_start))),
-                Arc::new(RwLock::<AppendOnlyStr>::new(str_buf.into())),
-            ));
-        }
+        let buffers = read_buffers(data, btep::MAX_FULL_SIZE).await?;
 
         let piece_count = data.read_u64().await? as usize;
 
@@ -374,36 +869,39 @@ impl Deserialize for Piece {
                 0 => None,
                 1 => Some((data.read_u64().await? as usize, false)),
                 2 => Some((data.read_u64().await? as usize, true)),
-                _ => unreachable!(),
+                x => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{x} is not a valid buffer kind"),
+                    ))
+                }
             };
 
             let id = data.read_u64().await? as usize;
+            let offset = data.read_u64().await? as usize;
             let start = data.read_u64().await? as usize;
             let end = data.read_u64().await? as usize;
-            builder.push(TableElem {
-                buf,
-                text: if let Some((bufid, _)) = buf {
-                    client_buffers[bufid]
-                        .1
-                        .read()
-                        .unwrap()
-                        .str_slice(start..end)
-                        .unwrap()
-                } else {
-                    original_buffer.str_slice(start..end).unwrap()
-                },
-                id,
-            });
+            let text = if let Some((bufid, _)) = buf {
+                buffers
+                    .clients
+                    .get(bufid)
+                    .and_then(|(_, buffer)| buffer.read().unwrap().str_slice(start..end))
+            } else {
+                buffers.original.1.str_slice(start..end)
+            };
+            let Some(text) = text else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{start}..{end} is not a valid slice of buffer {buf:?}"),
+                ));
+            };
+            let mut piece = TableElem::new(buf, id, text);
+            piece.offset = offset;
+            builder.push(piece);
         }
 
         Ok(Self {
-            buffers: Buffers {
-                original: (
-                    AutoIncrementing::new_with_start(original_start as usize),
-                    original_buffer,
-                ),
-                clients: client_buffers,
-            },
+            buffers,
             piece_table: Table::new(builder),
         })
     }
@@ -411,9 +909,66 @@ impl Deserialize for Piece {
 
 #[cfg(test)]
 mod test {
-    use std::io::BufReader;
+    use std::{
+        io::{self, BufReader},
+        sync::{Arc, RwLock},
+    };
+
+    use btep::{Deserialize, Serialize};
+    use futures::executor::block_on;
 
-    use crate::Piece;
+    use append_only_str::AppendOnlyStr;
+    use utils::other::{AutoIncrementing, CursorPos};
+
+    use crate::{
+        read_buffers,
+        table::{InnerTable, Table},
+        Buffers, Piece, TableElem,
+    };
+
+    /// Creates the text "abcdef" where "b" and "ef" come from a client buffer
+    fn interleaved(occupied: bool) -> Piece {
+        let original: AppendOnlyStr = "acd".into();
+        let client: Arc<RwLock<AppendOnlyStr>> = Arc::new(RwLock::new("bef".into()));
+        let piece_table = Table::from_iter([
+            TableElem::new(None, 0, original.str_slice(0..1).unwrap()),
+            TableElem::new(
+                Some((0, false)),
+                1,
+                client.read().unwrap().str_slice(0..1).unwrap(),
+            ),
+            TableElem::new(None, 2, original.str_slice(1..3).unwrap()),
+            TableElem::new(
+                Some((0, occupied)),
+                3,
+                client.read().unwrap().str_slice(1..3).unwrap(),
+            ),
+        ]);
+        Piece {
+            piece_table,
+            buffers: Buffers {
+                original: (AutoIncrementing::new(), original),
+                clients: vec![(Arc::new(RwLock::new(AutoIncrementing::new())), client)],
+            },
+        }
+    }
+
+    /// Creates a piece table from `text` with a single client
+    fn with_client(text: &str) -> Piece {
+        let mut piece = Piece::original_from_str(text);
+        piece.buffers.clients.push((
+            Arc::new(RwLock::new(AutoIncrementing::new())),
+            Arc::new(RwLock::new(AppendOnlyStr::new())),
+        ));
+        piece
+    }
+
+    fn texts(piece: &Piece) -> Vec<String> {
+        piece
+            .bufs()
+            .map(|x| x.read().text.as_str().to_string())
+            .collect()
+    }
 
     #[test]
     fn from_reader() {
@@ -428,4 +983,643 @@ mod test {
         assert_eq!(next.buf, None);
         assert!(iter.next().is_none());
     }
+
+    #[test]
+    fn next_id_exhaustion() {
+        let piece = with_client("");
+        let set_counter = |n| piece.buffers.clients[0].0.write().unwrap().set(n);
+        assert_eq!(piece.next_id(0), Some(1));
+        // The pairing multiplies the count with itself, so it runs out around the square root
+        let half = 1 << (usize::BITS / 2);
+        set_counter(half - 3);
+        assert!(piece.next_id(0).is_some());
+        set_counter(half - 1);
+        assert_eq!(piece.next_id(0), None);
+        set_counter(usize::MAX - 1);
+        assert_eq!(piece.next_id(0), None);
+        assert_eq!(piece.next_id(0), None);
+        // Nothing gets split when there is no id for the new piece
+        let mut piece = with_client("andy");
+        piece.buffers.clients[0].0.write().unwrap().set(usize::MAX);
+        assert!(piece.insert_at((0, 2).into(), 0).is_none());
+        assert_eq!(texts(&piece), ["andy"]);
+    }
+
+    #[test]
+    fn delete_within_piece() {
+        let mut piece = with_client("hello world");
+        let deleted = piece.delete_range((0, 2).into(), (0, 4).into());
+        assert_eq!(deleted, "ll");
+        assert_eq!(piece.chars().collect::<String>(), "heo world");
+        assert_eq!(texts(&piece), ["he", "o world"]);
+    }
+
+    #[test]
+    fn delete_keeps_positions() {
+        let mut piece = with_client("hello world");
+        piece.delete_range((0, 2).into(), (0, 4).into());
+        // Positions that were read before the deletion still point into both halves
+        assert!(piece.insert_at_id(Some((0, 1)), 0).is_some());
+        assert_eq!(texts(&piece), ["h", "", "e", "o world"]);
+        let mut other = with_client("hello world");
+        other.delete_range((0, 2).into(), (0, 4).into());
+        assert!(other.insert_at_id(Some((0, 5)), 0).is_some());
+        assert_eq!(texts(&other), ["he", "o", "", " world"]);
+    }
+
+    #[test]
+    fn delete_across_buffers() {
+        let mut piece = interleaved(false);
+        let deleted = piece.delete_range((0, 1).into(), (0, 5).into());
+        assert_eq!(deleted, "bcde");
+        assert_eq!(piece.chars().collect::<String>(), "af");
+        assert_eq!(texts(&piece), ["a", "f"]);
+    }
+
+    #[test]
+    fn delete_across_lines() {
+        let mut piece = with_client("abc\ndef\nghi");
+        let deleted = piece.delete_range((0, 2).into(), CursorPos { row: 2, col: 1 });
+        assert_eq!(deleted, "c\ndef\ng");
+        assert_eq!(piece.lines().collect::<Vec<_>>(), ["abhi"]);
+    }
+
+    #[test]
+    fn delete_keeps_occupied() {
+        let mut piece = interleaved(true);
+        let deleted = piece.delete_range((0, 2).into(), (0, 6).into());
+        assert_eq!(deleted, "cdef");
+        assert_eq!(texts(&piece), ["a", "b", ""]);
+        let binding = piece.read_full().unwrap();
+        let list = binding.read();
+        let last = list.back().unwrap().read();
+        assert_eq!(last.buf, Some((0, true)));
+        assert_eq!(last.text.end(), 3);
+    }
+
+    #[test]
+    fn delete_head_of_occupied() {
+        let mut piece = interleaved(true);
+        let deleted = piece.delete_range((0, 3).into(), (0, 5).into());
+        assert_eq!(deleted, "de");
+        assert_eq!(texts(&piece), ["a", "b", "c", "f"]);
+        assert_eq!(piece.chars().collect::<String>(), "abcf");
+    }
+
+    #[test]
+    fn delete_everything() {
+        let mut piece = with_client("andy");
+        let deleted = piece.delete_range((0, 0).into(), (0, 4).into());
+        assert_eq!(deleted, "andy");
+        assert_eq!(texts(&piece), [""]);
+        assert_eq!(piece.delete_range((0, 0).into(), (0, 4).into()), "");
+    }
+
+    /// Creates the text "ab\ncd\n\néf\n" with a single client, split over several pieces with
+    /// newlines at both the start and the end of pieces
+    fn multiline() -> Piece {
+        let original: AppendOnlyStr = "ab\ncd\n\néf\n".into();
+        let piece_table = Table::from_iter(
+            [0..1, 1..4, 4..5, 5..7, 7..10, 10..11]
+                .into_iter()
+                .enumerate()
+                .map(|(id, range)| TableElem::new(None, id, original.str_slice(range).unwrap())),
+        );
+        Piece {
+            piece_table,
+            buffers: Buffers {
+                original: (AutoIncrementing::new(), original),
+                clients: vec![(
+                    Arc::new(RwLock::new(AutoIncrementing::new())),
+                    Arc::new(RwLock::new(AppendOnlyStr::new())),
+                )],
+            },
+        }
+    }
+
+    /// Checks that every piece has the amount of newlines its text contains
+    fn assert_newlines(piece: &Piece) {
+        for elem in piece.bufs() {
+            let elem = elem.read();
+            assert_eq!(elem.newlines(), elem.text.matches('\n').count());
+        }
+    }
+
+    #[test]
+    fn newlines_are_counted() {
+        let piece = multiline();
+        assert_eq!(
+            piece
+                .bufs()
+                .map(|x| x.read().newlines())
+                .collect::<Vec<_>>(),
+            [0, 1, 0, 2, 0, 1]
+        );
+    }
+
+    #[test]
+    fn newlines_after_edits() {
+        let mut piece = interleaved(false);
+        piece.insert_at((0, 2).into(), 0).unwrap();
+        assert_newlines(&piece);
+        let mut piece = multiline();
+        piece.insert_at((1, 1).into(), 0).unwrap();
+        assert_newlines(&piece);
+        let mut piece = multiline();
+        piece.delete_range((0, 1).into(), (2, 0).into());
+        assert_newlines(&piece);
+        assert_eq!(piece.lines().collect::<Vec<_>>(), ["a", "éf"]);
+    }
+
+    #[test]
+    fn line_matches_lines() {
+        let piece = multiline();
+        for row in 0..6 {
+            assert_eq!(piece.line(row), piece.lines().nth(row), "row {row}");
+        }
+        let piece = Piece::original_from_str("abc\ndef");
+        for row in 0..3 {
+            assert_eq!(piece.line(row), piece.lines().nth(row), "row {row}");
+        }
+    }
+
+    #[test]
+    fn line_count() {
+        assert_eq!(multiline().line_count(), 5);
+        assert_eq!(Piece::new().line_count(), 1);
+        assert_eq!(Piece::original_from_str("ab").line_count(), 1);
+    }
+
+    #[test]
+    fn offset_of_skips_pieces() {
+        let piece = multiline();
+        let text = piece.chars().collect::<String>();
+        for row in 0..5 {
+            for col in 0..4 {
+                let expected: usize = text
+                    .split('\n')
+                    .take(row)
+                    .map(|x| x.len() + 1)
+                    .sum::<usize>()
+                    + text
+                        .split('\n')
+                        .nth(row)
+                        .unwrap_or_default()
+                        .chars()
+                        .take(col)
+                        .map(char::len_utf8)
+                        .sum::<usize>();
+                assert_eq!(
+                    piece.offset_of((row, col).into()),
+                    expected.min(text.len()),
+                    "{row}:{col}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn char_at() {
+        let piece = multiline();
+        let text = piece.chars().collect::<String>();
+        for idx in 0..text.len() + 2 {
+            assert_eq!(
+                piece.char_at(idx),
+                text.get(idx..).and_then(|x| x.chars().next()),
+                "{idx}"
+            );
+        }
+    }
+
+    #[test]
+    fn sums_follow_edits() {
+        let mut piece = multiline();
+        assert_eq!(piece.char_at(3), Some('c'));
+        assert_eq!(piece.line(3), Some("éf".into()));
+
+        piece.delete_range((0, 1).into(), (1, 1).into());
+        assert_eq!(piece.char_at(1), Some('d'));
+        assert_eq!(piece.line(2), Some("éf".into()));
+
+        // Pieces can be edited without going through `Piece`, like clients do when typing
+        let first = piece.bufs().next().unwrap();
+        let text = first.read().text.subslice(..0).unwrap();
+        first.write().unwrap().set_text(text);
+        assert_eq!(piece.char_at(0), Some('d'));
+        assert_eq!(piece.offset_of((1, 0).into()), 2);
+    }
+
+    #[test]
+    fn sums_follow_edited_pieces() {
+        let piece = multiline();
+        let rows = |piece: &Piece| {
+            (0..6)
+                .map(|row| (piece.line(row), piece.offset_of((row, 1).into())))
+                .collect::<Vec<_>>()
+        };
+        // Every piece loses its last char, one at a time, while the order stays the same
+        for elem in piece.bufs().collect::<Vec<_>>() {
+            let text = {
+                let read = elem.read();
+                let last = read.text.chars().last().map_or(0, char::len_utf8);
+                read.text.subslice(..read.text.len() - last).unwrap()
+            };
+            elem.write().unwrap().set_text(text);
+            assert_eq!(
+                rows(&piece),
+                rows(&Piece::original_from_str(&piece.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn compact_contiguous() {
+        let original: AppendOnlyStr = "abc\nde".into();
+        let mut piece = Piece {
+            piece_table: Table::from_iter([0..1, 1..3, 3..3, 3..5, 5..6].into_iter().map(
+                |range| {
+                    let mut elem =
+                        TableElem::new(None, 0, original.str_slice(range.clone()).unwrap());
+                    elem.offset = range.start;
+                    elem
+                },
+            )),
+            buffers: Buffers {
+                original: (AutoIncrementing::new(), original),
+                clients: vec![],
+            },
+        };
+        piece.compact();
+        assert_eq!(texts(&piece), ["abc\nde"]);
+        assert_eq!(piece.bufs().next().unwrap().read().newlines(), 1);
+    }
+
+    #[test]
+    fn compact_keeps_separate_buffers() {
+        let mut piece = interleaved(false);
+        piece.compact();
+        assert_eq!(texts(&piece), ["a", "b", "cd", "ef"]);
+    }
+
+    #[test]
+    fn compact_keeps_positions() {
+        let original: AppendOnlyStr = "abcdef".into();
+        let split = {
+            let mut elem = TableElem::new(None, 0, original.str_slice(2..3).unwrap());
+            elem.offset = 2;
+            elem
+        };
+        let mut piece = Piece {
+            piece_table: Table::from_iter([
+                TableElem::new(None, 0, original.str_slice(0..2).unwrap()),
+                split,
+                TableElem::new(None, 1, original.str_slice(3..6).unwrap()),
+            ]),
+            buffers: Buffers {
+                original: (AutoIncrementing::new(), original),
+                clients: vec![(
+                    Arc::new(RwLock::new(AutoIncrementing::new())),
+                    Arc::new(RwLock::new(AppendOnlyStr::new())),
+                )],
+            },
+        };
+        piece.compact();
+        assert_eq!(texts(&piece), ["abc", "def"]);
+        assert!(piece.insert_at_id(Some((0, 2)), 0).is_some());
+        assert!(piece.insert_at_id(Some((1, 1)), 0).is_some());
+        assert_eq!(texts(&piece), ["ab", "", "c", "d", "", "ef"]);
+    }
+
+    #[test]
+    fn compact_skips_occupied() {
+        let original: AppendOnlyStr = "acd".into();
+        let client: Arc<RwLock<AppendOnlyStr>> = Arc::new(RwLock::new("bef".into()));
+        let piece_table = Table::from_iter([
+            TableElem::new(
+                Some((0, false)),
+                1,
+                client.read().unwrap().str_slice(0..1).unwrap(),
+            ),
+            {
+                let mut elem = TableElem::new(
+                    Some((0, false)),
+                    1,
+                    client.read().unwrap().str_slice(1..2).unwrap(),
+                );
+                elem.offset = 1;
+                elem
+            },
+            TableElem::new(
+                Some((0, true)),
+                1,
+                client.read().unwrap().str_slice(2..3).unwrap(),
+            ),
+        ]);
+        let mut piece = Piece {
+            piece_table,
+            buffers: Buffers {
+                original: (AutoIncrementing::new(), original),
+                clients: vec![(Arc::new(RwLock::new(AutoIncrementing::new())), client)],
+            },
+        };
+        piece.compact();
+        assert_eq!(texts(&piece), ["be", "f"]);
+    }
+
+    #[test]
+    fn debug_json() {
+        let piece = interleaved(true);
+        piece.buffers.clients[0]
+            .1
+            .write()
+            .unwrap()
+            .push_str("\"\n\0");
+        assert_eq!(
+            piece.to_debug_json(),
+            r#"{
+  "original": "acd",
+  "clients": [
+    "bef\"\n\u0000"
+  ],
+  "pieces": [
+    {"id": 0, "buffer": null, "occupied": false, "start": 0, "end": 1, "text": "a"},
+    {"id": 1, "buffer": 0, "occupied": false, "start": 0, "end": 1, "text": "b"},
+    {"id": 2, "buffer": null, "occupied": false, "start": 1, "end": 3, "text": "cd"},
+    {"id": 3, "buffer": 0, "occupied": true, "start": 1, "end": 3, "text": "ef"}
+  ]
+}"#
+        );
+    }
+
+    /// Types `text` into the piece returned by `insert_at`, like a client would
+    fn type_into(piece: &Piece, slice: &InnerTable<TableElem>, text: &str) {
+        let buffer = &piece.buffers.clients[0].1;
+        buffer.write().unwrap().push_str(text);
+        let start = slice.read().text.start();
+        let text = buffer.read().unwrap().str_slice(start..).unwrap();
+        slice.write().unwrap().set_text(text);
+    }
+
+    #[test]
+    fn append_without_newline() {
+        let mut piece = with_client("ab\ncd");
+        let (split, slice) = piece.insert_at((1, 2).into(), 0).unwrap();
+        assert_eq!(split, None);
+        type_into(&piece, &slice, "ef");
+        assert_eq!(piece.chars().collect::<String>(), "ab\ncdef");
+        assert_eq!(texts(&piece), ["ab\ncd", "ef"]);
+    }
+
+    #[test]
+    fn append_with_newline() {
+        let mut piece = with_client("ab\ncd\n");
+        let (split, slice) = piece.insert_at((1, 2).into(), 0).unwrap();
+        assert_eq!(split, Some((0, 5)));
+        type_into(&piece, &slice, "ef");
+        assert_eq!(piece.chars().collect::<String>(), "ab\ncdef\n");
+
+        let mut piece = with_client("ab\ncd\n");
+        let (split, slice) = piece.insert_at((2, 0).into(), 0).unwrap();
+        assert_eq!(split, None);
+        type_into(&piece, &slice, "ef");
+        assert_eq!(piece.chars().collect::<String>(), "ab\ncd\nef");
+    }
+
+    #[test]
+    fn insert_out_of_range() {
+        let mut piece = with_client("ab\ncd");
+        let (split, slice) = piece.insert_at((0, 10).into(), 0).unwrap();
+        assert_eq!(split, Some((0, 2)));
+        type_into(&piece, &slice, "x");
+        assert_eq!(piece.chars().collect::<String>(), "abx\ncd");
+
+        let mut piece = with_client("ab\ncd");
+        let (split, slice) = piece.insert_at((10, 10).into(), 0).unwrap();
+        assert_eq!(split, None);
+        type_into(&piece, &slice, "x");
+        assert_eq!(piece.chars().collect::<String>(), "ab\ncdx");
+    }
+
+    #[test]
+    fn insert_at_id() {
+        let mut piece = with_client("ab\ncd\nef");
+        let mut other = with_client("ab\ncd\nef");
+        for (pos, text) in [((1, 1), "x"), ((0, 0), "y"), ((2, 2), "z"), ((1, 2), "é")] {
+            let (split, slice) = piece.insert_at(pos.into(), 0).unwrap();
+            type_into(&piece, &slice, text);
+            slice.write().unwrap().buf = Some((0, false));
+            let slice = other.insert_at_id(split, 0).unwrap();
+            type_into(&other, &slice, text);
+            slice.write().unwrap().buf = Some((0, false));
+        }
+        assert_eq!(piece.chars().collect::<String>(), "yab\ncxéd\nefz");
+        assert_eq!(texts(&piece), texts(&other));
+        // Parts of a split piece share its id, but not their offset within it
+        let ids = |piece: &Piece| {
+            (piece.bufs())
+                .map(|x| (x.read().id, x.read().offset))
+                .collect::<Vec<_>>()
+        };
+        let mut unique = ids(&piece);
+        assert_eq!(unique, ids(&other));
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), ids(&piece).len());
+
+        assert!(other.insert_at_id(Some((1000, 0)), 0).is_none());
+        let multibyte = other
+            .bufs()
+            .find(|x| x.read().text.as_str() == "é")
+            .unwrap()
+            .read()
+            .id;
+        assert!(other.insert_at_id(Some((multibyte, 1)), 0).is_none());
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut piece = with_client("ab\ncd\nef");
+        piece.buffers.clients.push((
+            Arc::new(RwLock::new(AutoIncrementing::new())),
+            Arc::new(RwLock::new(AppendOnlyStr::new())),
+        ));
+        let type_as = |piece: &Piece, client: usize, slice: &InnerTable<TableElem>, text| {
+            let buffer = &piece.buffers.clients[client].1;
+            buffer.write().unwrap().push_str(text);
+            let start = slice.read().text.start();
+            let text = buffer.read().unwrap().str_slice(start..).unwrap();
+            slice.write().unwrap().set_text(text);
+        };
+        let (_, slice) = piece.insert_at((1, 1).into(), 0).unwrap();
+        type_as(&piece, 0, &slice, "xé\n");
+        slice.write().unwrap().buf = Some((0, false));
+        let (_, slice) = piece.insert_at((0, 1).into(), 1).unwrap();
+        type_as(&piece, 1, &slice, "🦀");
+        let (_, slice) = piece.insert_at((3, 2).into(), 0).unwrap();
+        type_as(&piece, 0, &slice, "yz");
+        // Client 1 is still in insert mode, so its piece stays occupied
+        slice.write().unwrap().buf = Some((0, false));
+        let (_, slice) = piece.insert_at((0, 0).into(), 0).unwrap();
+        assert!(slice.read().text.is_empty());
+
+        let data = (&piece).serialize();
+        let round_trip = block_on(Piece::deserialize(&mut io::Cursor::new(&data))).unwrap();
+
+        assert_eq!(
+            round_trip.lines().collect::<Vec<_>>(),
+            ["a🦀b", "cxé", "d", "efyz"]
+        );
+        assert_eq!(
+            round_trip.lines().collect::<Vec<_>>(),
+            piece.lines().collect::<Vec<_>>()
+        );
+        let layout = |piece: &Piece| {
+            piece
+                .bufs()
+                .map(|x| {
+                    let x = x.read();
+                    (x.buf, x.id, x.text.start(), x.text.end(), x.newlines())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(layout(&round_trip), layout(&piece));
+        let occupied = layout(&round_trip)
+            .into_iter()
+            .filter_map(|(buf, ..)| buf.filter(|&(_, occupied)| occupied))
+            .collect::<Vec<_>>();
+        assert_eq!(occupied, [(0, true), (1, true)]);
+        for (a, b) in round_trip
+            .buffers
+            .clients
+            .iter()
+            .zip(&piece.buffers.clients)
+        {
+            assert_eq!(a.0.read().unwrap().peek(), b.0.read().unwrap().peek());
+            assert_eq!(**a.1.read().unwrap(), **b.1.read().unwrap());
+        }
+        assert_eq!(round_trip.buffers.clients.len(), 2);
+        assert_eq!(
+            round_trip.buffers.original.1.as_ref(),
+            piece.buffers.original.1.as_ref()
+        );
+        assert_eq!((&round_trip).serialize(), data);
+    }
+
+    #[test]
+    fn deserialize_rejects_bad_slices() {
+        let piece = interleaved(false);
+        let data = (&piece).serialize();
+        let round_trip = block_on(Piece::deserialize(&mut &data[..])).unwrap();
+        assert_eq!(texts(&round_trip), texts(&piece));
+
+        // The end of the last piece points past the end of its buffer
+        let mut corrupt = data.clone();
+        let len = corrupt.len();
+        corrupt[len - 8..].copy_from_slice(&100u64.to_be_bytes());
+        let err = block_on(Piece::deserialize(&mut &corrupt[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The last piece refers to a client that doesn't exist
+        let mut corrupt = data;
+        corrupt[len - 40..len - 32].copy_from_slice(&5u64.to_be_bytes());
+        let err = block_on(Piece::deserialize(&mut &corrupt[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn deserialize_bounds_buffers() {
+        let piece = interleaved(false);
+        let data = (&piece).serialize();
+        // The buffers end with the first 0xff, as none of the counters are large enough to
+        // contain one
+        let buffers_len = data.iter().position(|&x| x == 0xff).unwrap() + 1;
+        let buffers = block_on(read_buffers(&mut &data[..], buffers_len as u64)).unwrap();
+        assert_eq!(buffers.clients.len(), piece.buffers.clients.len());
+
+        let err = block_on(read_buffers(&mut &data[..], buffers_len as u64 - 1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Something other than a separator right after a buffer
+        let separator = data.iter().position(|&x| x == 0xfe).unwrap();
+        let mut corrupt = data[..separator].to_vec();
+        corrupt.push(0x80);
+        let err = block_on(Piece::deserialize(&mut &corrupt[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn shrink_buffers() {
+        let mut piece = with_client("abc\ndef");
+        let (_, slice) = piece.insert_at((0, 1).into(), 0).unwrap();
+        type_into(&piece, &slice, "xyz");
+        slice.write().unwrap().buf = Some((0, false));
+        let (_, slice) = piece.insert_at((1, 3).into(), 0).unwrap();
+        type_into(&piece, &slice, "uvw");
+        piece.delete_range((0, 2).into(), (0, 4).into());
+        piece.delete_range((1, 3).into(), (1, 5).into());
+        let before = texts(&piece);
+        assert_eq!(piece.chars().collect::<String>(), "axbc\ndefw");
+
+        piece.shrink_buffers();
+        assert_eq!(texts(&piece), before);
+        assert_eq!(piece.buffers.original.1.len(), "abc\ndef".len());
+        assert_eq!(&**piece.buffers.clients[0].1.read().unwrap(), "xw");
+        assert_eq!(slice.read().text.end(), 2);
+
+        // The piece being edited still points at the end of the buffer
+        type_into(&piece, &slice, "!");
+        assert_eq!(piece.chars().collect::<String>(), "axbc\ndefw!");
+    }
+
+    #[test]
+    fn bufs_from_row() {
+        // The text from the start of every row, read through `bufs_from_row`
+        let rows_of = |piece: &Piece| {
+            (0..=piece.line_count())
+                .map(|row| {
+                    let (offset, bufs) = piece.bufs_from_row(row)?;
+                    let mut ret = String::new();
+                    for (i, buf) in bufs.enumerate() {
+                        let text = &buf.read().text;
+                        ret.push_str(if i == 0 { &text[offset..] } else { text });
+                    }
+                    Some(ret)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut piece = with_client("ab\ncd\nef");
+        assert_eq!(
+            rows_of(&piece),
+            [
+                Some("ab\ncd\nef".into()),
+                Some("cd\nef".into()),
+                Some("ef".into()),
+                None
+            ]
+        );
+
+        let (_, slice) = piece.insert_at((1, 1).into(), 0).unwrap();
+        type_into(&piece, &slice, "x\ny\n");
+        assert_eq!(piece.chars().collect::<String>(), "ab\ncx\ny\nd\nef");
+        assert_eq!(rows_of(&piece)[2], Some("y\nd\nef".into()));
+        assert_eq!(rows_of(&piece)[3], Some("d\nef".into()));
+
+        // The piece ending with the newline in front of the row comes first
+        let (offset, bufs) = piece.bufs_from_row(3).unwrap();
+        assert_eq!(offset, "x\ny\n".len());
+        assert_eq!(bufs.count(), 2);
+
+        slice.write().unwrap().buf = Some((0, false));
+        piece.delete_range((0, 1).into(), (2, 1).into());
+        assert_eq!(piece.chars().collect::<String>(), "a\nd\nef");
+        assert_eq!(
+            rows_of(&piece),
+            [
+                Some("a\nd\nef".into()),
+                Some("d\nef".into()),
+                Some("ef".into()),
+                None
+            ]
+        );
+    }
 }
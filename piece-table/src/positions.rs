@@ -0,0 +1,213 @@
+//! Finds positions within the text of a `Piece`. The tree of the table knows how many bytes and
+//! newlines every subtree has, which allows searching for offsets and rows rather than walking
+//! through the entire text
+use utils::other::CursorPos;
+
+use crate::{
+    table::InnerTable,
+    tree::{Summaries, Summary},
+    Piece, TableElem,
+};
+
+type Sums<'a> = Summaries<'a, InnerTable<TableElem>>;
+
+/// Finds the piece that contains the byte at `offset`
+/// # Returns
+/// - The position of the piece within the table. This is the amount of pieces if the offset is
+///   at or past the end of the text
+/// - The amount of bytes in front of that piece
+fn piece_at(sums: &Sums<'_>, offset: usize) -> (usize, usize) {
+    let (piece, before) = sums.search(|x| x.bytes, offset);
+    (piece, before.bytes)
+}
+
+/// Finds the start of a row
+/// # Returns
+/// - The position of the piece the row starts in
+/// - The byte offset of the start of the row within that piece
+/// - The byte offset of the start of the row within the text
+///
+/// None if the text doesn't have that many rows
+fn row_start(sums: &Sums<'_>, row: usize) -> Option<(usize, usize, usize)> {
+    if row == 0 {
+        return Some((0, 0, 0));
+    }
+    let (piece, Summary { bytes, newlines }) = sums.search(|x| x.newlines, row - 1);
+    let (newline, _) = sums
+        .tree()
+        .get(piece)?
+        .read()
+        .text
+        .match_indices('\n')
+        .nth(row - newlines - 1)
+        .expect("The piece has enough newlines");
+    let skip = newline + '\n'.len_utf8();
+    Some((piece, skip, bytes + skip))
+}
+
+/// Converts a position in the text to a byte offset. See `Piece::offset_of`
+fn offset_of(sums: &Sums<'_>, pos: CursorPos) -> usize {
+    let Some((piece, skip, start)) = row_start(sums, pos.row) else {
+        return sums.total().bytes;
+    };
+    start
+        + chars_from(sums, piece, skip)
+            .take_while(|&c| c != '\n')
+            .take(pos.col)
+            .map(char::len_utf8)
+            .sum::<usize>()
+}
+
+/// Iterates over the text starting at the byte `skip` of the piece at position `piece`
+fn chars_from<'a>(
+    sums: &Sums<'a>,
+    piece: usize,
+    skip: usize,
+) -> impl Iterator<Item = char> + use<'a> {
+    sums.tree()
+        .iter_from(piece)
+        .enumerate()
+        .flat_map(move |(i, x)| {
+            let text = x.read().text.clone();
+            if i == 0 {
+                text.subslice(skip..)
+                    .expect("The row starts on a char boundary")
+            } else {
+                text
+            }
+            .owned_chars()
+        })
+}
+
+impl Piece {
+    /// Runs `f` with the summaries of the table, see `Table::summaries`
+    /// # Panics
+    /// The piece table is poisoned
+    fn with_sums<R>(&self, f: impl FnOnce(&Sums<'_>) -> R) -> R {
+        let reader = self
+            .read_full()
+            .expect("Could not get a reading lock on the table");
+        let list = reader.read();
+        let sums = self.piece_table.summaries(&list);
+        f(&sums)
+    }
+
+    /// Finds the offsets of both `start` and `end` in `sums` like `offset_of`, together with the
+    /// piece containing `start`, see `piece_at`. This allows finding them while the list is
+    /// already locked for writing
+    pub(crate) fn range_of(
+        sums: &Sums<'_>,
+        start: CursorPos,
+        end: CursorPos,
+    ) -> (usize, usize, (usize, usize)) {
+        let start = offset_of(sums, start);
+        (start, offset_of(sums, end), piece_at(sums, start))
+    }
+
+    /// Returns the char starting at the byte `index` of the text.
+    /// None if `index` is out of bounds or not on a char boundary
+    /// # Panics
+    /// The piece table is poisoned
+    #[must_use]
+    pub fn char_at(&self, index: usize) -> Option<char> {
+        self.with_sums(|x| {
+            let (piece, start) = piece_at(x, index);
+            x.tree()
+                .get(piece)?
+                .read()
+                .text
+                .get(index - start..)?
+                .chars()
+                .next()
+        })
+    }
+
+    /// Converts a position in the text to a byte offset from the start of the text.
+    /// Positions past the end of a line or the text are clamped
+    /// # Panics
+    /// The piece table is poisoned
+    #[must_use]
+    pub fn offset_of(&self, pos: CursorPos) -> usize {
+        self.with_sums(|x| offset_of(x, pos))
+    }
+
+    /// Moves `pos` onto the text. The column is clamped to the end of its row, which is where
+    /// text gets appended to it, and rows past the end of the text are clamped to its end. This
+    /// is the same position that `offset_of` clamps to
+    /// # Panics
+    /// The piece table is poisoned
+    #[must_use]
+    pub fn clamp(&self, pos: CursorPos) -> CursorPos {
+        self.with_sums(|x| {
+            let last_row = x.total().newlines;
+            let (row, col) = if pos.row > last_row {
+                (last_row, usize::MAX)
+            } else {
+                (pos.row, pos.col)
+            };
+            let (piece, skip, _) = row_start(x, row).expect("The row is within the text");
+            let col = chars_from(x, piece, skip)
+                .take_while(|&c| c != '\n')
+                .take(col)
+                .count();
+            CursorPos { row, col }
+        })
+    }
+
+    /// Finds the piece containing the byte at `offset`
+    /// # Returns
+    /// - The position of the piece within the table. This is the amount of pieces if the offset
+    ///   is at or past the end of the text
+    /// - The amount of bytes in front of that piece
+    pub(crate) fn piece_at(&self, offset: usize) -> (usize, usize) {
+        self.with_sums(|x| piece_at(x, offset))
+    }
+
+    /// Finds the pieces from where the row `row` starts, so that it can be read without going
+    /// through the text in front of it
+    /// # Returns
+    /// - The byte offset of the start of the row within the first piece
+    /// - The pieces starting at the one the row starts in. The piece with the newline in front of
+    ///   the row comes first, even if the row starts right after it, so that pieces which end at
+    ///   the start of the row are included. They are taken out of the table one at a time, see
+    ///   `Table::iter_from`
+    ///
+    /// None if the text doesn't have that many rows
+    /// # Panics
+    /// The piece table is poisoned
+    pub fn bufs_from_row(
+        &self,
+        row: usize,
+    ) -> Option<(usize, impl Iterator<Item = InnerTable<TableElem>>)> {
+        let (piece, skip, _) = self.with_sums(|x| row_start(x, row))?;
+        Some((skip, self.piece_table.iter_from(piece)))
+    }
+
+    /// The amount of rows in the text. This is one more than the amount of newlines, so a
+    /// trailing newline is followed by an empty row
+    /// # Panics
+    /// The piece table is poisoned
+    #[must_use]
+    pub fn line_count(&self) -> usize {
+        self.with_sums(|x| x.total().newlines + 1)
+    }
+
+    /// Returns a single line of the text. This is the same as `self.lines().nth(row)`, but
+    /// skips over the pieces in front of the line
+    /// # Panics
+    /// The piece table is poisoned
+    #[must_use]
+    pub fn line(&self, row: usize) -> Option<String> {
+        self.with_sums(|x| {
+            let (piece, skip, _) = row_start(x, row)?;
+            let mut ret = String::new();
+            for c in chars_from(x, piece, skip) {
+                if c == '\n' {
+                    return Some(ret);
+                }
+                ret.push(c);
+            }
+            (!ret.is_empty()).then_some(ret)
+        })
+    }
+}
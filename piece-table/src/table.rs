@@ -1,18 +1,20 @@
 //! Provides an implementation for `Table`.
 //! The `Table` is responsible for regulating the access to the values stored
 use std::{
-    collections::LinkedList,
+    collections::HashSet,
     fmt::Debug,
     ops::{Deref, DerefMut},
+    ptr,
     sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
+use crate::tree::{Element, Key, Summaries, Summary, Tree};
+
 /// A wrapper struct around a type T
 /// It allows for a list of T's to be read and mutated concurrently and has methods for locking
 /// down the entire method for reading and reordering purposes
 pub struct Table<T> {
-    #[allow(clippy::linkedlist)]
-    inner: Arc<RwLock<LinkedList<InnerTable<T>>>>,
+    inner: Arc<RwLock<Tree<InnerTable<T>>>>,
     state: Arc<RwLock<TableState>>,
 }
 
@@ -57,7 +59,7 @@ impl<T> Table<T> {
             state,
         }
     }
-    /// Returns a reading lock on the entire linked list
+    /// Returns a reading lock on the entire list
     /// This means that
     /// - Elements of the list cannot be modified
     /// - The order of listelements cannot be modified
@@ -72,10 +74,10 @@ impl<T> Table<T> {
         })
     }
 
-    /// Returns a writing lock on the order of the linked list
+    /// Returns a writing lock on the order of the list
     /// This menas that
     /// - Elemens of the list *can* still be modified
-    /// - No reading lock can be made on the entire linked list
+    /// - No reading lock can be made on the entire list
     /// # Errors
     /// - There is already a writing lock on the list
     /// - There is already a reading lock on the list
@@ -87,16 +89,62 @@ impl<T> Table<T> {
         })
     }
 
+    /// Iterates over the elements from position `index` onwards. Rather than copying them up
+    /// front, the list is only locked while each element is taken out of it, so changes to the
+    /// order of the list in the meantime shift which elements come next
+    /// # Panics
+    /// There is already a mutable lock on the full list when an element is taken out
+    pub fn iter_from(&self, index: usize) -> impl Iterator<Item = InnerTable<T>> {
+        let table = Self {
+            inner: Arc::clone(&self.inner),
+            state: Arc::clone(&self.state),
+        };
+        (index..).map_while(move |i| {
+            table
+                .read_full()
+                .expect("Could not get a reading lock on the table")
+                .read()
+                .get(i)
+                .cloned()
+        })
+    }
+
     /// Creates a clone of the state of this `Table`
     #[must_use]
     pub fn state(&self) -> Arc<RwLock<TableState>> {
         Arc::clone(&self.state)
     }
+
+    /// Returns the current generation of the table. See `TableState::generation`
+    /// # Panics
+    /// The state is poisoned
+    #[must_use]
+    pub fn generation(&self) -> usize {
+        self.state
+            .read()
+            .expect("The entire piece table is poisoned")
+            .generation()
+    }
+}
+
+impl<T: Measure> Table<T> {
+    /// Gives access to the summaries of `list`, which has to be the list of this table. The
+    /// elements that were changed since the summaries were last used get measured again first
+    /// # Panics
+    /// The state is poisoned
+    pub fn summaries<'a>(&self, list: &'a Tree<InnerTable<T>>) -> Summaries<'a, InnerTable<T>> {
+        list.summaries(|| {
+            self.state
+                .write()
+                .expect("The entire piece table is poisoned")
+                .take_changed()
+        })
+    }
 }
 
-impl<T> FromIterator<T> for Table<T> {
+impl<T: Measure> FromIterator<T> for Table<T> {
     fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
-        let state = Arc::new(RwLock::new(TableState::Unshared));
+        let state = Arc::new(RwLock::new(TableState::new()));
         Self {
             inner: Arc::new(RwLock::new(
                 iter.into_iter()
@@ -108,9 +156,79 @@ impl<T> FromIterator<T> for Table<T> {
     }
 }
 
+/// The state shared between a `Table` and all of its elements
+#[derive(Debug)]
+pub struct TableState {
+    /// The locks that are currently held
+    locks: LockState,
+    /// Incremented every time a mutable lock is released
+    generation: usize,
+    /// The elements whose mutable locks were released since the summaries of the list were last
+    /// used, by `InnerTable::id`
+    changed: HashSet<usize>,
+}
+
+impl TableState {
+    fn new() -> Self {
+        Self {
+            locks: LockState::Unshared,
+            generation: 0,
+            changed: HashSet::new(),
+        }
+    }
+
+    /// Returns the amount of times a mutable lock on either the full list or one of its elements
+    /// has been released. Anything computed from the contents of the table is still valid as long
+    /// as this hasn't changed
+    #[must_use]
+    pub const fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub(crate) fn lock_single(&mut self) {
+        self.locks.lock_single();
+    }
+
+    pub(crate) fn drop_single(&mut self) {
+        self.locks.drop_single();
+    }
+
+    pub(crate) fn lock_single_mut(&mut self) -> Result<(), LockError> {
+        self.locks.lock_single_mut()
+    }
+
+    pub(crate) fn drop_single_mut(&mut self, id: usize) {
+        self.locks.drop_single_mut();
+        self.generation += 1;
+        self.changed.insert(id);
+    }
+
+    /// Returns the elements that were changed since the last time this was called
+    fn take_changed(&mut self) -> HashSet<usize> {
+        std::mem::take(&mut self.changed)
+    }
+
+    pub(crate) fn lock_full(&mut self) -> Result<(), LockError> {
+        self.locks.lock_full()
+    }
+
+    pub(crate) fn drop_full(&mut self) {
+        self.locks.drop_full();
+    }
+
+    pub(crate) fn lock_full_mut(&mut self) -> Result<(), LockError> {
+        self.locks.lock_full_mut()
+    }
+
+    pub(crate) fn drop_full_mut(&mut self) {
+        self.locks.drop_full_mut();
+        self.generation += 1;
+    }
+}
+
 /// A state machine to control what kinds of locks can be made at what time
 #[derive(Debug)]
-pub enum TableState {
+pub enum LockState {
     /// There are no referenses to the list
     Unshared,
     /// The entire list only has immutable borrows. (full list borrows, single item immutable borrows)
@@ -121,8 +239,8 @@ pub enum TableState {
     Exclusive((usize, usize)),
 }
 
-impl TableState {
-    pub(crate) fn lock_single(&mut self) {
+impl LockState {
+    fn lock_single(&mut self) {
         match self {
             Self::Shared((0, _)) => unreachable!(),
 
@@ -134,7 +252,7 @@ impl TableState {
         }
     }
 
-    pub(crate) fn drop_single(&mut self) {
+    fn drop_single(&mut self) {
         match self {
             Self::Unshared
             | Self::Shared((0, _) | (_, 0))
@@ -147,7 +265,7 @@ impl TableState {
         }
     }
 
-    pub(crate) fn lock_single_mut(&mut self) -> Result<(), LockError> {
+    fn lock_single_mut(&mut self) -> Result<(), LockError> {
         match self {
             Self::Shared((0, _)) => unreachable!(),
 
@@ -158,7 +276,7 @@ impl TableState {
         Ok(())
     }
 
-    pub(crate) fn drop_single_mut(&mut self) {
+    fn drop_single_mut(&mut self) {
         match self {
             Self::SharedMuts((_, 0))
             | Self::Exclusive((_, 0))
@@ -170,7 +288,7 @@ impl TableState {
         };
     }
 
-    pub(crate) fn lock_full(&mut self) -> Result<(), LockError> {
+    fn lock_full(&mut self) -> Result<(), LockError> {
         match self {
             Self::Shared((0, _)) => unreachable!(),
 
@@ -183,7 +301,7 @@ impl TableState {
         Ok(())
     }
 
-    pub(crate) fn drop_full(&mut self) {
+    fn drop_full(&mut self) {
         match self {
             Self::Shared((0, _)) | Self::Unshared | Self::Exclusive(_) | Self::SharedMuts(_) => {
                 unreachable!()
@@ -195,7 +313,7 @@ impl TableState {
         };
     }
 
-    pub(crate) fn lock_full_mut(&mut self) -> Result<(), LockError> {
+    fn lock_full_mut(&mut self) -> Result<(), LockError> {
         match self {
             Self::Unshared => *self = Self::Exclusive((0, 0)),
             Self::SharedMuts((immuts, muts)) => *self = Self::Exclusive((*immuts, *muts)),
@@ -205,7 +323,7 @@ impl TableState {
         Ok(())
     }
 
-    pub(crate) fn drop_full_mut(&mut self) {
+    fn drop_full_mut(&mut self) {
         match self {
             Self::Unshared | Self::SharedMuts(_) | Self::Shared(_) => unreachable!(),
             Self::Exclusive((immuts, muts)) => *self = Self::SharedMuts((*immuts, *muts)),
@@ -219,8 +337,7 @@ impl TableState {
 /// - Elements within the list can still be mutated
 /// - Elements within the list can still be read
 pub struct TableWriter<T> {
-    #[allow(clippy::linkedlist)]
-    val: Arc<RwLock<LinkedList<InnerTable<T>>>>,
+    val: Arc<RwLock<Tree<InnerTable<T>>>>,
     state: Arc<RwLock<TableState>>,
 }
 
@@ -230,7 +347,7 @@ impl<T> TableWriter<T> {
     /// - You can't lock the entire list for reading/writing
     /// # Panics
     /// - The `RwLock` is poisoned
-    pub fn write(&self) -> RwLockWriteGuard<'_, LinkedList<InnerTable<T>>> {
+    pub fn write(&self) -> RwLockWriteGuard<'_, Tree<InnerTable<T>>> {
         self.val
             .write()
             .expect("The entire piece table is poisoned")
@@ -252,18 +369,16 @@ impl<T> Drop for TableWriter<T> {
 /// - The order of the elements cannot be mutated
 #[derive(Debug)]
 pub struct TableReader<T> {
-    #[allow(clippy::linkedlist)]
-    val: Arc<RwLock<LinkedList<InnerTable<T>>>>,
+    val: Arc<RwLock<Tree<InnerTable<T>>>>,
     state: Arc<RwLock<TableState>>,
 }
 
 impl<T> TableReader<T> {
-    #[allow(clippy::linkedlist)]
     /// Returns a reading lock on the List.
     /// Read the docs for `TableReader` for more
     /// # Panics
     /// the lock around the list has been poisoned
-    pub fn read(&self) -> RwLockReadGuard<'_, LinkedList<InnerTable<T>>> {
+    pub fn read(&self) -> RwLockReadGuard<'_, Tree<InnerTable<T>>> {
         self.val.read().expect("The entire piece table is poisoned")
     }
 }
@@ -324,11 +439,12 @@ impl<T> TableLocker<T> {
         Ok(TableLockWriter {
             value: self.value.write()?,
             state: Arc::clone(&self.state),
+            id: ptr::from_ref(self).addr(),
         })
     }
 }
 
-/// Represents a reading lock on an item within the linked list
+/// Represents a reading lock on an item within the list
 /// This means that
 /// - This item cannot be mutated by anything else
 pub struct TableLockReader<'a, T> {
@@ -361,6 +477,8 @@ impl<T> Drop for TableLockReader<'_, T> {
 pub struct TableLockWriter<'a, T> {
     value: RwLockWriteGuard<'a, T>,
     state: Arc<RwLock<TableState>>,
+    /// The `InnerTable::id` of the element
+    id: usize,
 }
 
 impl<T> Deref for TableLockWriter<'_, T> {
@@ -382,11 +500,11 @@ impl<T> Drop for TableLockWriter<'_, T> {
         self.state
             .write()
             .expect("The entire piece table is poisoned")
-            .drop_single_mut();
+            .drop_single_mut(self.id);
     }
 }
 
-/// The Inner values of the linked list. These are effectively wrappers around &strs.
+/// The Inner values of the list. These are effectively wrappers around &strs.
 pub struct InnerTable<T> {
     inner: Arc<TableLocker<T>>,
     /// The client whichs buffer is being referred to a `None` value signifies that it is from the
@@ -420,8 +538,8 @@ impl<T> InnerTable<T> {
     /// creates a builder for an innertable
     pub fn builder() -> InnerTableBuilder<T> {
         InnerTableBuilder {
-            inner: LinkedList::new(),
-            state: Arc::new(RwLock::new(TableState::Unshared)),
+            inner: Tree::new(),
+            state: Arc::new(RwLock::new(TableState::new())),
         }
     }
     #[must_use]
@@ -438,6 +556,12 @@ impl<T> InnerTable<T> {
         self.inner.write()
     }
 
+    /// Identifies the element. Clones of an `InnerTable` share the same id, and no two elements
+    /// that exist at the same time do
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.inner).addr()
+    }
+
     /// Creates a new `InnerTable`. This can be used for insertion after having used `read_full`
     #[must_use]
     pub fn new(value: T, state: Arc<RwLock<TableState>>) -> Self {
@@ -448,11 +572,26 @@ impl<T> InnerTable<T> {
     }
 }
 
+/// Values that can be stored in a `Table`, which keeps track of their summaries
+pub trait Measure {
+    /// Returns the key of the value together with its summary, see `Element::measure`
+    fn measure(&self) -> (Key, Summary);
+}
+
+impl<T: Measure> Element for InnerTable<T> {
+    fn id(&self) -> usize {
+        self.id()
+    }
+
+    fn measure(&self) -> (Key, Summary) {
+        self.read().measure()
+    }
+}
+
 /// A builder for a `Table`
 pub struct InnerTableBuilder<T> {
     /// The inner table being modified
-    #[allow(clippy::linkedlist)]
-    inner: LinkedList<InnerTable<T>>,
+    inner: Tree<InnerTable<T>>,
     /// The shared state
     state: Arc<RwLock<TableState>>,
 }
@@ -460,12 +599,13 @@ pub struct InnerTableBuilder<T> {
 impl<T> InnerTableBuilder<T> {
     /// returns its values so that it can be converted to a `Table`
     #[must_use]
-    #[allow(clippy::linkedlist)]
-    pub(crate) fn build(self) -> (LinkedList<InnerTable<T>>, Arc<RwLock<TableState>>) {
+    pub(crate) fn build(self) -> (Tree<InnerTable<T>>, Arc<RwLock<TableState>>) {
         (self.inner, self.state)
     }
+}
 
-    /// Appends a value to the linkedlist
+impl<T: Measure> InnerTableBuilder<T> {
+    /// Appends a value to the list
     pub fn push(&mut self, x: T) {
         self.inner
             .push_back(InnerTable::new(x, Arc::clone(&self.state)));
@@ -0,0 +1,865 @@
+//! Provides `Tree`, a list that is stored as a balanced binary tree. Every node knows the size of
+//! its subtree, so elements can be found, inserted and removed by their position in logarithmic
+//! time rather than by walking the list from the front.
+//! Every node also knows the sum of the `Summary`s of its subtree, so elements can be found by
+//! for example a byte offset in the same time, and the tree keeps track of where every element
+//! is, both by its id and by its key. Elements can change without the tree noticing, so it has to
+//! be told which ones did before the summaries are used, see `Tree::summaries`.
+//! The tree is balanced as a treap, using priorities from a pseudo random generator with a fixed
+//! seed, so the shape of a tree only depends on the operations done on it
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Debug},
+    iter,
+    ops::Add,
+    sync::{Mutex, MutexGuard},
+    vec,
+};
+
+/// What is summed up over the elements of a `Tree`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The length in bytes
+    pub bytes: usize,
+    /// The amount of newlines
+    pub newlines: usize,
+}
+
+impl Add for Summary {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            bytes: self.bytes + rhs.bytes,
+            newlines: self.newlines + rhs.newlines,
+        }
+    }
+}
+
+/// What elements can be looked up by, see `Summaries::up_to`. Pieces use their id and offset
+pub type Key = (usize, usize);
+
+/// An element that can be put into a `Tree`
+pub trait Element {
+    /// Identifies the element. No two elements within a tree can have the same id
+    fn id(&self) -> usize;
+
+    /// Returns the key of the element together with its summary. Elements can share a key
+    fn measure(&self) -> (Key, Summary);
+}
+
+#[derive(Clone)]
+struct Node<T> {
+    value: T,
+    priority: u64,
+    /// The amount of elements in the subtree rooted at this node
+    size: usize,
+    parent: Option<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+/// What the tree knows about the element in a slot, as of the last time it was measured
+#[derive(Debug, Clone, Copy, Default)]
+struct Measured {
+    key: Key,
+    summary: Summary,
+    /// The sum of the summaries of the subtree rooted at the node
+    total: Summary,
+}
+
+/// Everything that depends on measuring the elements. This is kept apart from the nodes, so that
+/// it can be brought up to date while the tree is only borrowed immutably
+#[derive(Debug, Clone, Default)]
+struct Measures {
+    /// By slot, like the nodes
+    slots: Vec<Measured>,
+    /// The slot of every element, by its key and then its id
+    keys: BTreeMap<(Key, usize), usize>,
+}
+
+impl Measures {
+    fn total(&self, link: Option<usize>) -> Summary {
+        link.map_or_else(Summary::default, |x| self.slots[x].total)
+    }
+
+    /// Recalculates the total of `slot` after its children or its summary have changed
+    fn sum(&mut self, slot: usize, left: Option<usize>, right: Option<usize>) {
+        self.slots[slot].total = self.total(left) + self.slots[slot].summary + self.total(right);
+    }
+}
+
+/// A list that can be indexed, and inserted into and removed from at any position, in
+/// logarithmic time
+pub struct Tree<T> {
+    /// The nodes are referred to by their slot in here. The slots of removed nodes are None until
+    /// they get reused
+    nodes: Vec<Option<Node<T>>>,
+    /// The slots that are None
+    free: Vec<usize>,
+    root: Option<usize>,
+    /// The slot of every element, by its id
+    slots: HashMap<usize, usize>,
+    measures: Mutex<Measures>,
+    /// The state of the generator for the priorities of new nodes
+    seed: u64,
+}
+
+impl<T> Tree<T> {
+    /// Creates an empty tree
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: None,
+            slots: HashMap::new(),
+            measures: Mutex::new(Measures::default()),
+            seed: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+
+    /// A xorshift generator. It doesn't have to be good, just spread out
+    fn next_priority(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    fn node(&self, slot: usize) -> &Node<T> {
+        self.nodes[slot]
+            .as_ref()
+            .expect("Links only point at nodes")
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node<T> {
+        self.nodes[slot]
+            .as_mut()
+            .expect("Links only point at nodes")
+    }
+
+    fn size(&self, link: Option<usize>) -> usize {
+        link.map_or(0, |x| self.node(x).size)
+    }
+
+    fn measures(&mut self) -> &mut Measures {
+        self.measures
+            .get_mut()
+            .expect("The measures of the tree got poisoned")
+    }
+
+    /// Recalculates the size and total of `slot` after its children have changed
+    fn update(&mut self, slot: usize) {
+        let Node { left, right, .. } = *self.node(slot);
+        for child in [left, right].into_iter().flatten() {
+            self.node_mut(child).parent = Some(slot);
+        }
+        self.node_mut(slot).size = self.size(left) + 1 + self.size(right);
+        self.measures().sum(slot, left, right);
+    }
+
+    fn set_root(&mut self, root: Option<usize>) {
+        self.root = root;
+        if let Some(root) = root {
+            self.node_mut(root).parent = None;
+        }
+    }
+
+    /// Joins two trees, where every element of `left` comes before every element of `right`
+    fn merge(&mut self, left: Option<usize>, right: Option<usize>) -> Option<usize> {
+        match (left, right) {
+            (None, x) | (x, None) => x,
+            (Some(left), Some(right)) => {
+                if self.node(left).priority >= self.node(right).priority {
+                    let merged = self.merge(self.node(left).right, Some(right));
+                    self.node_mut(left).right = merged;
+                    self.update(left);
+                    Some(left)
+                } else {
+                    let merged = self.merge(Some(left), self.node(right).left);
+                    self.node_mut(right).left = merged;
+                    self.update(right);
+                    Some(right)
+                }
+            }
+        }
+    }
+
+    /// Splits a tree into its first `at` elements and the rest
+    fn split(&mut self, link: Option<usize>, at: usize) -> (Option<usize>, Option<usize>) {
+        let Some(slot) = link else {
+            return (None, None);
+        };
+        let Node { left, right, .. } = *self.node(slot);
+        let left_size = self.size(left);
+        if at <= left_size {
+            let (left, right) = self.split(left, at);
+            self.node_mut(slot).left = right;
+            self.update(slot);
+            (left, Some(slot))
+        } else {
+            let (left, right) = self.split(right, at - left_size - 1);
+            self.node_mut(slot).right = left;
+            self.update(slot);
+            (Some(slot), right)
+        }
+    }
+
+    /// The slot of the element at `index`
+    fn slot_at(&self, mut index: usize) -> Option<usize> {
+        let mut link = self.root;
+        loop {
+            let slot = link?;
+            let node = self.node(slot);
+            let left_size = self.size(node.left);
+            match index.cmp(&left_size) {
+                Ordering::Less => link = node.left,
+                Ordering::Equal => return Some(slot),
+                Ordering::Greater => {
+                    index -= left_size + 1;
+                    link = node.right;
+                }
+            }
+        }
+    }
+
+    /// The position of the element in `slot`, found by walking up to the root
+    fn position_of_slot(&self, mut slot: usize) -> usize {
+        let mut ret = self.size(self.node(slot).left);
+        while let Some(parent) = self.node(slot).parent {
+            let parent_node = self.node(parent);
+            if parent_node.right == Some(slot) {
+                ret += self.size(parent_node.left) + 1;
+            }
+            slot = parent;
+        }
+        ret
+    }
+
+    /// The amount of elements in the tree
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.size(self.root)
+    }
+
+    /// Whether the tree has no elements
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Returns the element at `index`, or None if it's out of bounds
+    #[must_use]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.slot_at(index).map(|x| &self.node(x).value)
+    }
+
+    /// The first element
+    #[must_use]
+    pub fn front(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    /// The last element
+    #[must_use]
+    pub fn back(&self) -> Option<&T> {
+        self.get(self.len().checked_sub(1)?)
+    }
+
+    /// Iterates over the elements in order
+    #[must_use]
+    pub fn iter(&self) -> Iter<'_, T> {
+        let mut ret = Iter {
+            tree: self,
+            stack: Vec::new(),
+        };
+        ret.push_left(self.root);
+        ret
+    }
+
+    /// Iterates over the elements in order, starting at the element at `index`
+    #[must_use]
+    pub fn iter_from(&self, mut index: usize) -> Iter<'_, T> {
+        let mut ret = Iter {
+            tree: self,
+            stack: Vec::new(),
+        };
+        let mut link = self.root;
+        // Only the nodes at or after `index` are pushed
+        while let Some(slot) = link {
+            let node = self.node(slot);
+            let left_size = self.size(node.left);
+            if index <= left_size {
+                ret.stack.push(slot);
+                link = node.left;
+            } else {
+                index -= left_size + 1;
+                link = node.right;
+            }
+        }
+        ret
+    }
+
+    /// Creates a cursor at the first element, or at the ghost element if the tree is empty
+    pub fn cursor_front_mut(&mut self) -> CursorMut<'_, T> {
+        self.cursor_at_mut(0)
+    }
+
+    /// Creates a cursor at the element at `index`. The cursor is at the ghost element if `index`
+    /// is out of bounds
+    pub fn cursor_at_mut(&mut self, index: usize) -> CursorMut<'_, T> {
+        let index = index.min(self.len());
+        CursorMut { tree: self, index }
+    }
+}
+
+impl<T: Element> Tree<T> {
+    /// Puts `value` into a free slot as a node without children
+    fn alloc(&mut self, value: T) -> usize {
+        let priority = self.next_priority();
+        let id = value.id();
+        let (key, summary) = value.measure();
+        let node = Node {
+            value,
+            priority,
+            size: 1,
+            parent: None,
+            left: None,
+            right: None,
+        };
+        let slot = if let Some(slot) = self.free.pop() {
+            self.nodes[slot] = Some(node);
+            slot
+        } else {
+            self.nodes.push(Some(node));
+            self.nodes.len() - 1
+        };
+        let previous = self.slots.insert(id, slot);
+        assert!(previous.is_none(), "The element is already in the tree");
+        let measures = self.measures();
+        measures
+            .slots
+            .resize_with(measures.slots.len().max(slot + 1), Measured::default);
+        measures.slots[slot] = Measured {
+            key,
+            summary,
+            total: summary,
+        };
+        measures.keys.insert((key, id), slot);
+        slot
+    }
+
+    /// Takes the value out of `slot`, which has to be detached from the tree
+    fn dealloc(&mut self, slot: usize) -> T {
+        let value = self.nodes[slot]
+            .take()
+            .expect("Links only point at nodes")
+            .value;
+        self.free.push(slot);
+        let id = value.id();
+        self.slots.remove(&id);
+        let measures = self.measures();
+        let key = measures.slots[slot].key;
+        measures.keys.remove(&(key, id));
+        value
+    }
+
+    /// Inserts `value` so that it ends up at `index`, moving the elements after it back
+    /// # Panics
+    /// - `index` is larger than the length of the tree
+    /// - An element with the same id is already in the tree
+    pub fn insert(&mut self, index: usize, value: T) {
+        assert!(index <= self.len(), "Inserted past the end of the tree");
+        let slot = self.alloc(value);
+        let (left, right) = self.split(self.root, index);
+        let merged = self.merge(left, Some(slot));
+        let root = self.merge(merged, right);
+        self.set_root(root);
+    }
+
+    /// Removes the element at `index`, moving the elements after it forward
+    /// # Returns
+    /// The removed element, or None if `index` was out of bounds
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        let (left, right) = self.split(self.root, index);
+        let (removed, right) = self.split(right, 1);
+        let root = self.merge(left, right);
+        self.set_root(root);
+        removed.map(|x| self.dealloc(x))
+    }
+
+    /// Appends `value` to the end of the tree
+    /// # Panics
+    /// An element with the same id is already in the tree
+    pub fn push_back(&mut self, value: T) {
+        self.insert(self.len(), value);
+    }
+
+    /// The position of `value` in the tree, found by its id rather than by comparing it to every
+    /// element
+    #[must_use]
+    pub fn position(&self, value: &T) -> Option<usize> {
+        let slot = *self.slots.get(&value.id())?;
+        Some(self.position_of_slot(slot))
+    }
+
+    /// Gives access to the summaries of the elements. The elements whose ids are returned by
+    /// `changed` are measured again first, as the tree can't tell on its own when an element
+    /// changes. `changed` is only called once the summaries are locked, so no one can see them in
+    /// between
+    /// # Panics
+    /// The summaries got poisoned
+    pub fn summaries<I: IntoIterator<Item = usize>>(
+        &self,
+        changed: impl FnOnce() -> I,
+    ) -> Summaries<'_, T> {
+        let mut guard = self
+            .measures
+            .lock()
+            .expect("The measures of the tree got poisoned");
+        let measures = &mut *guard;
+        for id in changed() {
+            // Elements that aren't in the tree have been changed before they were inserted, or
+            // after they were removed
+            let Some(&slot) = self.slots.get(&id) else {
+                continue;
+            };
+            let (key, summary) = self.node(slot).value.measure();
+            let measured = &mut measures.slots[slot];
+            if measured.key != key {
+                measures.keys.remove(&(measured.key, id));
+                measures.keys.insert((key, id), slot);
+            }
+            measured.key = key;
+            measured.summary = summary;
+            // Every subtree containing the element has to be summed up again
+            let mut link = Some(slot);
+            while let Some(slot) = link {
+                let node = self.node(slot);
+                measures.sum(slot, node.left, node.right);
+                link = node.parent;
+            }
+        }
+        Summaries {
+            tree: self,
+            measures: guard,
+        }
+    }
+}
+
+impl<T> Default for Tree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Element> FromIterator<T> for Tree<T> {
+    /// Builds the tree in linear time. The nodes on the right edge of the tree are kept on a
+    /// stack, where each of them is missing its right child until it is popped
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut ret = Self::new();
+        let mut stack: Vec<usize> = Vec::new();
+        for value in iter {
+            let slot = ret.alloc(value);
+            let priority = ret.node(slot).priority;
+            let mut popped = None;
+            while let Some(&top) = stack.last().filter(|&&x| ret.node(x).priority < priority) {
+                stack.pop();
+                ret.node_mut(top).right = popped;
+                ret.update(top);
+                popped = Some(top);
+            }
+            ret.node_mut(slot).left = popped;
+            stack.push(slot);
+        }
+        let mut popped = None;
+        while let Some(top) = stack.pop() {
+            ret.node_mut(top).right = popped;
+            ret.update(top);
+            popped = Some(top);
+        }
+        ret.set_root(popped);
+        ret
+    }
+}
+
+impl<T: Clone> Clone for Tree<T> {
+    fn clone(&self) -> Self {
+        Self {
+            nodes: self.nodes.clone(),
+            free: self.free.clone(),
+            root: self.root,
+            slots: self.slots.clone(),
+            measures: Mutex::new(
+                self.measures
+                    .lock()
+                    .expect("The measures of the tree got poisoned")
+                    .clone(),
+            ),
+            seed: self.seed,
+        }
+    }
+}
+
+impl<T: Debug> Debug for Tree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T> IntoIterator for Tree<T> {
+    type Item = T;
+    type IntoIter = vec::IntoIter<T>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        let order = {
+            let mut iter = self.iter();
+            iter::from_fn(|| iter.next_slot()).collect::<Vec<_>>()
+        };
+        order
+            .into_iter()
+            .map(|x| {
+                self.nodes[x]
+                    .take()
+                    .expect("Every slot is visited once")
+                    .value
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Tree<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the elements of a `Tree` in order
+pub struct Iter<'a, T> {
+    tree: &'a Tree<T>,
+    /// The nodes whose value and right subtree haven't been visited yet. The next one is on top
+    stack: Vec<usize>,
+}
+
+impl<T> Iter<'_, T> {
+    fn push_left(&mut self, mut link: Option<usize>) {
+        while let Some(slot) = link {
+            self.stack.push(slot);
+            link = self.tree.node(slot).left;
+        }
+    }
+
+    fn next_slot(&mut self) -> Option<usize> {
+        let slot = self.stack.pop()?;
+        self.push_left(self.tree.node(slot).right);
+        Some(slot)
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = self.tree;
+        self.next_slot().map(|x| &tree.node(x).value)
+    }
+}
+
+/// The summaries of the elements of a `Tree`, see `Tree::summaries`. No one else can bring them
+/// up to date while this exists
+pub struct Summaries<'a, T> {
+    tree: &'a Tree<T>,
+    measures: MutexGuard<'a, Measures>,
+}
+
+impl<'a, T> Summaries<'a, T> {
+    /// The tree the summaries are of
+    #[must_use]
+    pub const fn tree(&self) -> &'a Tree<T> {
+        self.tree
+    }
+
+    /// The sum of the summaries of all elements
+    #[must_use]
+    pub fn total(&self) -> Summary {
+        self.measures.total(self.tree.root)
+    }
+
+    /// Finds the first element where `f` of the summaries of it and every element in front of it
+    /// sums up to more than `target`, like the element containing a byte offset. `f` should pick
+    /// one of the values of a summary
+    /// # Returns
+    /// - The position of the element. This is the length of the tree if there is none
+    /// - The sum of the summaries of the elements in front of it
+    pub fn search(&self, f: impl Fn(&Summary) -> usize, mut target: usize) -> (usize, Summary) {
+        let mut link = self.tree.root;
+        let mut position = 0;
+        let mut before = Summary::default();
+        while let Some(slot) = link {
+            let node = self.tree.node(slot);
+            let left = self.measures.total(node.left);
+            let up_to = left + self.measures.slots[slot].summary;
+            if target < f(&left) {
+                link = node.left;
+            } else if target < f(&up_to) {
+                return (position + self.tree.size(node.left), before + left);
+            } else {
+                target -= f(&up_to);
+                position += node.size - self.tree.size(node.right);
+                before = before + up_to;
+                link = node.right;
+            }
+        }
+        (position, before)
+    }
+
+    /// Iterates over the elements with a key up to and including `key`, from the largest key
+    /// down
+    /// # Returns
+    /// The key, position and summary of every element
+    pub fn up_to(&self, key: Key) -> impl Iterator<Item = (Key, usize, Summary)> + '_ {
+        self.measures
+            .keys
+            .range(..=(key, usize::MAX))
+            .rev()
+            .map(|(&(key, _), &slot)| {
+                (
+                    key,
+                    self.tree.position_of_slot(slot),
+                    self.measures.slots[slot].summary,
+                )
+            })
+    }
+}
+
+/// A cursor over a `Tree` which works like the cursors of `std::collections::LinkedList`. Besides
+/// the elements there is a ghost element between the last and the first element, which the cursor
+/// is at when it moves past either end.
+/// Elements are only handed out immutably, as the tree has to know about replaced elements
+pub struct CursorMut<'a, T> {
+    tree: &'a mut Tree<T>,
+    /// The position of the current element. It is the length of the tree at the ghost element
+    index: usize,
+}
+
+impl<T> CursorMut<'_, T> {
+    fn is_ghost(&self) -> bool {
+        self.index == self.tree.len()
+    }
+
+    /// The position of the current element, or None at the ghost element
+    #[must_use]
+    pub fn index(&self) -> Option<usize> {
+        (!self.is_ghost()).then_some(self.index)
+    }
+
+    /// The current element, or None at the ghost element
+    #[must_use]
+    pub fn current(&self) -> Option<&T> {
+        self.tree.get(self.index)
+    }
+
+    /// Moves to the next element. The ghost element is followed by the first element
+    pub fn move_next(&mut self) {
+        self.index = if self.is_ghost() { 0 } else { self.index + 1 };
+    }
+
+    /// Moves to the previous element. The first element is preceded by the ghost element
+    pub fn move_prev(&mut self) {
+        self.index = self.index.checked_sub(1).unwrap_or(self.tree.len());
+    }
+
+    /// The element after the current one
+    #[must_use]
+    pub fn peek_next(&self) -> Option<&T> {
+        let next = if self.is_ghost() { 0 } else { self.index + 1 };
+        self.tree.get(next)
+    }
+
+    /// The element before the current one
+    #[must_use]
+    pub fn peek_prev(&self) -> Option<&T> {
+        self.tree.get(self.index.checked_sub(1)?)
+    }
+
+    /// The tree the cursor is in
+    #[must_use]
+    pub const fn as_tree(&self) -> &Tree<T> {
+        self.tree
+    }
+}
+
+impl<T: Element> CursorMut<'_, T> {
+    /// Inserts `value` before the current element. At the ghost element it is appended to the
+    /// end of the tree
+    pub fn insert_before(&mut self, value: T) {
+        self.tree.insert(self.index, value);
+        self.index += 1;
+    }
+
+    /// Inserts `value` after the current element. At the ghost element it is put at the front of
+    /// the tree
+    pub fn insert_after(&mut self, value: T) {
+        if self.is_ghost() {
+            self.tree.insert(0, value);
+            self.index += 1;
+        } else {
+            self.tree.insert(self.index + 1, value);
+        }
+    }
+
+    /// Removes the current element and moves to the next one
+    /// # Returns
+    /// The removed element, or None at the ghost element
+    pub fn remove_current(&mut self) -> Option<T> {
+        self.tree.remove(self.index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Element, Key, Summary, Tree};
+
+    /// Numbers are their own id, and are as long as their value
+    impl Element for usize {
+        fn id(&self) -> usize {
+            *self
+        }
+
+        fn measure(&self) -> (Key, Summary) {
+            (
+                (self % 3, 0),
+                Summary {
+                    bytes: *self,
+                    newlines: usize::from(self.is_multiple_of(2)),
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn matches_vec() {
+        let mut tree = Tree::new();
+        let mut vec = Vec::new();
+        let mut seed = 7usize;
+        for i in 0..2000 {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            let index = (seed >> 33) % (vec.len() + 1);
+            if seed.is_multiple_of(3) {
+                assert_eq!(
+                    tree.remove(index),
+                    (index < vec.len()).then(|| vec.remove(index))
+                );
+            } else {
+                tree.insert(index, i);
+                vec.insert(index, i);
+            }
+            assert_eq!(tree.len(), vec.len());
+        }
+        assert!(tree.iter().eq(vec.iter()));
+        for i in [0, 1, vec.len() / 2, vec.len() - 1, vec.len()] {
+            assert!(tree.iter_from(i).eq(vec[i..].iter()));
+        }
+        assert!((0..vec.len()).all(|i| tree.get(i) == vec.get(i)));
+        assert_eq!(tree.get(vec.len()), None);
+        assert!(vec
+            .iter()
+            .enumerate()
+            .all(|(i, x)| tree.position(x) == Some(i)));
+        assert_eq!(tree.position(&2000), None);
+        assert!(tree.clone().into_iter().eq(vec.clone()));
+        assert!(vec
+            .iter()
+            .copied()
+            .collect::<Tree<_>>()
+            .iter()
+            .eq(vec.iter()));
+    }
+
+    #[test]
+    fn summaries() {
+        let vec = [4, 0, 3, 7, 5, 2, 9, 6, 1];
+        let mut tree = vec.into_iter().collect::<Tree<_>>();
+        tree.remove(4);
+        tree.insert(4, 10);
+        let vec = [4, 0, 3, 7, 10, 2, 9, 6, 1];
+        let summaries = tree.summaries(Vec::new);
+        assert_eq!(
+            summaries.total(),
+            Summary {
+                bytes: vec.iter().sum(),
+                newlines: vec.iter().filter(|x| x.is_multiple_of(2)).count(),
+            }
+        );
+        for target in 0..=vec.iter().sum() {
+            // The first element which ends after `target`, skipping the empty ones in front of it
+            let position = (0..vec.len())
+                .find(|&i| vec[..=i].iter().sum::<usize>() > target)
+                .unwrap_or(vec.len());
+            let (found, before) = summaries.search(|x| x.bytes, target);
+            assert_eq!(found, position, "{target}");
+            assert_eq!(before.bytes, vec[..position].iter().sum(), "{target}");
+        }
+        assert_eq!(
+            summaries.search(|x| x.newlines, 2),
+            (
+                4,
+                Summary {
+                    bytes: 14,
+                    newlines: 2
+                }
+            )
+        );
+
+        let keyed = summaries.up_to((1, 0)).collect::<Vec<_>>();
+        assert_eq!(keyed.len(), 8);
+        assert!(keyed[..4].iter().all(|&(key, _, _)| key == (1, 0)));
+        assert!(keyed[4..].iter().all(|&(key, _, _)| key == (0, 0)));
+        assert!(keyed
+            .iter()
+            .all(|&(_, position, summary)| summary.bytes == vec[position]));
+    }
+
+    #[test]
+    fn cursor() {
+        let mut tree = (1..=3).collect::<Tree<_>>();
+        let mut cursor = tree.cursor_front_mut();
+        cursor.move_prev();
+        assert_eq!(cursor.current(), None);
+        assert_eq!(cursor.peek_prev(), Some(&3));
+        assert_eq!(cursor.peek_next(), Some(&1));
+        cursor.insert_after(0);
+        cursor.insert_before(4);
+        assert_eq!(cursor.index(), None);
+        cursor.move_next();
+        assert_eq!(cursor.current(), Some(&0));
+        cursor.move_next();
+        cursor.move_next();
+        assert_eq!(cursor.remove_current(), Some(2));
+        assert_eq!(cursor.current(), Some(&3));
+        cursor.move_prev();
+        cursor.insert_after(5);
+        assert_eq!(cursor.peek_next(), Some(&5));
+        assert_eq!(cursor.index(), Some(1));
+        assert_eq!(cursor.as_tree().len(), 5);
+        assert!(tree.iter().eq(&[0, 1, 5, 3, 4]));
+
+        let mut empty = Tree::<usize>::new();
+        let mut cursor = empty.cursor_front_mut();
+        cursor.move_next();
+        assert_eq!(cursor.current(), None);
+        assert_eq!(cursor.remove_current(), None);
+        cursor.insert_before(1);
+        assert_eq!(cursor.peek_prev(), Some(&1));
+        assert_eq!(empty.back(), Some(&1));
+    }
+}
@@ -0,0 +1,46 @@
+//! The settings a server is run with
+use std::{
+    net::SocketAddrV4,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    time::Duration,
+};
+
+use text::LineEnding;
+
+/// Everything `run` needs to know about how to serve
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Where clients connect to
+    pub address: SocketAddrV4,
+    /// The file that is served, or the folder whose files are served
+    pub path: PathBuf,
+    /// How often the open files get saved. None only saves when a client asks for it
+    pub save_interval: Option<Duration>,
+    /// When this is set, a save also happens once no edits have been made for that long
+    pub save_debounce: Option<Duration>,
+    /// Whether clients are kept from making any changes
+    pub read_only: bool,
+    /// Whether clients show files starting with a `.` in folders by default
+    pub show_hidden: bool,
+    /// Clients that don't send anything, not even pings, for that long get disconnected
+    pub client_timeout: Option<Duration>,
+    /// Clients that don't send any actions other than pings for that long get disconnected
+    pub idle_timeout: Option<Duration>,
+    /// What lines get ended with when saving. None keeps the line ending that most lines of each
+    /// file used when it was opened
+    pub line_ending: Option<LineEnding>,
+    /// The amount of clients that can be connected at once. Anyone connecting past that is
+    /// turned away
+    pub max_clients: Option<NonZeroUsize>,
+    /// The size in bytes of the largest file clients can open
+    pub max_file_size: Option<NonZeroU64>,
+    /// Where a report of the open files is served, see `status_report`
+    pub status_address: Option<SocketAddrV4>,
+    /// A file that everything that changes the open files gets appended to, see `replay`
+    pub record: Option<PathBuf>,
+    /// The amount of failed authorization attempts in a row after which an address gets locked
+    /// out
+    #[cfg(feature = "security")]
+    pub auth_lockout: u32,
+}
@@ -1,115 +1,148 @@
 //! A server side for an editor meant to be used by multiple clients
 #![feature(never_type)]
 #![feature(iter_intersperse)]
+mod config;
+mod limit;
+mod record;
 #[cfg(feature = "security")]
 mod security;
 
 #[cfg(feature = "security")]
 use sqlx::SqlitePool;
+#[cfg(feature = "security")]
+use std::net::IpAddr;
 
 #[cfg(feature = "security")]
-pub use security::add_user;
+pub use security::{add_user, grant_access};
 #[cfg(feature = "security")]
-use security::{auth_check, create_tables};
+use security::{
+    auth_check, create_tables, granted_paths, may_open, visible_inhabitants, AuthLimiter,
+};
 
-use btep::{c2s::C2S, prelude::S2C, s2c::Inhabitant, Deserialize, Serialize};
+use btep::{
+    c2s::C2S,
+    prelude::S2C,
+    s2c::{FullWriter, Inhabitant},
+    version, Deserialize, Serialize,
+};
+pub use config::ServerConfig;
 use crossterm::style::Color;
-use futures::{executor::block_on, FutureExt};
+use futures::{executor::block_on, future, FutureExt};
+use limit::{ClientLimit, ClientSlot};
+pub use record::replay;
+use record::{apply_edit, Event, Recorder};
 use std::{
     collections::HashMap,
-    fs::{self, File, OpenOptions},
+    ffi::OsString,
+    fs::{self, File},
     io::{self, BufReader, BufWriter, Error, Write},
-    net::SocketAddrV4,
-    num::NonZeroU64,
-    path::{Path, PathBuf},
-    sync::Arc,
+    path::{Component, Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
+pub use text::LineEnding;
 use text::Text;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
-    sync::{Notify, RwLock},
-    time::sleep,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    signal,
+    sync::{watch, Notify, RwLock},
+    time::{sleep, timeout, timeout_at, Instant},
 };
 
-use utils::{bufread::BufReaderExt, other::AutoIncrementing};
+use utils::bufread::BufReaderExt;
 
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
-/// Runs the server for the editor.
+/// Runs the server for the editor, serving what `config` says
 #[allow(clippy::missing_panics_doc)]
 #[tokio::main]
-pub async fn run(
-    save_interval: Option<NonZeroU64>,
-    address: SocketAddrV4,
-    path: &Path,
-    #[cfg(feature = "security")] pool: SqlitePool,
-) {
-    #[cfg(feature = "security")]
-    let pool = Arc::new(pool);
+pub async fn run(config: ServerConfig, #[cfg(feature = "security")] pool: SqlitePool) {
     #[cfg(feature = "security")]
     create_tables(&pool)
         .await
         .expect("Failed to create the users table");
 
-    let server = TcpListener::bind(address).await.unwrap();
-    let is_file = fs::metadata(path).unwrap().file_type().is_file();
+    let server = TcpListener::bind(config.address).await.unwrap();
+    let is_file = fs::metadata(&config.path).unwrap().file_type().is_file();
     if !is_file {
         assert!(
-            fs::metadata(path).unwrap().file_type().is_dir(),
+            fs::metadata(&config.path).unwrap().file_type().is_dir(),
             "I don't handle non-file nor non-dir stuff"
         );
     }
 
-    let files: Arc<RwLock<HashMap<PathBuf, BufferData>>> = Arc::new(RwLock::new(HashMap::new()));
+    let shared = Arc::new(Shared {
+        serve_other: !is_file,
+        files: Arc::new(RwLock::new(HashMap::new())),
+        client_limit: Arc::new(ClientLimit::new(config.max_clients)),
+        recorder: (config.record.as_ref())
+            .map(|x| Arc::new(Recorder::create(x).expect("Failed to open the recording"))),
+        #[cfg(feature = "security")]
+        limiter: AuthLimiter::new(config.auth_lockout),
+        #[cfg(feature = "security")]
+        pool,
+        config,
+    });
+
+    if let Some(status_address) = shared.config.status_address {
+        let listener = TcpListener::bind(status_address).await.unwrap();
+        tokio::spawn(serve_status(listener, Arc::clone(&shared.files)));
+    }
 
+    let mut shutdown = pin!(signal::ctrl_c());
     loop {
-        let (stream, _) = server.accept().await.unwrap();
-        tokio::spawn(
-            handle_connection(
-                stream,
-                Arc::clone(&files),
-                save_interval,
-                path.to_path_buf(),
-                !is_file,
-                #[cfg(feature = "security")]
-                Arc::clone(&pool),
-            )
-            .then(move |output| async move {
+        let (stream, _) = tokio::select! {
+            accepted = server.accept() => accepted.unwrap(),
+            result = &mut shutdown => {
+                result.expect("Failed to listen for ctrl-c");
+                info!("Shutting down");
+                save_all(&shared.files).await;
+                return;
+            }
+        };
+        tokio::spawn(handle_connection(stream, Arc::clone(&shared)).then(
+            move |output| async move {
                 if let Err(e) = output {
                     error!("{e:?}");
                 }
-            }),
-        );
+            },
+        ));
     }
 }
 
-async fn handle_connection(
-    mut stream: TcpStream,
-    files: Arc<RwLock<HashMap<PathBuf, BufferData>>>,
-    save_interval: Option<NonZeroU64>,
-    path: PathBuf,
-    serve_other: bool,
-    #[cfg(feature = "security")] pool: Arc<SqlitePool>,
-) -> io::Result<()> {
+async fn handle_connection(mut stream: TcpStream, shared: Arc<Shared>) -> io::Result<()> {
     debug!("new Client");
 
     #[cfg(feature = "security")]
-    let pool = Arc::clone(&pool);
-
-    let username = match authorize(
+    let ip = stream.peer_addr()?.ip();
+    let (username, slot) = match authorize(
         &mut stream,
         #[cfg(feature = "security")]
-        &pool,
+        &shared.pool,
+        #[cfg(feature = "security")]
+        (&shared.limiter, ip),
     )
     .await
     {
         Ok(x) => {
+            let Some(slot) = shared.client_limit.try_acquire() else {
+                warn!("Turned {x} away, as the server is full");
+                stream.write_u8(5).await?;
+                stream.flush().await?;
+                return Ok(());
+            };
             stream.write_u8(0).await?;
             stream.flush().await?;
-            x
+            (x, slot)
         }
         Err(x) => {
             match x {
@@ -119,20 +152,18 @@ async fn handle_connection(
                     warn!("Bad password");
                     stream.write_u8(2).await?;
                 }
+                #[cfg(feature = "security")]
+                UserAuthError::TooManyAttempts(wait) => {
+                    warn!("Too many failed attempts, {wait:?} left before trying again is allowed");
+                    stream.write_u8(4).await?;
+                }
             }
             stream.flush().await?;
             return Ok(());
         }
     };
 
-    tokio::spawn(handle_client(
-        username,
-        stream,
-        files,
-        save_interval,
-        path,
-        serve_other,
-    ));
+    tokio::spawn(handle_client(username, stream, shared, slot));
     Ok(())
 }
 
@@ -142,55 +173,152 @@ async fn handle_connection(
 async fn handle_client(
     username: String,
     stream: TcpStream,
-    files: Arc<RwLock<HashMap<PathBuf, BufferData>>>,
-    save_interval: Option<NonZeroU64>,
-    path: PathBuf,
-    serve_other: bool,
+    shared: Arc<Shared>,
+    // Frees up the spot of the client once it is done
+    _slot: ClientSlot,
 ) -> Result<(), io::Error> {
+    let Shared {
+        config,
+        serve_other,
+        files,
+        recorder,
+        ..
+    } = &*shared;
+    let ServerConfig {
+        path,
+        read_only,
+        show_hidden,
+        ..
+    } = config;
+    let (serve_other, read_only, show_hidden) = (*serve_other, *read_only, *show_hidden);
     let (mut read, mut write) = stream.into_split();
-    let client_path = if serve_other {
-        let C2S::Path(client_path) = C2S::deserialize(&mut read).await? else {
-            warn!("Client sent wrong data");
+    version::write_version(&mut write).await?;
+    write.flush().await?;
+    let features = match version::read_version(&mut read).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("{username}: {e}");
             return Ok(());
-        };
-        let Ok(canonicalized) = path.join(client_path).canonicalize() else {
-            warn!("client path was invalid");
+        }
+    };
+    #[cfg(feature = "security")]
+    let granted = granted_paths(&username, &shared.pool)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Couldn't look up what {username} may open, so nothing is allowed: {e}");
+            Some(Vec::new())
+        });
+    // The granted paths are relative to the served folder
+    #[cfg(feature = "security")]
+    let access_root = if serve_other {
+        path.canonicalize()?
+    } else {
+        path.parent().map_or_else(PathBuf::new, Path::to_path_buf)
+    };
+    let client_path = match C2S::deserialize(&mut read).await? {
+        C2S::Path(_) if !serve_other => path.clone(),
+        C2S::Path(client_path) => {
+            let Some(canonicalized) = resolve_open_path(path, &client_path) else {
+                warn!("client path was invalid");
+                return Ok(());
+            };
+            canonicalized
+        }
+        C2S::Rename((from, to)) => {
+            // Users with limited access could otherwise move files in and out of what they can
+            // access
+            #[cfg(feature = "security")]
+            let read_only = read_only || granted.is_some();
+            let response = if serve_other && !read_only {
+                rename(files, path, &from, &to).await
+            } else {
+                None
+            };
+            let response: S2C<&Text> =
+                response.map_or(S2C::Rejected, |x| S2C::Folder((x, show_hidden)));
+            write.write_all(&response.serialize()).await?;
+            write.flush().await?;
             return Ok(());
-        };
-        if !(canonicalized.starts_with(path.canonicalize().unwrap())) {
-            trace!(
-                "client path was invalid: {canonicalized:?} vs {:?}",
-                path.canonicalize().unwrap()
-            );
+        }
+        C2S::CreateFile(new_path) => {
+            #[cfg(feature = "security")]
+            let read_only = read_only || granted.is_some();
+            let response = if serve_other && !read_only {
+                create_file(path, &new_path)
+            } else {
+                None
+            };
+            let response: S2C<&Text> =
+                response.map_or(S2C::Rejected, |x| S2C::Folder((x, show_hidden)));
+            write.write_all(&response.serialize()).await?;
+            write.flush().await?;
+            return Ok(());
+        }
+        _ => {
+            warn!("Client sent wrong data");
             return Ok(());
         }
-        canonicalized
-    } else {
-        C2S::deserialize(&mut read).await?;
-        path
     };
+    #[cfg(feature = "security")]
+    let relative = client_path
+        .strip_prefix(&access_root)
+        .unwrap_or(&client_path);
+    #[cfg(feature = "security")]
+    if let Some(granted) = &granted {
+        if !may_open(granted, relative) {
+            warn!("{username} tried to open {relative:?} without access");
+            write
+                .write_all(&S2C::<&Text>::AccessDenied.serialize())
+                .await?;
+            write.flush().await?;
+            return Ok(());
+        }
+    }
     if client_path.is_dir() {
         trace!("serving directory");
+        #[cfg_attr(not(feature = "security"), allow(unused_mut))]
+        let mut inhabitants = inhabitants(&client_path)?;
+        #[cfg(feature = "security")]
+        if let Some(granted) = &granted {
+            visible_inhabitants(granted, relative, &mut inhabitants);
+        }
         write
-            .write_all(
-                &S2C::Folder::<&Text>(
-                    client_path
-                        .read_dir()?
-                        .map(|x| x.map(|x| TryInto::<Inhabitant>::try_into(x).unwrap()))
-                        .collect::<Result<Vec<_>, io::Error>>()?,
-                )
-                .serialize(),
-            )
+            .write_all(&S2C::Folder::<&Text>((inhabitants, show_hidden)).serialize())
             .await?;
         write.flush().await?;
         return Ok(());
     }
     trace!("serving file");
+    if let Some(max) = config.max_file_size {
+        let size = fs::metadata(&client_path).map_or(0, |x| x.len());
+        if size > max.get() {
+            warn!("{username} tried to open {client_path:?}, which is {size} bytes");
+            write
+                .write_all(&S2C::<&Text>::TooLarge(max.get()).serialize())
+                .await?;
+            write.flush().await?;
+            return Ok(());
+        }
+    }
+    if !client_path.exists() {
+        let created = if read_only {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the server is read-only",
+            ))
+        } else {
+            client_path.parent().map_or(Ok(()), fs::create_dir_all)
+        };
+        if let Err(e) = created {
+            warn!("Couldn't create {client_path:?}: {e}");
+            return Ok(());
+        }
+    }
     let C2S::SetColor(new_client_color) = C2S::deserialize(&mut read).await? else {
         warn!("Client set bad color");
         return Ok(());
     };
-    {
+    let self_id = {
         let mut lock = files.write().await;
         let entry = lock.entry(client_path.clone()).or_insert_with(|| {
             let file = File::options()
@@ -201,94 +329,125 @@ async fn handle_client(
                 .open(&client_path)
                 .unwrap();
             info!("opened new file {client_path:?}");
-            let text = Arc::new(RwLock::new(
-                Text::original_from_reader(BufReader::new(file)).unwrap(),
-            ));
+            let mut text = Text::original_from_reader(BufReader::new(file)).unwrap();
+            if let Some(recorder) = &recorder {
+                recorder.record(&client_path, 0, Event::Opened(text.contents()));
+            }
+            if let Some(line_ending) = config.line_ending {
+                text.line_ending = line_ending;
+            }
+            let text = Arc::new(RwLock::new(text));
             let notifier = Arc::new(Notify::new());
             let ret = BufferData {
                 text: Arc::clone(&text),
-                colors: Arc::new(RwLock::new(Vec::new())),
+                colors: Arc::new(RwLock::new(HashMap::new())),
                 sockets: Arc::new(RwLock::new(HashMap::new())),
                 notifier: Arc::clone(&notifier),
-                counter: Arc::new(RwLock::new(AutoIncrementing::default())),
+                edited: Arc::new(Notify::new()),
+                save_requests: Arc::new(AtomicUsize::new(0)),
+                saved: Arc::new(watch::Sender::new(0)),
+                dirty: Arc::new(AtomicBool::new(false)),
+                recorder: recorder.clone(),
             };
 
-            spawn_saver(text, save_interval, notifier, client_path.clone());
+            spawn_saver(&ret, config, client_path.clone());
             ret
         });
-        let data = {
-            let data = entry.text.read().await;
-            let full = S2C::Full(&*data);
-            full.serialize()
-        };
-
-        write.write_all(&data).await?;
+        send_full(
+            &mut write,
+            &*entry.text.read().await,
+            read_only,
+            features.compression,
+        )
+        .await?;
 
+        let (colors, presence) = {
+            // Same locking order as `remove_clients`
+            let sockets = entry.sockets.read().await;
+            let text = entry.text.read().await;
+            let colors = entry.colors.read().await;
+            let presence = (sockets.keys())
+                .filter_map(|&id| Some((id, text.client(id).username.clone(), *colors.get(&id)?)))
+                .collect::<Vec<_>>();
+            let colors = (colors.iter())
+                .map(|(&id, &color)| (id, color))
+                .collect::<Vec<_>>();
+            (colors, presence)
+        };
+        write.write_all(&colors.serialize()).await?;
         write
-            .write_all(&entry.colors.read().await.serialize())
+            .write_all(&S2C::<&Text>::Presence(presence).serialize())
             .await?;
         write.flush().await?;
         debug!("Connected {:?}", username);
-        entry.text.write().await.add_client(&username);
-        entry.colors.write().await.push(new_client_color);
-    }
-
-    for (_, client) in files
-        .read()
-        .await
-        .get(&client_path)
-        .unwrap()
-        .sockets
-        .write()
-        .await
-        .iter_mut()
-    {
-        let username = username.clone();
-        block_on(async move {
-            client
-                .write_all(
-                    &S2C::<&Text>::NewClient((username.clone(), new_client_color)).serialize(),
-                )
-                .await?;
-
+        let self_id = entry.text.write().await.add_client(&username);
+        if let Some(recorder) = &recorder {
+            recorder.record(&client_path, self_id, Event::Joined(username.clone()));
+        }
+        entry.colors.write().await.insert(self_id, new_client_color);
+        // Still holding `files`, so that no message can reach the others between the text being
+        // sent and the new client being able to receive messages
+        let mut sockets = entry.sockets.write().await;
+        let message =
+            S2C::<&Text>::NewClient((self_id, username.clone(), new_client_color)).serialize();
+        for client in sockets.values_mut() {
+            client.write_all(&message).await?;
             client.flush().await?;
-            Ok::<_, io::Error>(())
-        })?;
-    }
-
-    let self_id = files
-        .read()
-        .await
-        .get(&client_path)
-        .unwrap()
-        .counter
-        .write()
-        .await
-        .get();
-    files
-        .read()
-        .await
-        .get(&client_path)
-        .unwrap()
-        .sockets
-        .write()
-        .await
-        .insert(self_id, write);
+        }
+        sockets.insert(self_id, write);
+        self_id
+    };
+    let mut last_active = Instant::now();
     loop {
         let mut to_remove = Vec::with_capacity(1);
         {
             let action = {
-                let action = C2S::deserialize(&mut read).await?;
+                let idle_deadline = config.idle_timeout.map(|x| last_active + x);
+                let action =
+                    match read_action(&mut read, config.client_timeout, idle_deadline).await {
+                        Ok(action) => action,
+                        Err(e) => {
+                            warn!("{self_id}: {e}");
+                            remove_clients(files, &client_path, &[self_id]).await?;
+                            return Err(e);
+                        }
+                    };
+                if matches!(action, C2S::Ping) {
+                    let tmp = files.read().await;
+                    let socket_lock = &mut tmp.get(&client_path).unwrap().sockets.write().await;
+                    if let Some(client) = socket_lock.get_mut(&self_id) {
+                        client.write_all(&S2C::<&Text>::Pong.serialize()).await?;
+                        client.flush().await?;
+                    }
+                    continue;
+                }
+                // Pings only keep the connection alive, they don't count as activity
+                last_active = Instant::now();
+                if read_only
+                    && matches!(
+                        action,
+                        C2S::Char(_)
+                            | C2S::Backspace(_)
+                            | C2S::Enter
+                            | C2S::EnterInsert(_)
+                            | C2S::Str(_)
+                    )
+                {
+                    warn!("{self_id} tried to modify a read-only file");
+                    let tmp = files.read().await;
+                    let socket_lock = &mut tmp.get(&client_path).unwrap().sockets.write().await;
+                    if let Some(client) = socket_lock.get_mut(&self_id) {
+                        client
+                            .write_all(&S2C::<&Text>::Rejected.serialize())
+                            .await?;
+                        client.flush().await?;
+                    }
+                    continue;
+                }
                 let tmp = files.read().await;
                 let binding = &mut tmp.get(&client_path).unwrap().text.write().await;
                 let lock = binding.client_mut(self_id);
                 match action {
-                    C2S::Char(c) => lock.push_char(c),
-                    C2S::Backspace(swaps) => drop(lock.backspace_with_swaps(swaps)),
-                    C2S::Enter => lock.push_char('\n'),
-                    C2S::EnterInsert(enter_insert) => {
-                        lock.enter_insert(enter_insert);
-                    }
                     C2S::Save => {
                         files
                             .read()
@@ -299,13 +458,38 @@ async fn handle_client(
                             .notify_one();
                         continue;
                     }
-                    C2S::ExitInsert => lock.exit_insert(),
-                    C2S::Path(_) | C2S::SetColor(_) => panic!("Can't set pat hnor color here"),
+                    C2S::Path(_) | C2S::SetColor(_) => {
+                        warn!("{self_id} tried to set its path or color while editing");
+                        continue;
+                    }
+                    C2S::Rename(_) | C2S::CreateFile(_) => {
+                        warn!("{self_id} tried to manage files while editing one");
+                        continue;
+                    }
+                    C2S::Ping => unreachable!("Pings are answered before the text is locked"),
+                    _ => {
+                        if let Err(e) = apply_edit(lock, &action) {
+                            warn!("{self_id} {e}");
+                            continue;
+                        }
+                    }
+                }
+                trace!(client = self_id, path = ?client_path, action = action.kind(), "applied");
+                if let Some(recorder) = &tmp.get(&client_path).unwrap().recorder {
+                    recorder.record(&client_path, self_id, Event::Action(action.clone()));
                 }
                 action
             };
 
             let tmp = files.read().await;
+            if matches!(
+                action,
+                C2S::Char(_) | C2S::Backspace(_) | C2S::Enter | C2S::Str(_)
+            ) {
+                let data = tmp.get(&client_path).unwrap();
+                data.dirty.store(true, Ordering::Release);
+                data.edited.notify_one();
+            }
             let socket_lock = &mut tmp.get(&client_path).unwrap().sockets.write().await;
             for (clientnr, client) in socket_lock.iter_mut() {
                 if *clientnr == self_id {
@@ -323,47 +507,223 @@ async fn handle_client(
                 };
             }
         }
-        {
-            let tmp = files.read().await;
-            let socket_lock = &mut tmp.get(&client_path).unwrap().sockets.write().await;
-            for client_to_remove in to_remove {
-                info!("removed client {client_to_remove}");
-                files
-                    .read()
-                    .await
-                    .get(&client_path)
-                    .unwrap()
-                    .text
-                    .write()
-                    .await
-                    .client_mut(client_to_remove)
-                    .exit_insert();
-
-                for (clientnr, client) in socket_lock.iter_mut() {
-                    if *clientnr == client_to_remove {
-                        continue;
-                    }
-                    let result = block_on(client.write_all(
-                        &S2C::Update::<&Text>((client_to_remove, C2S::ExitInsert)).serialize(),
-                    ));
-                    match result {
-                        Ok(()) => block_on(client.flush())?,
-                        Err(_) => {
-                            // The responsible thing to do would be to remove it here, but it'll be
-                            // removed anyways at the next iteration
-                        }
-                    };
+        remove_clients(files, &client_path, &to_remove).await?;
+    }
+}
+
+/// Sends `text` as an `S2C::Full`, a chunk at a time. The text is compressed when `compress` is
+/// set, which should only be done if the client supports it
+async fn send_full<W>(write: &mut W, text: &Text, read_only: bool, compress: bool) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut full = FullWriter::start(write, read_only, compress).await?;
+    text.write_full(&mut full).await?;
+    full.finish().await
+}
+
+/// Reads the next action sent by a client.
+/// # Errors
+/// - The client didn't send anything within `client_timeout`
+/// - `idle_deadline` passed before the client sent anything
+/// - Failing to read from the client
+async fn read_action(
+    read: &mut OwnedReadHalf,
+    client_timeout: Option<Duration>,
+    idle_deadline: Option<Instant>,
+) -> io::Result<C2S> {
+    let action = async {
+        let Some(client_timeout) = client_timeout else {
+            return C2S::deserialize(read).await;
+        };
+        timeout(client_timeout, C2S::deserialize(read))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "The client stopped responding"))?
+    };
+    let Some(idle_deadline) = idle_deadline else {
+        return action.await;
+    };
+    timeout_at(idle_deadline, action).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "The client has been idle for too long",
+        )
+    })?
+}
+
+/// Removes clients that can no longer be reached, and lets everyone else know that they left
+async fn remove_clients(
+    files: &RwLock<HashMap<PathBuf, BufferData>>,
+    client_path: &Path,
+    to_remove: &[usize],
+) -> io::Result<()> {
+    let tmp = files.read().await;
+    let entry = tmp.get(client_path).unwrap();
+    let socket_lock = &mut entry.sockets.write().await;
+    for &client_to_remove in to_remove {
+        info!("removed client {client_to_remove}");
+        entry.text.write().await.remove_client(client_to_remove);
+        if let Some(recorder) = &entry.recorder {
+            recorder.record(client_path, client_to_remove, Event::Left);
+        }
+        entry.colors.write().await.remove(&client_to_remove);
+
+        for (clientnr, client) in socket_lock.iter_mut() {
+            if *clientnr == client_to_remove {
+                continue;
+            }
+            let result =
+                block_on(client.write_all(&S2C::<&Text>::ClientLeft(client_to_remove).serialize()));
+            match result {
+                Ok(()) => block_on(client.flush())?,
+                Err(_) => {
+                    // The responsible thing to do would be to remove it here, but it'll be
+                    // removed anyways at the next iteration
+                }
+            };
+        }
+        socket_lock.remove(&client_to_remove);
+    }
+    Ok(())
+}
+
+/// Lexically removes `.` and `..` from a path sent by a client, without touching the filesystem.
+/// This way the same check applies whether the path exists or not
+/// # Returns
+/// None if the path is absolute or `..` would leave the directory it is relative to
+fn normalize(client_path: &Path) -> Option<PathBuf> {
+    let mut ret = PathBuf::new();
+    for component in client_path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !ret.pop() {
+                    trace!("{client_path:?} escapes the root");
+                    return None;
                 }
-                socket_lock.remove(&client_to_remove);
+            }
+            Component::Normal(name) => ret.push(name),
+            Component::RootDir | Component::Prefix(_) => {
+                trace!("{client_path:?} is absolute");
+                return None;
             }
         }
     }
+    Some(ret)
+}
+
+/// Resolves a path sent by a client to a path within `root`
+/// # Returns
+/// None if the path doesn't exist or points outside of `root`
+fn resolve_path(root: &Path, client_path: &Path) -> Option<PathBuf> {
+    // Symlinks can still point outside of root, so the canonicalized path is checked as well
+    let canonicalized = root.join(normalize(client_path)?).canonicalize().ok()?;
+    let root = root.canonicalize().ok()?;
+    if !canonicalized.starts_with(&root) {
+        trace!("client path was invalid: {canonicalized:?} vs {root:?}");
+        return None;
+    }
+    Some(canonicalized)
+}
+
+/// Resolves a path sent by a client to a path within `root` that it wants to open. Unlike
+/// `resolve_path` the path doesn't have to exist, but the part of it that doesn't may only consist
+/// of plain names, so it can't escape `root` once it is created
+/// # Returns
+/// None if the path points outside of `root`
+fn resolve_open_path(root: &Path, client_path: &Path) -> Option<PathBuf> {
+    let joined = root.join(normalize(client_path)?);
+    let mut existing = joined.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        missing.push(existing.file_name()?);
+        existing = existing.parent()?;
+    }
+    let mut ret = existing.canonicalize().ok()?;
+    let root = root.canonicalize().ok()?;
+    if !ret.starts_with(&root) {
+        trace!("client path was invalid: {ret:?} vs {root:?}");
+        return None;
+    }
+    ret.extend(missing.into_iter().rev());
+    Some(ret)
+}
+
+/// Resolves a path sent by a client to a path within `root` that doesn't exist yet
+/// # Returns
+/// None if the path already exists, or it would end up outside of `root`
+fn resolve_new_path(root: &Path, client_path: &Path) -> Option<PathBuf> {
+    let client_path = normalize(client_path)?;
+    let name = client_path.file_name()?;
+    let parent = resolve_path(root, client_path.parent()?)?;
+    let ret = parent.join(name);
+    if ret.exists() {
+        trace!("{ret:?} already exists");
+        return None;
+    }
+    Some(ret)
+}
+
+/// Lists the content of a folder, with the folders before the files
+fn inhabitants(path: &Path) -> io::Result<Vec<Inhabitant>> {
+    let mut ret = path
+        .read_dir()?
+        .map(|x| x.and_then(TryInto::<Inhabitant>::try_into))
+        .collect::<io::Result<Vec<_>>>()?;
+    ret.sort_unstable_by(|a, b| {
+        b.is_folder
+            .cmp(&a.is_folder)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(ret)
+}
+
+/// Renames a file within `root`.
+/// Files that have been opened by a client can't be renamed
+/// # Returns
+/// The new content of the folder `to` ended up in, or None if the rename was refused
+async fn rename(
+    files: &RwLock<HashMap<PathBuf, BufferData>>,
+    root: &Path,
+    from: &Path,
+    to: &Path,
+) -> Option<Vec<Inhabitant>> {
+    let from = resolve_path(root, from)?;
+    if from == root.canonicalize().ok()? {
+        warn!("Tried to rename the root");
+        return None;
+    }
+    let to = resolve_new_path(root, to)?;
+    if files.read().await.keys().any(|x| x.starts_with(&from)) {
+        warn!("Tried to rename {from:?} while it is open");
+        return None;
+    }
+    if let Err(e) = fs::rename(&from, &to) {
+        warn!("Failed to rename {from:?}: {e}");
+        return None;
+    }
+    info!("Renamed {from:?} to {to:?}");
+    inhabitants(to.parent()?).ok()
+}
+
+/// Creates a new empty file within `root`. Existing files are never overwritten
+/// # Returns
+/// The new content of the folder the file was created in, or None if it couldn't be created
+fn create_file(root: &Path, path: &Path) -> Option<Vec<Inhabitant>> {
+    let path = resolve_new_path(root, path)?;
+    if let Err(e) = File::create_new(&path) {
+        warn!("Failed to create {path:?}: {e}");
+        return None;
+    }
+    info!("Created {path:?}");
+    inhabitants(path.parent()?).ok()
 }
 
 /// Checks whether a socket supplies proper authorization credentials
 async fn authorize<T>(
     stream: &mut T,
     #[cfg(feature = "security")] pool: &SqlitePool,
+    #[cfg(feature = "security")] (limiter, ip): (&AuthLimiter, IpAddr),
 ) -> Result<String, UserAuthError>
 where
     T: AsyncRead + AsyncReadExt + AsyncWrite + Unpin + Send,
@@ -371,19 +731,33 @@ where
     let mut username = String::new();
     let delim = stream.read_valid_str(&mut username).await?;
     #[cfg(not(feature = "security"))]
-    assert_eq!(
-        delim,
-        Some(255),
-        "Client sent wrong byte, maybe you are running with --security"
-    );
+    if delim != Some(255) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Client sent wrong byte, maybe it is running with --security",
+        )
+        .into());
+    }
     #[cfg(feature = "security")]
     {
-        assert_eq!(delim, Some(254), "client running without security enabled");
+        if delim != Some(254) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Client sent wrong byte, maybe it is running without --security",
+            )
+            .into());
+        }
         let mut password = String::new();
-        stream.read_valid_str(&mut password).await.unwrap();
+        stream.read_valid_str(&mut password).await?;
+        // Checking the password is the expensive part, so it is skipped while waiting
+        if let Some(wait) = limiter.wait_time(ip) {
+            return Err(UserAuthError::TooManyAttempts(wait));
+        }
         if auth_check(&username, &password, pool).await.is_none() {
+            limiter.failed(ip);
             return Err(UserAuthError::BadPassword);
         };
+        limiter.succeeded(ip);
     }
     Ok(username.to_string())
 }
@@ -391,6 +765,9 @@ where
 enum UserAuthError {
     #[cfg(feature = "security")]
     BadPassword,
+    /// The address has failed too often recently and has to wait for the given duration
+    #[cfg(feature = "security")]
+    TooManyAttempts(Duration),
     IoError(Error),
 }
 
@@ -400,37 +777,413 @@ impl From<std::io::Error> for UserAuthError {
     }
 }
 
-fn spawn_saver(
-    text: Arc<RwLock<Text>>,
-    save_interval: Option<NonZeroU64>,
-    save_notify: Arc<Notify>,
-    path: PathBuf,
-) {
+/// Saves the text of `data` to `path` every `save_interval` of `config`, whenever its notifier
+/// is notified, and, if `save_debounce` is set, once no edits have been made for that long.
+/// `saved` is set to the amount of `save_requests` there were when the text was read, after every
+/// attempt at saving, whether it failed or not. `dirty` is cleared by every save that succeeds.
+/// Shrinking the buffers after a save is recorded by the recorder.
+/// Lines are ended with the line ending of the text
+fn spawn_saver(data: &BufferData, config: &ServerConfig, path: PathBuf) {
+    let text = Arc::clone(&data.text);
+    let sockets = Arc::clone(&data.sockets);
+    let save_notify = Arc::clone(&data.notifier);
+    let save_debounce = (config.save_debounce).map(|x| (x, Arc::clone(&data.edited)));
+    let save_requests = Arc::clone(&data.save_requests);
+    let saved = Arc::clone(&data.saved);
+    let dirty = Arc::clone(&data.dirty);
+    let recorder = data.recorder.clone();
+    let save_interval = config.save_interval;
     tokio::spawn(async move {
         loop {
+            let edits_paused = async {
+                let Some((debounce, edited)) = &save_debounce else {
+                    return future::pending().await;
+                };
+                edited.notified().await;
+                // Every edit restarts the timer
+                while timeout(*debounce, edited.notified()).await.is_ok() {}
+            };
             if let Some(x) = save_interval {
                 tokio::select!(
-                    () = sleep(Duration::from_secs(x.get())) => (),
+                    () = sleep(x) => (),
                     () = save_notify.notified() => {}
+                    () = edits_paused => (),
                 );
             } else {
-                save_notify.notified().await;
+                tokio::select!(
+                    () = save_notify.notified() => {}
+                    () = edits_paused => (),
+                );
             }
-            let file = OpenOptions::new().write(true).open(&path).unwrap();
-            let mut writer = BufWriter::new(file);
-            let buf_iter = text.read().await.bufs().map(|x| x.read().text.clone());
-            for elem in buf_iter {
-                writer.write_all(elem.as_bytes()).unwrap();
+            // Both are read before the text, so that edits made while saving aren't missed
+            let requests = save_requests.load(Ordering::Acquire);
+            dirty.store(false, Ordering::Release);
+            let (bufs, line_ending) = {
+                let text = text.read().await;
+                let bufs = (text.bufs())
+                    .map(|x| x.read().text().clone())
+                    .collect::<Vec<_>>();
+                (bufs, text.line_ending)
+            };
+            let chunks = bufs.iter().map(|x| line_ending.apply(x.as_str()));
+            if let Err(e) = write_atomically(&path, chunks) {
+                error!("Failed to save {path:?}: {e}");
+                dirty.store(true, Ordering::Release);
+                saved.send_replace(requests);
+                continue;
             }
             info!("Wrote to file");
+            {
+                let mut text = text.write().await;
+                text.shrink_buffers();
+                if let Some(recorder) = &recorder {
+                    recorder.record(&path, 0, Event::Shrunk);
+                }
+            }
+            for (clientnr, socket) in sockets.write().await.iter_mut() {
+                let result = async {
+                    socket.write_all(&S2C::<&Text>::Saved.serialize()).await?;
+                    socket.flush().await
+                }
+                .await;
+                if let Err(e) = result {
+                    warn!("Failed to tell {clientnr} about the save: {e}");
+                }
+            }
+            saved.send_replace(requests);
         }
     });
 }
 
+/// Accepts connections to `listener` and answers each with a status report, see `status_report`
+async fn serve_status(listener: TcpListener, files: Arc<RwLock<HashMap<PathBuf, BufferData>>>) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept a status connection: {e}");
+                continue;
+            }
+        };
+        let report = status_report(&*files.read().await).await;
+        let result = async {
+            stream.write_all(report.as_bytes()).await?;
+            stream.shutdown().await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to send the status: {e}");
+        }
+    }
+}
+
+/// A line for every open file with its path, the amount of clients connected to it and whether
+/// it has edits that haven't been saved, separated by tabs. The files are sorted by path
+async fn status_report(files: &HashMap<PathBuf, BufferData>) -> String {
+    let mut paths = files.keys().collect::<Vec<_>>();
+    paths.sort_unstable();
+    let mut ret = String::new();
+    for path in paths {
+        let data = &files[path];
+        let clients = data.sockets.read().await.len();
+        let dirty = if data.dirty.load(Ordering::Acquire) {
+            "dirty"
+        } else {
+            "clean"
+        };
+        ret.push_str(&format!("{}\t{clients}\t{dirty}\n", path.display()));
+    }
+    ret
+}
+
+/// Saves every open file with edits that haven't been saved, and waits for all the saves to be
+/// done
+async fn save_all(files: &RwLock<HashMap<PathBuf, BufferData>>) {
+    let pending = (files.read().await.iter())
+        .filter(|(_, data)| data.dirty.load(Ordering::Acquire))
+        .map(|(path, data)| {
+            // A save that is already going on could have read the text before the last edits, so
+            // only a save that starts after this request counts
+            let request = data.save_requests.fetch_add(1, Ordering::AcqRel) + 1;
+            data.notifier.notify_one();
+            (path.clone(), data.saved.subscribe(), request)
+        })
+        .collect::<Vec<_>>();
+    for (path, mut saved, request) in pending {
+        if saved.wait_for(|&x| x >= request).await.is_ok() {
+            info!("Saved {path:?} before shutting down");
+        }
+    }
+}
+
+/// Writes `chunks` to a temporary file next to `path`, which then replaces the file at `path`.
+/// This way the file is never left half written, even when the server dies while saving
+/// # Errors
+/// - `path` doesn't have a file name
+/// - Failing to write the temporary file or to move it to `path`
+fn write_atomically<I>(path: &Path, chunks: I) -> io::Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let Some(file_name) = path.file_name() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{path:?} doesn't point to a file"),
+        ));
+    };
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = (|| {
+        // Creating the file truncates anything left over from an earlier failed save
+        let file = File::create(&tmp_path)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        let mut writer = BufWriter::new(file);
+        for chunk in chunks {
+            writer.write_all(chunk.as_ref().as_bytes())?;
+        }
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// What every connection to the server shares
+struct Shared {
+    config: ServerConfig,
+    /// Whether a folder is served, out of which clients pick the files they open
+    serve_other: bool,
+    files: Arc<RwLock<HashMap<PathBuf, BufferData>>>,
+    client_limit: Arc<ClientLimit>,
+    recorder: Option<Arc<Recorder>>,
+    #[cfg(feature = "security")]
+    pool: SqlitePool,
+    #[cfg(feature = "security")]
+    limiter: AuthLimiter,
+}
+
 struct BufferData {
     text: Arc<RwLock<Text>>,
-    colors: Arc<RwLock<Vec<Color>>>,
+    /// The color of every connected client, by id
+    colors: Arc<RwLock<HashMap<usize, Color>>>,
     sockets: Arc<RwLock<HashMap<usize, OwnedWriteHalf>>>,
     notifier: Arc<Notify>,
-    counter: Arc<RwLock<AutoIncrementing>>,
+    /// Notified after every edit. Used to debounce saves
+    edited: Arc<Notify>,
+    /// Incremented for every save that has to wait for the text to be written
+    save_requests: Arc<AtomicUsize>,
+    /// The amount of `save_requests` the last attempt at saving was made after
+    saved: Arc<watch::Sender<usize>>,
+    /// Whether there are edits that haven't been saved yet
+    dirty: Arc<AtomicBool>,
+    recorder: Option<Arc<Recorder>>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        env,
+        fs::{self, File},
+        io::{self, BufReader},
+        path::PathBuf,
+        process,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use btep::{prelude::S2C, version::Features, Deserialize, BUFFER_SIZE};
+    use futures::{executor::block_on, join};
+    use text::Text;
+    use tokio::{
+        io::{duplex, AsyncReadExt},
+        sync::{watch, Notify, RwLock},
+    };
+
+    use super::{
+        inhabitants, normalize, resolve_open_path, save_all, send_full, status_report,
+        write_atomically, BufferData,
+    };
+
+    #[test]
+    fn save_shrunk_file() {
+        let path = env::temp_dir().join(format!("editor-shrunk-{}", process::id()));
+        fs::write(&path, "keep this\n".to_owned() + &"delete me\n".repeat(100)).unwrap();
+
+        let mut text =
+            Text::original_from_reader(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let id = text.add_client("andy");
+        text.client_mut(id).enter_insert((101, 0).into());
+        for _ in 0..1000 {
+            text.client_mut(id).backspace();
+        }
+        text.client_mut(id).exit_insert();
+
+        let bufs = text
+            .bufs()
+            .map(|x| x.read().text().clone())
+            .collect::<Vec<_>>();
+        write_atomically(&path, bufs.iter().map(|x| x.as_str())).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep this\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_missing_paths() {
+        let root = env::temp_dir().join(format!("editor-root-{}", process::id()));
+        fs::create_dir_all(root.join("exists")).unwrap();
+        let canonical = root.canonicalize().unwrap();
+
+        assert_eq!(
+            resolve_open_path(&root, "notes/2024/todo.md".as_ref()),
+            Some(canonical.join("notes/2024/todo.md"))
+        );
+        assert_eq!(
+            resolve_open_path(&root, "exists/new".as_ref()),
+            Some(canonical.join("exists/new"))
+        );
+        assert_eq!(resolve_open_path(&root, "missing/../../x".as_ref()), None);
+        assert_eq!(resolve_open_path(&root, "../x".as_ref()), None);
+        assert_eq!(resolve_open_path(&root, "../../etc/passwd".as_ref()), None);
+        assert_eq!(
+            resolve_open_path(&root, "missing/../x".as_ref()),
+            Some(canonical.join("x"))
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn folders_first() {
+        let root = env::temp_dir().join(format!("editor-listing-{}", process::id()));
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a"), "four").unwrap();
+        fs::write(root.join("c"), "").unwrap();
+
+        let listing = inhabitants(&root).unwrap();
+        let names = listing
+            .iter()
+            .map(|x| x.name.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["b", "a", "c"]);
+        assert_eq!(listing[1].size, 4);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("a/./b/../c".as_ref()), Some("a/c".into()));
+        assert_eq!(normalize("".as_ref()), Some("".into()));
+        assert_eq!(normalize("a/..".as_ref()), Some("".into()));
+        assert_eq!(normalize("a/../..".as_ref()), None);
+        assert_eq!(normalize("../../etc/passwd".as_ref()), None);
+        assert_eq!(normalize("/etc/passwd".as_ref()), None);
+    }
+
+    fn buffer_data() -> BufferData {
+        BufferData {
+            text: Arc::new(RwLock::new(Text::original_from_str(""))),
+            colors: Arc::new(RwLock::new(HashMap::new())),
+            sockets: Arc::new(RwLock::new(HashMap::new())),
+            notifier: Arc::new(Notify::new()),
+            edited: Arc::new(Notify::new()),
+            save_requests: Arc::new(AtomicUsize::new(0)),
+            saved: Arc::new(watch::Sender::new(0)),
+            dirty: Arc::new(AtomicBool::new(false)),
+            recorder: None,
+        }
+    }
+
+    #[test]
+    fn status() {
+        let files = HashMap::from([("b".into(), buffer_data()), ("a".into(), buffer_data())]);
+        files[&PathBuf::from("b")]
+            .dirty
+            .store(true, Ordering::Release);
+        assert_eq!(
+            block_on(status_report(&files)),
+            "a\t0\tclean\nb\t0\tdirty\n"
+        );
+    }
+
+    #[test]
+    fn save_all_waits_for_dirty_files() {
+        let dirty = buffer_data();
+        dirty.dirty.store(true, Ordering::Release);
+        // Stands in for the saver. A save from before the request doesn't count
+        dirty.saved.send_replace(0);
+        let saver = {
+            let (notifier, requests, saved) = (
+                Arc::clone(&dirty.notifier),
+                Arc::clone(&dirty.save_requests),
+                Arc::clone(&dirty.saved),
+            );
+            thread::spawn(move || {
+                block_on(notifier.notified());
+                saved.send_replace(requests.load(Ordering::Acquire));
+            })
+        };
+        // Nothing ever saves this one, so `save_all` would hang if it waited for it
+        let clean = buffer_data();
+        let clean_requests = Arc::clone(&clean.save_requests);
+        let files = RwLock::new(HashMap::from([
+            ("dirty".into(), dirty),
+            ("clean".into(), clean),
+        ]));
+        block_on(save_all(&files));
+        saver.join().unwrap();
+        assert_eq!(clean_requests.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn send_full_to_slow_reader() {
+        let mut text = Text::original_from_str(&"andy\n".repeat(BUFFER_SIZE));
+        text.add_client("andy");
+        text.client_mut(0).enter_insert((2, 0).into());
+        text.client_mut(0).push_str(&"son\n".repeat(BUFFER_SIZE));
+        // Far smaller than a chunk, so the server has to wait for the client over and over
+        let (mut server, mut client) = duplex(64);
+        let (sent, received) = block_on(async {
+            join!(
+                async {
+                    send_full(&mut server, &text, true, Features::supported().compression).await?;
+                    drop(server);
+                    Ok::<_, io::Error>(())
+                },
+                async {
+                    let mut received = Vec::new();
+                    // Only takes a few bytes at a time
+                    let mut buf = [0; 7];
+                    loop {
+                        match client.read(&mut buf).await? {
+                            0 => break,
+                            read => received.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    Ok::<_, io::Error>(received)
+                }
+            )
+        });
+        sent.unwrap();
+        let received = received.unwrap();
+        let S2C::Full((round_trip, read_only)) =
+            block_on(S2C::<Text>::deserialize(&mut &received[..])).unwrap()
+        else {
+            panic!("Expected a full message");
+        };
+        assert!(read_only);
+        assert_eq!(round_trip.contents(), text.contents());
+    }
 }
@@ -0,0 +1,73 @@
+//! Limits the amount of clients that can be connected at once
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Keeps track of how many clients are connected, so that there are never more than `max` at
+/// once
+#[derive(Debug)]
+pub(crate) struct ClientLimit {
+    connected: AtomicUsize,
+    /// None if any amount of clients may connect
+    max: Option<NonZeroUsize>,
+}
+
+/// The spot of a connected client. It is given back to the `ClientLimit` when this is dropped
+#[derive(Debug)]
+pub(crate) struct ClientSlot(Arc<ClientLimit>);
+
+impl ClientLimit {
+    pub(crate) const fn new(max: Option<NonZeroUsize>) -> Self {
+        Self {
+            connected: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    /// Takes up a spot for a new client
+    /// # Returns
+    /// None if the server is full
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<ClientSlot> {
+        self.connected
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |connected| {
+                self.max
+                    .is_none_or(|max| connected < max.get())
+                    .then_some(connected + 1)
+            })
+            .ok()?;
+        Some(ClientSlot(Arc::clone(self)))
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.connected.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{num::NonZeroUsize, sync::Arc};
+
+    use super::ClientLimit;
+
+    #[test]
+    fn client_limit() {
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(2)));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+
+        let unlimited = Arc::new(ClientLimit::new(None));
+        let slots = (0..100)
+            .map(|_| unlimited.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(slots.len(), 100);
+    }
+}
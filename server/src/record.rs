@@ -0,0 +1,285 @@
+//! Records everything that changes the open files, so that it can be replayed offline to
+//! reproduce desyncs
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use btep::{
+    c2s::{EnterInsert, C2S},
+    Deserialize, Serialize,
+};
+use futures::executor::block_on;
+use text::{client::Client, Text};
+use tokio::io::AsyncReadExt;
+
+/// Something that changed the text of a file
+#[derive(Debug, Clone)]
+pub(crate) enum Event {
+    /// The file was opened with the given contents
+    Opened(String),
+    /// A client with the given username connected
+    Joined(String),
+    /// The client disconnected
+    Left,
+    /// The client sent an action that was applied
+    Action(C2S),
+    /// The buffers were shrunk after a save
+    Shrunk,
+}
+
+/// A recorded event, together with when it happened, which file it happened to and the id of the
+/// client that caused it. The id is 0 for events that aren't caused by a client
+#[derive(Debug)]
+struct Entry {
+    /// Milliseconds since the unix epoch
+    timestamp: u64,
+    path: PathBuf,
+    id: usize,
+    event: Event,
+}
+
+/// Appends the events of every open file to a file
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    /// Opens `path` for recording. Events are appended to whatever was recorded before
+    /// # Errors
+    /// - Failing to open or create the file
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Records `event`. Every event is flushed right away, so that the recording is complete
+    /// even if the server dies
+    /// # Panics
+    /// - The writer got poisoned
+    pub(crate) fn record(&self, path: &Path, id: usize, event: Event) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| u64::try_from(x.as_millis()).unwrap_or(u64::MAX));
+        let entry = Entry {
+            timestamp,
+            path: path.to_path_buf(),
+            id,
+            event,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer
+            .write_all(&entry.serialize())
+            .and_then(|()| writer.flush())
+        {
+            tracing::error!("Failed to record {entry:?}: {e}");
+        }
+    }
+}
+
+impl Serialize for Entry {
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend(self.timestamp.to_be_bytes());
+        ret.extend(
+            self.path
+                .to_str()
+                .expect("The os doesn't use utf-8 paths for some reason")
+                .serialize(),
+        );
+        ret.extend(self.id.serialize());
+        match &self.event {
+            Event::Opened(contents) => {
+                ret.push(0);
+                ret.extend(contents.serialize());
+            }
+            Event::Joined(username) => {
+                ret.push(1);
+                ret.extend(username.serialize());
+            }
+            Event::Left => ret.push(2),
+            Event::Action(action) => {
+                ret.push(3);
+                ret.extend(action.serialize());
+            }
+            Event::Shrunk => ret.push(4),
+        }
+        ret
+    }
+}
+
+impl Deserialize for Entry {
+    async fn deserialize<T>(data: &mut T) -> io::Result<Self>
+    where
+        Self: Sized,
+        T: AsyncReadExt + Unpin + Send,
+    {
+        let timestamp = data.read_u64().await?;
+        let path = String::deserialize(data).await?.into();
+        let id = usize::deserialize(data).await?;
+        let event = match data.read_u8().await? {
+            0 => Event::Opened(String::deserialize(data).await?),
+            1 => Event::Joined(String::deserialize(data).await?),
+            2 => Event::Left,
+            3 => Event::Action(C2S::deserialize(data).await?),
+            4 => Event::Shrunk,
+            x => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{x} is not a recorded event"),
+                ))
+            }
+        };
+        Ok(Self {
+            timestamp,
+            path,
+            id,
+            event,
+        })
+    }
+}
+
+/// Applies an action that edits the text, or enters or exits insert mode, to `client`
+/// # Errors
+/// A description of why the action couldn't be applied
+pub(crate) fn apply_edit(client: &mut Client, action: &C2S) -> Result<(), &'static str> {
+    let pushed = match *action {
+        C2S::Char(_) | C2S::Backspace(_) | C2S::Enter | C2S::Str(_) if client.data.is_none() => {
+            return Err("tried to edit without being in insert mode");
+        }
+        C2S::Char(c) => client.push_char(c),
+        C2S::Backspace(swaps) => {
+            client.backspace_with_swaps(swaps);
+            true
+        }
+        C2S::Enter => client.push_char('\n'),
+        C2S::Str(ref text) => client.push_str(text),
+        C2S::EnterInsert(EnterInsert { id, offset }) => {
+            if !client.enter_insert_at(id, offset) {
+                return Err("tried to enter insert mode in an unknown piece");
+            }
+            true
+        }
+        C2S::ExitInsert => {
+            client.exit_insert();
+            true
+        }
+        _ => return Err("sent an action that doesn't edit the text"),
+    };
+    if !pushed {
+        return Err("ran out of ids for new pieces");
+    }
+    Ok(())
+}
+
+/// Replays a recording made with `--record`, starting every file from the contents it was
+/// opened with
+/// # Returns
+/// The lines of every recorded file at the end of the recording, sorted by path
+/// # Errors
+/// - Failing to read the recording
+/// - The recording is malformed, or replaying it doesn't end up with the same clients as the
+///   server had
+pub fn replay(path: &Path) -> io::Result<Vec<(PathBuf, Vec<String>)>> {
+    let data = fs::read(path)?;
+    let mut data = &data[..];
+    let mut texts = HashMap::new();
+    while !data.is_empty() {
+        let entry = block_on(Entry::deserialize(&mut data))?;
+        let desync = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{what} for {:?} at {}", entry.path, entry.timestamp),
+            )
+        };
+        if let Event::Opened(contents) = &entry.event {
+            texts.insert(entry.path.clone(), Text::original_from_str(contents));
+            continue;
+        }
+        let Some(text) = texts.get_mut(&entry.path) else {
+            return Err(desync("An event happened before the file was opened"));
+        };
+        if entry.id >= text.clients().len() && !matches!(entry.event, Event::Joined(_)) {
+            return Err(desync("An unknown client caused an event"));
+        }
+        match &entry.event {
+            Event::Opened(_) => unreachable!("Handled above"),
+            Event::Joined(username) => {
+                if text.add_client(username) != entry.id {
+                    return Err(desync("A client joined with a different id"));
+                }
+            }
+            Event::Left => text.remove_client(entry.id),
+            Event::Action(action) => {
+                apply_edit(text.client_mut(entry.id), action).map_err(desync)?;
+            }
+            Event::Shrunk => text.shrink_buffers(),
+        }
+    }
+    let mut ret = texts
+        .into_iter()
+        .map(|(path, text)| (path, text.lines().collect()))
+        .collect::<Vec<_>>();
+    ret.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::Path, process};
+
+    use btep::c2s::{EnterInsert, C2S};
+    use text::Text;
+
+    use super::{apply_edit, replay, Event, Recorder};
+
+    #[test]
+    fn record_and_replay() {
+        let path = env::temp_dir().join(format!("editor-record-{}", process::id()));
+        let file = Path::new("a");
+        let recorder = Recorder::create(&path).unwrap();
+
+        let mut text = Text::original_from_str("ab\ncd");
+        recorder.record(file, 0, Event::Opened(text.contents()));
+        for username in ["andy", "son"] {
+            let id = text.add_client(username);
+            recorder.record(file, id, Event::Joined(username.to_string()));
+        }
+        for (id, pos, to_push) in [(0, (1, 1), "x\n"), (1, (0, 0), "y"), (0, (0, 2), "z")] {
+            let (offset, piece) = text.client_mut(id).enter_insert(pos.into()).unwrap();
+            let actions = [
+                C2S::EnterInsert(EnterInsert { id: piece, offset }),
+                C2S::Str(to_push.to_string()),
+                C2S::Backspace(0),
+                C2S::ExitInsert,
+            ];
+            for action in actions {
+                // The client already entered insert mode
+                if !matches!(action, C2S::EnterInsert(_)) {
+                    apply_edit(text.client_mut(id), &action).unwrap();
+                }
+                recorder.record(file, id, Event::Action(action));
+            }
+            if id == 1 {
+                text.shrink_buffers();
+                recorder.record(file, 0, Event::Shrunk);
+            }
+        }
+        text.remove_client(1);
+        recorder.record(file, 1, Event::Left);
+        drop(recorder);
+
+        let replayed = replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].0, file);
+        assert_eq!(replayed[0].1, text.lines().collect::<Vec<_>>());
+    }
+}
@@ -2,8 +2,82 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use btep::s2c::Inhabitant;
 use sqlx::SqlitePool;
-use std::str;
+use std::{
+    cmp,
+    collections::HashMap,
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    str,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The longest an address has to wait between attempts before it gets locked out
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// How long an address is locked out for after too many failed attempts
+const LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Keeps track of failed authorization attempts per address. Every failed attempt doubles the
+/// time the address has to wait before it can try again, and after `lockout_threshold` failed
+/// attempts in a row it is locked out for `LOCKOUT`
+#[derive(Debug)]
+pub(crate) struct AuthLimiter {
+    /// The amount of failed attempts in a row and when the last one happened
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+    lockout_threshold: u32,
+}
+
+impl AuthLimiter {
+    pub(crate) fn new(lockout_threshold: u32) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            lockout_threshold,
+        }
+    }
+
+    /// How long `ip` has to wait before it is allowed to try to authorize again
+    /// # Returns
+    /// None if it can try right away
+    pub(crate) fn wait_time(&self, ip: IpAddr) -> Option<Duration> {
+        self.wait_time_at(ip, Instant::now())
+    }
+
+    fn wait_time_at(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let (count, last) = *self.failures.lock().unwrap().get(&ip)?;
+        let wait = if count >= self.lockout_threshold {
+            LOCKOUT
+        } else {
+            cmp::min(
+                Duration::from_secs(1) * 2u32.saturating_pow(count - 1),
+                MAX_BACKOFF,
+            )
+        };
+        (last + wait)
+            .checked_duration_since(now)
+            .filter(|x| !x.is_zero())
+    }
+
+    /// Registers a failed attempt from `ip`
+    pub(crate) fn failed(&self, ip: IpAddr) {
+        self.failed_at(ip, Instant::now());
+    }
+
+    fn failed_at(&self, ip: IpAddr, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        // Addresses that haven't failed in a long time don't need to be remembered
+        failures.retain(|_, (_, last)| now.duration_since(*last) < LOCKOUT);
+        let entry = failures.entry(ip).or_insert((0, now));
+        *entry = (entry.0.saturating_add(1), now);
+    }
+
+    /// Forgets the failed attempts of `ip` after it authorized successfully
+    pub(crate) fn succeeded(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+    }
+}
 
 pub(crate) async fn auth_check(username: &str, password: &str, pool: &SqlitePool) -> Option<()> {
     let phc: (String,) = sqlx::query_as("SELECT phc FROM users WHERE username=$1")
@@ -31,6 +105,89 @@ pub(crate) async fn create_tables(pool: &SqlitePool) -> Result<(), sqlx::Error>
     )
     .execute(pool)
     .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS access (
+            id INTEGER PRIMARY KEY NOT NULL,
+            username TEXT NOT NULL REFERENCES users(username),
+            path TEXT NOT NULL,
+            UNIQUE(username, path)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The paths, relative to the served folder, that `username` has been granted access to
+/// # Returns
+/// None if the user hasn't been granted access to anything specific, in which case they can open
+/// everything
+/// # Errors
+/// - Failing to read from the database
+pub(crate) async fn granted_paths(
+    username: &str,
+    pool: &SqlitePool,
+) -> Result<Option<Vec<PathBuf>>, sqlx::Error> {
+    let paths: Vec<(String,)> = sqlx::query_as("SELECT path FROM access WHERE username=$1")
+        .bind(username)
+        .fetch_all(pool)
+        .await?;
+    Ok((!paths.is_empty()).then(|| paths.into_iter().map(|(x,)| normalize(&x)).collect()))
+}
+
+/// Removes the `.` components of `path` and resolves its `..` components, without looking at the
+/// file system. This is the form granted paths are compared in
+fn normalize(path: &str) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if ret.file_name().is_some() => {
+                ret.pop();
+            }
+            component => ret.push(component),
+        }
+    }
+    ret
+}
+
+/// Whether a user that has been granted access to `granted` may open `path`. Folders leading up
+/// to a granted path can be opened as well, so that the user can navigate to it
+pub(crate) fn may_open(granted: &[PathBuf], path: &Path) -> bool {
+    granted
+        .iter()
+        .any(|x| path.starts_with(x) || x.starts_with(path))
+}
+
+/// Removes the entries of `folder` that a user that has been granted access to `granted` may not
+/// open. Only the folders leading up to a granted path are kept, not everything next to them
+pub(crate) fn visible_inhabitants(
+    granted: &[PathBuf],
+    folder: &Path,
+    inhabitants: &mut Vec<Inhabitant>,
+) {
+    inhabitants.retain(|x| may_open(granted, &folder.join(&x.name)));
+}
+
+/// Lets `username` open `path`, which is relative to the served folder. Once a user has been
+/// granted access to a path, they can only open the paths they have been granted access to.
+/// `path` is stored normalized, so `./dir/file` and `dir/file` are the same path
+/// # Errors
+/// - The user already has access to the path
+/// - Failing to write to the database
+pub async fn grant_access(
+    pool: &SqlitePool,
+    username: &str,
+    path: &str,
+) -> Result<(), sqlx::Error> {
+    create_tables(pool).await?;
+    sqlx::query("INSERT INTO access (username, path) VALUES ($1, $2)")
+        .bind(username)
+        .bind(normalize(path).to_string_lossy())
+        .execute(pool)
+        .await?;
     Ok(())
 }
 
@@ -51,3 +208,72 @@ pub async fn add_user(
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    use std::{ffi::OsString, path::PathBuf};
+
+    use btep::s2c::Inhabitant;
+
+    use super::{may_open, normalize, visible_inhabitants, AuthLimiter, LOCKOUT};
+
+    #[test]
+    fn normalized_grants() {
+        assert_eq!(normalize("./dir/file"), PathBuf::from("dir/file"));
+        assert_eq!(normalize("dir/./other/../file/"), PathBuf::from("dir/file"));
+        let granted = [normalize("./dir/file")];
+        assert!(may_open(&granted, &PathBuf::from("dir/file")));
+        assert!(may_open(&granted, &PathBuf::from("dir")));
+        assert!(!may_open(&granted, &PathBuf::from("dir/other")));
+    }
+
+    #[test]
+    fn only_granted_inhabitants() {
+        let inhabitant = |name: &str, is_folder| Inhabitant {
+            name: OsString::from(name),
+            is_folder,
+            size: 0,
+        };
+        let granted = [PathBuf::from("dir/file"), PathBuf::from("dir/sub")];
+        let mut inhabitants = vec![
+            inhabitant("sub", true),
+            inhabitant("unrelated", true),
+            inhabitant("file", false),
+            inhabitant("secret", false),
+        ];
+        visible_inhabitants(&granted, &PathBuf::from("dir"), &mut inhabitants);
+        let names = inhabitants
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["sub", "file"]);
+    }
+
+    #[test]
+    fn backoff() {
+        let limiter = AuthLimiter::new(3);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::BROADCAST);
+        let now = Instant::now();
+        assert_eq!(limiter.wait_time_at(ip, now), None);
+
+        limiter.failed_at(ip, now);
+        assert_eq!(limiter.wait_time_at(ip, now), Some(Duration::from_secs(1)));
+        assert_eq!(limiter.wait_time_at(ip, now + Duration::from_secs(1)), None);
+        assert_eq!(limiter.wait_time_at(other, now), None);
+
+        limiter.failed_at(ip, now);
+        assert_eq!(limiter.wait_time_at(ip, now), Some(Duration::from_secs(2)));
+
+        limiter.failed_at(ip, now);
+        assert_eq!(limiter.wait_time_at(ip, now), Some(LOCKOUT));
+
+        limiter.succeeded(ip);
+        assert_eq!(limiter.wait_time_at(ip, now), None);
+    }
+}
@@ -1,13 +1,16 @@
 //! Implements a client type which can be used to insert data into the piece table
 use std::{
-    collections::linked_list::CursorMut,
     fmt::Debug,
     sync::{Arc, RwLock},
 };
 
 use append_only_str::AppendOnlyStr;
-use piece_table::{table::InnerTable, Piece, TableElem};
-use utils::other::{AutoIncrementing, CursorPos};
+use piece_table::{
+    table::InnerTable,
+    tree::{CursorMut, Tree},
+    Piece, TableElem,
+};
+use utils::other::CursorPos;
 
 /// A client which can input text into a `Piece`
 #[derive(Debug)]
@@ -16,13 +19,14 @@ pub struct Client {
     pub piece: Arc<RwLock<Piece>>,
     /// The current buffer we are editing
     pub buffer: Arc<RwLock<AppendOnlyStr>>,
-    /// A conuter used to generate unique ids
-    pub(crate) id_counter: Arc<RwLock<AutoIncrementing>>,
     /// The id of the buffer this client is editing
     pub bufnr: usize,
     /// None -> You are currently not in insert mode
     pub data: Option<Insertdata>,
     pub username: String,
+    /// Whether the client is currently connected. Disconnected clients keep their buffer so
+    /// that it can be reused if they reconnect
+    pub connected: bool,
 }
 
 /// Stores data related to being in insert mode
@@ -45,15 +49,14 @@ impl Client {
         piece: Arc<RwLock<Piece>>,
         buffer: Arc<RwLock<AppendOnlyStr>>,
         bufnr: usize,
-        id_counter: Arc<RwLock<AutoIncrementing>>,
     ) -> Self {
         Self {
             username: username.to_string(),
             piece,
             buffer,
             bufnr,
-            id_counter,
             data: None,
+            connected: true,
         }
     }
 
@@ -73,32 +76,31 @@ impl Client {
     /// this function will probably only panic when there are locking errors though
     pub fn backspace(&mut self) -> (Option<char>, usize) {
         let binding = self.data.as_mut().unwrap();
-        let slice = binding.slice.read();
+        let current = binding.slice.clone();
+        // Even if nothing gets deleted, so that every client creates a new piece on the next
+        // insertion, and the ids of the pieces stay the same between them
         binding.has_deleted = true;
-        let (deleted, swaps) = if slice.text.is_empty() {
-            let binding = self
-                .piece
-                .write()
-                .unwrap()
-                .piece_table
-                .write_full()
-                .unwrap();
-
-            let mut binding2 = binding.write();
-            let mut cursor = binding2.cursor_front_mut();
-            while cursor.current().unwrap().read().text != slice.text {
-                cursor.move_next();
-            }
-            drop(slice);
-            self.delete_from_cursor(&mut cursor)
-        } else {
+        let slice = binding.slice.read();
+        if !slice.text().is_empty() {
             drop(slice);
-            (Self::do_backspace(&binding.slice), 0)
-        };
+            return (Self::do_backspace(&binding.slice), 0);
+        }
+        let binding = self
+            .piece
+            .write()
+            .unwrap()
+            .piece_table
+            .write_full()
+            .unwrap();
 
-        (deleted, swaps)
+        let mut binding2 = binding.write();
+        drop(slice);
+        let mut cursor = cursor_at(&mut binding2, &current);
+        self.delete_from_cursor(&mut cursor)
     }
 
+    /// Moves the empty pieces in front of the cursor behind it, and deletes the last character
+    /// of the first piece that isn't empty. The cursor has to be at the piece of this client
     fn delete_from_cursor(
         &self,
         cursor: &mut CursorMut<'_, InnerTable<TableElem>>,
@@ -106,7 +108,10 @@ impl Client {
         let mut swap_count = 0;
         loop {
             cursor.move_prev();
-            if cursor.current().unwrap().read().text.is_empty() {
+            if cursor
+                .current()
+                .is_some_and(|current| current.read().text().is_empty())
+            {
                 swap_count += 1;
                 let curr = cursor.remove_current().unwrap();
                 cursor.insert_after(curr);
@@ -130,61 +135,36 @@ impl Client {
 
     fn do_backspace(binding: &InnerTable<TableElem>) -> Option<char> {
         let slice = &mut binding.write().unwrap();
-        let ret = slice.text.chars().last();
-        debug_assert!(!slice.text.is_empty());
-        slice.text = slice
-            .text
-            .subslice(0..slice.text.len() - slice.text.chars().last().unwrap().len_utf8())
+        let ret = slice.text().chars().last();
+        debug_assert!(!slice.text().is_empty());
+        let text = slice
+            .text()
+            .subslice(0..slice.text().len() - slice.text().chars().last().unwrap().len_utf8())
             .unwrap();
+        slice.set_text(text);
         ret
     }
 
-    /// Does backspace with a predetermined amonut of swaps
+    /// Does a backspace sent by another client, which made `swaps` swaps doing it.
+    /// The same character gets deleted as by `backspace`, so `swaps` is only used to check that
+    /// the clients agree
     /// # Panics
-    /// - More swaps were required (in debug)
+    /// - A different amount of swaps was required (in debug)
     /// - poisoning
     pub fn backspace_with_swaps(&mut self, swaps: usize) -> Option<char> {
-        let (ret, swaps) = if swaps == 0 {
-            self.backspace()
-        } else {
-            let binding = self.data.as_mut().unwrap();
-            let slice = binding.slice.read();
-
-            let binding = self
-                .piece
-                .write()
-                .unwrap()
-                .piece_table
-                .write_full()
-                .unwrap();
-
-            let mut binding2 = binding.write();
-            let mut cursor = binding2.cursor_front_mut();
-            while cursor.current().unwrap().read().text != slice.text {
-                cursor.move_next();
-            }
-            for _ in 0..swaps {
-                cursor.move_prev();
-                if cursor.current().unwrap().read().text.is_empty() {
-                    let curr = cursor.remove_current().unwrap();
-                    cursor.insert_after(curr);
-                } else {
-                    break;
-                }
-            }
-            drop(slice);
-            self.delete_from_cursor(&mut cursor)
-        };
-        debug_assert_eq!(swaps, 0);
+        let (ret, made) = self.backspace();
+        debug_assert_eq!(made, swaps, "The clients disagree on the pieces");
         ret
     }
 
     /// appends a char at the current location
+    /// # Returns
+    /// Whether the char was pushed, see `push_str`
     /// # Panics
     /// - Insert mode isn't entered
     /// - We can't read our own buffer. This is most likely this crates fault
-    pub fn push_char(&mut self, to_push: char) {
-        self.push_str(&to_push.to_string());
+    pub fn push_char(&mut self, to_push: char) -> bool {
+        self.push_str(&to_push.to_string())
     }
 
     /// Exits insert mode
@@ -198,39 +178,41 @@ impl Client {
     }
 
     /// appends a string at the current location
+    /// # Returns
+    /// Whether the string was pushed. Pushing after deleting in front of the insertion needs a
+    /// new piece, which fails once the client has run out of ids for them
     /// # Panics
     /// - Insert mode isn't entered
     /// - We can't read our own buffer. This is most likely this crates fault
-    pub fn push_str(&mut self, to_push: &str) {
+    pub fn push_str(&mut self, to_push: &str) -> bool {
         // println!("{:?}", self.buffer);
         assert!(
             self.data.is_some(),
             "You can only push stuff after entering insert mode"
         );
         if to_push.is_empty() {
-            return;
+            return true;
         }
 
         if self.data.as_ref().unwrap().has_deleted {
-            let slice = &self.data.as_mut().unwrap().slice;
+            let slice = &self.data.as_ref().unwrap().slice;
 
-            let client_count = self.piece.read().unwrap().buffers.clients.len();
+            let Some(id) = self.piece.read().unwrap().next_id(self.bufnr) else {
+                return false;
+            };
             let binding = &self.piece.write().unwrap().piece_table;
             let binding2 = binding.write_full().unwrap();
             let mut binding3 = binding2.write();
-            let mut cursor = binding3.cursor_front_mut();
-            while cursor.current().unwrap().read().text != slice.read().text {
-                cursor.move_next();
-            }
+            let mut cursor = cursor_at(&mut binding3, slice);
             if let Some(buf) = cursor.current().unwrap().write().unwrap().buf.as_mut() {
                 buf.1 = false;
             }
             cursor.insert_after(InnerTable::new(
-                TableElem {
-                    buf: Some((self.bufnr, true)),
-                    text: self.buffer.read().unwrap().str_slice_end(),
-                    id: self.id_counter.write().unwrap().get() * client_count + self.bufnr,
-                },
+                TableElem::new(
+                    Some((self.bufnr, true)),
+                    id,
+                    self.buffer.read().unwrap().str_slice_end(),
+                ),
                 binding.state(),
             ));
             self.data = Some(Insertdata {
@@ -242,36 +224,86 @@ impl Client {
         let slice = &self.data.as_mut().unwrap().slice;
 
         self.buffer.write().unwrap().push_str(to_push);
-        let a = &mut slice.write().unwrap().text;
-        *a = self.buffer.read().unwrap().str_slice(a.start()..).unwrap();
+        let a = &mut slice.write().unwrap();
+        let text = self
+            .buffer
+            .read()
+            .unwrap()
+            .str_slice(a.text().start()..)
+            .unwrap();
+        a.set_text(text);
+        true
     }
 
     /// Allows for insertion.
-    /// Takes an `InnerTable` as an argument as to where the text should be inserted
+    /// Takes an `InnerTable` as an argument as to where the text should be inserted.
+    /// `pos` is moved onto the text first, so a stale position can't insert out of bounds.
+    /// If the client already was in insert mode, that is exited first
     /// # Return
-    /// It returns the id of the buffer that got split
+    /// - The offset into the piece that got split. None when appending to the end of the text
+    /// - The id of the piece that got split, or the id of the new piece when appending
+    ///
+    /// Passing these to `enter_insert_at` enters insert mode at the same place.
+    /// None if the client has run out of ids for new pieces, in which case insert mode isn't
+    /// entered
     /// # Panics
     /// probably only failed locks
-    pub fn enter_insert(&mut self, pos: CursorPos) -> (Option<usize>, usize) {
-        let (offset, inner_table) = self.piece.write().unwrap().insert_at(pos, self.bufnr);
-        // println!("{inner_table:?}");
-        let idx = inner_table.read().id;
-        // FIXME: The reason this is here is to fix a stupid bug. I should use std::pin::Pin to fix
-        // this.
-        // The issue is that entering insert mode and immediately exiting insert mode results in
-        // two identical slices. They will have the same start, end **and** pointers. We push an
-        // arbitrary string here to offset this pointer.
-        self.buffer.write().unwrap().push_str("\0");
-        inner_table.write().unwrap().text = self
-            .buffer
-            .read()
+    pub fn enter_insert(&mut self, pos: CursorPos) -> Option<(Option<usize>, usize)> {
+        self.exit_insert();
+        let pos = self.piece.read().unwrap().clamp(pos);
+        let (split, inner_table) = self.piece.write().unwrap().insert_at(pos, self.bufnr)?;
+        let ret = split.map_or_else(
+            || (None, inner_table.read().id),
+            |(id, offset)| (Some(offset), id),
+        );
+        self.start_insert(inner_table);
+        Some(ret)
+    }
+
+    /// Enters insert mode at an offset into the piece with the id `id`, like it was returned by
+    /// `enter_insert`. If the client already was in insert mode, that is exited first
+    /// # Return
+    /// Whether insert mode was entered. This fails if the piece doesn't exist, the offset isn't
+    /// valid within it or the client has run out of ids for new pieces
+    /// # Panics
+    /// probably only failed locks
+    pub fn enter_insert_at(&mut self, id: usize, offset: Option<usize>) -> bool {
+        self.exit_insert();
+        let Some(inner_table) = self
+            .piece
+            .write()
             .unwrap()
-            .str_slice(self.buffer.read().unwrap().len()..)
-            .unwrap();
+            .insert_at_id(offset.map(|offset| (id, offset)), self.bufnr)
+        else {
+            return false;
+        };
+        self.start_insert(inner_table);
+        true
+    }
+
+    /// Starts editing the newly inserted `inner_table`
+    fn start_insert(&mut self, inner_table: InnerTable<TableElem>) {
+        inner_table
+            .write()
+            .unwrap()
+            .set_text(self.buffer.read().unwrap().str_slice_end());
         self.data = Some(Insertdata {
             slice: inner_table,
             has_deleted: false,
         });
-        (offset, idx)
     }
 }
+
+/// Creates a cursor at `slice`. It is found by the id of its element, rather than by going
+/// through the table
+/// # Panics
+/// - The slice isn't in the table
+fn cursor_at<'a>(
+    list: &'a mut Tree<InnerTable<TableElem>>,
+    slice: &InnerTable<TableElem>,
+) -> CursorMut<'a, InnerTable<TableElem>> {
+    let position = list
+        .position(slice)
+        .expect("The slice being edited should be in the table");
+    list.cursor_at_mut(position)
+}
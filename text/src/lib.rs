@@ -1,18 +1,20 @@
 //! A crate above the piece table for handling actual text with more helper functions
-#![feature(linked_list_cursors)]
 
 use std::{
-    io::{self, Read},
+    io::{self, Read, Write},
     sync::{Arc, RwLock},
 };
 
 use append_only_str::AppendOnlyStr;
-use btep::{Deserialize, Serialize};
+use btep::{s2c::FullWriter, Deserialize, Serialize};
 use client::{Client, Insertdata};
+pub use line_ending::LineEnding;
 use piece_table::{table::InnerTable, Piece, TableElem};
-use tokio::io::AsyncReadExt;
-use utils::other::AutoIncrementing;
+use tokio::io::{AsyncReadExt, AsyncWrite};
+use utils::other::{AutoIncrementing, CursorPos};
 pub mod client;
+pub mod line_ending;
+pub mod snapshot;
 
 /// A wrapper around a piece table.
 /// It creates wrapper methods and adds support for multiple clients to interface more easily with
@@ -21,30 +23,85 @@ pub mod client;
 pub struct Text {
     pub table: Arc<RwLock<Piece>>,
     clients: Vec<Client>,
+    /// What lines get ended with when the text is written to a file. This is the line ending used
+    /// by most lines of the file this was read from, unless it gets overridden, and it is sent
+    /// along with the text
+    pub line_ending: LineEnding,
 }
 
 impl Serialize for &Text {
     fn serialize(&self) -> Vec<u8> {
         let mut ret = Vec::new();
-        let to_extend = (&*self.table.read().unwrap()).serialize();
-        ret.extend((to_extend.len() as u64).to_be_bytes());
-        ret.extend(to_extend);
-
-        ret.extend((self.clients.len() as u64).to_be_bytes());
-
-        ret.extend(self.clients.iter().flat_map(|x| {
-            let mut ret = Vec::new();
-            ret.extend(x.username.serialize());
-            if let Some(Insertdata { slice, .. }) = &x.data {
-                ret.push(1);
-                ret.extend((slice.read().text.start() as u64).to_be_bytes());
-                ret.extend((slice.read().text.end() as u64).to_be_bytes());
+        self.serialize_into(&mut ret)
+            .expect("Writing to a Vec can't fail");
+        ret
+    }
+
+    fn serialize_into<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        (&*self.table.read().unwrap()).serialize_into(writer)?;
+        self.serialize_clients(writer)
+    }
+}
+
+impl Text {
+    /// Writes the same bytes as serializing it to `writer`, which sends them off a chunk at a
+    /// time. The text of the buffers is sliced up front and the pieces are taken out one at a
+    /// time, so no lock on the piece table is held while waiting on the writer
+    /// # Errors
+    /// - Failing to write to the writer
+    /// # Panics
+    /// The piece table got poisoned
+    pub async fn write_full<W>(&self, writer: &mut FullWriter<'_, W>) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let (buffers, count, pieces) = {
+            let piece = self.table.read().unwrap();
+            let count = piece.piece_table.read_full().unwrap().read().len();
+            (piece.buffer_parts(), count, piece.piece_table.iter_from(0))
+        };
+        for (prefix, text) in buffers {
+            writer.write_all(&prefix).await?;
+            writer.write_all(text.as_bytes()).await?;
+        }
+        writer.write_all(&[0xff]).await?;
+        writer.write_all(&(count as u64).to_be_bytes()).await?;
+        for piece in pieces.take(count) {
+            let piece = piece.read().serialize();
+            writer.write_all(&piece).await?;
+        }
+
+        let mut clients = Vec::new();
+        self.serialize_clients(&mut clients)?;
+        writer.write_all(&clients).await
+    }
+
+    /// Writes everything that comes after the piece table when serializing
+    fn serialize_clients<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_all(&(self.clients.len() as u64).to_be_bytes())?;
+
+        for x in &self.clients {
+            x.username.serialize_into(writer)?;
+            x.connected.serialize_into(writer)?;
+            if let Some(Insertdata {
+                slice, has_deleted, ..
+            }) = &x.data
+            {
+                writer.write_all(&[1])?;
+                slice.read().id.serialize_into(writer)?;
+                slice.read().offset.serialize_into(writer)?;
+                has_deleted.serialize_into(writer)?;
             } else {
-                ret.push(0);
+                writer.write_all(&[0])?;
             }
-            ret
-        }));
-        ret
+        }
+        self.line_ending.serialize_into(writer)
     }
 }
 
@@ -53,27 +110,31 @@ impl Deserialize for Text {
     where
         T: AsyncReadExt + Unpin + Send,
     {
-        let _len = data.read_u64().await? as usize;
-        // NOTE: We should probably limit the length of data here
         let piece = Piece::deserialize(data).await?;
-
         let arced = Arc::new(RwLock::new(piece));
 
+        // Checked before anything is allocated for the clients, as the count can't be trusted
         let client_count = data.read_u64().await? as usize;
-        // assert_eq!(client_count, 0);
+        if client_count > arced.read().unwrap().buffers.clients.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "There are more clients than client buffers",
+            ));
+        }
 
-        let mut clients = Vec::with_capacity(client_count as usize);
+        let mut clients = Vec::with_capacity(client_count);
         for counter in 0..client_count {
             let username = String::deserialize(data).await?;
+            let connected = bool::deserialize(data).await?;
             if data.read_u8().await? == 1 {
-                let start = data.read_u64().await? as usize;
-                let end = data.read_u64().await? as usize;
+                let id = usize::deserialize(data).await?;
+                let offset = usize::deserialize(data).await?;
+                let has_deleted = bool::deserialize(data).await?;
 
                 clients.push(Client {
                     username,
                     piece: Arc::clone(&arced),
                     buffer: Arc::clone(&arced.read().unwrap().buffers.clients[counter].1),
-                    id_counter: Arc::clone(&arced.read().unwrap().buffers.clients[counter].0),
                     data: arced
                         .read()
                         .unwrap()
@@ -87,40 +148,46 @@ impl Deserialize for Text {
                             if inner.buf.map(|(x, _)| x) != Some(counter) {
                                 return false;
                             };
-                            inner.text.start() == start && inner.text.end() == end
+                            inner.id == id && inner.offset == offset
                         })
                         .cloned()
-                        .map(|slice| Insertdata {
-                            slice,
-                            has_deleted: false,
-                        }),
+                        .map(|slice| Insertdata { slice, has_deleted }),
                     bufnr: counter,
+                    connected,
                 });
             } else {
                 clients.push(Client {
                     username,
                     piece: Arc::clone(&arced),
                     buffer: Arc::clone(&arced.read().unwrap().buffers.clients[counter].1),
-                    id_counter: Arc::clone(&arced.read().unwrap().buffers.clients[counter].0),
                     data: None,
                     bufnr: counter,
+                    connected,
                 });
             }
         }
         Ok(Self {
             table: arced,
             clients,
+            line_ending: LineEnding::deserialize(data).await?,
         })
     }
 }
 
 impl Text {
     /// Creates a new piece table with the orginal buffer filled in from the reader.
+    /// `\r\n` is turned into `\n`, and the line ending that was used the most is stored in
+    /// `line_ending`
     /// # Errors
     /// - The reader failed to read
-    pub fn original_from_reader<T: Read>(read: T) -> io::Result<Self> {
-        let piece = Piece::original_from_reader(read)?;
-        Ok(Self::with_piece(piece))
+    /// - The data isn't valid utf-8
+    pub fn original_from_reader<T: Read>(mut read: T) -> io::Result<Self> {
+        let mut original = String::new();
+        read.read_to_string(&mut original)?;
+        let line_ending = LineEnding::detect(&original);
+        let mut ret = Self::original_from_str(&original.replace("\r\n", "\n"));
+        ret.line_ending = line_ending;
+        Ok(ret)
     }
 
     /// Creates a new piece table with the orginal buffer filled in from the reader.
@@ -138,6 +205,7 @@ impl Text {
         Self {
             table: Arc::new(RwLock::new(piece)),
             clients: Vec::new(),
+            line_ending: LineEnding::default(),
         }
     }
 
@@ -147,13 +215,41 @@ impl Text {
         Self {
             table: Arc::new(RwLock::new(Piece::new())),
             clients: Vec::new(),
+            line_ending: LineEnding::default(),
         }
     }
 
-    /// Creates a `Client` with an attached buffer
+    /// Creates a `Client` with an attached buffer.
+    /// A disconnected client gets reused, together with its buffer, so that the amount of clients
+    /// doesn't grow with every connection. One with the same username is preferred. The pieces
+    /// created by the old client stay valid, as the new one keeps on appending to its buffer and
+    /// counting its ids. Others have to be told the returned id, see `add_client_at`
     /// # Panics
     /// probably only when failing to lock the buffers
     pub fn add_client(&mut self, username: &str) -> usize {
+        let idx = (self.clients.iter())
+            .position(|x| !x.connected && x.username == username)
+            .or_else(|| self.clients.iter().position(|x| !x.connected))
+            .unwrap_or(self.clients.len());
+        self.add_client_at(idx, username);
+        idx
+    }
+
+    /// Connects the client with the id `idx`, which is how the server tells everyone else about
+    /// the client it picked in `add_client`. `idx` either has to be a disconnected client, which
+    /// then gets reused, or the id one past the last client, which creates a new one
+    /// # Panics
+    /// - `idx` belongs to a connected client
+    /// - `idx` is larger than the amount of clients
+    /// - failing to lock the buffers
+    pub fn add_client_at(&mut self, idx: usize, username: &str) {
+        if let Some(client) = self.clients.get_mut(idx) {
+            assert!(!client.connected, "Client {idx} is already connected");
+            client.connected = true;
+            username.clone_into(&mut client.username);
+            return;
+        }
+        assert_eq!(idx, self.clients.len(), "Client {idx} skips an id");
         let buf = Arc::new(RwLock::new(AppendOnlyStr::new()));
         let counter = Arc::new(RwLock::new(AutoIncrementing::new()));
         self.table
@@ -161,15 +257,21 @@ impl Text {
             .unwrap()
             .buffers
             .clients
-            .push((Arc::clone(&counter), Arc::clone(&buf)));
+            .push((counter, Arc::clone(&buf)));
         self.clients.push(Client::new(
             username,
             Arc::clone(&self.table),
             buf,
             self.clients.len(),
-            counter,
         ));
-        self.clients.len() - 1
+    }
+
+    /// Marks a client as disconnected, making it leave insert mode.
+    /// Its buffer is kept around so that its slot can be reused by `add_client`
+    pub fn remove_client(&mut self, idx: usize) {
+        let client = &mut self.clients[idx];
+        client.exit_insert();
+        client.connected = false;
     }
 
     /// Creates an iterator over the lines in the buffer
@@ -179,6 +281,38 @@ impl Text {
         self.table.read().unwrap().lines()
     }
 
+    /// Gets a single line of the buffer
+    /// # Panics
+    /// A failed lock on reading the entire list
+    #[must_use]
+    pub fn line(&self, row: usize) -> Option<String> {
+        self.table.read().unwrap().line(row)
+    }
+
+    /// The amount of rows in the buffer, including the empty row after a trailing newline
+    /// # Panics
+    /// A failed lock on reading the entire list
+    #[must_use]
+    pub fn line_count(&self) -> usize {
+        self.table.read().unwrap().line_count()
+    }
+
+    /// Moves `pos` onto the text, see `Piece::clamp`
+    /// # Panics
+    /// A failed lock on reading the entire list
+    #[must_use]
+    pub fn clamp(&self, pos: CursorPos) -> CursorPos {
+        self.table.read().unwrap().clamp(pos)
+    }
+
+    /// The entire buffer as a single string, including any trailing newline
+    /// # Panics
+    /// A failed lock on reading the entire list
+    #[must_use]
+    pub fn contents(&self) -> String {
+        self.table.read().unwrap().to_string()
+    }
+
     /// Creates an iterator characters in the list
     /// # Panics
     /// A failed lock on reading the entire list
@@ -186,6 +320,14 @@ impl Text {
         self.table.read().unwrap().chars()
     }
 
+    /// Drops the parts of the buffers that aren't referenced anymore. See
+    /// `Piece::shrink_buffers`
+    /// # Panics
+    /// - Stuff got poisoned
+    pub fn shrink_buffers(&mut self) {
+        self.table.write().unwrap().shrink_buffers();
+    }
+
     /// Creates an iterator over the buffers of the table
     /// # Panics
     /// - Stuff got poisoned
@@ -193,6 +335,18 @@ impl Text {
         self.table.read().unwrap().bufs()
     }
 
+    /// The pieces from where the row `row` starts, together with the byte offset of the row
+    /// within the first of them. See `Piece::bufs_from_row`
+    /// # Panics
+    /// A failed lock on reading the entire list
+    #[must_use]
+    pub fn bufs_from_row(
+        &self,
+        row: usize,
+    ) -> Option<(usize, impl Iterator<Item = InnerTable<TableElem>>)> {
+        self.table.read().unwrap().bufs_from_row(row)
+    }
+
     /// returns an immutable reference to a given client
     #[must_use]
     pub fn client(&self, idx: usize) -> &Client {
@@ -219,7 +373,15 @@ impl Default for Text {
 
 #[cfg(test)]
 mod test {
-    use crate::Text;
+    use std::io;
+
+    use btep::{
+        s2c::{FullWriter, S2C},
+        Deserialize, Serialize, BUFFER_SIZE,
+    };
+    use futures::executor::block_on;
+
+    use crate::{LineEnding, Text};
 
     #[test]
     fn insert() {
@@ -234,6 +396,147 @@ mod test {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn contents() {
+        let text = Text::original_from_str("a\nb");
+        let trailing = Text::original_from_str("a\nb\n");
+        assert_eq!(text.contents(), "a\nb");
+        assert_eq!(trailing.contents(), "a\nb\n");
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            trailing.lines().collect::<Vec<_>>()
+        );
+
+        let mut text = Text::original_from_str("ac\n");
+        text.add_client("andy");
+        text.clients[0].enter_insert((0, 1).into());
+        text.clients[0].push_str("b\n");
+        assert_eq!(text.contents(), "ab\nc\n");
+    }
+
+    #[test]
+    fn crlf_from_reader() {
+        let text = Text::original_from_reader(&b"a\r\nb\nc\r\n"[..]).unwrap();
+        assert_eq!(text.contents(), "a\nb\nc\n");
+        assert_eq!(text.line_ending, LineEnding::CrLf);
+
+        let text = Text::original_from_reader(&b"a\nb"[..]).unwrap();
+        assert_eq!(text.contents(), "a\nb");
+        assert_eq!(text.line_ending, LineEnding::Lf);
+    }
+
+    #[test]
+    fn line_ending_is_sent() {
+        let text = Text::original_from_reader(&b"a\r\nb"[..]).unwrap();
+        let data = (&text).serialize();
+        let round_trip = block_on(Text::deserialize(&mut &data[..])).unwrap();
+        assert_eq!(round_trip.line_ending, LineEnding::CrLf);
+
+        let mut invalid = data;
+        *invalid.last_mut().unwrap() = 2;
+        let err = block_on(Text::deserialize(&mut &invalid[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reconnect_reuses_buffer() {
+        let mut text = Text::new();
+        let andy = text.add_client("andy");
+        let other = text.add_client("andy");
+        assert_ne!(andy, other);
+
+        text.client_mut(andy).enter_insert((0, 0).into());
+        text.client_mut(andy).push_str("andy");
+        assert_eq!(text.add_client("someone"), 2);
+        text.remove_client(andy);
+        assert!(text.client(andy).data.is_none());
+
+        assert_eq!(text.add_client("andy"), andy);
+        assert_eq!(text.table.read().unwrap().buffers.clients.len(), 3);
+
+        text.client_mut(andy).enter_insert((0, 4).into());
+        text.client_mut(andy).push_str("son");
+        assert_eq!(text.lines().next(), Some("andyson".into()));
+        assert_eq!(
+            text.client(andy).buffer.read().unwrap().to_string(),
+            "andyson"
+        );
+    }
+
+    #[test]
+    fn reuse_slot_of_other_user() {
+        let mut text = Text::original_from_str("ab");
+        let andy = text.add_client("andy");
+        text.add_client("son");
+        text.client_mut(andy).enter_insert((0, 1).into());
+        text.client_mut(andy).push_str("xy");
+        let old_piece = text.client(andy).data.as_ref().unwrap().slice.read().id;
+        text.remove_client(andy);
+
+        let other = text.add_client("other");
+        assert_eq!(other, andy);
+        assert_eq!(text.client(other).username, "other");
+        assert_eq!(text.table.read().unwrap().buffers.clients.len(), 2);
+        // The reused slot keeps on counting ids, so new pieces don't collide with old ones
+        text.client_mut(other).enter_insert((0, 3).into());
+        assert_ne!(
+            text.client(other).data.as_ref().unwrap().slice.read().id,
+            old_piece
+        );
+        text.client_mut(other).push_str("z");
+        assert_eq!(text.contents(), "axyzb");
+
+        // Someone joining while nobody has left gets a new slot
+        assert_eq!(text.add_client("andy"), 2);
+    }
+
+    #[test]
+    fn add_client_at() {
+        let mut server = Text::original_from_str("ab");
+        let mut peer = Text::original_from_str("ab");
+        for username in ["andy", "son"] {
+            let id = server.add_client(username);
+            peer.add_client_at(id, username);
+        }
+        server.remove_client(0);
+        peer.remove_client(0);
+        let id = server.add_client("other");
+        peer.add_client_at(id, "other");
+        assert_eq!(peer.client(0).username, "other");
+        assert_eq!(peer.table.read().unwrap().buffers.clients.len(), 2);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut text = Text::original_from_str("ab");
+        let andy = text.add_client("andy");
+        let original = text.snapshot();
+        text.client_mut(andy).enter_insert((0, 1).into());
+        text.client_mut(andy).push_str("xy");
+        let typed = text.snapshot();
+        assert_eq!(typed.contents(), "axyb");
+        text.client_mut(andy).push_str("z");
+
+        text.restore(&original).unwrap();
+        assert_eq!(text.contents(), "ab");
+        assert!(text.client(andy).data.is_none());
+        text.restore(&typed).unwrap();
+        assert_eq!(text.contents(), "axyb");
+
+        // The restored pieces can be edited like any other
+        text.client_mut(andy).enter_insert((0, 3).into());
+        text.client_mut(andy).push_str("w");
+        assert_eq!(text.contents(), "axywb");
+
+        text.restore(&original).unwrap();
+        text.shrink_buffers();
+        assert_eq!(
+            text.restore(&typed).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(text.contents(), "ab");
+    }
+
     #[test]
     fn two_clients_non_overlapping() {
         let mut text = Text::new();
@@ -321,7 +624,7 @@ mod test {
                 .unwrap()
                 .read()
                 .iter()
-                .map(|x| x.read().text.as_str().to_string())
+                .map(|x| x.read().text().as_str().to_string())
                 .collect::<Vec<_>>()
         );
 
@@ -415,4 +718,193 @@ mod test {
         assert_eq!(iter.next(), Some("txte".to_string()));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn enter_insert_at() {
+        let mut text = Text::original_from_str("ab\ncd");
+        let mut other = Text::original_from_str("ab\ncd");
+        text.add_client("");
+        other.add_client("");
+        for (pos, to_push) in [((1, 1), "x"), ((0, 0), "y"), ((3, 0), "z"), ((1, 2), "w")] {
+            let (offset, id) = text.client_mut(0).enter_insert(pos.into()).unwrap();
+            text.client_mut(0).push_str(to_push);
+            assert!(other.client_mut(0).enter_insert_at(id, offset));
+            other.client_mut(0).push_str(to_push);
+        }
+        assert_eq!(text.chars().collect::<String>(), "yab\ncxwdz");
+        assert_eq!(
+            text.chars().collect::<String>(),
+            other.chars().collect::<String>()
+        );
+        assert!(!other.client_mut(0).enter_insert_at(1000, Some(0)));
+        assert!(other.client(0).data.is_none());
+    }
+
+    #[test]
+    fn deserialize_bounds() {
+        let mut text = Text::original_from_str("ab\ncd");
+        text.add_client("andy");
+        text.client_mut(0).enter_insert((1, 1).into());
+        text.client_mut(0).push_str("x");
+        let data = (&text).serialize();
+        let round_trip = block_on(Text::deserialize(&mut &data[..])).unwrap();
+        assert_eq!(round_trip.contents(), "ab\ncxd");
+
+        // The client count comes right after the piece table
+        let piece_len = (&*text.table.read().unwrap()).serialize().len();
+        let mut too_many_clients = data[..piece_len].to_vec();
+        too_many_clients.extend(u64::MAX.to_be_bytes());
+        let err = block_on(Text::deserialize(&mut &too_many_clients[..])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert!(block_on(Text::deserialize(&mut &data[..piece_len - 1])).is_err());
+    }
+
+    #[test]
+    fn write_full() {
+        let mut text = Text::original_from_str(&"ab\ncd\n".repeat(BUFFER_SIZE));
+        text.add_client("andy");
+        text.add_client("son");
+        text.client_mut(0).enter_insert((1, 1).into());
+        text.client_mut(0).push_str(&"x".repeat(BUFFER_SIZE));
+        text.client_mut(1).enter_insert((3, 0).into());
+        text.client_mut(1).push_str("y");
+
+        let mut written = Vec::new();
+        block_on(async {
+            let mut writer = FullWriter::start(&mut written, true, false).await?;
+            text.write_full(&mut writer).await?;
+            writer.finish().await
+        })
+        .unwrap();
+        assert_eq!(written, S2C::Full((&text, true)).serialize());
+    }
+
+    #[test]
+    fn backspace_across_newline() {
+        // `origin` is where andy is editing, and `other` is where son is editing
+        let mut origin = Text::original_from_str("ab\ncd");
+        let mut other = Text::original_from_str("ab\ncd");
+        for text in [&mut origin, &mut other] {
+            text.add_client("andy");
+            text.add_client("son");
+        }
+        let (offset, id) = other.client_mut(1).enter_insert((0, 2).into()).unwrap();
+        assert!(origin.client_mut(1).enter_insert_at(id, offset));
+        for text in [&mut origin, &mut other] {
+            text.client_mut(1).push_str("x");
+        }
+
+        let (offset, id) = origin.client_mut(0).enter_insert((1, 0).into()).unwrap();
+        assert!(other.client_mut(0).enter_insert_at(id, offset));
+        let (deleted, swaps) = origin.client_mut(0).backspace();
+        assert_eq!(deleted, Some('\n'));
+        assert_eq!(other.client_mut(0).backspace_with_swaps(swaps), Some('\n'));
+        // Someone joining now has to agree with both of them as well
+        let data = (&origin).serialize();
+        let mut joined = block_on(Text::deserialize(&mut &data[..])).unwrap();
+        for text in [&mut origin, &mut other, &mut joined] {
+            text.client_mut(0).push_str("y");
+            text.client_mut(1).push_str("z");
+            assert_eq!(text.lines().collect::<Vec<_>>(), ["abxzycd"]);
+        }
+        assert_eq!((&origin).serialize(), (&other).serialize());
+        assert_eq!((&origin).serialize(), (&joined).serialize());
+
+        // andy entering insert mode right in front of son leaves an empty piece to swap past
+        let (offset, id) = origin.client_mut(0).enter_insert((0, 6).into()).unwrap();
+        assert!(other.client_mut(0).enter_insert_at(id, offset));
+        let (offset, id) = other.client_mut(1).enter_insert((0, 6).into()).unwrap();
+        assert!(origin.client_mut(1).enter_insert_at(id, offset));
+        let (deleted, swaps) = other.client_mut(1).backspace();
+        assert_eq!((deleted, swaps), (Some('c'), 1));
+        assert_eq!(origin.client_mut(1).backspace_with_swaps(swaps), Some('c'));
+        for text in [&mut origin, &mut other] {
+            text.client_mut(1).push_str("\n");
+            text.client_mut(0).push_str("w");
+            assert_eq!(text.lines().collect::<Vec<_>>(), ["abxzy", "wd"]);
+        }
+        assert_eq!((&origin).serialize(), (&other).serialize());
+    }
+
+    #[test]
+    fn simultaneous_enter_insert() {
+        for (son_pos, expected) in [((0, 1), "axybc"), ((0, 2), "axbyc")] {
+            for andy_first in [true, false] {
+                // The server and the texts of andy and son, who each enter insert mode before
+                // they hear about the other doing so
+                let mut texts: [Text; 3] = std::array::from_fn(|_| Text::original_from_str("abc"));
+                for text in &mut texts {
+                    text.add_client("andy");
+                    text.add_client("son");
+                }
+                let [server, andy, son] = &mut texts;
+                let (offset, id) = andy.client_mut(0).enter_insert((0, 1).into()).unwrap();
+                let andy_insert = (id, offset);
+                let (offset, id) = son.client_mut(1).enter_insert(son_pos.into()).unwrap();
+                let son_insert = (id, offset);
+
+                let order = if andy_first { [0, 1] } else { [1, 0] };
+                for client in order {
+                    let (id, offset) = [andy_insert, son_insert][client];
+                    assert!(server.client_mut(client).enter_insert_at(id, offset));
+                }
+                assert!(andy
+                    .client_mut(1)
+                    .enter_insert_at(son_insert.0, son_insert.1));
+                assert!(son
+                    .client_mut(0)
+                    .enter_insert_at(andy_insert.0, andy_insert.1));
+
+                for text in &mut texts {
+                    text.client_mut(0).push_str("x");
+                    text.client_mut(1).push_str("y");
+                    assert_eq!(text.lines().collect::<Vec<_>>(), [expected], "{andy_first}");
+                }
+                let [server, andy, son] = &texts;
+                assert_eq!(server.serialize(), andy.serialize());
+                assert_eq!(server.serialize(), son.serialize());
+            }
+        }
+    }
+
+    #[test]
+    fn insert_out_of_bounds() {
+        let mut text = Text::new();
+        text.add_client("andy");
+        assert_eq!(text.clamp((5, 0).into()), (0, 0).into());
+        text.client_mut(0).enter_insert((5, 0).into());
+        text.client_mut(0).push_str("x");
+        assert_eq!(text.contents(), "x");
+
+        let mut text = Text::original_from_str("ab\ncd\n");
+        text.add_client("andy");
+        assert_eq!(text.clamp((1, 7).into()), (1, 2).into());
+        assert_eq!(text.clamp((7, 1).into()), (2, 0).into());
+        text.client_mut(0).enter_insert((1, 7).into());
+        text.client_mut(0).push_str("x");
+        assert_eq!(text.contents(), "ab\ncdx\n");
+    }
+
+    #[test]
+    fn reenter_insert_without_typing() {
+        let mut text = Text::original_from_str("abc");
+        text.add_client("andy");
+        for _ in 0..2 {
+            text.client_mut(0).enter_insert((0, 1).into());
+            text.client_mut(0).exit_insert();
+        }
+        text.client_mut(0).enter_insert((0, 1).into());
+        // The empty pieces left behind can't be told apart by their text
+        let data = (&text).serialize();
+        let mut joined = block_on(Text::deserialize(&mut &data[..])).unwrap();
+        for text in [&mut text, &mut joined] {
+            text.client_mut(0).push_str("xy");
+            assert_eq!(text.client_mut(0).backspace(), (Some('y'), 0));
+            text.client_mut(0).push_str("z");
+        }
+        assert_eq!(joined.contents(), "axzbc");
+        assert_eq!(text.contents(), "axzbc");
+        assert_eq!(text.client(0).buffer.read().unwrap().to_string(), "xyz");
+    }
 }
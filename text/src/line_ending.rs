@@ -0,0 +1,86 @@
+//! Detection of the line endings used by a file
+use std::{borrow::Cow, io};
+
+use btep::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+/// The characters that end a line in a file.
+/// The text itself always uses `\n`, this is only used when reading and writing files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// `\n`, used by pretty much everything but Windows
+    #[default]
+    Lf,
+    /// `\r\n`, used by Windows
+    CrLf,
+}
+
+impl LineEnding {
+    /// Finds the line ending used by most lines in `text`.
+    /// Ties, and text without any newlines, count as `Lf`
+    #[must_use]
+    pub fn detect(text: &str) -> Self {
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count() - crlf;
+        if crlf > lf {
+            Self::CrLf
+        } else {
+            Self::Lf
+        }
+    }
+
+    /// Replaces every `\n` in `text` with this line ending
+    #[must_use]
+    pub fn apply(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Lf => Cow::Borrowed(text),
+            Self::CrLf => Cow::Owned(text.replace('\n', "\r\n")),
+        }
+    }
+}
+
+impl Serialize for LineEnding {
+    fn serialize(&self) -> Vec<u8> {
+        vec![match self {
+            Self::Lf => 0,
+            Self::CrLf => 1,
+        }]
+    }
+}
+
+impl Deserialize for LineEnding {
+    async fn deserialize<D>(data: &mut D) -> io::Result<Self>
+    where
+        D: AsyncReadExt + Unpin + Send,
+    {
+        match data.read_u8().await? {
+            0 => Ok(Self::Lf),
+            1 => Ok(Self::CrLf),
+            x => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("An invalid line ending was found ({x})"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LineEnding;
+
+    #[test]
+    fn detect() {
+        assert_eq!(LineEnding::detect(""), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::CrLf);
+        assert_eq!(LineEnding::detect("a\r\nb\nc\r\n"), LineEnding::CrLf);
+        assert_eq!(LineEnding::detect("a\r\nb\nc\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\n"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn apply() {
+        assert_eq!(LineEnding::Lf.apply("a\nb\n"), "a\nb\n");
+        assert_eq!(LineEnding::CrLf.apply("a\nb\n"), "a\r\nb\r\n");
+    }
+}
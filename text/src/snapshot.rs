@@ -0,0 +1,120 @@
+//! Checkpoints of a `Text` which it can be rolled back to. As the buffers only ever grow, a
+//! snapshot only has to remember which parts of them the pieces pointed at
+use std::io;
+
+use append_only_str::slices::StrSlice;
+use piece_table::{table::InnerTable, Piece, TableElem};
+
+use crate::Text;
+
+/// The pieces of a `Text` at some point. See `Text::snapshot`
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The buffer, id, offset and text of every piece
+    pieces: Vec<(Option<usize>, usize, usize, StrSlice)>,
+    /// The length of the original buffer, followed by the lengths of the client buffers
+    lengths: Vec<usize>,
+}
+
+impl Snapshot {
+    /// The text at the time the snapshot was taken
+    #[must_use]
+    pub fn contents(&self) -> String {
+        self.pieces.iter().map(|(.., text)| &**text).collect()
+    }
+}
+
+impl Text {
+    /// Takes a snapshot of the pieces, which the text can be rolled back to with `restore`
+    /// # Panics
+    /// A failed lock on reading the entire list
+    #[must_use]
+    pub fn snapshot(&self) -> Snapshot {
+        let table = self.table.read().unwrap();
+        let pieces = table
+            .bufs()
+            .map(|x| {
+                let x = x.read();
+                (x.buf.map(|(buf, _)| buf), x.id, x.offset, x.text().clone())
+            })
+            .collect();
+        Snapshot {
+            pieces,
+            lengths: buffer_lengths(&table),
+        }
+    }
+
+    /// Rolls the text back to `snapshot`. Every client leaves insert mode, as the pieces they
+    /// were editing are gone. The text of clients other than this one isn't told about it, so
+    /// this is meant for texts that aren't shared
+    /// # Errors
+    /// - The snapshot wasn't taken of this text, or the buffers have been shrunk since, so its
+    ///   pieces don't point at the same text anymore
+    /// # Panics
+    /// Stuff got poisoned
+    pub fn restore(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        for client in &mut self.clients {
+            client.exit_insert();
+        }
+        let table = self.table.read().unwrap();
+        let moved = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The text the snapshot points at has moved",
+            )
+        };
+        // Buffers only grow unless they are shrunk
+        let lengths = buffer_lengths(&table);
+        if snapshot.lengths.len() > lengths.len()
+            || snapshot
+                .lengths
+                .iter()
+                .zip(&lengths)
+                .any(|(old, new)| old > new)
+        {
+            return Err(moved());
+        }
+        let mut pieces = Vec::with_capacity(snapshot.pieces.len());
+        for (buf, id, offset, text) in &snapshot.pieces {
+            let range = text.start()..text.end();
+            let current = if let Some(buf) = buf {
+                table.buffers.clients[*buf]
+                    .1
+                    .read()
+                    .unwrap()
+                    .str_slice(range)
+            } else {
+                table.buffers.original.1.str_slice(range)
+            }
+            .filter(|current| **current == **text)
+            .ok_or_else(moved)?;
+            let mut elem = TableElem::new(buf.map(|buf| (buf, false)), *id, current);
+            elem.offset = *offset;
+            pieces.push(elem);
+        }
+
+        let binding = table
+            .piece_table
+            .write_full()
+            .expect("The entire piece table is poisoned");
+        let state = table.piece_table.state();
+        *binding.write() = pieces
+            .into_iter()
+            .map(|x| InnerTable::new(x, state.clone()))
+            .collect();
+        Ok(())
+    }
+}
+
+/// The length of the original buffer, followed by the lengths of the client buffers
+fn buffer_lengths(piece: &Piece) -> Vec<usize> {
+    std::iter::once(piece.buffers.original.1.len())
+        .chain(
+            piece
+                .buffers
+                .clients
+                .iter()
+                .map(|(_, buffer)| buffer.read().unwrap().len()),
+        )
+        .collect()
+}
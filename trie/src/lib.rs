@@ -1,4 +1,7 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{
+    collections::{hash_map, HashMap},
+    hash::Hash,
+};
 
 #[derive(Debug)]
 pub struct Trie<K, V>
@@ -90,7 +93,40 @@ where
         !curr.nodes.is_empty()
     }
 
-    /// Removes a key from the trie and returns it
+    /// Finds every value that is stored below `prefix`, which is what can follow after `prefix`
+    /// has been typed. The value at `prefix` itself isn't included.
+    /// The returned keys are relative to `prefix`, and aren't in any particular order
+    pub fn completions<I>(&self, prefix: I) -> Vec<(Vec<K>, &V)>
+    where
+        I: IntoIterator<Item = K>,
+        K: Eq + Clone,
+    {
+        let mut iter = prefix.into_iter();
+        let nodes = if let Some(next) = iter.next() {
+            let Some(mut curr) = self.nodes.get(&next) else {
+                return Vec::new();
+            };
+            for elem in iter {
+                let Some(binding) = curr.nodes.get(&elem) else {
+                    return Vec::new();
+                };
+                curr = binding;
+            }
+            &curr.nodes
+        } else {
+            &self.nodes
+        };
+        Iter::new(nodes).collect()
+    }
+
+    /// Iterates over every key and value stored in the trie, in no particular order
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.nodes)
+    }
+
+    /// Removes a key from the trie and returns it.
+    /// Every node on the way to the key which is left without both a value and children gets
+    /// dropped, so removing keys doesn't leave empty chains of nodes behind
     pub fn remove<I>(&mut self, key: I) -> Option<V>
     where
         I: IntoIterator<Item = K>,
@@ -117,6 +153,63 @@ where
     }
 }
 
+/// An iterator over the keys and values of a `Trie`. The keys are built up while walking down
+/// the trie, so every item owns its key
+pub struct Iter<'a, K, V>
+where
+    K: Hash,
+{
+    /// The keys leading to the node whose children are iterated over by the top of `stack`
+    path: Vec<K>,
+    stack: Vec<hash_map::Iter<'a, K, TrieChild<K, V>>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V>
+where
+    K: Hash,
+{
+    fn new(nodes: &'a HashMap<K, TrieChild<K, V>>) -> Self {
+        Self {
+            path: Vec::new(),
+            stack: vec![nodes.iter()],
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V>
+where
+    K: Hash + Clone,
+{
+    type Item = (Vec<K>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((key, child)) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                self.path.pop();
+                continue;
+            };
+            self.path.push(key.clone());
+            self.stack.push(child.nodes.iter());
+            if let Some(value) = &child.value {
+                return Some((self.path.clone(), value));
+            }
+        }
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a Trie<K, V>
+where
+    K: Hash + Clone,
+{
+    type Item = (Vec<K>, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 #[derive(Debug)]
 pub struct TrieChild<K, V>
 where
@@ -209,4 +302,71 @@ mod test {
         assert_eq!(trie.remove([1, 2]), None);
         assert_eq!(trie.remove([1, 2, 3]), Some(()));
     }
+
+    #[test]
+    fn remove_prunes_chain() {
+        let mut trie = Trie::new();
+        trie.insert([1, 2, 3, 4], ());
+        assert_eq!(trie.remove([1, 2, 3, 4]), Some(()));
+        assert!(!trie.exists_child([1]));
+        assert!(!trie.exists_child([]));
+
+        trie.insert([1, 2, 3, 4], ());
+        trie.insert([1, 5], ());
+        assert_eq!(trie.remove([1, 2, 3, 4]), Some(()));
+        assert!(!trie.exists_child([1, 2]));
+        assert!(trie.exists_child([1]));
+        assert_eq!(trie.get([1, 5]), Some((&(), true)));
+    }
+
+    #[test]
+    fn completions() {
+        let trie = Trie::from([
+            ("ab".chars(), 1),
+            ("abc".chars(), 2),
+            ("abd".chars(), 3),
+            ("b".chars(), 4),
+        ]);
+        let sorted = |completions: Vec<(Vec<char>, &i32)>| {
+            let mut ret = completions
+                .into_iter()
+                .map(|(key, value)| (key.into_iter().collect::<String>(), *value))
+                .collect::<Vec<_>>();
+            ret.sort();
+            ret
+        };
+        assert_eq!(
+            sorted(trie.completions("".chars())),
+            [
+                ("ab".into(), 1),
+                ("abc".into(), 2),
+                ("abd".into(), 3),
+                ("b".into(), 4)
+            ]
+        );
+        assert_eq!(
+            sorted(trie.completions("ab".chars())),
+            [("c".into(), 2), ("d".into(), 3)]
+        );
+        assert_eq!(trie.completions("abc".chars()), []);
+        assert_eq!(trie.completions("x".chars()), []);
+    }
+
+    #[test]
+    fn iter() {
+        let mut trie = Trie::from([(vec![1, 2], 'a'), (vec![1, 2, 3], 'b'), (vec![4], 'c')]);
+        let mut all = trie
+            .iter()
+            .map(|(key, value)| (key, *value))
+            .collect::<Vec<_>>();
+        all.sort_unstable();
+        assert_eq!(
+            all,
+            [(vec![1, 2], 'a'), (vec![1, 2, 3], 'b'), (vec![4], 'c')]
+        );
+
+        trie.remove([1, 2, 3]);
+        assert_eq!((&trie).into_iter().count(), 2);
+        assert_eq!(Trie::<u8, ()>::new().iter().count(), 0);
+    }
 }
@@ -8,7 +8,13 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 pub trait BufReaderExt {
     /// Reads from a buffer until something that isn't utf-8 compliant is found.
     /// Errors are ill-defined for overlong-encoded stuff
-    /// Returns none if the buffer was read to completion
+    /// # Returns
+    /// - The byte that can't start a utf-8 sequence, which is usually used as a delimiter
+    /// - `None` if the buffer was read to completion
+    /// # Errors
+    /// - Failing to read from the buffer
+    /// - A multi-byte sequence is malformed or cut off by the end of the buffer. The error message
+    ///   contains the offending bytes
     fn read_valid_str(
         &mut self,
         buffer: &mut String,
@@ -39,14 +45,27 @@ where
 
             let mut buf = [0; 4];
             buf[0] = first;
-            for x in buf.iter_mut().take(byte_width).skip(1) {
-                *x = self.read_u8().await?;
+            for read in 1..byte_width {
+                buf[read] = match self.read_u8().await {
+                    Ok(x) => x,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Truncated utf-8 sequence {:x?}", &buf[..read]),
+                        ));
+                    }
+                    Err(e) => return Err(e),
+                };
             }
             let utf_slice = &buf[..byte_width];
             match str::from_utf8(utf_slice) {
                 Ok(x) => buffer.push_str(x),
-                // FIXME: This api should honestly be rewritter from scratch
-                Err(_) => panic!("Multiple incorrect bytes instead of one: {utf_slice:?}"),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid utf-8 sequence {utf_slice:x?}"),
+                    ))
+                }
             }
         }
     }
@@ -76,4 +95,31 @@ mod test {
         assert_eq!(&buf, "andy");
         assert_eq!(blocking, Some(0xff));
     }
+
+    #[test]
+    fn invalid_multibyte() {
+        let mut reader = BufReader::new(&b"an\xe2\x28\xa1"[..]);
+        let mut buf = String::new();
+        let err = block_on(reader.read_valid_str(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lone_continuation() {
+        let mut reader = BufReader::new(&b"an\x80dy"[..]);
+        let mut buf = String::new();
+        let blocking = block_on(reader.read_valid_str(&mut buf)).unwrap();
+        assert_eq!(&buf, "an");
+        assert_eq!(blocking, Some(0x80));
+    }
+
+    #[test]
+    fn truncated_multibyte() {
+        let mut reader = BufReader::new(&b"an\xe2\x82"[..]);
+        let mut buf = String::new();
+        let err = block_on(reader.read_valid_str(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("[e2, 82]"));
+        assert_eq!(&buf, "an");
+    }
 }
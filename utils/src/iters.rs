@@ -54,6 +54,7 @@ where
 }
 
 /// An iterator type granting fixed sized slices.
+/// Items that don't fill a whole chunk at the end can be recovered with `into_remainder`
 ///
 /// # Example
 /// ```
@@ -72,6 +73,27 @@ where
     I::Item: Copy,
 {
     iter: I,
+    remainder: Vec<I::Item>,
+}
+
+impl<I, const N: usize> Chunks<I, N>
+where
+    I: Iterator,
+    I::Item: Copy,
+{
+    /// Returns the items that were left over after the last full chunk.
+    /// This is only filled once `next` has returned `None`
+    ///
+    /// # Example
+    /// ```
+    /// # use utils::iters::IteratorExt;
+    /// let mut iter = (0..5).chunks::<2>();
+    /// assert_eq!(iter.by_ref().count(), 2);
+    /// assert_eq!(iter.into_remainder(), vec![4]);
+    /// ```
+    pub fn into_remainder(self) -> Vec<I::Item> {
+        self.remainder
+    }
 }
 
 impl<T, const N: usize> Iterator for Chunks<T, N>
@@ -83,9 +105,17 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut ret = [MaybeUninit::<T::Item>::uninit(); N];
-        let mut ret_iter = ret.iter_mut();
-        for _ in 0..N {
-            ret_iter.next()?.write(self.iter.next()?);
+        let mut written = 0;
+        while written < N {
+            let Some(item) = self.iter.next() else {
+                // Safety:
+                // Every item before `written` has been written to
+                self.remainder
+                    .extend(ret[..written].iter().map(|x| unsafe { x.assume_init() }));
+                return None;
+            };
+            ret[written].write(item);
+            written += 1;
         }
         Some(
             // Safety:
@@ -96,6 +126,44 @@ where
     }
 }
 
+/// An iterator grouping consecutive items with the same key into runs.
+///
+/// # Example
+/// ```
+/// # use utils::iters::IteratorExt;
+/// let mut iter = [1, 1, 2, 3, 3].into_iter().group_runs(|x| *x);
+/// assert_eq!(iter.next(), Some(vec![1, 1]));
+/// assert_eq!(iter.next(), Some(vec![2]));
+/// assert_eq!(iter.next(), Some(vec![3, 3]));
+/// assert_eq!(iter.next(), None);
+/// ```
+pub struct GroupRuns<I, F>
+where
+    I: Iterator,
+{
+    iter: Peekable<I>,
+    key: F,
+}
+
+impl<I, F, K> Iterator for GroupRuns<I, F>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> K,
+    K: PartialEq,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.iter.next()?;
+        let key = (self.key)(&first);
+        let mut run = vec![first];
+        while let Some(item) = self.iter.next_if(|x| (self.key)(x) == key) {
+            run.push(item);
+        }
+        Some(run)
+    }
+}
+
 /// `InnerIteratorExt` is a trait which exposes trait bounds on the inner iterator. This is used
 /// for methods that require a wrapping iterator
 pub trait InnerIteratorExt<T>: Iterator + Sized
@@ -143,6 +211,13 @@ pub trait IteratorExt: Iterator + Sized {
     fn chunks<const N: usize>(self) -> Chunks<Self, N>
     where
         Self::Item: Copy;
+
+    /// Groups consecutive items for which `key` returns the same value.
+    /// See `GroupRuns` for more
+    fn group_runs<F, K>(self, key: F) -> GroupRuns<Self, F>
+    where
+        F: FnMut(&Self::Item) -> K,
+        K: PartialEq;
 }
 
 impl<T> IteratorExt for T
@@ -153,7 +228,21 @@ where
     where
         Self::Item: Copy,
     {
-        Chunks { iter: self }
+        Chunks {
+            iter: self,
+            remainder: Vec::new(),
+        }
+    }
+
+    fn group_runs<F, K>(self, key: F) -> GroupRuns<Self, F>
+    where
+        F: FnMut(&Self::Item) -> K,
+        K: PartialEq,
+    {
+        GroupRuns {
+            iter: self.peekable(),
+            key,
+        }
     }
 }
 
@@ -190,6 +279,34 @@ mod test {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn chunks_remainder() {
+        let mut iter = (0..5).chunks::<2>();
+        assert_eq!(iter.next(), Some([0, 1]));
+        assert_eq!(iter.next(), Some([2, 3]));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.into_remainder(), vec![4]);
+
+        let mut iter = (0..4).chunks::<2>();
+        assert_eq!(iter.by_ref().count(), 2);
+        assert_eq!(iter.into_remainder(), Vec::new());
+    }
+
+    #[test]
+    fn group_runs() {
+        let runs = [1, 1, 2, 2, 2, 3].into_iter().group_runs(|x| *x);
+        assert_eq!(
+            runs.collect::<Vec<_>>(),
+            vec![vec![1, 1], vec![2, 2, 2], vec![3]]
+        );
+
+        let mut runs = "ab12c".chars().group_runs(char::is_ascii_digit);
+        assert_eq!(runs.next(), Some(vec!['a', 'b']));
+        assert_eq!(runs.next(), Some(vec!['1', '2']));
+        assert_eq!(runs.next(), Some(vec!['c']));
+        assert_eq!(runs.next(), None);
+    }
+
     #[test]
     fn exact_size_chunk() {
         assert_ne!((0..24).chunks::<24>().next(), None);
@@ -20,12 +20,14 @@ impl AutoIncrementing {
         Self { counter: start }
     }
 
-    /// Gets the underlying value and increments self
+    /// Gets the underlying value and increments self.
+    /// # Returns
+    /// None once the counter has reached `usize::MAX`, so that no value is handed out twice
     #[must_use]
-    pub fn get(&mut self) -> usize {
+    pub fn get(&mut self) -> Option<usize> {
         let ret = self.counter;
-        self.counter += 1;
-        ret
+        self.counter = self.counter.checked_add(1)?;
+        Some(ret)
     }
 
     /// Gets the underlying value without incrementing
@@ -33,6 +35,16 @@ impl AutoIncrementing {
     pub const fn peek(&self) -> usize {
         self.counter
     }
+
+    /// Sets the counter back to 0
+    pub fn reset(&mut self) {
+        self.set(0);
+    }
+
+    /// Sets the counter, so that `n` is the next value handed out by `get`
+    pub fn set(&mut self, n: usize) {
+        self.counter = n;
+    }
 }
 
 /// `CursorPos` is effectively an (x, y) tuple.
@@ -44,6 +56,37 @@ pub struct CursorPos {
     pub col: usize,
 }
 
+impl CursorPos {
+    /// Moves the position by `(row, col)`, stopping at 0 instead of panicking
+    #[must_use]
+    pub const fn saturating_add(self, (row, col): (isize, isize)) -> Self {
+        Self {
+            row: self.row.saturating_add_signed(row),
+            col: self.col.saturating_add_signed(col),
+        }
+    }
+
+    /// Subtracts `rhs` from the position, stopping at 0 instead of panicking
+    #[must_use]
+    pub const fn saturating_sub(self, rhs: Self) -> Self {
+        Self {
+            row: self.row.saturating_sub(rhs.row),
+            col: self.col.saturating_sub(rhs.col),
+        }
+    }
+
+    /// Moves the position onto the closest char in a text whose lines have the lengths in
+    /// `line_lengths`. On empty lines, and without any lines, the column becomes 0
+    #[must_use]
+    pub fn clamp_to(self, line_lengths: &[usize]) -> Self {
+        let row = self.row.min(line_lengths.len().saturating_sub(1));
+        let col = line_lengths
+            .get(row)
+            .map_or(0, |len| self.col.min(len.saturating_sub(1)));
+        Self { row, col }
+    }
+}
+
 impl From<(usize, usize)> for CursorPos {
     fn from((row, col): (usize, usize)) -> Self {
         Self { row, col }
@@ -91,14 +134,52 @@ impl From<CursorPos> for (usize, usize) {
 
 #[cfg(test)]
 mod test {
-    use super::AutoIncrementing;
+    use super::{AutoIncrementing, CursorPos};
 
     #[test]
     fn autoincrement() {
         let mut incrementing = AutoIncrementing::new();
-        assert_eq!(incrementing.get(), 0);
-        assert_eq!(incrementing.get(), 1);
-        assert_eq!(incrementing.get(), 2);
-        assert_eq!(incrementing.get(), 3);
+        assert_eq!(incrementing.get(), Some(0));
+        assert_eq!(incrementing.get(), Some(1));
+        assert_eq!(incrementing.get(), Some(2));
+        assert_eq!(incrementing.get(), Some(3));
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut incrementing = AutoIncrementing::new_with_start(usize::MAX - 1);
+        assert_eq!(incrementing.get(), Some(usize::MAX - 1));
+        assert_eq!(incrementing.get(), None);
+        assert_eq!(incrementing.peek(), usize::MAX);
+        assert_eq!(incrementing.get(), None);
+    }
+
+    #[test]
+    fn reset_and_set() {
+        let mut incrementing = AutoIncrementing::new();
+        assert_eq!(incrementing.get(), Some(0));
+        incrementing.set(10);
+        assert_eq!(incrementing.get(), Some(10));
+        incrementing.reset();
+        assert_eq!(incrementing.peek(), 0);
+        assert_eq!(incrementing.get(), Some(0));
+    }
+
+    #[test]
+    fn saturating_cursor() {
+        let pos = CursorPos::from((1, 2));
+        assert_eq!(pos.saturating_add((0, -3)), (1, 0).into());
+        assert_eq!(pos.saturating_add((-2, 1)), (0, 3).into());
+        assert_eq!(pos.saturating_sub((3, 1).into()), (0, 1).into());
+    }
+
+    #[test]
+    fn clamp_cursor() {
+        let lines = [5, 0, 3];
+        assert_eq!(CursorPos::from((0, 10)).clamp_to(&lines), (0, 4).into());
+        assert_eq!(CursorPos::from((1, 2)).clamp_to(&lines), (1, 0).into());
+        assert_eq!(CursorPos::from((7, 2)).clamp_to(&lines), (2, 2).into());
+        assert_eq!(CursorPos::from((7, 9)).clamp_to(&lines), (2, 2).into());
+        assert_eq!(CursorPos::from((3, 3)).clamp_to(&[]), (0, 0).into());
     }
 }